@@ -0,0 +1,2 @@
+ALTER TABLE events ADD COLUMN redacts TEXT;
+ALTER TABLE events ADD COLUMN redacted_by TEXT;
@@ -0,0 +1,10 @@
+CREATE TABLE receipts (
+    room_id TEXT NOT NULL,
+    user_id TEXT NOT NULL,
+    receipt_type TEXT NOT NULL,
+    event_id TEXT NOT NULL,
+    event_ordering BIGINT NOT NULL,
+    ts BIGINT NOT NULL,
+    ordering BIGSERIAL NOT NULL,
+    PRIMARY KEY (room_id, user_id, receipt_type)
+);
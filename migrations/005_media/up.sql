@@ -0,0 +1,8 @@
+CREATE TABLE media (
+    media_id TEXT PRIMARY KEY,
+    user_id TEXT NOT NULL,
+    content_type TEXT NOT NULL,
+    upload_name TEXT,
+    content_length BIGINT NOT NULL,
+    created_at TIMESTAMP NOT NULL DEFAULT now()
+);
@@ -0,0 +1,12 @@
+CREATE TABLE push_rules (
+    user_id TEXT NOT NULL,
+    kind TEXT NOT NULL,
+    rule_id TEXT NOT NULL,
+    priority INTEGER NOT NULL,
+    is_default BOOLEAN NOT NULL,
+    enabled BOOLEAN NOT NULL,
+    conditions TEXT NOT NULL,
+    pattern TEXT,
+    actions TEXT NOT NULL,
+    PRIMARY KEY (user_id, kind, rule_id)
+);
@@ -0,0 +1,3 @@
+DROP INDEX events_topic_search_idx;
+DROP INDEX events_name_search_idx;
+DROP INDEX events_body_search_idx;
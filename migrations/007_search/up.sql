@@ -0,0 +1,9 @@
+CREATE INDEX events_body_search_idx ON events
+    USING GIN (to_tsvector('english', content::jsonb ->> 'body'))
+    WHERE event_type = 'm.room.message';
+CREATE INDEX events_name_search_idx ON events
+    USING GIN (to_tsvector('english', content::jsonb ->> 'name'))
+    WHERE event_type = 'm.room.name';
+CREATE INDEX events_topic_search_idx ON events
+    USING GIN (to_tsvector('english', content::jsonb ->> 'topic'))
+    WHERE event_type = 'm.room.topic';
@@ -0,0 +1 @@
+ALTER TABLE events ADD COLUMN transaction_id TEXT;
@@ -0,0 +1,2 @@
+ALTER TABLE events DROP COLUMN prev_content;
+ALTER TABLE events DROP COLUMN replaces_state;
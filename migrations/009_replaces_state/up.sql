@@ -0,0 +1,2 @@
+ALTER TABLE events ADD COLUMN replaces_state TEXT;
+ALTER TABLE events ADD COLUMN prev_content TEXT;
@@ -0,0 +1,4 @@
+DROP TABLE room_tag_changes;
+ALTER TABLE room_account_data DROP COLUMN ordering;
+ALTER TABLE account_data DROP COLUMN ordering;
+DROP SEQUENCE account_data_ordering_seq;
@@ -0,0 +1,15 @@
+CREATE SEQUENCE account_data_ordering_seq;
+
+ALTER TABLE account_data
+    ADD COLUMN ordering BIGINT NOT NULL DEFAULT nextval('account_data_ordering_seq');
+ALTER TABLE room_account_data
+    ADD COLUMN ordering BIGINT NOT NULL DEFAULT nextval('account_data_ordering_seq');
+
+CREATE TABLE room_tag_changes (
+    user_id TEXT NOT NULL,
+    room_id TEXT NOT NULL,
+    ordering BIGINT NOT NULL DEFAULT nextval('account_data_ordering_seq'),
+    PRIMARY KEY (user_id, room_id)
+);
+
+INSERT INTO room_tag_changes (user_id, room_id) SELECT DISTINCT user_id, room_id FROM room_tags;
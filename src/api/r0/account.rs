@@ -7,6 +7,7 @@ use iron::{Chain, Handler, IronError, IronResult, Plugin, Request, Response};
 use crate::crypto::hash_password;
 use crate::db::DB;
 use crate::error::ApiError;
+use crate::lazy_loading::LazyLoading;
 use crate::middleware::{
     AccessTokenAuth, DataTypeParam, JsonRequest, MiddlewareChain, RoomIdParam, UserIdParam,
 };
@@ -65,6 +66,7 @@ middleware_chain!(DeactivateAccount, [AccessTokenAuth]);
 impl Handler for DeactivateAccount {
     fn handle(&self, request: &mut Request<'_, '_>) -> IronResult<Response> {
         let connection = DB::from_request(request)?;
+        let lazy_loading = LazyLoading::from_request(request)?;
 
         {
             let token = request
@@ -73,6 +75,7 @@ impl Handler for DeactivateAccount {
                 .expect("AccessTokenAuth should ensure an access token");
 
             token.revoke(&connection)?;
+            lazy_loading.forget(token.id);
         }
 
         let user = request
@@ -284,7 +287,7 @@ mod tests {
             Status::Forbidden
         );
 
-        assert_eq!(test.post(&deactivate, r#"{}"#).status, Status::Forbidden);
+        assert_eq!(test.post(&deactivate, r#"{}"#).status, Status::Unauthorized);
     }
 
     #[test]
@@ -0,0 +1,311 @@
+//! Endpoint for retrieving the events around an event.
+
+use std::cmp;
+use std::convert::TryInto;
+use std::str::FromStr;
+
+use iron::status::Status;
+use iron::{Chain, Handler, IronResult, Request, Response};
+use ruma_events::collections::all::{RoomEvent, StateEvent};
+use url::Url;
+
+use crate::db::DB;
+use crate::error::ApiError;
+use crate::middleware::{AccessTokenAuth, EventIdParam, MiddlewareChain, RoomIdParam};
+use crate::models::event::Event;
+use crate::models::room_membership::RoomMembership;
+use crate::models::user::User;
+use crate::modifier::SerializableResponse;
+use crate::query::Batch;
+
+/// The number of events returned around the event when the client does not specify a limit.
+const DEFAULT_LIMIT: i64 = 10;
+
+/// The maximum number of events returned around the event, whatever limit the client asks for.
+const MAX_LIMIT: i64 = 100;
+
+/// The `/rooms/:room_id/context/:event_id` endpoint.
+#[derive(Clone, Copy, Debug)]
+pub struct EventContext;
+
+/// The body of the response for this API.
+#[derive(Debug, Serialize)]
+struct EventContextResponse {
+    /// A token to paginate backward from the earliest returned event.
+    start: String,
+    /// A token to paginate forward from the latest returned event.
+    end: String,
+    /// The events before the requested event, most recent first.
+    events_before: Vec<RoomEvent>,
+    /// The requested event.
+    event: RoomEvent,
+    /// The events after the requested event, oldest first.
+    events_after: Vec<RoomEvent>,
+    /// The state of the room at the latest returned event.
+    state: Vec<StateEvent>,
+}
+
+middleware_chain!(EventContext, [RoomIdParam, EventIdParam, AccessTokenAuth]);
+
+impl Handler for EventContext {
+    fn handle(&self, request: &mut Request<'_, '_>) -> IronResult<Response> {
+        let user = request
+            .extensions
+            .get::<User>()
+            .expect("AccessTokenAuth should ensure a user")
+            .clone();
+
+        let room_id = request
+            .extensions
+            .get::<RoomIdParam>()
+            .expect("RoomIdParam should ensure a room_id")
+            .clone();
+
+        let event_id = request
+            .extensions
+            .get::<EventIdParam>()
+            .expect("EventIdParam should ensure an EventId")
+            .clone();
+
+        let url: Url = request.url.clone().into();
+        let mut limit = DEFAULT_LIMIT;
+        for (key, value) in url.query_pairs().into_owned() {
+            if key == "limit" {
+                let requested =
+                    i64::from_str(&value).map_err(|err| ApiError::invalid_param("limit", err))?;
+
+                if requested < 0 {
+                    Err(ApiError::invalid_param("limit", "Must not be negative"))?;
+                }
+
+                limit = cmp::min(requested, MAX_LIMIT);
+            }
+        }
+
+        let connection = DB::from_request(request)?;
+
+        let visible_until = match RoomMembership::find(&connection, &room_id, &user.id)? {
+            Some(membership) => membership.visible_until(&connection)?,
+            None => None,
+        };
+
+        let until = match visible_until {
+            Some(until) => until,
+            None => Err(ApiError::unauthorized(
+                "The user is not a member of the room".to_string(),
+            ))?,
+        };
+
+        let event = match Event::find(&connection, &event_id)? {
+            Some(event) => event,
+            None => Err(ApiError::not_found(None))?,
+        };
+
+        let hidden = match until {
+            Some(until) => event.ordering > until,
+            None => false,
+        };
+
+        if event.room_id.as_ref() != Some(&room_id) || hidden {
+            Err(ApiError::not_found(None))?;
+        }
+
+        let before_limit = limit / 2;
+        let (events_before, events_after) = Event::find_context(
+            &connection,
+            &event,
+            before_limit,
+            limit - before_limit,
+            until,
+        )?;
+
+        let start = events_before.last().unwrap_or(&event).ordering - 1;
+        let end = events_after.last().unwrap_or(&event).ordering;
+
+        let state = Event::get_room_state_at(&connection, &room_id, end)?;
+
+        let response = EventContextResponse {
+            start: Batch::new(start, 0, 0, 0, 0).to_string(),
+            end: Batch::new(end, 0, 0, 0, 0).to_string(),
+            events_before: events_before
+                .into_iter()
+                .map(|event| event.for_user(&user.id).try_into())
+                .collect::<Result<_, _>>()?,
+            event: event.for_user(&user.id).try_into()?,
+            events_after: events_after
+                .into_iter()
+                .map(|event| event.for_user(&user.id).try_into())
+                .collect::<Result<_, _>>()?,
+            state: state
+                .into_iter()
+                .map(TryInto::try_into)
+                .collect::<Result<_, _>>()?,
+        };
+
+        Ok(Response::with((Status::Ok, SerializableResponse(response))))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::test::{Response, Test};
+    use iron::status::Status;
+    use serde_json::Value;
+
+    /// Get the context of an event.
+    fn get_context(
+        test: &Test,
+        access_token: &str,
+        room_id: &str,
+        event_id: &str,
+        limit: u64,
+    ) -> Response {
+        let context_path = format!(
+            "/_matrix/client/r0/rooms/{}/context/${}:ruma.test?limit={}&access_token={}",
+            room_id, event_id, limit, access_token
+        );
+
+        test.get(&context_path)
+    }
+
+    /// Return the bodies of a list of message events.
+    fn bodies(events: &Value) -> Vec<&str> {
+        events
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|event| event.pointer("/content/body").unwrap().as_str().unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn get_event_context() {
+        let test = Test::new();
+        let (alice, room_id) = test.initial_fixtures("{}");
+
+        test.send_message(&alice.token, &room_id, "1", 1);
+        test.send_message(&alice.token, &room_id, "2", 2);
+        let response = test.send_message(&alice.token, &room_id, "3", 3);
+        let event_id = response.json().get("event_id").unwrap().as_str().unwrap();
+        test.send_message(&alice.token, &room_id, "4", 4);
+        test.send_message(&alice.token, &room_id, "5", 5);
+
+        let response = get_context(&test, &alice.token, &room_id, event_id, 2);
+
+        assert_eq!(response.status, Status::Ok);
+        let json = response.json();
+        assert_eq!(
+            json.pointer("/event/content/body")
+                .unwrap()
+                .as_str()
+                .unwrap(),
+            "3"
+        );
+        assert_eq!(bodies(json.get("events_before").unwrap()), vec!["2"]);
+        assert_eq!(bodies(json.get("events_after").unwrap()), vec!["4"]);
+        assert!(json
+            .get("state")
+            .unwrap()
+            .as_array()
+            .unwrap()
+            .iter()
+            .any(|event| event.get("type").unwrap() == "m.room.create"));
+
+        let messages_path = format!(
+            "/_matrix/client/r0/rooms/{}/messages?from={}&dir=f&access_token={}",
+            room_id,
+            json.get("end").unwrap().as_str().unwrap(),
+            alice.token
+        );
+        let response = test.get(&messages_path);
+
+        assert_eq!(response.status, Status::Ok);
+        assert_eq!(bodies(response.json().get("chunk").unwrap()), vec!["5"]);
+
+        let messages_path = format!(
+            "/_matrix/client/r0/rooms/{}/messages?from={}&dir=b&limit=1&access_token={}",
+            room_id,
+            json.get("start").unwrap().as_str().unwrap(),
+            alice.token
+        );
+        let response = test.get(&messages_path);
+
+        assert_eq!(response.status, Status::Ok);
+        assert_eq!(bodies(response.json().get("chunk").unwrap()), vec!["1"]);
+    }
+
+    #[test]
+    fn get_context_after_leaving_the_room() {
+        let test = Test::new();
+        let (alice, room_id) = test.initial_fixtures(r#"{"visibility": "public"}"#);
+        let bob = test.create_user();
+
+        test.join_room(&bob.token, &room_id);
+        let response = test.send_message(&alice.token, &room_id, "Seen", 1);
+        let seen_event_id = response.json().get("event_id").unwrap().as_str().unwrap();
+        test.leave_room(&bob.token, &room_id);
+        let response = test.send_message(&alice.token, &room_id, "Unseen", 2);
+        let unseen_event_id = response.json().get("event_id").unwrap().as_str().unwrap();
+
+        let response = get_context(&test, &bob.token, &room_id, seen_event_id, 10);
+
+        assert_eq!(response.status, Status::Ok);
+        let events_after = response.json().get("events_after").unwrap();
+        assert!(events_after
+            .as_array()
+            .unwrap()
+            .iter()
+            .all(|event| event.get("type").unwrap() != "m.room.message"));
+
+        let response = get_context(&test, &bob.token, &room_id, unseen_event_id, 10);
+
+        assert_eq!(response.status, Status::NotFound);
+    }
+
+    #[test]
+    fn get_context_of_event_in_another_room() {
+        let test = Test::new();
+        let (alice, room_id) = test.initial_fixtures("{}");
+        let other_room_id = test.create_room(&alice.token);
+
+        let response = test.send_message(&alice.token, &other_room_id, "Hi", 1);
+        let event_id = response.json().get("event_id").unwrap().as_str().unwrap();
+
+        let response = get_context(&test, &alice.token, &room_id, event_id, 10);
+
+        assert_eq!(response.status, Status::NotFound);
+    }
+
+    #[test]
+    fn get_context_without_membership() {
+        let test = Test::new();
+        let (alice, room_id) = test.initial_fixtures("{}");
+        let bob = test.create_user();
+
+        let response = test.send_message(&alice.token, &room_id, "Hi", 1);
+        let event_id = response.json().get("event_id").unwrap().as_str().unwrap();
+
+        let response = get_context(&test, &bob.token, &room_id, event_id, 10);
+
+        assert_eq!(response.status, Status::Forbidden);
+    }
+
+    #[test]
+    fn forbidden_for_users_that_forgot_the_room() {
+        let test = Test::new();
+        let (alice, room_id) = test.initial_fixtures(r#"{"visibility": "public"}"#);
+        let bob = test.create_user();
+
+        assert_eq!(test.join_room(&bob.token, &room_id).status, Status::Ok);
+        let response = test.send_message(&alice.token, &room_id, "Hi", 1);
+        let event_id = response.json().get("event_id").unwrap().as_str().unwrap();
+        assert_eq!(test.leave_room(&bob.token, &room_id).status, Status::Ok);
+
+        let response = get_context(&test, &bob.token, &room_id, event_id, 10);
+        assert_eq!(response.status, Status::Ok);
+
+        assert_eq!(test.forget_room(&bob.token, &room_id).status, Status::Ok);
+        let response = get_context(&test, &bob.token, &room_id, event_id, 10);
+        assert_eq!(response.status, Status::Forbidden);
+    }
+}
@@ -1,14 +1,19 @@
-//! Endpoints for managing room aliases.
+//! Endpoints for managing room aliases and the visibility of rooms in the directory.
 
 use bodyparser;
 use iron::status::Status;
 use iron::{Chain, Handler, IronResult, Plugin, Request, Response};
+use ruma_events::EventType;
 use ruma_identifiers::RoomId;
 
+use crate::auth::AuthState;
 use crate::config::Config;
 use crate::db::DB;
 use crate::error::ApiError;
-use crate::middleware::{AccessTokenAuth, JsonRequest, MiddlewareChain, RoomAliasIdParam};
+use crate::middleware::{
+    AccessTokenAuth, JsonRequest, MiddlewareChain, RoomAliasIdParam, RoomIdParam,
+};
+use crate::models::room::{Room, RoomVisibility};
 use crate::models::room_alias::{NewRoomAlias, RoomAlias};
 use crate::models::user::User;
 use crate::modifier::{EmptyResponse, SerializableResponse};
@@ -136,6 +141,96 @@ impl Handler for PutRoomAlias {
     }
 }
 
+/// The GET `/directory/list/room/:room_id` endpoint.
+#[derive(Clone, Copy, Debug)]
+pub struct GetRoomVisibility;
+
+/// The body of the request and response for the room visibility APIs.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+struct RoomVisibilityBody {
+    /// Whether the room is visible in the directory.
+    visibility: RoomVisibility,
+}
+
+middleware_chain!(GetRoomVisibility, [RoomIdParam]);
+
+impl Handler for GetRoomVisibility {
+    fn handle(&self, request: &mut Request<'_, '_>) -> IronResult<Response> {
+        let room_id = request
+            .extensions
+            .get::<RoomIdParam>()
+            .expect("RoomIdParam should ensure a RoomId")
+            .clone();
+
+        let connection = DB::from_request(request)?;
+
+        let room = match Room::find(&connection, &room_id)? {
+            Some(room) => room,
+            None => Err(ApiError::not_found(
+                "The room was not found on this server".to_string(),
+            ))?,
+        };
+
+        let response = RoomVisibilityBody {
+            visibility: if room.public {
+                RoomVisibility::Public
+            } else {
+                RoomVisibility::Private
+            },
+        };
+
+        Ok(Response::with((Status::Ok, SerializableResponse(response))))
+    }
+}
+
+/// The PUT `/directory/list/room/:room_id` endpoint.
+#[derive(Clone, Copy, Debug)]
+pub struct PutRoomVisibility;
+
+middleware_chain!(
+    PutRoomVisibility,
+    [JsonRequest, RoomIdParam, AccessTokenAuth]
+);
+
+impl Handler for PutRoomVisibility {
+    fn handle(&self, request: &mut Request<'_, '_>) -> IronResult<Response> {
+        let visibility = match request.get::<bodyparser::Struct<RoomVisibilityBody>>() {
+            Ok(Some(req)) => req.visibility,
+            Ok(None) | Err(_) => Err(ApiError::bad_json(None))?,
+        };
+
+        let room_id = request
+            .extensions
+            .get::<RoomIdParam>()
+            .expect("RoomIdParam should ensure a RoomId")
+            .clone();
+
+        let user = request
+            .extensions
+            .get::<User>()
+            .expect("AccessTokenAuth should ensure a user")
+            .clone();
+
+        let connection = DB::from_request(request)?;
+
+        let mut room = match Room::find(&connection, &room_id)? {
+            Some(room) => room,
+            None => Err(ApiError::not_found(
+                "The room was not found on this server".to_string(),
+            ))?,
+        };
+
+        // Publishing a room in the directory requires the same power level as setting its
+        // canonical alias.
+        AuthState::load(&connection, &room_id)?
+            .authorize_state_change(&user.id, &EventType::RoomCanonicalAlias)?;
+
+        room.set_public(&connection, visibility == RoomVisibility::Public)?;
+
+        Ok(Response::with(EmptyResponse(Status::Ok)))
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use crate::test::Test;
@@ -280,4 +375,171 @@ mod tests {
             "IO_RUMA_ALIAS_TAKEN"
         );
     }
+
+    #[test]
+    fn get_room_visibility() {
+        let test = Test::new();
+        let user = test.create_user();
+        let public_room_id = test.create_public_room(&user.token);
+        let private_room_id = test.create_private_room(&user.token);
+
+        let response = test.get(&format!(
+            "/_matrix/client/r0/directory/list/room/{}",
+            public_room_id
+        ));
+
+        assert_eq!(response.status, Status::Ok);
+        assert_eq!(
+            response.json().get("visibility").unwrap().as_str().unwrap(),
+            "public"
+        );
+
+        let response = test.get(&format!(
+            "/_matrix/client/r0/directory/list/room/{}",
+            private_room_id
+        ));
+
+        assert_eq!(response.status, Status::Ok);
+        assert_eq!(
+            response.json().get("visibility").unwrap().as_str().unwrap(),
+            "private"
+        );
+    }
+
+    #[test]
+    fn get_visibility_of_unknown_room() {
+        let test = Test::new();
+
+        let response = test.get("/_matrix/client/r0/directory/list/room/!nonexistent:ruma.test");
+
+        assert_eq!(response.status, Status::NotFound);
+        assert_eq!(
+            response.json().get("errcode").unwrap().as_str().unwrap(),
+            "M_NOT_FOUND"
+        );
+    }
+
+    #[test]
+    fn put_room_visibility() {
+        let test = Test::new();
+        let user = test.create_user();
+        let room_id = test.create_private_room(&user.token);
+
+        let visibility_path = format!(
+            "/_matrix/client/r0/directory/list/room/{}?access_token={}",
+            room_id, user.token
+        );
+
+        let response = test.put(&visibility_path, r#"{"visibility": "public"}"#);
+
+        assert_eq!(response.status, Status::Ok);
+
+        let response = test.get(&visibility_path);
+
+        assert_eq!(
+            response.json().get("visibility").unwrap().as_str().unwrap(),
+            "public"
+        );
+
+        let response = test.get("/_matrix/client/r0/publicRooms");
+        let chunk = response.json().get("chunk").unwrap().as_array().unwrap();
+
+        assert_eq!(chunk.len(), 1);
+        assert_eq!(chunk[0].get("room_id").unwrap().as_str().unwrap(), room_id);
+
+        let response = test.put(&visibility_path, r#"{"visibility": "private"}"#);
+
+        assert_eq!(response.status, Status::Ok);
+
+        let response = test.get("/_matrix/client/r0/publicRooms");
+        let chunk = response.json().get("chunk").unwrap().as_array().unwrap();
+
+        assert!(chunk.is_empty());
+    }
+
+    #[test]
+    fn put_room_visibility_with_invalid_visibility() {
+        let test = Test::new();
+        let user = test.create_user();
+        let room_id = test.create_private_room(&user.token);
+
+        let visibility_path = format!(
+            "/_matrix/client/r0/directory/list/room/{}?access_token={}",
+            room_id, user.token
+        );
+
+        let response = test.put(&visibility_path, r#"{"visibility": "hidden"}"#);
+
+        assert_eq!(response.status, Status::UnprocessableEntity);
+        assert_eq!(
+            response.json().get("errcode").unwrap().as_str().unwrap(),
+            "M_BAD_JSON"
+        );
+    }
+
+    #[test]
+    fn put_room_visibility_without_power_level() {
+        let test = Test::new();
+        let alice = test.create_user();
+        let bob = test.create_user();
+        let room_id = test.create_public_room(&alice.token);
+
+        assert_eq!(test.join_room(&bob.token, &room_id).status, Status::Ok);
+
+        let power_levels = format!(
+            r#"{{
+                "ban": 50,
+                "events": {{ "m.room.canonical_alias": 50 }},
+                "events_default": 0,
+                "invite": 50,
+                "kick": 50,
+                "redact": 50,
+                "state_default": 0,
+                "users": {{ "{}": 100 }},
+                "users_default": 0
+            }}"#,
+            alice.id
+        );
+        let response =
+            test.send_state_event(&alice.token, &room_id, "m.room.power_levels", &power_levels);
+
+        assert_eq!(response.status, Status::Ok);
+
+        let visibility_path = format!(
+            "/_matrix/client/r0/directory/list/room/{}?access_token={}",
+            room_id, bob.token
+        );
+
+        let response = test.put(&visibility_path, r#"{"visibility": "private"}"#);
+
+        assert_eq!(response.status, Status::Forbidden);
+        assert_eq!(
+            response.json().get("errcode").unwrap().as_str().unwrap(),
+            "M_FORBIDDEN"
+        );
+
+        let response = test.get(&visibility_path);
+
+        assert_eq!(
+            response.json().get("visibility").unwrap().as_str().unwrap(),
+            "public"
+        );
+    }
+
+    #[test]
+    fn put_room_visibility_as_non_member() {
+        let test = Test::new();
+        let alice = test.create_user();
+        let bob = test.create_user();
+        let room_id = test.create_private_room(&alice.token);
+
+        let visibility_path = format!(
+            "/_matrix/client/r0/directory/list/room/{}?access_token={}",
+            room_id, bob.token
+        );
+
+        let response = test.put(&visibility_path, r#"{"visibility": "public"}"#);
+
+        assert_eq!(response.status, Status::Forbidden);
+    }
 }
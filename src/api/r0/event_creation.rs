@@ -3,7 +3,6 @@
 use std::convert::TryInto;
 
 use bodyparser;
-use diesel::pg::PgConnection;
 use diesel::prelude::*;
 use iron::{status, Chain, Handler, IronError, IronResult, Plugin, Request, Response};
 use router::Router;
@@ -19,27 +18,27 @@ use ruma_events::room::join_rules::JoinRulesEvent;
 use ruma_events::room::message::MessageEvent;
 use ruma_events::room::name::NameEvent;
 use ruma_events::room::power_levels::PowerLevelsEvent;
+use ruma_events::room::redaction::{RedactionEvent, RedactionEventContent};
 use ruma_events::room::third_party_invite::ThirdPartyInviteEvent;
 use ruma_events::room::topic::TopicEvent;
 use ruma_events::{CustomRoomEvent, CustomStateEvent, EventType};
-use ruma_identifiers::{EventId, RoomId};
+use ruma_identifiers::EventId;
 use serde::Deserialize;
 use serde_json::{from_str, from_value, to_string, Value};
 
+use crate::auth::authorize_event;
 use crate::config::Config;
 use crate::db::DB;
 use crate::error::{ApiError, MapApiError};
 use crate::middleware::{
-    AccessTokenAuth, EventTypeParam, JsonRequest, MiddlewareChain, RoomIdParam, TransactionIdParam,
+    AccessTokenAuth, EventIdParam, EventTypeParam, JsonRequest, MiddlewareChain, RoomIdParam,
+    TransactionIdParam,
 };
 use crate::models::access_token::AccessToken;
-use crate::models::event::NewEvent;
-use crate::models::room::Room;
-use crate::models::room_membership::RoomMembership;
+use crate::models::event::{Event, NewEvent};
 use crate::models::transaction::Transaction;
 use crate::models::user::User;
 use crate::modifier::SerializableResponse;
-use crate::schema::events;
 
 macro_rules! room_event {
     (
@@ -126,10 +125,11 @@ impl Handler for SendMessageEvent {
             .expect("EventTypeParam should ensure an EventType")
             .clone();
 
-        request
+        let transaction_id = request
             .extensions
             .get::<TransactionIdParam>()
-            .expect("TransactionIdParam should ensure a TransactionId");
+            .expect("TransactionIdParam should ensure a TransactionId")
+            .clone();
 
         let user = request
             .extensions
@@ -146,7 +146,7 @@ impl Handler for SendMessageEvent {
             ApiError::unknown("Failed to generated event ID for the new event.".to_string())
         })?;
 
-        let room_event: NewEvent = match event_type {
+        let mut room_event: NewEvent = match event_type {
             EventType::CallAnswer => room_event!(
                 AnswerEvent,
                 event_content,
@@ -208,6 +208,8 @@ impl Handler for SendMessageEvent {
             }
         };
 
+        room_event.transaction_id = Some(transaction_id);
+
         let connection = DB::from_request(request)?;
 
         let path = request.url.path().join("/");
@@ -229,12 +231,9 @@ impl Handler for SendMessageEvent {
 
         connection
             .transaction(|| {
-                verify_permissions(&connection, &room_id, &user, &event_type)?;
+                authorize_event(&connection, &room_event)?;
 
-                diesel::insert_into(events::table)
-                    .values(&room_event)
-                    .execute(&*connection)
-                    .map_err(ApiError::from)?;
+                Event::create(&connection, &room_event)?;
 
                 let serialized_response = to_string(&response).map_err(ApiError::from)?;
 
@@ -439,12 +438,9 @@ impl Handler for StateMessageEvent {
 
         connection
             .transaction(|| {
-                verify_permissions(&connection, &room_id, &user, &event_type)?;
+                authorize_event(&connection, &state_event)?;
 
-                diesel::insert_into(events::table)
-                    .values(&state_event)
-                    .execute(&*connection)
-                    .map_err(ApiError::from)
+                Event::create(&connection, &state_event)
             })
             .map_err(ApiError::from)?;
 
@@ -456,52 +452,120 @@ impl Handler for StateMessageEvent {
     }
 }
 
-/// Check if a `User` has permission to create an event in a given `Room`.
-fn verify_permissions(
-    connection: &PgConnection,
-    room_id: &RoomId,
-    user: &User,
-    event_type: &EventType,
-) -> Result<(), ApiError> {
-    let room = match Room::find(connection, room_id)? {
-        Some(room) => room,
-        None => Err(ApiError::unauthorized(
-            "The room was not found on this server".to_string(),
-        ))?,
-    };
+/// The `/rooms/:room_id/redact/:event_id/:transaction_id` endpoint.
+#[derive(Clone, Copy, Debug)]
+pub struct RedactEvent;
 
-    match RoomMembership::find(connection, room_id, &user.id)? {
-        Some(membership) => {
-            if membership.membership != "join" {
-                Err(ApiError::unauthorized(format!(
-                    "The user {} has not joined the room",
-                    user.id
-                )))?
-            }
+/// The body of the request for this API.
+#[derive(Clone, Debug, Deserialize)]
+struct RedactEventRequest {
+    /// The reason for the event being redacted.
+    reason: Option<String>,
+}
+
+middleware_chain!(
+    RedactEvent,
+    [
+        JsonRequest,
+        RoomIdParam,
+        EventIdParam,
+        TransactionIdParam,
+        AccessTokenAuth
+    ]
+);
+
+impl Handler for RedactEvent {
+    fn handle(&self, request: &mut Request<'_, '_>) -> IronResult<Response> {
+        let room_id = request
+            .extensions
+            .get::<RoomIdParam>()
+            .expect("Should have been required by RoomIdParam.")
+            .clone();
+
+        let redacts = request
+            .extensions
+            .get::<EventIdParam>()
+            .expect("EventIdParam should ensure an EventId")
+            .clone();
+
+        let transaction_id = request
+            .extensions
+            .get::<TransactionIdParam>()
+            .expect("TransactionIdParam should ensure a TransactionId")
+            .clone();
+
+        let user = request
+            .extensions
+            .get::<User>()
+            .expect("AccessTokenAuth should ensure a user")
+            .clone();
+
+        let reason = match request.get::<bodyparser::Struct<RedactEventRequest>>() {
+            Ok(Some(req)) => req.reason,
+            Ok(None) => Err(ApiError::bad_json(None))?,
+            Err(err) => Err(ApiError::bad_json(err.to_string()))?,
+        };
+
+        let config = Config::from_request(request)?;
+        let event_id = EventId::new(&config.domain).map_api_err(|_| {
+            ApiError::unknown("Failed to generated event ID for the new event.".to_string())
+        })?;
+
+        let mut redaction_event: NewEvent = RedactionEvent {
+            content: RedactionEventContent { reason },
+            event_id: event_id.clone(),
+            event_type: EventType::RoomRedaction,
+            origin_server_ts: 0,
+            redacts: redacts.clone(),
+            room_id: Some(room_id.clone()),
+            sender: user.id.clone(),
+            unsigned: None,
         }
-        None => Err(ApiError::unauthorized(format!(
-            "The user {} is not a member of the room",
-            user.id
-        )))?,
-    }
+        .try_into()?;
 
-    let power_levels = room.current_power_levels(&*connection)?;
-    let user_power_level = power_levels
-        .users
-        .get(&user.id)
-        .unwrap_or(&power_levels.users_default);
-    let required_power_level = power_levels
-        .events
-        .get(event_type)
-        .unwrap_or(&power_levels.events_default);
-
-    if required_power_level > user_power_level {
-        return Err(ApiError::unauthorized(
-            "Insufficient power level to create this event.".to_string(),
-        ));
-    }
+        redaction_event.transaction_id = Some(transaction_id);
+
+        let connection = DB::from_request(request)?;
+
+        let path = request.url.path().join("/");
+        let token = (*request
+            .extensions
+            .get::<AccessToken>()
+            .expect("AccessTokenAuth should ensure an access token"))
+        .clone();
 
-    Ok(())
+        if let Some(transaction) = Transaction::find(&connection, &path, &token.value)? {
+            let response: EventResponse =
+                from_str(&transaction.response).map_err(ApiError::from)?;
+            return Ok(Response::with((status::Ok, SerializableResponse(response))));
+        }
+
+        let response = EventResponse {
+            event_id: event_id.opaque_id().to_string(),
+        };
+
+        connection.transaction(|| {
+            authorize_event(&connection, &redaction_event)?;
+
+            let redacted_event = Event::find(&connection, &redacts)?
+                .expect("authorize_event should ensure the redacted event exists");
+
+            Event::create(&connection, &redaction_event)?;
+
+            redacted_event.redact(&connection, &event_id)?;
+
+            let serialized_response = to_string(&response).map_err(ApiError::from)?;
+
+            Transaction::create(
+                &connection,
+                path.clone(),
+                token.value.clone(),
+                serialized_response,
+            )
+        })?;
+
+        Ok(Response::with((status::Ok, SerializableResponse(response))))
+    }
 }
 
 /// Enforces an empty state key for an event type that requires it.
@@ -531,8 +595,11 @@ where
 
 #[cfg(test)]
 mod tests {
-    use crate::test::Test;
+    use crate::test::{Response, Test};
     use iron::status::Status;
+    use serde_json::json;
+
+    use crate::query::SyncOptions;
 
     #[test]
     fn create_message_event() {
@@ -689,10 +756,10 @@ mod tests {
             "kick": 100,
             "redact": 0,
             "state_default": 0,
-            "users": {{ "{}": 50 }},
+            "users": {{ "{}": 100, "{}": 50 }},
             "users_default": 0
         }}"#,
-            bob.id
+            alice.id, bob.id
         );
 
         let response = test.put(&state_event_path, &event_content);
@@ -714,10 +781,10 @@ mod tests {
             "kick": 100,
             "redact": 0,
             "state_default": 0,
-            "users": {{ "{}": 50 }},
+            "users": {{ "{}": 100, "{}": 50 }},
             "users_default": 0
         }}"#,
-            bob.id
+            alice.id, bob.id
         );
 
         // Now everyone can send messages
@@ -728,6 +795,87 @@ mod tests {
         assert_eq!(response.status, Status::Ok);
     }
 
+    #[test]
+    fn state_events_require_state_default() {
+        let test = Test::new();
+        let alice = test.create_user();
+        let bob = test.create_user();
+
+        let room_options = format!(r#"{{ "invite": [ "{}" ] }}"#, bob.id);
+        let room_id = test.create_room_with_params(&alice.token, &room_options);
+        assert_eq!(test.join_room(&bob.token, &room_id).status, Status::Ok);
+
+        let event_content = format!(
+            r#"{{
+            "events": {{ }},
+            "events_default": 0,
+            "state_default": 50,
+            "users": {{ "{}": 100 }},
+            "users_default": 0
+        }}"#,
+            alice.id
+        );
+        let response = test.send_state_event(
+            &alice.token,
+            &room_id,
+            "m.room.power_levels",
+            &event_content,
+        );
+        assert_eq!(response.status, Status::Ok);
+
+        let response =
+            test.send_state_event(&bob.token, &room_id, "m.room.topic", r#"{"topic": "Hi"}"#);
+        assert_eq!(response.status, Status::Forbidden);
+        assert_eq!(
+            response.json().get("error").unwrap().as_str().unwrap(),
+            "Insufficient power level to create this event."
+        );
+
+        let response = test.send_message(&bob.token, &room_id, "Hi", 1);
+        assert_eq!(response.status, Status::Ok);
+    }
+
+    #[test]
+    fn change_power_levels_of_higher_users() {
+        let test = Test::new();
+        let alice = test.create_user();
+        let bob = test.create_user();
+
+        let room_options = format!(r#"{{ "invite": [ "{}" ] }}"#, bob.id);
+        let room_id = test.create_room_with_params(&alice.token, &room_options);
+        assert_eq!(test.join_room(&bob.token, &room_id).status, Status::Ok);
+
+        let event_content = format!(
+            r#"{{
+            "events": {{ }},
+            "users": {{ "{}": 100, "{}": 50 }}
+        }}"#,
+            alice.id, bob.id
+        );
+        let response = test.send_state_event(
+            &alice.token,
+            &room_id,
+            "m.room.power_levels",
+            &event_content,
+        );
+        assert_eq!(response.status, Status::Ok);
+
+        let event_content = format!(
+            r#"{{
+            "events": {{ }},
+            "users": {{ "{}": 50, "{}": 50 }}
+        }}"#,
+            alice.id, bob.id
+        );
+        let response =
+            test.send_state_event(&bob.token, &room_id, "m.room.power_levels", &event_content);
+        assert_eq!(response.status, Status::Forbidden);
+        assert_eq!(
+            response.json().get("error").unwrap().as_str().unwrap(),
+            "Insufficient power level to change these power levels"
+        );
+    }
+
     #[test]
     fn create_events_with_transactions() {
         let test = Test::new();
@@ -746,4 +894,262 @@ mod tests {
         let third_event_id = response.json().get("event_id").unwrap().as_str().unwrap();
         assert_ne!(third_event_id, second_event_id);
     }
+
+    /// Redact an event, using the opaque ID returned when it was sent.
+    fn redact(
+        test: &Test,
+        access_token: &str,
+        room_id: &str,
+        event_id: &str,
+        txn_id: u64,
+    ) -> Response {
+        let redact_path = format!(
+            "/_matrix/client/r0/rooms/{}/redact/${}:ruma.test/{}?access_token={}",
+            room_id, event_id, txn_id, access_token
+        );
+
+        test.put(&redact_path, r#"{"reason": "Spam"}"#)
+    }
+
+    #[test]
+    fn redact_own_message() {
+        let test = Test::new();
+        let (alice, room_id) = test.initial_fixtures(r#"{"visibility": "public"}"#);
+
+        let response = test.send_message(&alice.token, &room_id, "Spam", 1);
+        let event_id = response.json().get("event_id").unwrap().as_str().unwrap();
+
+        let response = redact(&test, &alice.token, &room_id, event_id, 1);
+        assert_eq!(response.status, Status::Ok);
+        let redaction_id = response.json().get("event_id").unwrap().as_str().unwrap();
+
+        let options = SyncOptions {
+            filter: None,
+            since: None,
+            full_state: false,
+            set_presence: None,
+            timeout: 0,
+        };
+        let response = test.sync(&alice.token, options);
+        let events = response
+            .json()
+            .pointer(&format!("/rooms/join/{}/timeline/events", room_id))
+            .unwrap()
+            .as_array()
+            .unwrap();
+
+        let message = events
+            .iter()
+            .find(|event| event.get("type").unwrap().as_str().unwrap() == "m.room.message")
+            .unwrap();
+        assert_eq!(message.get("content").unwrap(), &json!({}));
+
+        let redaction = events.last().unwrap();
+        assert_eq!(
+            redaction.get("type").unwrap().as_str().unwrap(),
+            "m.room.redaction"
+        );
+        assert_eq!(
+            redaction.get("event_id").unwrap().as_str().unwrap(),
+            format!("${}:ruma.test", redaction_id)
+        );
+        assert_eq!(
+            redaction.get("redacts").unwrap().as_str().unwrap(),
+            format!("${}:ruma.test", event_id)
+        );
+        assert_eq!(
+            redaction
+                .pointer("/content/reason")
+                .unwrap()
+                .as_str()
+                .unwrap(),
+            "Spam"
+        );
+    }
+
+    #[test]
+    fn redact_with_transactions() {
+        let test = Test::new();
+        let (alice, room_id) = test.initial_fixtures(r#"{"visibility": "public"}"#);
+
+        let response = test.send_message(&alice.token, &room_id, "Spam", 1);
+        let event_id = response.json().get("event_id").unwrap().as_str().unwrap();
+
+        let response = redact(&test, &alice.token, &room_id, event_id, 1);
+        assert_eq!(response.status, Status::Ok);
+        let first_redaction_id = response.json().get("event_id").unwrap().as_str().unwrap();
+
+        // Using the same transaction ID.
+        let response = redact(&test, &alice.token, &room_id, event_id, 1);
+        assert_eq!(response.status, Status::Ok);
+        let second_redaction_id = response.json().get("event_id").unwrap().as_str().unwrap();
+        assert_eq!(first_redaction_id, second_redaction_id);
+    }
+
+    #[test]
+    fn redact_requires_power_level_for_events_of_other_users() {
+        let test = Test::new();
+        let (alice, room_id) = test.initial_fixtures(r#"{"visibility": "public"}"#);
+        let bob = test.create_user();
+        assert_eq!(test.join_room(&bob.token, &room_id).status, Status::Ok);
+
+        let response = test.send_message(&alice.token, &room_id, "Hi", 1);
+        let alice_event_id = response.json().get("event_id").unwrap().as_str().unwrap();
+        let response = test.send_message(&bob.token, &room_id, "Spam", 1);
+        let bob_event_id = response.json().get("event_id").unwrap().as_str().unwrap();
+
+        let response = redact(&test, &bob.token, &room_id, alice_event_id, 1);
+        assert_eq!(response.status, Status::Forbidden);
+
+        let response = redact(&test, &alice.token, &room_id, bob_event_id, 1);
+        assert_eq!(response.status, Status::Ok);
+    }
+
+    #[test]
+    fn redact_unknown_event() {
+        let test = Test::new();
+        let (alice, room_id) = test.initial_fixtures(r#"{"visibility": "public"}"#);
+
+        let response = redact(&test, &alice.token, &room_id, "unknown", 1);
+        assert_eq!(response.status, Status::NotFound);
+    }
+
+    #[test]
+    fn redact_event_from_another_room() {
+        let test = Test::new();
+        let (alice, room_id) = test.initial_fixtures(r#"{"visibility": "public"}"#);
+        let other_room_id = test.create_room(&alice.token);
+
+        let response = test.send_message(&alice.token, &other_room_id, "Hi", 1);
+        let event_id = response.json().get("event_id").unwrap().as_str().unwrap();
+
+        let response = redact(&test, &alice.token, &room_id, event_id, 1);
+        assert_eq!(response.status, Status::NotFound);
+    }
+
+    #[test]
+    fn redacted_state_keeps_allowed_keys() {
+        let test = Test::new();
+        let (alice, room_id) = test.initial_fixtures(r#"{"visibility": "public"}"#);
+
+        let room_state_path = format!(
+            "/_matrix/client/r0/rooms/{}/state?access_token={}",
+            room_id, alice.token
+        );
+        let response = test.get(&room_state_path);
+        let power_levels_event_id = response
+            .json()
+            .as_array()
+            .unwrap()
+            .iter()
+            .find(|event| event.get("type").unwrap().as_str().unwrap() == "m.room.power_levels")
+            .unwrap()
+            .get("event_id")
+            .unwrap()
+            .as_str()
+            .unwrap()
+            .to_string();
+
+        let redact_path = format!(
+            "/_matrix/client/r0/rooms/{}/redact/{}/1?access_token={}",
+            room_id, power_levels_event_id, alice.token
+        );
+        assert_eq!(test.put(&redact_path, "{}").status, Status::Ok);
+
+        let power_levels_path = format!(
+            "/_matrix/client/r0/rooms/{}/state/m.room.power_levels?access_token={}",
+            room_id, alice.token
+        );
+        let response = test.get(&power_levels_path);
+        assert_eq!(response.status, Status::Ok);
+        let content = response.json().as_object().unwrap();
+        assert!(content.contains_key("ban"));
+        assert!(content.contains_key("users"));
+        assert!(!content.contains_key("invite"));
+    }
+
+    #[test]
+    fn redacting_replaced_state_strips_previous_content() {
+        let test = Test::new();
+        let (alice, room_id) = test.initial_fixtures(r#"{"topic": "Secret"}"#);
+
+        let response = test.send_state_event(
+            &alice.token,
+            &room_id,
+            "m.room.topic",
+            r#"{"topic": "Public"}"#,
+        );
+        assert_eq!(response.status, Status::Ok);
+
+        let room_state_path = format!(
+            "/_matrix/client/r0/rooms/{}/state?access_token={}",
+            room_id, alice.token
+        );
+        let find_topic_event = |response: &Response| {
+            response
+                .json()
+                .as_array()
+                .unwrap()
+                .iter()
+                .find(|event| event.get("type").unwrap().as_str().unwrap() == "m.room.topic")
+                .unwrap()
+                .clone()
+        };
+
+        let topic_event = find_topic_event(&test.get(&room_state_path));
+        assert_eq!(
+            topic_event.pointer("/prev_content/topic").unwrap(),
+            "Secret"
+        );
+        let replaced_event_id = topic_event
+            .pointer("/unsigned/replaces_state")
+            .unwrap()
+            .as_str()
+            .unwrap();
+
+        let redact_path = format!(
+            "/_matrix/client/r0/rooms/{}/redact/{}/1?access_token={}",
+            room_id, replaced_event_id, alice.token
+        );
+        assert_eq!(test.put(&redact_path, "{}").status, Status::Ok);
+
+        let topic_event = find_topic_event(&test.get(&room_state_path));
+        assert_eq!(topic_event.pointer("/content/topic").unwrap(), "Public");
+        assert!(topic_event.get("prev_content").is_none());
+        assert_eq!(
+            topic_event.pointer("/unsigned/prev_content").unwrap(),
+            &json!({})
+        );
+    }
+
+    #[test]
+    fn redacted_message_in_messages() {
+        let test = Test::new();
+        let (alice, room_id) = test.initial_fixtures(r#"{"visibility": "public"}"#);
+
+        let response = test.send_message(&alice.token, &room_id, "Spam", 1);
+        let event_id = response.json().get("event_id").unwrap().as_str().unwrap();
+        assert_eq!(
+            redact(&test, &alice.token, &room_id, event_id, 1).status,
+            Status::Ok
+        );
+
+        let messages_path = format!(
+            "/_matrix/client/r0/rooms/{}/messages?from=0_0_0_0_0&dir=f&access_token={}",
+            room_id, alice.token
+        );
+        let response = test.get(&messages_path);
+        assert_eq!(response.status, Status::Ok);
+        let message = response
+            .json()
+            .get("chunk")
+            .unwrap()
+            .as_array()
+            .unwrap()
+            .iter()
+            .find(|event| event.get("type").unwrap().as_str().unwrap() == "m.room.message")
+            .unwrap()
+            .clone();
+        assert_eq!(message.get("content").unwrap(), &json!({}));
+    }
 }
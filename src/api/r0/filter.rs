@@ -6,6 +6,7 @@ use serde_json::{from_str, to_value};
 
 use crate::db::DB;
 use crate::error::ApiError;
+use crate::filtering::validate;
 use crate::middleware::{
     AccessTokenAuth, FilterIdParam, JsonRequest, MiddlewareChain, UserIdParam,
 };
@@ -77,6 +78,8 @@ impl Handler for PostFilter {
             Ok(None) | Err(_) => Err(ApiError::bad_json(None))?,
         };
 
+        validate(&filter).map_err(ApiError::bad_json)?;
+
         let connection = DB::from_request(request)?;
 
         let id = Filter::create(
@@ -146,4 +149,21 @@ mod tests {
         let response = test.get(&get_filter_path);
         assert_eq!(response.status, Status::NotFound);
     }
+
+    #[test]
+    fn post_invalid_filter() {
+        let test = Test::new();
+        let carl = test.create_user();
+        let filter_path = format!(
+            "/_matrix/client/r0/user/{}/filter?access_token={}",
+            carl.id, carl.token
+        );
+
+        let response = test.post(&filter_path, r#"{"event_fields": ["content\\"]}"#);
+        assert_eq!(response.status, Status::UnprocessableEntity);
+        assert_eq!(
+            response.json().get("errcode").unwrap().as_str().unwrap(),
+            "M_BAD_JSON"
+        );
+    }
 }
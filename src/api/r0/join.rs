@@ -18,6 +18,7 @@ use crate::models::room_alias::RoomAlias;
 use crate::models::room_membership::{RoomMembership, RoomMembershipOptions};
 use crate::models::user::User;
 use crate::modifier::{EmptyResponse, SerializableResponse};
+use crate::notifier::{Notifier, Topic};
 
 /// The `/rooms/:room_id/join` endpoint.
 #[derive(Clone, Copy, Debug)]
@@ -87,7 +88,12 @@ impl Handler for JoinRoomWithIdOrAlias {
             }
         };
 
-        join_room(room_id, user, &connection, &config)
+        let response = join_room(room_id.clone(), user, &connection, &config)?;
+
+        // The room is not part of the path when joining by alias.
+        Notifier::from_request(request)?.notify(vec![Topic::Room(room_id)]);
+
+        Ok(response)
     }
 }
 
@@ -103,6 +109,7 @@ fn join_room(
         user_id: user.id.clone(),
         sender: user.id,
         membership: "join".to_string(),
+        reason: None,
     };
 
     let room_membership =
@@ -143,6 +150,7 @@ impl Handler for LeaveRoom {
             user_id: user.id.clone(),
             sender: user.id.clone(),
             membership: "leave".to_string(),
+            reason: None,
         };
 
         if Room::find(&connection, &room_id)?.is_none() {
@@ -172,6 +180,45 @@ impl Handler for LeaveRoom {
     }
 }
 
+/// The `/rooms/:room_id/forget` endpoint.
+#[derive(Clone, Copy, Debug)]
+pub struct ForgetRoom;
+
+middleware_chain!(ForgetRoom, [JsonRequest, RoomIdParam, AccessTokenAuth]);
+
+impl Handler for ForgetRoom {
+    fn handle(&self, request: &mut Request<'_, '_>) -> IronResult<Response> {
+        let user = request
+            .extensions
+            .get::<User>()
+            .expect("AccessTokenAuth should ensure a user")
+            .clone();
+
+        let connection = DB::from_request(request)?;
+
+        let room_id = request
+            .extensions
+            .get::<RoomIdParam>()
+            .expect("Should have been required by RoomIdParam.")
+            .clone();
+
+        match RoomMembership::find(&connection, &room_id, &user.id)? {
+            Some(mut room_membership) => match room_membership.membership.as_str() {
+                "leave" | "ban" => {
+                    room_membership.forget(&connection)?;
+                    Ok(Response::with(EmptyResponse(Status::Ok)))
+                }
+                _ => Err(ApiError::unauthorized(
+                    "The user has to leave the room before forgetting it".to_string(),
+                ))?,
+            },
+            None => Err(ApiError::unauthorized(
+                "User not in room or uninvited".to_string(),
+            ))?,
+        }
+    }
+}
+
 /// The `/rooms/:room_id/kick` endpoint.
 #[derive(Clone, Copy, Debug)]
 pub struct KickFromRoom;
@@ -201,8 +248,8 @@ impl Handler for KickFromRoom {
             .expect("AccessTokenAuth should ensure a user")
             .clone();
 
-        let kickee_id = match request.get::<bodyparser::Struct<KickFromRoomRequest>>() {
-            Ok(Some(req)) => req.user_id,
+        let (kickee_id, reason) = match request.get::<bodyparser::Struct<KickFromRoomRequest>>() {
+            Ok(Some(req)) => (req.user_id, req.reason),
             Ok(None) => Err(ApiError::bad_json(None))?,
             Err(err) => Err(ApiError::bad_json(err.to_string()))?,
         };
@@ -210,19 +257,11 @@ impl Handler for KickFromRoom {
         let connection = DB::from_request(request)?;
         let config = Config::from_request(request)?;
 
-        let room = match Room::find(&connection, &room_id)? {
-            Some(room) => room,
-            None => Err(ApiError::unauthorized(
+        if Room::find(&connection, &room_id)?.is_none() {
+            Err(ApiError::unauthorized(
                 "The room was not found on this server".to_string(),
-            ))?,
-        };
-
-        match RoomMembership::find(&connection, &room_id, &kicker.id)? {
-            Some(ref membership) if membership.membership == "join" => {}
-            _ => Err(ApiError::unauthorized(
-                "The kicker is not currently in the room".to_string(),
-            ))?,
-        };
+            ))?;
+        }
 
         let mut kickee_membership = match RoomMembership::find(&connection, &room_id, &kickee_id)? {
             Some(ref membership) if membership.membership == "join" => membership.clone(),
@@ -231,27 +270,132 @@ impl Handler for KickFromRoom {
             ))?,
         };
 
-        let power_levels = room.current_power_levels(&connection)?;
-        let user_power_level = power_levels
-            .users
-            .get(&kicker.id)
-            .unwrap_or(&power_levels.users_default);
-
-        if power_levels.kick > *user_power_level {
-            Err(ApiError::unauthorized(
-                "Insufficient power level to kick a user".to_string(),
-            ))?;
-        }
-
         let room_membership_options = RoomMembershipOptions {
             room_id,
-            user_id: kickee_id,
+            user_id: kickee_id.clone(),
             sender: kicker.id,
             membership: "leave".to_string(),
+            reason,
         };
 
         kickee_membership.update(&connection, &config.domain, room_membership_options)?;
 
+        Notifier::from_request(request)?.notify(vec![Topic::User(kickee_id)]);
+
+        Ok(Response::with(EmptyResponse(Status::Ok)))
+    }
+}
+
+/// The `/rooms/:room_id/ban` endpoint.
+#[derive(Clone, Copy, Debug)]
+pub struct BanFromRoom;
+
+/// The body of the request for this API.
+#[derive(Clone, Debug, Deserialize)]
+struct BanFromRoomRequest {
+    /// The reason the user has been banned.
+    pub reason: Option<String>,
+    /// The fully qualified user ID of the user being banned.
+    pub user_id: UserId,
+}
+
+middleware_chain!(BanFromRoom, [JsonRequest, RoomIdParam, AccessTokenAuth]);
+
+impl Handler for BanFromRoom {
+    fn handle(&self, request: &mut Request<'_, '_>) -> IronResult<Response> {
+        let room_id = request
+            .extensions
+            .get::<RoomIdParam>()
+            .expect("RoomIdParam should ensure a room_id")
+            .clone();
+
+        let banner = request
+            .extensions
+            .get::<User>()
+            .expect("AccessTokenAuth should ensure a user")
+            .clone();
+
+        let (bannee_id, reason) = match request.get::<bodyparser::Struct<BanFromRoomRequest>>() {
+            Ok(Some(req)) => (req.user_id, req.reason),
+            Ok(None) => Err(ApiError::bad_json(None))?,
+            Err(err) => Err(ApiError::bad_json(err.to_string()))?,
+        };
+
+        let connection = DB::from_request(request)?;
+        let config = Config::from_request(request)?;
+
+        let room_membership_options = RoomMembershipOptions {
+            room_id,
+            user_id: bannee_id.clone(),
+            sender: banner.id,
+            membership: "ban".to_string(),
+            reason,
+        };
+
+        RoomMembership::upsert(&connection, &config.domain, room_membership_options)?;
+
+        Notifier::from_request(request)?.notify(vec![Topic::User(bannee_id)]);
+
+        Ok(Response::with(EmptyResponse(Status::Ok)))
+    }
+}
+
+/// The `/rooms/:room_id/unban` endpoint.
+#[derive(Clone, Copy, Debug)]
+pub struct UnbanFromRoom;
+
+/// The body of the request for this API.
+#[derive(Clone, Debug, Deserialize)]
+struct UnbanFromRoomRequest {
+    /// The fully qualified user ID of the user being unbanned.
+    pub user_id: UserId,
+}
+
+middleware_chain!(UnbanFromRoom, [JsonRequest, RoomIdParam, AccessTokenAuth]);
+
+impl Handler for UnbanFromRoom {
+    fn handle(&self, request: &mut Request<'_, '_>) -> IronResult<Response> {
+        let room_id = request
+            .extensions
+            .get::<RoomIdParam>()
+            .expect("RoomIdParam should ensure a room_id")
+            .clone();
+
+        let unbanner = request
+            .extensions
+            .get::<User>()
+            .expect("AccessTokenAuth should ensure a user")
+            .clone();
+
+        let unbannee_id = match request.get::<bodyparser::Struct<UnbanFromRoomRequest>>() {
+            Ok(Some(req)) => req.user_id,
+            Ok(None) => Err(ApiError::bad_json(None))?,
+            Err(err) => Err(ApiError::bad_json(err.to_string()))?,
+        };
+
+        let connection = DB::from_request(request)?;
+        let config = Config::from_request(request)?;
+
+        let mut unbannee_membership =
+            match RoomMembership::find(&connection, &room_id, &unbannee_id)? {
+                Some(ref membership) if membership.membership == "ban" => membership.clone(),
+                _ => Err(ApiError::unauthorized(
+                    "The user is not banned from the room".to_string(),
+                ))?,
+            };
+
+        let room_membership_options = RoomMembershipOptions {
+            room_id,
+            user_id: unbannee_id.clone(),
+            sender: unbanner.id,
+            membership: "leave".to_string(),
+            reason: None,
+        };
+
+        unbannee_membership.update(&connection, &config.domain, room_membership_options)?;
+
+        Notifier::from_request(request)?.notify(vec![Topic::User(unbannee_id)]);
+
         Ok(Response::with(EmptyResponse(Status::Ok)))
     }
 }
@@ -329,28 +473,25 @@ impl Handler for InviteToRoom {
 
         let new_membership_options = RoomMembershipOptions {
             room_id,
-            user_id: invitee_id,
+            user_id: invitee_id.clone(),
             sender: inviter.id,
             membership: "invite".to_string(),
+            reason: None,
         };
 
-        if let Some(mut entry) = invitee_membership {
-            match entry.membership.as_ref() {
-                "invite" => {}
-                "ban" => Err(ApiError::unauthorized(
-                    "The invited user is banned from the room".to_string(),
-                ))?,
-                "join" => Err(ApiError::unauthorized(
-                    "The invited user has already joined".to_string(),
-                ))?,
-                _ => {
-                    entry.update(&connection, &config.domain, new_membership_options)?;
-                }
+        match invitee_membership {
+            // Inviting a user again does not create a new member event.
+            Some(ref entry) if entry.membership == "invite" => {}
+            Some(mut entry) => {
+                entry.update(&connection, &config.domain, new_membership_options)?;
+            }
+            None => {
+                RoomMembership::create(&connection, &config.domain, new_membership_options)?;
             }
-        } else {
-            RoomMembership::create(&connection, &config.domain, new_membership_options)?;
         }
 
+        Notifier::from_request(request)?.notify(vec![Topic::User(invitee_id)]);
+
         Ok(Response::with(EmptyResponse(Status::Ok)))
     }
 }
@@ -827,15 +968,17 @@ mod tests {
         let bob = test.create_user();
         let carl = test.create_user();
 
-        let room_options = format!(r#"{{"invite": ["{}"]}}"#, bob.id);
+        let room_options = format!(r#"{{"invite": ["{}", "{}"]}}"#, bob.id, carl.id);
         let room_id = test.create_room_with_params(&alice.token, &room_options);
 
+        assert_eq!(test.join_room(&carl.token, &room_id).status, Status::Ok);
+
         let response = test.kick_from_room(&bob.token, &room_id, &carl.id, None);
 
         assert_eq!(response.status, Status::Forbidden);
         assert_eq!(
             response.json().get("error").unwrap().as_str().unwrap(),
-            "The kicker is not currently in the room"
+            format!("The user {} has not joined the room", bob.id)
         );
     }
 
@@ -856,4 +999,366 @@ mod tests {
             "The kickee is not currently in the room"
         );
     }
+
+    #[test]
+    fn ban_user() {
+        let test = Test::new();
+        let (alice, room_id) = test.initial_fixtures(r#"{"visibility": "public"}"#);
+        let bob = test.create_user();
+
+        assert_eq!(test.join_room(&bob.token, &room_id).status, Status::Ok);
+        assert_eq!(
+            test.ban_from_room(&alice.token, &room_id, &bob.id, Some("Spam"))
+                .status,
+            Status::Ok
+        );
+
+        let member_path = format!(
+            "/_matrix/client/r0/rooms/{}/state/m.room.member/{}?access_token={}",
+            room_id, bob.id, alice.token
+        );
+        let response = test.get(&member_path);
+        assert_eq!(response.status, Status::Ok);
+        assert_eq!(response.json().get("membership").unwrap(), "ban");
+        assert_eq!(response.json().get("reason").unwrap(), "Spam");
+
+        let response = test.send_message(&bob.token, &room_id, "Hi", 1);
+        assert_eq!(response.status, Status::Forbidden);
+
+        // Bob can't join the room again.
+        let response = test.join_room(&bob.token, &room_id);
+        assert_eq!(response.status, Status::Forbidden);
+        assert_eq!(
+            response.json().get("error").unwrap().as_str().unwrap(),
+            "The user is banned from the room"
+        );
+    }
+
+    #[test]
+    fn ban_user_not_in_room() {
+        let test = Test::new();
+        let (alice, room_id) = test.initial_fixtures(r#"{"visibility": "public"}"#);
+        let bob = test.create_user();
+
+        assert_eq!(
+            test.ban_from_room(&alice.token, &room_id, &bob.id, None)
+                .status,
+            Status::Ok
+        );
+
+        assert_eq!(
+            test.join_room(&bob.token, &room_id).status,
+            Status::Forbidden
+        );
+    }
+
+    #[test]
+    fn ban_user_without_permissions() {
+        let test = Test::new();
+        let (alice, room_id) = test.initial_fixtures(r#"{"visibility": "public"}"#);
+        let bob = test.create_user();
+        let carl = test.create_user();
+
+        assert_eq!(test.join_room(&bob.token, &room_id).status, Status::Ok);
+        assert_eq!(test.join_room(&carl.token, &room_id).status, Status::Ok);
+
+        let response = test.ban_from_room(&bob.token, &room_id, &carl.id, None);
+        assert_eq!(response.status, Status::Forbidden);
+        assert_eq!(
+            response.json().get("error").unwrap().as_str().unwrap(),
+            "Insufficient power level to ban a user"
+        );
+
+        let response = test.ban_from_room(&bob.token, &room_id, &alice.id, None);
+        assert_eq!(response.status, Status::Forbidden);
+    }
+
+    #[test]
+    fn banner_not_in_room() {
+        let test = Test::new();
+        let (alice, room_id) = test.initial_fixtures(r#"{"visibility": "public"}"#);
+        let bob = test.create_user();
+
+        let response = test.ban_from_room(&bob.token, &room_id, &alice.id, None);
+        assert_eq!(response.status, Status::Forbidden);
+        assert_eq!(
+            response.json().get("error").unwrap().as_str().unwrap(),
+            format!("The user {} is not a member of the room", bob.id)
+        );
+    }
+
+    #[test]
+    fn banned_user_cannot_be_invited() {
+        let test = Test::new();
+        let (alice, room_id) = test.initial_fixtures(r#"{"visibility": "public"}"#);
+        let bob = test.create_user();
+
+        assert_eq!(
+            test.ban_from_room(&alice.token, &room_id, &bob.id, None)
+                .status,
+            Status::Ok
+        );
+
+        let response = test.invite(&alice.token, &room_id, &bob.id);
+        assert_eq!(response.status, Status::Forbidden);
+        assert_eq!(
+            response.json().get("error").unwrap().as_str().unwrap(),
+            "The invited user is banned from the room"
+        );
+    }
+
+    #[test]
+    fn unban_user() {
+        let test = Test::new();
+        let (alice, room_id) = test.initial_fixtures(r#"{"visibility": "public"}"#);
+        let bob = test.create_user();
+
+        assert_eq!(test.join_room(&bob.token, &room_id).status, Status::Ok);
+        assert_eq!(
+            test.ban_from_room(&alice.token, &room_id, &bob.id, None)
+                .status,
+            Status::Ok
+        );
+        assert_eq!(
+            test.unban_from_room(&alice.token, &room_id, &bob.id).status,
+            Status::Ok
+        );
+
+        assert_eq!(test.join_room(&bob.token, &room_id).status, Status::Ok);
+    }
+
+    #[test]
+    fn unban_user_not_banned() {
+        let test = Test::new();
+        let (alice, room_id) = test.initial_fixtures(r#"{"visibility": "public"}"#);
+        let bob = test.create_user();
+
+        assert_eq!(test.join_room(&bob.token, &room_id).status, Status::Ok);
+
+        let response = test.unban_from_room(&alice.token, &room_id, &bob.id);
+        assert_eq!(response.status, Status::Forbidden);
+        assert_eq!(
+            response.json().get("error").unwrap().as_str().unwrap(),
+            "The user is not banned from the room"
+        );
+    }
+
+    #[test]
+    fn unban_user_without_permissions() {
+        let test = Test::new();
+        let (alice, room_id) = test.initial_fixtures(r#"{"visibility": "public"}"#);
+        let bob = test.create_user();
+        let carl = test.create_user();
+
+        assert_eq!(test.join_room(&bob.token, &room_id).status, Status::Ok);
+        assert_eq!(
+            test.ban_from_room(&alice.token, &room_id, &carl.id, None)
+                .status,
+            Status::Ok
+        );
+
+        let response = test.unban_from_room(&bob.token, &room_id, &carl.id);
+        assert_eq!(response.status, Status::Forbidden);
+        assert_eq!(
+            response.json().get("error").unwrap().as_str().unwrap(),
+            "Insufficient power level to unban a user"
+        );
+    }
+
+    #[test]
+    fn rejoin_public_room_after_leaving() {
+        let test = Test::new();
+        let (_, room_id) = test.initial_fixtures(r#"{"visibility": "public"}"#);
+        let bob = test.create_user();
+
+        assert_eq!(test.join_room(&bob.token, &room_id).status, Status::Ok);
+        assert_eq!(test.leave_room(&bob.token, &room_id).status, Status::Ok);
+        assert_eq!(test.join_room(&bob.token, &room_id).status, Status::Ok);
+    }
+
+    #[test]
+    fn rejoin_invite_only_room_after_leaving() {
+        let test = Test::new();
+        let alice = test.create_user();
+        let bob = test.create_user();
+
+        let body = format!(r#"{{"visibility": "private", "invite": ["{}"]}}"#, bob.id);
+        let room_id = test.create_room_with_params(&alice.token, &body);
+
+        assert_eq!(test.join_room(&bob.token, &room_id).status, Status::Ok);
+        assert_eq!(test.leave_room(&bob.token, &room_id).status, Status::Ok);
+
+        let response = test.join_room(&bob.token, &room_id);
+        assert_eq!(response.status, Status::Forbidden);
+        assert_eq!(
+            response.json().get("errcode").unwrap().as_str().unwrap(),
+            "M_FORBIDDEN"
+        );
+
+        // A new invite allows Bob to join again.
+        assert_eq!(
+            test.invite(&alice.token, &room_id, &bob.id).status,
+            Status::Ok
+        );
+        assert_eq!(test.join_room(&bob.token, &room_id).status, Status::Ok);
+    }
+
+    #[test]
+    fn rejoin_invite_only_room_after_kick() {
+        let test = Test::new();
+        let alice = test.create_user();
+        let bob = test.create_user();
+
+        let body = format!(r#"{{"visibility": "private", "invite": ["{}"]}}"#, bob.id);
+        let room_id = test.create_room_with_params(&alice.token, &body);
+
+        assert_eq!(test.join_room(&bob.token, &room_id).status, Status::Ok);
+        assert_eq!(
+            test.kick_from_room(&alice.token, &room_id, &bob.id, None)
+                .status,
+            Status::Ok
+        );
+
+        assert_eq!(
+            test.join_room(&bob.token, &room_id).status,
+            Status::Forbidden
+        );
+    }
+
+    #[test]
+    fn join_invite_only_room_via_alias_without_invite() {
+        let test = Test::new();
+        let alice = test.create_user();
+        let bob = test.create_user();
+        test.create_room_with_params(
+            &alice.token,
+            r#"{"room_alias_name":"secret", "visibility": "private"}"#,
+        );
+
+        let room_join_path = format!(
+            "/_matrix/client/r0/join/{}?access_token={}",
+            "%23secret:ruma.test", bob.token
+        );
+
+        let response = test.post(&room_join_path, r"{}");
+        assert_eq!(response.status, Status::Forbidden);
+    }
+
+    #[test]
+    fn join_room_after_join_rules_change() {
+        let test = Test::new();
+        let (alice, room_id) = test.initial_fixtures(r#"{"visibility": "public"}"#);
+        let bob = test.create_user();
+
+        assert_eq!(test.join_room(&bob.token, &room_id).status, Status::Ok);
+        assert_eq!(test.leave_room(&bob.token, &room_id).status, Status::Ok);
+
+        let response = test.send_state_event(
+            &alice.token,
+            &room_id,
+            "m.room.join_rules",
+            r#"{"join_rule": "invite"}"#,
+        );
+        assert_eq!(response.status, Status::Ok);
+
+        assert_eq!(
+            test.join_room(&bob.token, &room_id).status,
+            Status::Forbidden
+        );
+    }
+
+    #[test]
+    fn join_knock_and_private_rooms_without_invite() {
+        let test = Test::new();
+        let (alice, room_id) = test.initial_fixtures(r#"{"visibility": "public"}"#);
+        let bob = test.create_user();
+
+        for join_rule in &["knock", "private"] {
+            let response = test.send_state_event(
+                &alice.token,
+                &room_id,
+                "m.room.join_rules",
+                &format!(r#"{{"join_rule": "{}"}}"#, join_rule),
+            );
+            assert_eq!(response.status, Status::Ok);
+
+            assert_eq!(
+                test.join_room(&bob.token, &room_id).status,
+                Status::Forbidden
+            );
+        }
+    }
+
+    #[test]
+    fn join_non_existent_room() {
+        let test = Test::new();
+        let alice = test.create_user();
+
+        let response = test.join_room(&alice.token, "!nonexistent:ruma.test");
+        assert_eq!(response.status, Status::Forbidden);
+        assert_eq!(
+            response.json().get("error").unwrap().as_str().unwrap(),
+            "The room was not found on this server"
+        );
+    }
+
+    #[test]
+    fn forget_room_after_leaving() {
+        let test = Test::new();
+        let (_, room_id) = test.initial_fixtures(r#"{"visibility": "public"}"#);
+        let bob = test.create_user();
+
+        assert_eq!(test.join_room(&bob.token, &room_id).status, Status::Ok);
+        assert_eq!(test.leave_room(&bob.token, &room_id).status, Status::Ok);
+
+        let response = test.forget_room(&bob.token, &room_id);
+        assert_eq!(response.status, Status::Ok);
+        test.check_empty_response(response);
+
+        // Forgetting a room again is allowed.
+        assert_eq!(test.forget_room(&bob.token, &room_id).status, Status::Ok);
+    }
+
+    #[test]
+    fn forget_room_after_ban() {
+        let test = Test::new();
+        let (alice, room_id) = test.initial_fixtures(r#"{"visibility": "public"}"#);
+        let bob = test.create_user();
+
+        assert_eq!(test.join_room(&bob.token, &room_id).status, Status::Ok);
+        assert_eq!(
+            test.ban_from_room(&alice.token, &room_id, &bob.id, None)
+                .status,
+            Status::Ok
+        );
+
+        assert_eq!(test.forget_room(&bob.token, &room_id).status, Status::Ok);
+    }
+
+    #[test]
+    fn forget_joined_room() {
+        let test = Test::new();
+        let (alice, room_id) = test.initial_fixtures(r#"{"visibility": "public"}"#);
+
+        let response = test.forget_room(&alice.token, &room_id);
+        assert_eq!(response.status, Status::Forbidden);
+        assert_eq!(
+            response.json().get("error").unwrap().as_str().unwrap(),
+            "The user has to leave the room before forgetting it"
+        );
+    }
+
+    #[test]
+    fn forget_room_without_membership() {
+        let test = Test::new();
+        let (_, room_id) = test.initial_fixtures(r#"{"visibility": "public"}"#);
+        let bob = test.create_user();
+
+        let response = test.forget_room(&bob.token, &room_id);
+        assert_eq!(response.status, Status::Forbidden);
+        assert_eq!(
+            response.json().get("error").unwrap().as_str().unwrap(),
+            "User not in room or uninvited"
+        );
+    }
 }
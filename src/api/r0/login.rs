@@ -124,6 +124,7 @@ impl Handler for Login {
             &connection,
             &registered_user.id,
             &config.macaroon_secret_key,
+            config.access_token_lifetime,
         )?;
 
         let response = LoginResponse {
@@ -4,6 +4,7 @@ use iron::status::Status;
 use iron::{Chain, Handler, IronResult, Request, Response};
 
 use crate::db::DB;
+use crate::lazy_loading::LazyLoading;
 use crate::middleware::{AccessTokenAuth, MiddlewareChain};
 use crate::models::access_token::AccessToken;
 use crate::modifier::EmptyResponse;
@@ -17,6 +18,7 @@ middleware_chain!(Logout, [AccessTokenAuth]);
 impl Handler for Logout {
     fn handle(&self, request: &mut Request<'_, '_>) -> IronResult<Response> {
         let connection = DB::from_request(request)?;
+        let lazy_loading = LazyLoading::from_request(request)?;
 
         let access_token = request
             .extensions
@@ -24,6 +26,7 @@ impl Handler for Logout {
             .expect("AccessTokenAuth should ensure an access token");
 
         access_token.revoke(&connection)?;
+        lazy_loading.forget(access_token.id);
 
         Ok(Response::with(EmptyResponse(Status::Ok)))
     }
@@ -43,6 +46,6 @@ mod tests {
         let login_path = format!("/_matrix/client/r0/logout?access_token={}", user.token);
 
         assert!(test.post(&login_path, "{}").status.is_success());
-        assert_eq!(test.post(&login_path, "{}").status, Status::Forbidden);
+        assert_eq!(test.post(&login_path, "{}").status, Status::Unauthorized);
     }
 }
@@ -0,0 +1,696 @@
+//! Endpoints for the media repository.
+
+use std::io::Read;
+use std::str::FromStr;
+
+use diesel::pg::PgConnection;
+use image::gif::Decoder as GIFDecoder;
+use image::jpeg::JPEGDecoder;
+use image::png::PNGDecoder;
+use image::{
+    guess_format, load_from_memory, FilterType, GenericImageView, ImageDecoder, ImageFormat,
+    ImageOutputFormat, ImageResult,
+};
+use iron::headers::ContentType;
+use iron::mime::Mime;
+use iron::status::Status;
+use iron::{Chain, Handler, IronResult, Request, Response};
+use router::Router;
+use url::define_encode_set;
+use url::percent_encoding::{percent_decode, utf8_percent_encode, SIMPLE_ENCODE_SET};
+use url::Url;
+
+use crate::config::Config;
+use crate::db::DB;
+use crate::error::ApiError;
+use crate::middleware::{AccessTokenAuth, MediaIdParam, MiddlewareChain};
+use crate::models::media::Media;
+use crate::models::user::User;
+use crate::modifier::SerializableResponse;
+
+/// The largest upload accepted, in bytes.
+const MAX_UPLOAD_SIZE: u64 = 10 * 1024 * 1024;
+
+/// The largest width or height that can be requested for a thumbnail.
+const MAX_THUMBNAIL_SIZE: u32 = 1024;
+
+/// The largest number of pixels an image can have to be thumbnailed.
+const MAX_THUMBNAIL_SOURCE_PIXELS: u64 = 32 * 1024 * 1024;
+
+/// The content type used when none is known.
+const DEFAULT_CONTENT_TYPE: &str = "application/octet-stream";
+
+/// The content types that cannot run scripts, and can therefore be displayed inline by browsers.
+///
+/// Any other content is served as an attachment, so that uploaded HTML or SVG cannot be rendered
+/// within the origin of the homeserver.
+const INLINE_CONTENT_TYPES: &[&str] = &[
+    "text/plain",
+    "text/csv",
+    "application/json",
+    "image/jpeg",
+    "image/png",
+    "image/gif",
+    "image/webp",
+    "image/bmp",
+    "video/mp4",
+    "video/webm",
+    "video/ogg",
+    "audio/mp4",
+    "audio/mpeg",
+    "audio/ogg",
+    "audio/webm",
+    "audio/wav",
+];
+
+/// The Content-Security-Policy of media responses, forbidding any active content.
+const MEDIA_CONTENT_SECURITY_POLICY: &str = "sandbox; default-src 'none'; script-src 'none'; \
+                                             plugin-types application/pdf; style-src 'unsafe-inline'; \
+                                             media-src 'self'; object-src 'self';";
+
+define_encode_set! {
+    /// The characters that must be percent-encoded in the file name of a Content-Disposition
+    /// header, as defined by [RFC 5987](https://tools.ietf.org/html/rfc5987#section-3.2).
+    pub FILE_NAME_ENCODE_SET = [SIMPLE_ENCODE_SET] | {
+        ' ', '"', '%', '\'', '(', ')', '*', ',', '/', ':', ';', '<', '=', '>', '?', '@', '[',
+        '\\', ']', '{', '}'
+    }
+}
+
+/// The POST `/upload` endpoint.
+#[derive(Clone, Copy, Debug)]
+pub struct UploadMedia;
+
+/// The body of the response for this API.
+#[derive(Clone, Debug, Serialize)]
+struct UploadMediaResponse {
+    /// The `mxc://` URI of the uploaded content.
+    content_uri: String,
+}
+
+middleware_chain!(UploadMedia, [AccessTokenAuth]);
+
+impl Handler for UploadMedia {
+    fn handle(&self, request: &mut Request<'_, '_>) -> IronResult<Response> {
+        let user = request
+            .extensions
+            .get::<User>()
+            .expect("AccessTokenAuth should ensure a user")
+            .clone();
+
+        let url: Url = request.url.clone().into();
+        let upload_name = url
+            .query_pairs()
+            .find(|(key, _)| key == "filename")
+            .map(|(_, value)| value.into_owned());
+
+        let content_type = match request.headers.get::<ContentType>() {
+            Some(content_type) => content_type.to_string(),
+            None => DEFAULT_CONTENT_TYPE.to_string(),
+        };
+
+        let mut content = Vec::new();
+        request
+            .body
+            .by_ref()
+            .take(MAX_UPLOAD_SIZE + 1)
+            .read_to_end(&mut content)
+            .map_err(ApiError::from)?;
+
+        if content.len() as u64 > MAX_UPLOAD_SIZE {
+            Err(ApiError::too_large(format!(
+                "Uploads are limited to {} bytes",
+                MAX_UPLOAD_SIZE
+            )))?;
+        }
+
+        let connection = DB::from_request(request)?;
+        let config = Config::from_request(request)?;
+
+        let media = Media::create(
+            &connection,
+            &config.media_directory,
+            user.id,
+            content_type,
+            upload_name,
+            &content,
+        )?;
+
+        let response = UploadMediaResponse {
+            content_uri: media.content_uri(&config.domain),
+        };
+
+        Ok(Response::with((Status::Ok, SerializableResponse(response))))
+    }
+}
+
+/// The GET `/download/:server_name/:media_id` endpoint.
+///
+/// Also handles `/download/:server_name/:media_id/:file_name`, which overrides the file name
+/// given when the media was uploaded.
+#[derive(Clone, Copy, Debug)]
+pub struct DownloadMedia;
+
+middleware_chain!(DownloadMedia, [MediaIdParam]);
+
+impl Handler for DownloadMedia {
+    fn handle(&self, request: &mut Request<'_, '_>) -> IronResult<Response> {
+        let media_id = request
+            .extensions
+            .get::<MediaIdParam>()
+            .expect("MediaIdParam should ensure a media ID")
+            .clone();
+
+        let file_name = match request
+            .extensions
+            .get::<Router>()
+            .expect("Params object is missing")
+            .find("file_name")
+        {
+            Some(file_name) => Some(
+                percent_decode(file_name.as_bytes())
+                    .decode_utf8()
+                    .map_err(|err| ApiError::invalid_param("file_name", err))?
+                    .into_owned(),
+            ),
+            None => None,
+        };
+
+        let connection = DB::from_request(request)?;
+        let config = Config::from_request(request)?;
+
+        let media = find_media(&connection, &media_id)?;
+        let content = media.read_content(&config.media_directory)?;
+
+        let mut response = Response::with((Status::Ok, content));
+        set_content_type(&mut response, &media.content_type);
+
+        let disposition = if is_inline_content_type(&media.content_type) {
+            "inline"
+        } else {
+            "attachment"
+        };
+        let content_disposition = match file_name.or(media.upload_name) {
+            Some(file_name) => format!(
+                "{}; filename*=UTF-8''{}",
+                disposition,
+                utf8_percent_encode(&file_name, FILE_NAME_ENCODE_SET)
+            ),
+            None => disposition.to_string(),
+        };
+        response.headers.set_raw(
+            "Content-Disposition",
+            vec![content_disposition.into_bytes()],
+        );
+
+        Ok(response)
+    }
+}
+
+/// The GET `/thumbnail/:server_name/:media_id` endpoint.
+#[derive(Clone, Copy, Debug)]
+pub struct GetThumbnail;
+
+/// How a thumbnail is fitted to the requested size.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum ThumbnailMethod {
+    /// Scale the image down to fit inside the requested size, keeping its aspect ratio.
+    Scale,
+    /// Scale the image down to cover the requested size, then crop what overflows.
+    Crop,
+}
+
+impl FromStr for ThumbnailMethod {
+    type Err = String;
+
+    fn from_str(method: &str) -> Result<Self, Self::Err> {
+        match method {
+            "scale" => Ok(ThumbnailMethod::Scale),
+            "crop" => Ok(ThumbnailMethod::Crop),
+            _ => Err(format!("unknown method {}", method)),
+        }
+    }
+}
+
+middleware_chain!(GetThumbnail, [MediaIdParam]);
+
+impl Handler for GetThumbnail {
+    fn handle(&self, request: &mut Request<'_, '_>) -> IronResult<Response> {
+        let media_id = request
+            .extensions
+            .get::<MediaIdParam>()
+            .expect("MediaIdParam should ensure a media ID")
+            .clone();
+
+        let url: Url = request.url.clone().into();
+
+        let mut width = None;
+        let mut height = None;
+        let mut method = ThumbnailMethod::Scale;
+        for (key, value) in url.query_pairs().into_owned() {
+            match key.as_ref() {
+                "width" => width = Some(parse_thumbnail_size("width", &value)?),
+                "height" => height = Some(parse_thumbnail_size("height", &value)?),
+                "method" => {
+                    method = ThumbnailMethod::from_str(&value)
+                        .map_err(|err| ApiError::invalid_param("method", err))?;
+                }
+                _ => (),
+            }
+        }
+
+        let width = width.ok_or_else(|| ApiError::missing_param("width"))?;
+        let height = height.ok_or_else(|| ApiError::missing_param("height"))?;
+
+        let connection = DB::from_request(request)?;
+        let config = Config::from_request(request)?;
+
+        let media = find_media(&connection, &media_id)?;
+
+        // JPEG thumbnails are kept for photos, anything else is converted to PNG.
+        let (output_format, thumbnail_content_type) = match media.content_type.as_ref() {
+            "image/jpeg" => (ImageFormat::JPEG, "image/jpeg"),
+            _ => (ImageFormat::PNG, "image/png"),
+        };
+        let thumbnail_name = format!("{}x{}-{:?}", width, height, method).to_lowercase();
+
+        if let Some(thumbnail) = media.read_thumbnail(&config.media_directory, &thumbnail_name)? {
+            let mut response = Response::with((Status::Ok, thumbnail));
+            set_content_type(&mut response, thumbnail_content_type);
+
+            return Ok(response);
+        }
+
+        let content = media.read_content(&config.media_directory)?;
+
+        let thumbnail = generate_thumbnail(&content, width, height, method, output_format)?;
+
+        // Without a thumbnail, the image is already small enough to be its own thumbnail.
+        let (body, content_type) = match thumbnail {
+            Some(thumbnail) => {
+                media.write_thumbnail(&config.media_directory, &thumbnail_name, &thumbnail)?;
+
+                (thumbnail, thumbnail_content_type)
+            }
+            None => (content, media.content_type.as_ref()),
+        };
+
+        let mut response = Response::with((Status::Ok, body));
+        set_content_type(&mut response, content_type);
+
+        Ok(response)
+    }
+}
+
+/// Look up local media, failing if it does not exist.
+fn find_media(connection: &PgConnection, media_id: &str) -> Result<Media, ApiError> {
+    match Media::find(connection, media_id)? {
+        Some(media) => Ok(media),
+        None => Err(ApiError::not_found("The media was not found".to_string())),
+    }
+}
+
+/// Set the Content-Type header of a response, falling back to a generic type if the given one is
+/// not valid.
+///
+/// Also sets the headers preventing browsers from sniffing another type or running the content.
+fn set_content_type(response: &mut Response, content_type: &str) {
+    let mime = content_type
+        .parse::<Mime>()
+        .or_else(|_| DEFAULT_CONTENT_TYPE.parse::<Mime>())
+        .expect("the default content type should be valid");
+
+    response.headers.set(ContentType(mime));
+    response.headers.set_raw(
+        "Content-Security-Policy",
+        vec![MEDIA_CONTENT_SECURITY_POLICY.as_bytes().to_vec()],
+    );
+    response
+        .headers
+        .set_raw("X-Content-Type-Options", vec![b"nosniff".to_vec()]);
+}
+
+/// Whether content of the given type can safely be displayed inline by browsers.
+///
+/// Parameters such as the charset are ignored.
+fn is_inline_content_type(content_type: &str) -> bool {
+    let essence = content_type
+        .split(';')
+        .next()
+        .unwrap_or_default()
+        .trim()
+        .to_lowercase();
+
+    INLINE_CONTENT_TYPES.contains(&essence.as_str())
+}
+
+/// Parse the requested width or height of a thumbnail.
+fn parse_thumbnail_size(param_name: &str, value: &str) -> Result<u32, ApiError> {
+    let size = u32::from_str(value).map_err(|err| ApiError::invalid_param(param_name, err))?;
+
+    if size == 0 || size > MAX_THUMBNAIL_SIZE {
+        Err(ApiError::invalid_param(
+            param_name,
+            format!("must be between 1 and {}", MAX_THUMBNAIL_SIZE),
+        ))?;
+    }
+
+    Ok(size)
+}
+
+/// Generate a thumbnail of an image in the given format.
+///
+/// Images are never scaled up, so `None` is returned if the image already fits in the requested
+/// size.
+fn generate_thumbnail(
+    content: &[u8],
+    width: u32,
+    height: u32,
+    method: ThumbnailMethod,
+    output_format: ImageFormat,
+) -> Result<Option<Vec<u8>>, ApiError> {
+    let format = match guess_format(content) {
+        Ok(format @ ImageFormat::PNG)
+        | Ok(format @ ImageFormat::JPEG)
+        | Ok(format @ ImageFormat::GIF) => format,
+        _ => Err(ApiError::not_found(
+            "Thumbnails are only available for PNG, JPEG and GIF images".to_string(),
+        ))?,
+    };
+
+    // Only the header is read here, so that huge images are rejected before they are decoded.
+    let (source_width, source_height) = image_dimensions(content, format)
+        .map_err(|_| ApiError::not_found("The image could not be decoded".to_string()))?;
+
+    if source_width.saturating_mul(source_height) > MAX_THUMBNAIL_SOURCE_PIXELS {
+        Err(ApiError::too_large(
+            "The image is too large to be thumbnailed".to_string(),
+        ))?;
+    }
+
+    let image = load_from_memory(content)
+        .map_err(|_| ApiError::not_found("The image could not be decoded".to_string()))?;
+
+    if image.width() <= width && image.height() <= height {
+        return Ok(None);
+    }
+
+    let thumbnail = match method {
+        ThumbnailMethod::Scale => image.resize(width, height, FilterType::Triangle),
+        ThumbnailMethod::Crop => image.resize_to_fill(
+            width.min(image.width()),
+            height.min(image.height()),
+            FilterType::Triangle,
+        ),
+    };
+
+    let output_format = match output_format {
+        ImageFormat::JPEG => ImageOutputFormat::JPEG(80),
+        _ => ImageOutputFormat::PNG,
+    };
+
+    let mut thumbnail_content = Vec::new();
+    thumbnail
+        .write_to(&mut thumbnail_content, output_format)
+        .map_err(|_| ApiError::unknown("The thumbnail could not be encoded".to_string()))?;
+
+    Ok(Some(thumbnail_content))
+}
+
+/// Read the dimensions of an image from its header without decoding it.
+fn image_dimensions(content: &[u8], format: ImageFormat) -> ImageResult<(u64, u64)> {
+    match format {
+        ImageFormat::PNG => PNGDecoder::new(content).map(|decoder| decoder.dimensions()),
+        ImageFormat::JPEG => JPEGDecoder::new(content).map(|decoder| decoder.dimensions()),
+        _ => GIFDecoder::new(content).map(|decoder| decoder.dimensions()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{load_from_memory, DynamicImage, GenericImageView, ImageFormat, ImageOutputFormat};
+    use iron::status::Status;
+
+    use super::{generate_thumbnail, ThumbnailMethod};
+    use crate::test::Test;
+
+    /// Encode a blank image of the given size as a PNG.
+    fn png(width: u32, height: u32) -> Vec<u8> {
+        let mut content = Vec::new();
+        DynamicImage::new_rgb8(width, height)
+            .write_to(&mut content, ImageOutputFormat::PNG)
+            .unwrap();
+        content
+    }
+
+    /// The dimensions of an encoded image.
+    fn dimensions(content: &[u8]) -> (u32, u32) {
+        let image = load_from_memory(content).unwrap();
+        (image.width(), image.height())
+    }
+
+    #[test]
+    fn upload_and_download() {
+        let test = Test::new();
+        let alice = test.create_user();
+
+        let response = test.upload_media(&alice.token, "text/plain", Some("hello.txt"), "Hello");
+        assert_eq!(response.status, Status::Ok);
+        let content_uri = response
+            .json()
+            .get("content_uri")
+            .unwrap()
+            .as_str()
+            .unwrap();
+        assert!(content_uri.starts_with("mxc://ruma.test/"));
+
+        let download_path = format!(
+            "/_matrix/media/r0/download/{}",
+            content_uri.trim_start_matches("mxc://")
+        );
+        let response = test.get(&download_path);
+
+        assert_eq!(response.status, Status::Ok);
+        assert_eq!(response.body, "Hello");
+        assert_eq!(
+            response.headers.get_raw("Content-Type").unwrap()[0],
+            b"text/plain".to_vec()
+        );
+        assert_eq!(
+            response.headers.get_raw("Content-Disposition").unwrap()[0],
+            b"inline; filename*=UTF-8''hello.txt".to_vec()
+        );
+        assert_eq!(
+            response.headers.get_raw("X-Content-Type-Options").unwrap()[0],
+            b"nosniff".to_vec()
+        );
+        assert!(String::from_utf8(
+            response.headers.get_raw("Content-Security-Policy").unwrap()[0].clone()
+        )
+        .unwrap()
+        .starts_with("sandbox; default-src 'none';"));
+    }
+
+    #[test]
+    fn download_active_content_as_attachment() {
+        let test = Test::new();
+        let alice = test.create_user();
+
+        let response = test.upload_media(
+            &alice.token,
+            "text/html",
+            Some("page.html"),
+            "<script>alert(1)</script>",
+        );
+        let content_uri = response
+            .json()
+            .get("content_uri")
+            .unwrap()
+            .as_str()
+            .unwrap();
+
+        let download_path = format!(
+            "/_matrix/media/r0/download/{}",
+            content_uri.trim_start_matches("mxc://")
+        );
+        let response = test.get(&download_path);
+
+        assert_eq!(response.status, Status::Ok);
+        assert_eq!(
+            response.headers.get_raw("Content-Disposition").unwrap()[0],
+            b"attachment; filename*=UTF-8''page.html".to_vec()
+        );
+    }
+
+    #[test]
+    fn download_with_file_name() {
+        let test = Test::new();
+        let alice = test.create_user();
+
+        let response = test.upload_media(&alice.token, "text/plain", None, "Hello");
+        let content_uri = response
+            .json()
+            .get("content_uri")
+            .unwrap()
+            .as_str()
+            .unwrap();
+
+        let download_path = format!(
+            "/_matrix/media/r0/download/{}/hello%20world.txt",
+            content_uri.trim_start_matches("mxc://")
+        );
+        let response = test.get(&download_path);
+
+        assert_eq!(response.status, Status::Ok);
+        assert_eq!(
+            response.headers.get_raw("Content-Disposition").unwrap()[0],
+            b"inline; filename*=UTF-8''hello%20world.txt".to_vec()
+        );
+    }
+
+    #[test]
+    fn upload_without_access_token() {
+        let test = Test::new();
+
+        let response = test.post("/_matrix/media/r0/upload", "Hello");
+
+        assert_eq!(response.status, Status::Forbidden);
+    }
+
+    #[test]
+    fn download_unknown_media() {
+        let test = Test::new();
+
+        let response = test.get("/_matrix/media/r0/download/ruma.test/unknown");
+
+        assert_eq!(response.status, Status::NotFound);
+    }
+
+    #[test]
+    fn download_media_from_another_server() {
+        let test = Test::new();
+        let alice = test.create_user();
+
+        let response = test.upload_media(&alice.token, "text/plain", None, "Hello");
+        let content_uri = response
+            .json()
+            .get("content_uri")
+            .unwrap()
+            .as_str()
+            .unwrap();
+        let media_id = content_uri.rsplit('/').next().unwrap();
+
+        let response = test.get(&format!(
+            "/_matrix/media/r0/download/matrix.org/{}",
+            media_id
+        ));
+
+        assert_eq!(response.status, Status::NotFound);
+    }
+
+    #[test]
+    fn thumbnail_of_non_image() {
+        let test = Test::new();
+        let alice = test.create_user();
+
+        let response = test.upload_media(&alice.token, "text/plain", None, "Hello");
+        let content_uri = response
+            .json()
+            .get("content_uri")
+            .unwrap()
+            .as_str()
+            .unwrap();
+
+        let thumbnail_path = format!(
+            "/_matrix/media/r0/thumbnail/{}?width=32&height=32",
+            content_uri.trim_start_matches("mxc://")
+        );
+        let response = test.get(&thumbnail_path);
+
+        assert_eq!(response.status, Status::NotFound);
+    }
+
+    #[test]
+    fn thumbnail_with_invalid_size() {
+        let test = Test::new();
+        let alice = test.create_user();
+
+        let response = test.upload_media(&alice.token, "text/plain", None, "Hello");
+        let content_uri = response
+            .json()
+            .get("content_uri")
+            .unwrap()
+            .as_str()
+            .unwrap();
+
+        let thumbnail_path = format!(
+            "/_matrix/media/r0/thumbnail/{}?width=0&height=32",
+            content_uri.trim_start_matches("mxc://")
+        );
+        let response = test.get(&thumbnail_path);
+
+        assert_eq!(response.status, Status::BadRequest);
+    }
+
+    #[test]
+    fn scaled_thumbnail_keeps_aspect_ratio() {
+        let thumbnail = generate_thumbnail(
+            &png(200, 100),
+            50,
+            50,
+            ThumbnailMethod::Scale,
+            ImageFormat::PNG,
+        )
+        .unwrap()
+        .unwrap();
+
+        assert_eq!(dimensions(&thumbnail), (50, 25));
+    }
+
+    #[test]
+    fn cropped_thumbnail_fills_requested_size() {
+        let thumbnail = generate_thumbnail(
+            &png(200, 100),
+            50,
+            50,
+            ThumbnailMethod::Crop,
+            ImageFormat::PNG,
+        )
+        .unwrap()
+        .unwrap();
+
+        assert_eq!(dimensions(&thumbnail), (50, 50));
+    }
+
+    #[test]
+    fn huge_images_are_not_decoded() {
+        let mut content = Vec::new();
+        DynamicImage::new_rgb8(1, 1)
+            .write_to(&mut content, ImageOutputFormat::GIF)
+            .unwrap();
+
+        // Claim a 65535x65535 logical screen in the GIF header.
+        for byte in &mut content[6..10] {
+            *byte = 0xff;
+        }
+
+        let thumbnail =
+            generate_thumbnail(&content, 50, 50, ThumbnailMethod::Scale, ImageFormat::PNG);
+
+        assert!(thumbnail.is_err());
+    }
+
+    #[test]
+    fn small_images_are_not_scaled_up() {
+        let thumbnail = generate_thumbnail(
+            &png(20, 10),
+            50,
+            50,
+            ThumbnailMethod::Scale,
+            ImageFormat::PNG,
+        )
+        .unwrap();
+
+        assert!(thumbnail.is_none());
+    }
+}
@@ -0,0 +1,525 @@
+//! Endpoint for paginating through the events of a room.
+
+use std::cmp;
+use std::convert::TryInto;
+use std::str::FromStr;
+
+use iron::status::Status;
+use iron::{Chain, Handler, IronResult, Request, Response};
+use ruma_events::collections::all::{RoomEvent, StateEvent};
+use serde_json::from_str;
+use url::Url;
+
+use crate::db::DB;
+use crate::error::ApiError;
+use crate::lazy_loading::LazyLoading;
+use crate::middleware::{AccessTokenAuth, MiddlewareChain, RoomIdParam};
+use crate::models::access_token::AccessToken;
+use crate::models::event::{Direction, Event};
+use crate::models::filter::RoomEventFilter;
+use crate::models::room_membership::RoomMembership;
+use crate::models::user::User;
+use crate::modifier::SerializableResponse;
+use crate::query::Batch;
+
+/// The number of events returned when the client does not specify a limit.
+const DEFAULT_LIMIT: i64 = 10;
+
+/// The maximum number of events returned at once, whatever limit the client asks for.
+const MAX_LIMIT: i64 = 100;
+
+/// The `/rooms/:room_id/messages` endpoint.
+#[derive(Clone, Copy, Debug)]
+pub struct Messages;
+
+/// The body of the response for this API.
+#[derive(Debug, Serialize)]
+struct MessagesResponse {
+    /// The token the pagination starts from.
+    start: String,
+    /// The token the pagination ends at, to be passed as `from` to continue paginating.
+    end: String,
+    /// A list of room events, in the order they were paginated.
+    chunk: Vec<RoomEvent>,
+    /// The member events of the senders in `chunk`, when the filter lazy-loads members.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    state: Vec<StateEvent>,
+}
+
+middleware_chain!(Messages, [RoomIdParam, AccessTokenAuth]);
+
+impl Handler for Messages {
+    fn handle(&self, request: &mut Request<'_, '_>) -> IronResult<Response> {
+        let user = request
+            .extensions
+            .get::<User>()
+            .expect("AccessTokenAuth should ensure a user")
+            .clone();
+
+        let room_id = request
+            .extensions
+            .get::<RoomIdParam>()
+            .expect("RoomIdParam should ensure a room_id")
+            .clone();
+
+        let url: Url = request.url.clone().into();
+        let query_pairs = url.query_pairs().into_owned();
+
+        let mut from = None;
+        let mut to = None;
+        let mut direction = None;
+        let mut limit = DEFAULT_LIMIT;
+        let mut filter: Option<RoomEventFilter> = None;
+        for tuple in query_pairs {
+            match (tuple.0.as_ref(), tuple.1.as_ref()) {
+                ("from", value) => {
+                    let batch = Batch::from_str(value)
+                        .map_err(|err| ApiError::invalid_param("from", err))?;
+                    from = Some(batch);
+                }
+                ("to", value) => {
+                    let batch =
+                        Batch::from_str(value).map_err(|err| ApiError::invalid_param("to", err))?;
+                    to = Some(batch);
+                }
+                ("dir", value) => {
+                    let dir = Direction::from_str(value)
+                        .map_err(|err| ApiError::invalid_param("dir", err))?;
+                    direction = Some(dir);
+                }
+                ("limit", value) => {
+                    let requested = i64::from_str(value)
+                        .map_err(|err| ApiError::invalid_param("limit", err))?;
+
+                    if requested < 0 {
+                        Err(ApiError::invalid_param("limit", "Must not be negative"))?;
+                    }
+
+                    limit = cmp::min(requested, MAX_LIMIT);
+                }
+                ("filter", value) => {
+                    let content =
+                        from_str(value).map_err(|err| ApiError::invalid_param("filter", err))?;
+                    filter = Some(content);
+                }
+                _ => (),
+            }
+        }
+
+        let from = match from {
+            Some(from) => from,
+            None => Err(ApiError::missing_param("from"))?,
+        };
+
+        let direction = match direction {
+            Some(direction) => direction,
+            None => Err(ApiError::missing_param("dir"))?,
+        };
+
+        let connection = DB::from_request(request)?;
+
+        let visible_until = match RoomMembership::find(&connection, &room_id, &user.id)? {
+            Some(membership) => membership.visible_until(&connection)?,
+            None => None,
+        };
+
+        let until = match visible_until {
+            Some(until) => until,
+            None => Err(ApiError::unauthorized(
+                "The user is not a member of the room".to_string(),
+            ))?,
+        };
+
+        let mut room_from = from.room_key;
+        let mut room_to = to.map(|batch| batch.room_key);
+
+        // Users who are no longer in the room may only see what happened until they left.
+        if let Some(until) = until {
+            match direction {
+                Direction::Backward => {
+                    room_from = cmp::min(room_from, until);
+                }
+                Direction::Forward => {
+                    room_to = Some(match room_to {
+                        Some(room_to) => cmp::min(room_to, until),
+                        None => until,
+                    });
+                }
+            }
+        }
+
+        let events = Event::paginate_room_events(
+            &connection,
+            &room_id,
+            room_from,
+            room_to,
+            direction,
+            limit,
+            filter.as_ref(),
+        )?;
+
+        let mut end = from;
+        if let Some(last_event) = events.last() {
+            end.room_key = match direction {
+                Direction::Backward => last_event.ordering - 1,
+                Direction::Forward => last_event.ordering,
+            };
+        }
+
+        let (lazy_load_members, include_redundant_members) = match filter {
+            Some(ref filter) => (filter.lazy_load_members, filter.include_redundant_members),
+            None => (false, false),
+        };
+
+        let mut state = Vec::new();
+        if lazy_load_members {
+            let mut senders = Vec::new();
+            for event in &events {
+                if !senders.contains(&event.sender) {
+                    senders.push(event.sender.clone());
+                }
+            }
+            let ordering = events.iter().map(|event| event.ordering).max().unwrap_or(0);
+            let mut members =
+                Event::get_room_members_at(&connection, &room_id, &senders, ordering)?;
+
+            let access_token_id = request
+                .extensions
+                .get::<AccessToken>()
+                .expect("AccessTokenAuth should ensure an access token")
+                .id;
+            LazyLoading::from_request(request)?.send_paginated(
+                access_token_id,
+                &room_id,
+                &mut members,
+                include_redundant_members,
+            );
+
+            for member in members {
+                state.push(member.try_into()?);
+            }
+        }
+
+        let mut chunk = Vec::new();
+        for event in events {
+            chunk.push(event.for_user(&user.id).try_into()?);
+        }
+
+        let response = MessagesResponse {
+            start: from.to_string(),
+            end: end.to_string(),
+            chunk,
+            state,
+        };
+
+        Ok(Response::with((Status::Ok, SerializableResponse(response))))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::test::Test;
+    use iron::status::Status;
+    use ruma_events::presence::PresenceState;
+    use serde_json::{from_str, Value};
+
+    use crate::models::filter::ContentFilter;
+    use crate::query::{Batch, SyncOptions};
+
+    /// Return the `next_batch` token of an initial sync, pointing after the latest event.
+    fn current_batch(test: &Test, access_token: &str) -> Batch {
+        let options = SyncOptions {
+            filter: None,
+            since: None,
+            full_state: false,
+            set_presence: Some(PresenceState::Online),
+            timeout: 0,
+        };
+
+        Test::get_next_batch(&test.sync(access_token, options))
+    }
+
+    /// Return the message bodies of the `m.room.message` events in a `/messages` response.
+    fn bodies(response: &Value) -> Vec<String> {
+        response
+            .get("chunk")
+            .unwrap()
+            .as_array()
+            .unwrap()
+            .iter()
+            .filter(|event| event.get("type").unwrap().as_str().unwrap() == "m.room.message")
+            .map(|event| {
+                event
+                    .pointer("/content/body")
+                    .unwrap()
+                    .as_str()
+                    .unwrap()
+                    .to_string()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn paginate_backward_from_sync() {
+        let test = Test::new();
+        let (alice, room_id) = test.initial_fixtures(r#"{"visibility": "public"}"#);
+
+        for i in 1..=5 {
+            test.send_message(&alice.token, &room_id, &format!("message {}", i), i);
+        }
+
+        let filter: ContentFilter = from_str(r#"{"room":{"timeline":{"limit":2}}}"#).unwrap();
+        let options = SyncOptions {
+            filter: Some(filter),
+            since: None,
+            full_state: false,
+            set_presence: Some(PresenceState::Online),
+            timeout: 0,
+        };
+        let response = test.sync(&alice.token, options);
+        let prev_batch = response
+            .json()
+            .pointer(&format!("/rooms/join/{}/timeline/prev_batch", room_id))
+            .unwrap()
+            .as_str()
+            .unwrap()
+            .to_string();
+
+        let messages_path = format!(
+            "/_matrix/client/r0/rooms/{}/messages?from={}&dir=b&limit=2&access_token={}",
+            room_id, prev_batch, alice.token
+        );
+        let response = test.get(&messages_path);
+        assert_eq!(response.status, Status::Ok);
+        assert_eq!(bodies(response.json()), vec!["message 3", "message 2"]);
+        assert_eq!(
+            response.json().get("start").unwrap().as_str().unwrap(),
+            prev_batch
+        );
+
+        // Continue from the end token of the previous page.
+        let end = response.json().get("end").unwrap().as_str().unwrap();
+        let messages_path = format!(
+            "/_matrix/client/r0/rooms/{}/messages?from={}&dir=b&limit=1&access_token={}",
+            room_id, end, alice.token
+        );
+        let response = test.get(&messages_path);
+        assert_eq!(response.status, Status::Ok);
+        assert_eq!(bodies(response.json()), vec!["message 1"]);
+    }
+
+    #[test]
+    fn paginate_forward_and_backward_are_consistent() {
+        let test = Test::new();
+        let (alice, room_id) = test.initial_fixtures(r#"{"visibility": "public"}"#);
+        let start = current_batch(&test, &alice.token);
+
+        for i in 1..=3 {
+            test.send_message(&alice.token, &room_id, &format!("message {}", i), i);
+        }
+
+        let messages_path = format!(
+            "/_matrix/client/r0/rooms/{}/messages?from={}&dir=f&access_token={}",
+            room_id, start, alice.token
+        );
+        let response = test.get(&messages_path);
+        assert_eq!(response.status, Status::Ok);
+        assert_eq!(
+            bodies(response.json()),
+            vec!["message 1", "message 2", "message 3"]
+        );
+
+        // Paginating backward from the forward end token returns the same events in reverse,
+        // bounded by the original start token.
+        let end = response.json().get("end").unwrap().as_str().unwrap();
+        let messages_path = format!(
+            "/_matrix/client/r0/rooms/{}/messages?from={}&to={}&dir=b&access_token={}",
+            room_id, end, start, alice.token
+        );
+        let response = test.get(&messages_path);
+        assert_eq!(response.status, Status::Ok);
+        assert_eq!(
+            bodies(response.json()),
+            vec!["message 3", "message 2", "message 1"]
+        );
+        assert_eq!(
+            response
+                .json()
+                .get("chunk")
+                .unwrap()
+                .as_array()
+                .unwrap()
+                .len(),
+            3
+        );
+    }
+
+    #[test]
+    fn filter_by_type() {
+        let test = Test::new();
+        let (alice, room_id) = test.initial_fixtures(r#"{"visibility": "public"}"#);
+        let start = current_batch(&test, &alice.token);
+
+        test.send_message(&alice.token, &room_id, "Hi", 1);
+        test.send_state_event(
+            &alice.token,
+            &room_id,
+            "m.room.topic",
+            r#"{"topic": "Testing"}"#,
+        );
+
+        let messages_path = format!(
+            "/_matrix/client/r0/rooms/{}/messages?from={}&dir=f&filter={}&access_token={}",
+            room_id, start, r#"{"types":["m.room.topic"]}"#, alice.token
+        );
+        let response = test.get(&messages_path);
+        assert_eq!(response.status, Status::Ok);
+        let chunk = response.json().get("chunk").unwrap().as_array().unwrap();
+        assert_eq!(chunk.len(), 1);
+        assert_eq!(
+            chunk[0].get("type").unwrap().as_str().unwrap(),
+            "m.room.topic"
+        );
+    }
+
+    #[test]
+    fn left_user_cannot_see_later_events() {
+        let test = Test::new();
+        let (alice, room_id) = test.initial_fixtures(r#"{"visibility": "public"}"#);
+        let bob = test.create_user();
+        assert_eq!(test.join_room(&bob.token, &room_id).status, Status::Ok);
+
+        test.send_message(&alice.token, &room_id, "before", 1);
+        assert_eq!(test.leave_room(&bob.token, &room_id).status, Status::Ok);
+        test.send_message(&alice.token, &room_id, "after", 2);
+
+        let end = current_batch(&test, &alice.token);
+        let messages_path = format!(
+            "/_matrix/client/r0/rooms/{}/messages?from={}&dir=b&access_token={}",
+            room_id, end, bob.token
+        );
+        let response = test.get(&messages_path);
+        assert_eq!(response.status, Status::Ok);
+        assert_eq!(bodies(response.json()), vec!["before"]);
+    }
+
+    #[test]
+    fn member_events_include_previous_content() {
+        let test = Test::new();
+        let (alice, room_id) = test.initial_fixtures(r#"{"visibility": "public"}"#);
+
+        let displayname_path = format!(
+            "/_matrix/client/r0/profile/{}/displayname?access_token={}",
+            alice.id, alice.token
+        );
+        test.put(&displayname_path, r#"{"displayname": "Alice"}"#);
+        test.put(&displayname_path, r#"{"displayname": "Alicia"}"#);
+
+        let end = current_batch(&test, &alice.token);
+        let messages_path = format!(
+            "/_matrix/client/r0/rooms/{}/messages?from={}&dir=b&limit=2&filter={}&access_token={}",
+            room_id, end, "%7B%22types%22%3A%5B%22m.room.member%22%5D%7D", alice.token
+        );
+        let response = test.get(&messages_path);
+        assert_eq!(response.status, Status::Ok);
+
+        let chunk = response.json().get("chunk").unwrap();
+        let latest = chunk.get(0).unwrap();
+        let previous = chunk.get(1).unwrap();
+        assert_eq!(
+            latest
+                .pointer("/content/displayname")
+                .unwrap()
+                .as_str()
+                .unwrap(),
+            "Alicia"
+        );
+        assert_eq!(
+            latest
+                .pointer("/prev_content/displayname")
+                .unwrap()
+                .as_str()
+                .unwrap(),
+            "Alice"
+        );
+        assert_eq!(
+            latest.pointer("/unsigned/replaces_state").unwrap(),
+            previous.get("event_id").unwrap()
+        );
+    }
+
+    #[test]
+    fn lazy_load_members_of_senders() {
+        let test = Test::new();
+        let (alice, room_id) = test.initial_fixtures(r#"{"visibility": "public"}"#);
+        let bob = test.create_user();
+        assert_eq!(test.join_room(&bob.token, &room_id).status, Status::Ok);
+
+        test.send_message(&alice.token, &room_id, "Hi", 1);
+
+        let end = current_batch(&test, &alice.token);
+        let messages_path = format!(
+            "/_matrix/client/r0/rooms/{}/messages?from={}&dir=b&limit=1&filter={}&access_token={}",
+            room_id, end, "%7B%22lazy_load_members%22%3Atrue%7D", bob.token
+        );
+        let response = test.get(&messages_path);
+        assert_eq!(response.status, Status::Ok);
+        assert_eq!(bodies(response.json()), vec!["Hi"]);
+
+        let state = response.json().get("state").unwrap().as_array().unwrap();
+        assert_eq!(state.len(), 1);
+        assert_eq!(state[0].get("type").unwrap(), "m.room.member");
+        assert_eq!(
+            state[0].get("state_key").unwrap().as_str().unwrap(),
+            alice.id
+        );
+
+        // The member event was already sent to this client.
+        let response = test.get(&messages_path);
+        assert_eq!(response.status, Status::Ok);
+        assert!(response.json().get("state").is_none());
+    }
+
+    #[test]
+    fn forbidden_for_non_members() {
+        let test = Test::new();
+        let (alice, room_id) = test.initial_fixtures(r#"{"visibility": "public"}"#);
+        let bob = test.create_user();
+
+        let end = current_batch(&test, &alice.token);
+        let messages_path = format!(
+            "/_matrix/client/r0/rooms/{}/messages?from={}&dir=b&access_token={}",
+            room_id, end, bob.token
+        );
+        assert_eq!(test.get(&messages_path).status, Status::Forbidden);
+    }
+
+    #[test]
+    fn missing_and_invalid_params() {
+        let test = Test::new();
+        let (alice, room_id) = test.initial_fixtures(r#"{"visibility": "public"}"#);
+
+        let messages_path = format!(
+            "/_matrix/client/r0/rooms/{}/messages?dir=b&access_token={}",
+            room_id, alice.token
+        );
+        let response = test.get(&messages_path);
+        assert_eq!(response.status, Status::BadRequest);
+        Test::assert_json_keys(response.json(), vec!["errcode", "error"]);
+        assert_eq!(
+            response.json().get("errcode").unwrap().as_str().unwrap(),
+            "M_MISSING_PARAM"
+        );
+
+        let messages_path = format!(
+            "/_matrix/client/r0/rooms/{}/messages?from=0_0_0_0_0&dir=x&access_token={}",
+            room_id, alice.token
+        );
+        let response = test.get(&messages_path);
+        assert_eq!(response.status, Status::BadRequest);
+        assert_eq!(
+            response.json().get("errcode").unwrap().as_str().unwrap(),
+            "IO_RUMA_INVALID_PARAM"
+        );
+    }
+}
@@ -1,37 +1,61 @@
 //! API endpoints for the 0.x.x version of the Matrix spec.
 
 pub use self::account::{AccountPassword, DeactivateAccount, PutAccountData, PutRoomAccountData};
-pub use self::directory::{DeleteRoomAlias, GetRoomAlias, PutRoomAlias};
-pub use self::event_creation::{SendMessageEvent, StateMessageEvent};
+pub use self::context::EventContext;
+pub use self::directory::{
+    DeleteRoomAlias, GetRoomAlias, GetRoomVisibility, PutRoomAlias, PutRoomVisibility,
+};
+pub use self::event_creation::{RedactEvent, SendMessageEvent, StateMessageEvent};
 pub use self::filter::{GetFilter, PostFilter};
-pub use self::join::{InviteToRoom, JoinRoom, JoinRoomWithIdOrAlias, KickFromRoom, LeaveRoom};
+pub use self::join::{
+    BanFromRoom, ForgetRoom, InviteToRoom, JoinRoom, JoinRoomWithIdOrAlias, KickFromRoom,
+    LeaveRoom, UnbanFromRoom,
+};
 pub use self::login::Login;
 pub use self::logout::Logout;
+pub use self::media::{DownloadMedia, GetThumbnail, UploadMedia};
 pub use self::members::Members;
+pub use self::messages::Messages;
 pub use self::presence::{GetPresenceList, GetPresenceStatus, PostPresenceList, PutPresenceStatus};
 pub use self::profile::{GetAvatarUrl, GetDisplayName, Profile, PutAvatarUrl, PutDisplayName};
+pub use self::public_rooms::{GetPublicRooms, PostPublicRooms};
+pub use self::push_rules::{
+    DeletePushRule, GetPushRule, GetPushRuleActions, GetPushRuleEnabled, GetPushRules,
+    GetScopedPushRules, PutPushRule, PutPushRuleActions, PutPushRuleEnabled,
+};
 pub use self::pushers::{GetPushers, SetPushers};
+pub use self::receipts::PostReceipt;
 pub use self::registration::Register;
 pub use self::room_creation::CreateRoom;
-pub use self::room_info::RoomState;
+pub use self::room_info::{GetStateEvent, RoomState};
+pub use self::search::Search;
 pub use self::sync::Sync;
 pub use self::tags::{DeleteTag, GetTags, PutTag};
+pub use self::typing::PutTyping;
 pub use self::versions::Versions;
 
 mod account;
+mod context;
 mod directory;
 mod event_creation;
 mod filter;
 mod join;
 mod login;
 mod logout;
+mod media;
 mod members;
+mod messages;
 mod presence;
 mod profile;
+mod public_rooms;
+mod push_rules;
 mod pushers;
+mod receipts;
 mod registration;
 mod room_creation;
 mod room_info;
+mod search;
 mod sync;
 mod tags;
+mod typing;
 mod versions;
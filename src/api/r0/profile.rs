@@ -8,9 +8,12 @@ use crate::config::Config;
 use crate::db::DB;
 use crate::error::ApiError;
 use crate::middleware::{AccessTokenAuth, JsonRequest, MiddlewareChain, UserIdParam};
+use crate::models::media::Media;
 use crate::models::profile::Profile as DataProfile;
+use crate::models::room_membership::RoomMembership;
 use crate::models::user::User;
 use crate::modifier::{EmptyResponse, SerializableResponse};
+use crate::notifier::{Notifier, Topic};
 
 /// The `/profile/:user_id` endpoint.
 #[derive(Clone, Copy, Debug)]
@@ -150,6 +153,20 @@ impl Handler for PutAvatarUrl {
             return Err(IronError::from(error));
         }
 
+        // Only media stored by this server can be checked, remote URIs are taken as they are.
+        if let Some(ref avatar_url) = avatar_url_request.avatar_url {
+            let local_prefix = format!("mxc://{}/", config.domain);
+
+            if avatar_url.starts_with(&local_prefix)
+                && Media::find(&connection, &avatar_url[local_prefix.len()..])?.is_none()
+            {
+                Err(ApiError::invalid_param(
+                    "avatar_url",
+                    "The media does not exist",
+                ))?;
+            }
+        }
+
         DataProfile::update_avatar_url(
             &connection,
             &config.domain,
@@ -157,7 +174,12 @@ impl Handler for PutAvatarUrl {
             avatar_url_request.avatar_url,
         )?;
 
-        DataProfile::update_memberships(&connection, &config.domain, user_id)?;
+        DataProfile::update_memberships(&connection, &config.domain, user_id.clone())?;
+
+        // The new profile shows up in the member events of every joined room.
+        let room_ids =
+            RoomMembership::find_room_ids_by_uid_and_state(&connection, &user_id, "join")?;
+        Notifier::from_request(request)?.notify(room_ids.into_iter().map(Topic::Room));
 
         Ok(Response::with(EmptyResponse(Status::Ok)))
     }
@@ -261,7 +283,12 @@ impl Handler for PutDisplayName {
             displayname_request.displayname,
         )?;
 
-        DataProfile::update_memberships(&connection, &config.domain, user_id)?;
+        DataProfile::update_memberships(&connection, &config.domain, user_id.clone())?;
+
+        // The new profile shows up in the member events of every joined room.
+        let room_ids =
+            RoomMembership::find_room_ids_by_uid_and_state(&connection, &user_id, "join")?;
+        Notifier::from_request(request)?.notify(room_ids.into_iter().map(Topic::Room));
 
         Ok(Response::with(EmptyResponse(Status::Ok)))
     }
@@ -354,6 +381,36 @@ mod tests {
         );
     }
 
+    #[test]
+    fn put_avatar_url_with_local_media() {
+        let test = Test::new();
+        let carl = test.create_user();
+
+        let response = test.upload_media(&carl.token, "image/png", None, "avatar");
+        let content_uri = response
+            .json()
+            .get("content_uri")
+            .unwrap()
+            .as_str()
+            .unwrap();
+
+        let put_avatar_url_path = format!(
+            "/_matrix/client/r0/profile/{}/avatar_url?access_token={}",
+            carl.id, carl.token
+        );
+        let response = test.put(
+            &put_avatar_url_path,
+            &format!(r#"{{"avatar_url": "{}"}}"#, content_uri),
+        );
+        assert_eq!(response.status, Status::Ok);
+
+        let response = test.put(
+            &put_avatar_url_path,
+            r#"{"avatar_url": "mxc://ruma.test/wefh34uihSDRGhw34"}"#,
+        );
+        assert_eq!(response.status, Status::BadRequest);
+    }
+
     #[test]
     fn put_displayname() {
         let test = Test::new();
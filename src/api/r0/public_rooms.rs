@@ -0,0 +1,515 @@
+//! Endpoints for listing the public rooms on the server.
+
+use std::collections::HashMap;
+use std::str::FromStr;
+
+use bodyparser;
+use diesel::pg::PgConnection;
+use iron::status::Status;
+use iron::{Chain, Handler, IronResult, Plugin, Request, Response};
+use ruma_events::room::avatar::AvatarEventContent;
+use ruma_events::room::canonical_alias::CanonicalAliasEventContent;
+use ruma_events::room::guest_access::{GuestAccess, GuestAccessEventContent};
+use ruma_events::room::history_visibility::{HistoryVisibility, HistoryVisibilityEventContent};
+use ruma_events::room::name::NameEventContent;
+use ruma_events::room::topic::TopicEventContent;
+use ruma_events::EventType;
+use ruma_identifiers::{RoomAliasId, RoomId};
+use serde_json::from_str;
+use url::Url;
+
+use crate::db::DB;
+use crate::error::ApiError;
+use crate::middleware::{AccessTokenAuth, JsonRequest, MiddlewareChain};
+use crate::models::event::Event;
+use crate::models::room::Room;
+use crate::models::room_membership::RoomMembership;
+use crate::modifier::SerializableResponse;
+
+/// The state events shown in the listing of a room.
+const CHUNK_EVENT_TYPES: [EventType; 6] = [
+    EventType::RoomAvatar,
+    EventType::RoomCanonicalAlias,
+    EventType::RoomGuestAccess,
+    EventType::RoomHistoryVisibility,
+    EventType::RoomName,
+    EventType::RoomTopic,
+];
+
+/// The state events searched by the filter of a request.
+const SEARCH_EVENT_TYPES: [EventType; 3] = [
+    EventType::RoomCanonicalAlias,
+    EventType::RoomName,
+    EventType::RoomTopic,
+];
+
+/// The `/publicRooms` endpoint when using the GET method.
+#[derive(Clone, Copy, Debug)]
+pub struct GetPublicRooms;
+
+/// The `/publicRooms` endpoint when using the POST method.
+#[derive(Clone, Copy, Debug)]
+pub struct PostPublicRooms;
+
+/// The parameters of a request to list the public rooms.
+#[derive(Clone, Debug, Default, Deserialize)]
+struct PublicRoomsRequest {
+    /// The maximum number of rooms to return.
+    limit: Option<usize>,
+    /// A pagination token from a previous response.
+    since: Option<String>,
+    /// A filter for the returned rooms.
+    filter: Option<PublicRoomsFilter>,
+}
+
+/// A filter for the rooms returned by this API.
+#[derive(Clone, Debug, Deserialize)]
+struct PublicRoomsFilter {
+    /// A string to search for in the room's name, topic and canonical alias.
+    generic_search_term: Option<String>,
+}
+
+/// The body of the response for this API.
+#[derive(Debug, Serialize)]
+struct PublicRoomsResponse {
+    /// A page of public rooms.
+    chunk: Vec<PublicRoomsChunk>,
+    /// A pagination token for the next page, if there are more rooms.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    next_batch: Option<String>,
+    /// A pagination token for the previous page, if this is not the first one.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    prev_batch: Option<String>,
+    /// The number of rooms matching the filter.
+    total_room_count_estimate: usize,
+}
+
+/// A public room, as shown in the listing.
+#[derive(Debug, Serialize)]
+struct PublicRoomsChunk {
+    /// The URL of the room's avatar.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    avatar_url: Option<String>,
+    /// The room's canonical alias.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    canonical_alias: Option<RoomAliasId>,
+    /// Whether or not guest users can join the room.
+    guest_can_join: bool,
+    /// The room's name.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    name: Option<String>,
+    /// The number of users who have joined the room.
+    num_joined_members: u64,
+    /// The room's ID.
+    room_id: RoomId,
+    /// The room's topic.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    topic: Option<String>,
+    /// Whether or not the room's history can be read without joining it.
+    world_readable: bool,
+}
+
+middleware_chain!(GetPublicRooms);
+
+impl Handler for GetPublicRooms {
+    fn handle(&self, request: &mut Request<'_, '_>) -> IronResult<Response> {
+        let url: Url = request.url.clone().into();
+
+        let mut public_rooms_request = PublicRoomsRequest::default();
+        for (key, value) in url.query_pairs().into_owned() {
+            match key.as_ref() {
+                "limit" => {
+                    let limit = usize::from_str(&value)
+                        .map_err(|err| ApiError::invalid_param("limit", err))?;
+                    public_rooms_request.limit = Some(limit);
+                }
+                "since" => public_rooms_request.since = Some(value),
+                _ => (),
+            }
+        }
+
+        let connection = DB::from_request(request)?;
+
+        list_public_rooms(&connection, public_rooms_request)
+    }
+}
+
+middleware_chain!(PostPublicRooms, [JsonRequest, AccessTokenAuth]);
+
+impl Handler for PostPublicRooms {
+    fn handle(&self, request: &mut Request<'_, '_>) -> IronResult<Response> {
+        let public_rooms_request = match request.get::<bodyparser::Struct<PublicRoomsRequest>>() {
+            Ok(Some(public_rooms_request)) => public_rooms_request,
+            Ok(None) => Err(ApiError::bad_json(None))?,
+            Err(err) => Err(ApiError::bad_json(err.to_string()))?,
+        };
+
+        let connection = DB::from_request(request)?;
+
+        list_public_rooms(&connection, public_rooms_request)
+    }
+}
+
+/// Handles the work of listing the public rooms for both methods.
+///
+/// Rooms are ordered by their number of joined members and paginated by their position in that
+/// order. Only the rooms of the requested page are loaded in full.
+fn list_public_rooms(
+    connection: &PgConnection,
+    public_rooms_request: PublicRoomsRequest,
+) -> IronResult<Response> {
+    let since = match public_rooms_request.since {
+        Some(ref since) => {
+            usize::from_str(since).map_err(|err| ApiError::invalid_param("since", err))?
+        }
+        None => 0,
+    };
+
+    if public_rooms_request.limit == Some(0) {
+        Err(ApiError::invalid_param(
+            "limit",
+            "The limit must be positive",
+        ))?;
+    }
+
+    let search_term = public_rooms_request
+        .filter
+        .and_then(|filter| filter.generic_search_term)
+        .map(|search_term| search_term.to_lowercase());
+
+    let mut room_ids: Vec<RoomId> = Room::find_public(connection)?
+        .into_iter()
+        .map(|room| room.id)
+        .collect();
+
+    let mut num_joined_members: HashMap<RoomId, u64> = HashMap::new();
+    for room_id in RoomMembership::find_joined_room_ids(connection, &room_ids)? {
+        *num_joined_members.entry(room_id).or_insert(0) += 1;
+    }
+
+    if let Some(ref search_term) = search_term {
+        room_ids = public_rooms_chunks(connection, room_ids, &SEARCH_EVENT_TYPES)?
+            .into_iter()
+            .filter(|chunk| chunk.matches(search_term))
+            .map(|chunk| chunk.room_id)
+            .collect();
+    }
+
+    room_ids.sort_by(|a, b| {
+        let members = |room_id| num_joined_members.get(room_id).cloned().unwrap_or(0);
+
+        members(b)
+            .cmp(&members(a))
+            .then_with(|| a.to_string().cmp(&b.to_string()))
+    });
+
+    let total_room_count_estimate = room_ids.len();
+    let limit = public_rooms_request
+        .limit
+        .unwrap_or(total_room_count_estimate);
+    let end = since.saturating_add(limit);
+
+    let next_batch = if end > since && end < total_room_count_estimate {
+        Some(end.to_string())
+    } else {
+        None
+    };
+    let prev_batch = if since > 0 {
+        Some(since.saturating_sub(limit).to_string())
+    } else {
+        None
+    };
+
+    let page = room_ids.into_iter().skip(since).take(limit).collect();
+    let mut chunk = public_rooms_chunks(connection, page, &CHUNK_EVENT_TYPES)?;
+
+    for room in &mut chunk {
+        room.num_joined_members = num_joined_members.get(&room.room_id).cloned().unwrap_or(0);
+    }
+
+    let response = PublicRoomsResponse {
+        chunk,
+        next_batch,
+        prev_batch,
+        total_room_count_estimate,
+    };
+
+    Ok(Response::with((Status::Ok, SerializableResponse(response))))
+}
+
+/// Build the listing entries of the given rooms from their current state events of the given
+/// types, keeping the order of the rooms.
+fn public_rooms_chunks(
+    connection: &PgConnection,
+    room_ids: Vec<RoomId>,
+    event_types: &[EventType],
+) -> Result<Vec<PublicRoomsChunk>, ApiError> {
+    let events = Event::get_rooms_current_state_events(connection, &room_ids, event_types)?;
+
+    let mut chunks: Vec<PublicRoomsChunk> =
+        room_ids.into_iter().map(PublicRoomsChunk::new).collect();
+    let positions: HashMap<RoomId, usize> = chunks
+        .iter()
+        .enumerate()
+        .map(|(position, chunk)| (chunk.room_id.clone(), position))
+        .collect();
+
+    for event in events {
+        let position = event
+            .room_id
+            .as_ref()
+            .and_then(|room_id| positions.get(room_id));
+
+        if let Some(&position) = position {
+            chunks[position].apply(&event);
+        }
+    }
+
+    Ok(chunks)
+}
+
+impl PublicRoomsChunk {
+    /// Create an entry for a room without any of its state.
+    fn new(room_id: RoomId) -> Self {
+        Self {
+            avatar_url: None,
+            canonical_alias: None,
+            guest_can_join: false,
+            name: None,
+            num_joined_members: 0,
+            room_id,
+            topic: None,
+            world_readable: false,
+        }
+    }
+
+    /// Update the entry with one of the room's current state events.
+    ///
+    /// Redacted state events do not match their types anymore and are treated as missing.
+    fn apply(&mut self, event: &Event) {
+        match EventType::from(event.event_type.as_ref()) {
+            EventType::RoomAvatar => {
+                self.avatar_url = from_str::<AvatarEventContent>(&event.content)
+                    .ok()
+                    .map(|content| content.url);
+            }
+            EventType::RoomCanonicalAlias => {
+                self.canonical_alias = from_str::<CanonicalAliasEventContent>(&event.content)
+                    .ok()
+                    .and_then(|content| content.alias);
+            }
+            EventType::RoomGuestAccess => {
+                self.guest_can_join = from_str::<GuestAccessEventContent>(&event.content)
+                    .ok()
+                    .map(|content| content.guest_access)
+                    == Some(GuestAccess::CanJoin);
+            }
+            EventType::RoomHistoryVisibility => {
+                self.world_readable = from_str::<HistoryVisibilityEventContent>(&event.content)
+                    .ok()
+                    .map(|content| content.history_visibility)
+                    == Some(HistoryVisibility::WorldReadable);
+            }
+            EventType::RoomName => {
+                self.name = from_str::<NameEventContent>(&event.content)
+                    .ok()
+                    .and_then(|content| content.name);
+            }
+            EventType::RoomTopic => {
+                self.topic = from_str::<TopicEventContent>(&event.content)
+                    .ok()
+                    .map(|content| content.topic);
+            }
+            _ => {}
+        }
+    }
+
+    /// Whether the room's name, topic or canonical alias contain the lowercase search term.
+    fn matches(&self, search_term: &str) -> bool {
+        let canonical_alias = self.canonical_alias.as_ref().map(ToString::to_string);
+
+        [
+            self.name.as_ref(),
+            self.topic.as_ref(),
+            canonical_alias.as_ref(),
+        ]
+        .iter()
+        .filter_map(|field| *field)
+        .any(|field| field.to_lowercase().contains(search_term))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::test::Test;
+    use iron::status::Status;
+
+    #[test]
+    fn list_public_rooms() {
+        let test = Test::new();
+        let alice = test.create_user();
+        let bob = test.create_user();
+
+        let room_options = r#"{
+            "visibility": "public",
+            "room_alias_name": "lobby",
+            "name": "The Lobby",
+            "topic": "Say hi",
+            "initial_state": [
+                {
+                    "state_key": "",
+                    "type": "m.room.avatar",
+                    "content": { "url": "mxc://ruma.test/avatar" }
+                },
+                {
+                    "state_key": "",
+                    "type": "m.room.history_visibility",
+                    "content": { "history_visibility": "world_readable" }
+                }
+            ]
+        }"#;
+        let room_id = test.create_room_with_params(&alice.token, room_options);
+        test.create_private_room(&alice.token);
+
+        assert_eq!(test.join_room(&bob.token, &room_id).status, Status::Ok);
+
+        let response = test.get("/_matrix/client/r0/publicRooms");
+        assert_eq!(response.status, Status::Ok);
+
+        let json = response.json();
+        assert_eq!(json.get("total_room_count_estimate").unwrap(), 1);
+        assert!(json.get("next_batch").is_none());
+        assert!(json.get("prev_batch").is_none());
+
+        let chunk = json.get("chunk").unwrap().as_array().unwrap();
+        assert_eq!(chunk.len(), 1);
+
+        let room = &chunk[0];
+        assert_eq!(room.get("room_id").unwrap().as_str().unwrap(), room_id);
+        assert_eq!(room.get("name").unwrap().as_str().unwrap(), "The Lobby");
+        assert_eq!(room.get("topic").unwrap().as_str().unwrap(), "Say hi");
+        assert_eq!(
+            room.get("canonical_alias").unwrap().as_str().unwrap(),
+            "#lobby:ruma.test"
+        );
+        assert_eq!(
+            room.get("avatar_url").unwrap().as_str().unwrap(),
+            "mxc://ruma.test/avatar"
+        );
+        assert_eq!(room.get("num_joined_members").unwrap(), 2);
+        assert_eq!(room.get("world_readable").unwrap(), true);
+        assert_eq!(room.get("guest_can_join").unwrap(), false);
+    }
+
+    #[test]
+    fn rooms_are_ordered_by_joined_members() {
+        let test = Test::new();
+        let alice = test.create_user();
+        let bob = test.create_user();
+
+        let quiet_room_id = test.create_public_room(&alice.token);
+        let busy_room_id = test.create_public_room(&alice.token);
+
+        assert_eq!(test.join_room(&bob.token, &busy_room_id).status, Status::Ok);
+
+        let response = test.get("/_matrix/client/r0/publicRooms");
+        let chunk = response.json().get("chunk").unwrap().as_array().unwrap();
+
+        let room_ids: Vec<&str> = chunk
+            .iter()
+            .map(|room| room.get("room_id").unwrap().as_str().unwrap())
+            .collect();
+        assert_eq!(room_ids, vec![&*busy_room_id, &*quiet_room_id]);
+    }
+
+    #[test]
+    fn paginate_public_rooms() {
+        let test = Test::new();
+        let alice = test.create_user();
+
+        for _ in 0..3 {
+            test.create_public_room(&alice.token);
+        }
+
+        let response = test.get("/_matrix/client/r0/publicRooms?limit=2");
+        assert_eq!(response.status, Status::Ok);
+
+        let json = response.json();
+        assert_eq!(json.get("chunk").unwrap().as_array().unwrap().len(), 2);
+        assert_eq!(json.get("total_room_count_estimate").unwrap(), 3);
+        assert!(json.get("prev_batch").is_none());
+
+        let next_batch = json.get("next_batch").unwrap().as_str().unwrap();
+
+        let response = test.get(&format!(
+            "/_matrix/client/r0/publicRooms?limit=2&since={}",
+            next_batch
+        ));
+        assert_eq!(response.status, Status::Ok);
+
+        let json = response.json();
+        assert_eq!(json.get("chunk").unwrap().as_array().unwrap().len(), 1);
+        assert!(json.get("next_batch").is_none());
+        assert_eq!(json.get("prev_batch").unwrap().as_str().unwrap(), "0");
+    }
+
+    #[test]
+    fn search_public_rooms() {
+        let test = Test::new();
+        let alice = test.create_user();
+
+        let rust_room_id = test
+            .create_room_with_params(&alice.token, r#"{"visibility": "public", "name": "Rust"}"#);
+        test.create_room_with_params(
+            &alice.token,
+            r#"{"visibility": "public", "name": "Matrix", "topic": "Everything Matrix"}"#,
+        );
+        let alias_room_id = test.create_room_with_params(
+            &alice.token,
+            r#"{"visibility": "public", "room_alias_name": "rustaceans"}"#,
+        );
+
+        let public_rooms_path = format!(
+            "/_matrix/client/r0/publicRooms?access_token={}",
+            alice.token
+        );
+        let response = test.post(
+            &public_rooms_path,
+            r#"{"filter": {"generic_search_term": "RUST"}}"#,
+        );
+        assert_eq!(response.status, Status::Ok);
+
+        let json = response.json();
+        assert_eq!(json.get("total_room_count_estimate").unwrap(), 2);
+
+        let mut room_ids: Vec<&str> = json
+            .get("chunk")
+            .unwrap()
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|room| room.get("room_id").unwrap().as_str().unwrap())
+            .collect();
+        room_ids.sort();
+
+        let mut expected_room_ids = vec![&*rust_room_id, &*alias_room_id];
+        expected_room_ids.sort();
+        assert_eq!(room_ids, expected_room_ids);
+    }
+
+    #[test]
+    fn invalid_since() {
+        let test = Test::new();
+
+        let response = test.get("/_matrix/client/r0/publicRooms?since=next");
+        assert_eq!(response.status, Status::BadRequest);
+    }
+
+    #[test]
+    fn zero_limit() {
+        let test = Test::new();
+
+        let response = test.get("/_matrix/client/r0/publicRooms?limit=0");
+        assert_eq!(response.status, Status::BadRequest);
+    }
+}
@@ -0,0 +1,779 @@
+//! Endpoints for push rules.
+
+use std::convert::TryFrom;
+
+use bodyparser;
+use iron::status::Status;
+use iron::{Chain, Handler, IronResult, Plugin, Request, Response};
+use ruma_identifiers::{RoomId, UserId};
+use url::Url;
+
+use crate::db::DB;
+use crate::error::ApiError;
+use crate::middleware::{
+    AccessTokenAuth, JsonRequest, MiddlewareChain, PushRuleKindParam, PushRuleScopeParam,
+    RuleIdParam,
+};
+use crate::models::push_rule::{Action, NewPushRule, PushCondition, PushRule, PushRuleKind};
+use crate::models::user::User;
+use crate::modifier::{EmptyResponse, SerializableResponse};
+
+/// A push rule, as returned by the API.
+#[derive(Clone, Debug, Serialize)]
+pub struct PushRuleResponse {
+    /// The ID of the rule.
+    rule_id: String,
+    /// Whether the rule is one of the default rules.
+    default: bool,
+    /// Whether the rule is evaluated.
+    enabled: bool,
+    /// The actions of the rule.
+    actions: Vec<Action>,
+    /// The conditions of an override or underride rule.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    conditions: Option<Vec<PushCondition>>,
+    /// The pattern of a content rule.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pattern: Option<String>,
+}
+
+/// All push rules of a user, grouped by kind.
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct Ruleset {
+    /// The content rules.
+    content: Vec<PushRuleResponse>,
+    /// The override rules.
+    #[serde(rename = "override")]
+    override_rules: Vec<PushRuleResponse>,
+    /// The room rules.
+    room: Vec<PushRuleResponse>,
+    /// The sender rules.
+    sender: Vec<PushRuleResponse>,
+    /// The underride rules.
+    underride: Vec<PushRuleResponse>,
+}
+
+/// The body of the response for the GET `/pushrules` endpoint.
+#[derive(Clone, Debug, Serialize)]
+pub struct PushRulesResponse {
+    /// The push rules of the global scope.
+    global: Ruleset,
+}
+
+/// The body of the request for the PUT `/pushrules/:scope/:kind/:rule_id` endpoint.
+#[derive(Clone, Debug, Deserialize)]
+struct PutPushRuleRequest {
+    /// The actions of the rule.
+    actions: Vec<Action>,
+    /// The conditions of an override or underride rule.
+    #[serde(default)]
+    conditions: Vec<PushCondition>,
+    /// The pattern of a content rule.
+    pattern: Option<String>,
+}
+
+/// The body of the request and response for the `enabled` endpoints.
+#[derive(Clone, Copy, Debug, Deserialize, Serialize)]
+struct EnabledBody {
+    /// Whether the rule is evaluated.
+    enabled: bool,
+}
+
+/// The body of the request and response for the `actions` endpoints.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+struct ActionsBody {
+    /// The actions of the rule.
+    actions: Vec<Action>,
+}
+
+impl PushRuleResponse {
+    /// Convert a stored push rule, showing only the fields relevant to its kind.
+    fn from_rule(rule: &PushRule) -> Result<Self, ApiError> {
+        let kind = rule.kind()?;
+
+        let conditions = match kind {
+            PushRuleKind::Override | PushRuleKind::Underride => Some(rule.conditions()?),
+            _ => None,
+        };
+
+        let pattern = match kind {
+            PushRuleKind::Content => rule.pattern.clone(),
+            _ => None,
+        };
+
+        Ok(Self {
+            rule_id: rule.rule_id.clone(),
+            default: rule.is_default,
+            enabled: rule.enabled,
+            actions: rule.actions()?,
+            conditions,
+            pattern,
+        })
+    }
+}
+
+impl Ruleset {
+    /// Group push rules, which must be in the order they are evaluated.
+    fn from_rules(rules: &[PushRule]) -> Result<Self, ApiError> {
+        let mut ruleset = Self::default();
+
+        for rule in rules {
+            let response = PushRuleResponse::from_rule(rule)?;
+
+            match rule.kind()? {
+                PushRuleKind::Override => ruleset.override_rules.push(response),
+                PushRuleKind::Content => ruleset.content.push(response),
+                PushRuleKind::Room => ruleset.room.push(response),
+                PushRuleKind::Sender => ruleset.sender.push(response),
+                PushRuleKind::Underride => ruleset.underride.push(response),
+            }
+        }
+
+        Ok(ruleset)
+    }
+}
+
+/// Return the push rule named by the URL path parameters.
+fn find_push_rule(request: &mut Request<'_, '_>) -> Result<PushRule, ApiError> {
+    let kind = *request
+        .extensions
+        .get::<PushRuleKindParam>()
+        .expect("PushRuleKindParam should ensure a push rule kind");
+
+    let rule_id = request
+        .extensions
+        .get::<RuleIdParam>()
+        .expect("RuleIdParam should ensure a rule ID")
+        .clone();
+
+    let user = request
+        .extensions
+        .get::<User>()
+        .expect("AccessTokenAuth should ensure a user")
+        .clone();
+
+    let connection = DB::from_request(request)?;
+
+    match PushRule::find(&connection, &user.id, kind, &rule_id)? {
+        Some(rule) => Ok(rule),
+        None => Err(ApiError::not_found(format!(
+            "The push rule {} was not found",
+            rule_id
+        ))),
+    }
+}
+
+/// The GET `/pushrules` endpoint.
+#[derive(Clone, Copy, Debug)]
+pub struct GetPushRules;
+
+middleware_chain!(GetPushRules, [AccessTokenAuth]);
+
+impl Handler for GetPushRules {
+    fn handle(&self, request: &mut Request<'_, '_>) -> IronResult<Response> {
+        let user = request
+            .extensions
+            .get::<User>()
+            .expect("AccessTokenAuth should ensure a user")
+            .clone();
+
+        let connection = DB::from_request(request)?;
+
+        let rules = PushRule::find_by_user(&connection, &user.id)?;
+
+        let response = PushRulesResponse {
+            global: Ruleset::from_rules(&rules)?,
+        };
+
+        Ok(Response::with((Status::Ok, SerializableResponse(response))))
+    }
+}
+
+/// The GET `/pushrules/:scope` endpoint.
+#[derive(Clone, Copy, Debug)]
+pub struct GetScopedPushRules;
+
+middleware_chain!(GetScopedPushRules, [PushRuleScopeParam, AccessTokenAuth]);
+
+impl Handler for GetScopedPushRules {
+    fn handle(&self, request: &mut Request<'_, '_>) -> IronResult<Response> {
+        let user = request
+            .extensions
+            .get::<User>()
+            .expect("AccessTokenAuth should ensure a user")
+            .clone();
+
+        let connection = DB::from_request(request)?;
+
+        let rules = PushRule::find_by_user(&connection, &user.id)?;
+
+        let response = Ruleset::from_rules(&rules)?;
+
+        Ok(Response::with((Status::Ok, SerializableResponse(response))))
+    }
+}
+
+/// The GET `/pushrules/:scope/:kind/:rule_id` endpoint.
+#[derive(Clone, Copy, Debug)]
+pub struct GetPushRule;
+
+middleware_chain!(
+    GetPushRule,
+    [
+        PushRuleScopeParam,
+        PushRuleKindParam,
+        RuleIdParam,
+        AccessTokenAuth
+    ]
+);
+
+impl Handler for GetPushRule {
+    fn handle(&self, request: &mut Request<'_, '_>) -> IronResult<Response> {
+        let rule = find_push_rule(request)?;
+
+        let response = PushRuleResponse::from_rule(&rule)?;
+
+        Ok(Response::with((Status::Ok, SerializableResponse(response))))
+    }
+}
+
+/// The PUT `/pushrules/:scope/:kind/:rule_id` endpoint.
+#[derive(Clone, Copy, Debug)]
+pub struct PutPushRule;
+
+middleware_chain!(
+    PutPushRule,
+    [
+        PushRuleScopeParam,
+        PushRuleKindParam,
+        RuleIdParam,
+        JsonRequest,
+        AccessTokenAuth
+    ]
+);
+
+impl Handler for PutPushRule {
+    fn handle(&self, request: &mut Request<'_, '_>) -> IronResult<Response> {
+        let kind = *request
+            .extensions
+            .get::<PushRuleKindParam>()
+            .expect("PushRuleKindParam should ensure a push rule kind");
+
+        let rule_id = request
+            .extensions
+            .get::<RuleIdParam>()
+            .expect("RuleIdParam should ensure a rule ID")
+            .clone();
+
+        let user = request
+            .extensions
+            .get::<User>()
+            .expect("AccessTokenAuth should ensure a user")
+            .clone();
+
+        let put_push_rule_request = match request.get::<bodyparser::Struct<PutPushRuleRequest>>() {
+            Ok(Some(put_push_rule_request)) => put_push_rule_request,
+            Ok(None) | Err(_) => Err(ApiError::bad_json(None))?,
+        };
+
+        // Rule IDs starting with a dot are reserved for the default rules.
+        if rule_id.starts_with('.') {
+            Err(ApiError::invalid_param(
+                "rule_id",
+                "Default rules cannot be replaced",
+            ))?;
+        }
+
+        match kind {
+            PushRuleKind::Room => {
+                RoomId::try_from(rule_id.as_str())
+                    .map_err(|err| ApiError::invalid_param("rule_id", err))?;
+            }
+            PushRuleKind::Sender => {
+                UserId::try_from(rule_id.as_str())
+                    .map_err(|err| ApiError::invalid_param("rule_id", err))?;
+            }
+            PushRuleKind::Content if put_push_rule_request.pattern.is_none() => {
+                Err(ApiError::missing_param("pattern"))?;
+            }
+            _ => {}
+        }
+
+        let url: Url = request.url.clone().into();
+        let mut before = None;
+        let mut after = None;
+
+        for (key, value) in url.query_pairs().into_owned() {
+            match key.as_ref() {
+                "before" => before = Some(value),
+                "after" => after = Some(value),
+                _ => {}
+            }
+        }
+
+        let new_rule = NewPushRule {
+            rule_id,
+            conditions: match kind {
+                PushRuleKind::Override | PushRuleKind::Underride => {
+                    put_push_rule_request.conditions
+                }
+                _ => Vec::new(),
+            },
+            pattern: match kind {
+                PushRuleKind::Content => put_push_rule_request.pattern,
+                _ => None,
+            },
+            actions: put_push_rule_request.actions,
+        };
+
+        let connection = DB::from_request(request)?;
+
+        PushRule::put(
+            &connection,
+            &user.id,
+            kind,
+            new_rule,
+            before.as_ref().map(String::as_str),
+            after.as_ref().map(String::as_str),
+        )?;
+
+        Ok(Response::with(EmptyResponse(Status::Ok)))
+    }
+}
+
+/// The DELETE `/pushrules/:scope/:kind/:rule_id` endpoint.
+#[derive(Clone, Copy, Debug)]
+pub struct DeletePushRule;
+
+middleware_chain!(
+    DeletePushRule,
+    [
+        PushRuleScopeParam,
+        PushRuleKindParam,
+        RuleIdParam,
+        AccessTokenAuth
+    ]
+);
+
+impl Handler for DeletePushRule {
+    fn handle(&self, request: &mut Request<'_, '_>) -> IronResult<Response> {
+        let rule = find_push_rule(request)?;
+
+        if rule.is_default {
+            Err(ApiError::invalid_param(
+                "rule_id",
+                "Default rules cannot be deleted",
+            ))?;
+        }
+
+        let connection = DB::from_request(request)?;
+
+        rule.delete(&connection)?;
+
+        Ok(Response::with(EmptyResponse(Status::Ok)))
+    }
+}
+
+/// The GET `/pushrules/:scope/:kind/:rule_id/enabled` endpoint.
+#[derive(Clone, Copy, Debug)]
+pub struct GetPushRuleEnabled;
+
+middleware_chain!(
+    GetPushRuleEnabled,
+    [
+        PushRuleScopeParam,
+        PushRuleKindParam,
+        RuleIdParam,
+        AccessTokenAuth
+    ]
+);
+
+impl Handler for GetPushRuleEnabled {
+    fn handle(&self, request: &mut Request<'_, '_>) -> IronResult<Response> {
+        let rule = find_push_rule(request)?;
+
+        let response = EnabledBody {
+            enabled: rule.enabled,
+        };
+
+        Ok(Response::with((Status::Ok, SerializableResponse(response))))
+    }
+}
+
+/// The PUT `/pushrules/:scope/:kind/:rule_id/enabled` endpoint.
+#[derive(Clone, Copy, Debug)]
+pub struct PutPushRuleEnabled;
+
+middleware_chain!(
+    PutPushRuleEnabled,
+    [
+        PushRuleScopeParam,
+        PushRuleKindParam,
+        RuleIdParam,
+        JsonRequest,
+        AccessTokenAuth
+    ]
+);
+
+impl Handler for PutPushRuleEnabled {
+    fn handle(&self, request: &mut Request<'_, '_>) -> IronResult<Response> {
+        let enabled = match request.get::<bodyparser::Struct<EnabledBody>>() {
+            Ok(Some(enabled_body)) => enabled_body.enabled,
+            Ok(None) | Err(_) => Err(ApiError::bad_json(None))?,
+        };
+
+        let mut rule = find_push_rule(request)?;
+
+        let connection = DB::from_request(request)?;
+
+        rule.set_enabled(&connection, enabled)?;
+
+        Ok(Response::with(EmptyResponse(Status::Ok)))
+    }
+}
+
+/// The GET `/pushrules/:scope/:kind/:rule_id/actions` endpoint.
+#[derive(Clone, Copy, Debug)]
+pub struct GetPushRuleActions;
+
+middleware_chain!(
+    GetPushRuleActions,
+    [
+        PushRuleScopeParam,
+        PushRuleKindParam,
+        RuleIdParam,
+        AccessTokenAuth
+    ]
+);
+
+impl Handler for GetPushRuleActions {
+    fn handle(&self, request: &mut Request<'_, '_>) -> IronResult<Response> {
+        let rule = find_push_rule(request)?;
+
+        let response = ActionsBody {
+            actions: rule.actions()?,
+        };
+
+        Ok(Response::with((Status::Ok, SerializableResponse(response))))
+    }
+}
+
+/// The PUT `/pushrules/:scope/:kind/:rule_id/actions` endpoint.
+#[derive(Clone, Copy, Debug)]
+pub struct PutPushRuleActions;
+
+middleware_chain!(
+    PutPushRuleActions,
+    [
+        PushRuleScopeParam,
+        PushRuleKindParam,
+        RuleIdParam,
+        JsonRequest,
+        AccessTokenAuth
+    ]
+);
+
+impl Handler for PutPushRuleActions {
+    fn handle(&self, request: &mut Request<'_, '_>) -> IronResult<Response> {
+        let actions = match request.get::<bodyparser::Struct<ActionsBody>>() {
+            Ok(Some(actions_body)) => actions_body.actions,
+            Ok(None) | Err(_) => Err(ApiError::bad_json(None))?,
+        };
+
+        let mut rule = find_push_rule(request)?;
+
+        let connection = DB::from_request(request)?;
+
+        rule.set_actions(&connection, &actions)?;
+
+        Ok(Response::with(EmptyResponse(Status::Ok)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::query::SyncOptions;
+    use crate::test::Test;
+    use iron::status::Status;
+    use serde_json::Value;
+
+    fn rule_ids(rules: &Value) -> Vec<&str> {
+        rules
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|rule| rule.get("rule_id").unwrap().as_str().unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn get_default_push_rules() {
+        let test = Test::new();
+        let alice = test.create_user();
+
+        let response = test.get(&format!(
+            "/_matrix/client/r0/pushrules/?access_token={}",
+            alice.token
+        ));
+
+        assert_eq!(response.status, Status::Ok);
+
+        let global = response.json().get("global").unwrap();
+        let override_rules = rule_ids(global.get("override").unwrap());
+        assert_eq!(override_rules[0], ".m.rule.master");
+        assert!(override_rules.contains(&".m.rule.contains_display_name"));
+        assert_eq!(
+            rule_ids(global.get("content").unwrap()),
+            vec![".m.rule.contains_user_name"]
+        );
+        assert!(rule_ids(global.get("room").unwrap()).is_empty());
+        assert!(rule_ids(global.get("sender").unwrap()).is_empty());
+        assert_eq!(
+            *rule_ids(global.get("underride").unwrap()).last().unwrap(),
+            ".m.rule.encrypted"
+        );
+
+        let content_rule = global.pointer("/content/0").unwrap();
+        assert_eq!(
+            content_rule.get("pattern").unwrap().as_str().unwrap(),
+            alice.id.split(':').next().unwrap().trim_start_matches('@')
+        );
+        assert!(content_rule.get("conditions").is_none());
+    }
+
+    #[test]
+    fn put_and_get_push_rule() {
+        let test = Test::new();
+        let alice = test.create_user();
+
+        let rule_path = format!(
+            "/_matrix/client/r0/pushrules/global/content/cake?access_token={}",
+            alice.token
+        );
+        let response = test.put(
+            &rule_path,
+            r#"{"pattern": "cake*lie", "actions": ["notify", {"set_tweak": "highlight"}]}"#,
+        );
+        assert_eq!(response.status, Status::Ok);
+
+        let response = test.get(&rule_path);
+        assert_eq!(response.status, Status::Ok);
+
+        let rule = response.json();
+        assert_eq!(rule.get("rule_id").unwrap().as_str().unwrap(), "cake");
+        assert_eq!(rule.get("default").unwrap(), false);
+        assert_eq!(rule.get("enabled").unwrap(), true);
+        assert_eq!(rule.get("pattern").unwrap().as_str().unwrap(), "cake*lie");
+        assert_eq!(
+            rule.get("actions").unwrap().to_string(),
+            r#"["notify",{"set_tweak":"highlight"}]"#
+        );
+    }
+
+    #[test]
+    fn new_push_rules_are_placed_before_default_rules() {
+        let test = Test::new();
+        let alice = test.create_user();
+
+        for rule_id in &["first", "second"] {
+            let response = test.put(
+                &format!(
+                    "/_matrix/client/r0/pushrules/global/override/{}?access_token={}",
+                    rule_id, alice.token
+                ),
+                r#"{"actions": ["dont_notify"], "conditions": []}"#,
+            );
+            assert_eq!(response.status, Status::Ok);
+        }
+
+        let response = test.put(
+            &format!(
+                "/_matrix/client/r0/pushrules/global/override/third?after=first&access_token={}",
+                alice.token
+            ),
+            r#"{"actions": ["dont_notify"]}"#,
+        );
+        assert_eq!(response.status, Status::Ok);
+
+        let response = test.get(&format!(
+            "/_matrix/client/r0/pushrules/global/?access_token={}",
+            alice.token
+        ));
+        let override_rules = rule_ids(response.json().get("override").unwrap());
+
+        assert_eq!(
+            override_rules[..5],
+            [
+                ".m.rule.master",
+                "second",
+                "first",
+                "third",
+                ".m.rule.suppress_notices"
+            ]
+        );
+    }
+
+    #[test]
+    fn push_rules_cannot_be_placed_relative_to_default_rules() {
+        let test = Test::new();
+        let alice = test.create_user();
+
+        let response = test.put(
+            &format!(
+                "/_matrix/client/r0/pushrules/global/override/mine?before=.m.rule.master&access_token={}",
+                alice.token
+            ),
+            r#"{"actions": ["notify"]}"#,
+        );
+        assert_eq!(response.status, Status::BadRequest);
+
+        let response = test.put(
+            &format!(
+                "/_matrix/client/r0/pushrules/global/override/mine?after=unknown&access_token={}",
+                alice.token
+            ),
+            r#"{"actions": ["notify"]}"#,
+        );
+        assert_eq!(response.status, Status::NotFound);
+    }
+
+    #[test]
+    fn invalid_push_rules() {
+        let test = Test::new();
+        let alice = test.create_user();
+
+        let invalid_rules = [
+            ("global/override/.m.rule.mine", r#"{"actions": ["notify"]}"#),
+            ("global/content/cake", r#"{"actions": ["notify"]}"#),
+            ("global/room/not_a_room", r#"{"actions": ["notify"]}"#),
+            ("global/sender/not_a_user", r#"{"actions": ["notify"]}"#),
+            ("device/override/mine", r#"{"actions": ["notify"]}"#),
+            ("global/unknown/mine", r#"{"actions": ["notify"]}"#),
+        ];
+
+        for (path, body) in &invalid_rules {
+            let response = test.put(
+                &format!(
+                    "/_matrix/client/r0/pushrules/{}?access_token={}",
+                    path, alice.token
+                ),
+                body,
+            );
+            assert_eq!(response.status, Status::BadRequest, "{}", path);
+        }
+
+        let response = test.put(
+            &format!(
+                "/_matrix/client/r0/pushrules/global/override/mine?access_token={}",
+                alice.token
+            ),
+            r#"{"actions": ["explode"]}"#,
+        );
+        assert_eq!(response.status, Status::UnprocessableEntity);
+    }
+
+    #[test]
+    fn delete_push_rule() {
+        let test = Test::new();
+        let alice = test.create_user();
+
+        let rule_path = format!(
+            "/_matrix/client/r0/pushrules/global/sender/{}?access_token={}",
+            alice.id, alice.token
+        );
+        let response = test.put(&rule_path, r#"{"actions": ["dont_notify"]}"#);
+        assert_eq!(response.status, Status::Ok);
+
+        let response = test.delete(&rule_path);
+        assert_eq!(response.status, Status::Ok);
+
+        let response = test.get(&rule_path);
+        assert_eq!(response.status, Status::NotFound);
+
+        let response = test.delete(&format!(
+            "/_matrix/client/r0/pushrules/global/override/.m.rule.master?access_token={}",
+            alice.token
+        ));
+        assert_eq!(response.status, Status::BadRequest);
+    }
+
+    #[test]
+    fn enable_push_rule() {
+        let test = Test::new();
+        let alice = test.create_user();
+
+        let enabled_path = format!(
+            "/_matrix/client/r0/pushrules/global/override/.m.rule.master/enabled?access_token={}",
+            alice.token
+        );
+
+        let response = test.get(&enabled_path);
+        assert_eq!(response.status, Status::Ok);
+        assert_eq!(response.json().get("enabled").unwrap(), false);
+
+        let response = test.put(&enabled_path, r#"{"enabled": true}"#);
+        assert_eq!(response.status, Status::Ok);
+
+        let response = test.get(&enabled_path);
+        assert_eq!(response.json().get("enabled").unwrap(), true);
+    }
+
+    #[test]
+    fn set_push_rule_actions() {
+        let test = Test::new();
+        let alice = test.create_user();
+
+        let actions_path = format!(
+            "/_matrix/client/r0/pushrules/global/underride/.m.rule.message/actions?access_token={}",
+            alice.token
+        );
+
+        let response = test.put(&actions_path, r#"{"actions": ["dont_notify"]}"#);
+        assert_eq!(response.status, Status::Ok);
+
+        let response = test.get(&actions_path);
+        assert_eq!(response.status, Status::Ok);
+        assert_eq!(
+            response.json().get("actions").unwrap().to_string(),
+            r#"["dont_notify"]"#
+        );
+    }
+
+    #[test]
+    fn room_rule_mutes_notifications() {
+        let test = Test::new();
+        let (alice, room_id) = test.initial_fixtures(r#"{"visibility": "public"}"#);
+        let bob = test.create_user();
+
+        assert_eq!(test.join_room(&bob.token, &room_id).status, Status::Ok);
+
+        let response = test.put(
+            &format!(
+                "/_matrix/client/r0/pushrules/global/room/{}?access_token={}",
+                room_id, bob.token
+            ),
+            r#"{"actions": ["dont_notify"]}"#,
+        );
+        assert_eq!(response.status, Status::Ok);
+
+        let response = test.send_message(&alice.token, &room_id, "Hi", 1);
+        assert_eq!(response.status, Status::Ok);
+
+        let options = SyncOptions {
+            filter: None,
+            since: None,
+            full_state: false,
+            set_presence: None,
+            timeout: 0,
+        };
+
+        let response = test.sync(&bob.token, options);
+
+        let counts = response
+            .json()
+            .pointer(&format!("/rooms/join/{}/unread_notifications", room_id))
+            .unwrap();
+        assert_eq!(counts.get("notification_count").unwrap(), 0);
+        assert_eq!(counts.get("highlight_count").unwrap(), 0);
+    }
+}
@@ -0,0 +1,166 @@
+//! Endpoints for receipts.
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use iron::status::Status;
+use iron::{Chain, Handler, IronResult, Request, Response};
+
+use crate::db::DB;
+use crate::error::ApiError;
+use crate::middleware::{
+    AccessTokenAuth, EventIdParam, MiddlewareChain, ReceiptTypeParam, RoomIdParam,
+};
+use crate::models::event::Event;
+use crate::models::receipt::{NewReceipt, Receipt};
+use crate::models::room_membership::RoomMembership;
+use crate::models::user::User;
+use crate::modifier::EmptyResponse;
+
+/// The POST `/rooms/:room_id/receipt/:receipt_type/:event_id` endpoint.
+#[derive(Clone, Copy, Debug)]
+pub struct PostReceipt;
+
+middleware_chain!(
+    PostReceipt,
+    [RoomIdParam, ReceiptTypeParam, EventIdParam, AccessTokenAuth]
+);
+
+impl Handler for PostReceipt {
+    fn handle(&self, request: &mut Request<'_, '_>) -> IronResult<Response> {
+        let room_id = request
+            .extensions
+            .get::<RoomIdParam>()
+            .expect("RoomIdParam should ensure a RoomId")
+            .clone();
+
+        let receipt_type = request
+            .extensions
+            .get::<ReceiptTypeParam>()
+            .expect("ReceiptTypeParam should ensure a receipt type")
+            .clone();
+
+        let event_id = request
+            .extensions
+            .get::<EventIdParam>()
+            .expect("EventIdParam should ensure an EventId")
+            .clone();
+
+        let user = request
+            .extensions
+            .get::<User>()
+            .expect("AccessTokenAuth should ensure a user")
+            .clone();
+
+        if receipt_type != "m.read" {
+            Err(ApiError::invalid_param(
+                "receipt_type",
+                "Only m.read receipts are supported",
+            ))?;
+        }
+
+        let connection = DB::from_request(request)?;
+
+        let is_joined = match RoomMembership::find(&connection, &room_id, &user.id)? {
+            Some(membership) => membership.membership == "join",
+            None => false,
+        };
+
+        if !is_joined {
+            Err(ApiError::unauthorized(
+                "The user is not a member of the room".to_string(),
+            ))?;
+        }
+
+        let event = match Event::find(&connection, &event_id)? {
+            Some(event) if event.room_id.as_ref() == Some(&room_id) => event,
+            _ => Err(ApiError::not_found(
+                "The event was not found in the room".to_string(),
+            ))?,
+        };
+
+        let since_epoch = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_err(ApiError::from)?;
+
+        let new_receipt = NewReceipt {
+            room_id,
+            user_id: user.id,
+            receipt_type,
+            event_id,
+            event_ordering: event.ordering,
+            ts: since_epoch.as_secs() as i64 * 1000 + i64::from(since_epoch.subsec_millis()),
+        };
+
+        Receipt::upsert(&connection, &new_receipt)?;
+
+        Ok(Response::with(EmptyResponse(Status::Ok)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::test::Test;
+    use iron::status::Status;
+
+    #[test]
+    fn send_read_receipt() {
+        let test = Test::new();
+        let (alice, room_id) = test.initial_fixtures("{}");
+
+        let response = test.send_message(&alice.token, &room_id, "Hi", 1);
+        let event_id = response.json().get("event_id").unwrap().as_str().unwrap();
+
+        let response = test.send_read_receipt(&alice.token, &room_id, event_id);
+
+        assert_eq!(response.status, Status::Ok);
+    }
+
+    #[test]
+    fn send_unsupported_receipt_type() {
+        let test = Test::new();
+        let (alice, room_id) = test.initial_fixtures("{}");
+
+        let response = test.send_message(&alice.token, &room_id, "Hi", 1);
+        let event_id = response.json().get("event_id").unwrap().as_str().unwrap();
+
+        let receipt_path = format!(
+            "/_matrix/client/r0/rooms/{}/receipt/m.delivered/${}:ruma.test?access_token={}",
+            room_id, event_id, alice.token
+        );
+        let response = test.post(&receipt_path, "{}");
+
+        assert_eq!(response.status, Status::BadRequest);
+        assert_eq!(
+            response.json().get("errcode").unwrap().as_str().unwrap(),
+            "IO_RUMA_INVALID_PARAM"
+        );
+    }
+
+    #[test]
+    fn send_read_receipt_for_event_of_another_room() {
+        let test = Test::new();
+        let (alice, room_id) = test.initial_fixtures("{}");
+        let other_room_id = test.create_room(&alice.token);
+
+        let response = test.send_message(&alice.token, &other_room_id, "Hi", 1);
+        let event_id = response.json().get("event_id").unwrap().as_str().unwrap();
+
+        let response = test.send_read_receipt(&alice.token, &room_id, event_id);
+
+        assert_eq!(response.status, Status::NotFound);
+    }
+
+    #[test]
+    fn send_read_receipt_without_membership() {
+        let test = Test::new();
+        let (alice, room_id) = test.initial_fixtures("{}");
+        let bob = test.create_user();
+
+        let response = test.send_message(&alice.token, &room_id, "Hi", 1);
+        let event_id = response.json().get("event_id").unwrap().as_str().unwrap();
+
+        let response = test.send_read_receipt(&bob.token, &room_id, event_id);
+
+        assert_eq!(response.status, Status::Forbidden);
+    }
+}
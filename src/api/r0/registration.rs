@@ -124,8 +124,12 @@ impl Handler for Register {
             return Err(IronError::from(error));
         }
 
-        let (user, access_token) =
-            User::create(&connection, &new_user, &config.macaroon_secret_key)?;
+        let (user, access_token) = User::create(
+            &connection,
+            &new_user,
+            &config.macaroon_secret_key,
+            config.access_token_lifetime,
+        )?;
 
         let new_profile = Profile {
             id: user.id.clone(),
@@ -3,7 +3,6 @@
 use std::convert::From;
 
 use bodyparser;
-use diesel::prelude::*;
 use iron::status::Status;
 use iron::{Chain, Handler, IronResult, Plugin, Request, Response};
 use ruma_events::stripped::StrippedState;
@@ -15,9 +14,9 @@ use crate::db::DB;
 use crate::error::ApiError;
 use crate::middleware::{AccessTokenAuth, JsonRequest, MiddlewareChain};
 use crate::models::room::{CreationOptions, NewRoom, Room, RoomPreset, RoomVisibility};
-use crate::models::room_membership::{RoomMembership, RoomMembershipOptions};
 use crate::models::user::User;
 use crate::modifier::SerializableResponse;
+use crate::notifier::{Notifier, Topic};
 
 /// The `/createRoom` endpoint.
 #[derive(Clone, Copy, Debug)]
@@ -109,22 +108,13 @@ impl Handler for CreateRoom {
             topic: create_room_request.topic,
         };
 
-        let room: Room = connection
-            .transaction::<Room, ApiError, _>(|| {
-                let room = Room::create(&connection, &new_room, &config.domain, &creation_options)?;
+        let room = Room::create(&connection, &new_room, &config.domain, &creation_options)?;
 
-                let options = RoomMembershipOptions {
-                    room_id: room.id.clone(),
-                    user_id: room.user_id.clone(),
-                    sender: room.user_id.clone(),
-                    membership: "join".to_string(),
-                };
+        if let Some(ref invite_list) = creation_options.invite_list {
+            let invitees = invite_list.iter().cloned().map(Topic::User);
 
-                RoomMembership::create(&connection, &config.domain, options)?;
-
-                Ok(room)
-            })
-            .map_err(ApiError::from)?;
+            Notifier::from_request(request)?.notify(invitees);
+        }
 
         let response = CreateRoomResponse { room_id: room.id };
 
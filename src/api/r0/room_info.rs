@@ -1,14 +1,19 @@
-//! Endpoint for retrieving the state of a room.
+//! Endpoints for retrieving the state of a room.
 
 use std::convert::TryInto;
 
+use diesel::pg::PgConnection;
 use iron::status::Status;
 use iron::{Chain, Handler, IronResult, Request, Response};
+use router::Router;
 use ruma_events::collections::all::StateEvent;
+use ruma_identifiers::RoomId;
+use serde_json::{from_str, Value};
+use url::percent_encoding::percent_decode;
 
 use crate::db::DB;
 use crate::error::ApiError;
-use crate::middleware::{AccessTokenAuth, MiddlewareChain, RoomIdParam};
+use crate::middleware::{AccessTokenAuth, EventTypeParam, MiddlewareChain, RoomIdParam};
 use crate::models::event::Event;
 use crate::models::room::Room;
 use crate::models::room_membership::RoomMembership;
@@ -37,40 +42,7 @@ impl Handler for RoomState {
 
         let connection = DB::from_request(request)?;
 
-        let room = match Room::find(&connection, &room_id)? {
-            Some(room) => room,
-            None => Err(ApiError::unauthorized(
-                "The room was not found on this server".to_string(),
-            ))?,
-        };
-
-        let membership = RoomMembership::find(&connection, &room.id, &user.id)?;
-
-        if membership.is_none() {
-            Err(ApiError::unauthorized(
-                "The user is not a member of the room".to_string(),
-            ))?
-        }
-
-        let membership_state = membership.clone().unwrap().membership;
-        let mut events = Vec::<Event>::new();
-
-        match membership_state.as_ref() {
-            "join" => {
-                events.append(&mut Event::get_room_full_state(&connection, &room_id)?);
-            }
-            "leave" => {
-                let last_event = Event::find(&connection, &membership.unwrap().event_id)?
-                    .expect("A room membership should be associated with an event");
-
-                events.append(&mut Event::get_room_state_events_until(
-                    &connection,
-                    &room_id,
-                    &last_event,
-                )?);
-            }
-            _ => {}
-        }
+        let events = visible_room_state(&connection, &room_id, &user)?;
 
         let mut state_events: Vec<StateEvent> = Vec::new();
 
@@ -85,6 +57,100 @@ impl Handler for RoomState {
     }
 }
 
+/// The `/rooms/:room_id/state/:event_type` and `/rooms/:room_id/state/:event_type/:state_key`
+/// endpoints.
+#[derive(Clone, Copy, Debug)]
+pub struct GetStateEvent;
+
+middleware_chain!(
+    GetStateEvent,
+    [RoomIdParam, EventTypeParam, AccessTokenAuth]
+);
+
+impl Handler for GetStateEvent {
+    fn handle(&self, request: &mut Request<'_, '_>) -> IronResult<Response> {
+        let params = request
+            .extensions
+            .get::<Router>()
+            .expect("Params object is missing")
+            .clone();
+
+        let user = request
+            .extensions
+            .get::<User>()
+            .expect("AccessTokenAuth should ensure a user")
+            .clone();
+
+        let room_id = request
+            .extensions
+            .get::<RoomIdParam>()
+            .expect("RoomIdParam should ensure a room_id")
+            .clone();
+
+        let event_type = request
+            .extensions
+            .get::<EventTypeParam>()
+            .expect("EventTypeParam should ensure an EventType")
+            .to_string();
+
+        let state_key = percent_decode(params.find("state_key").unwrap_or("").as_bytes())
+            .decode_utf8()
+            .map_err(|err| ApiError::invalid_param("state_key", err))?;
+
+        let connection = DB::from_request(request)?;
+
+        let event = visible_room_state(&connection, &room_id, &user)?
+            .into_iter()
+            .find(|event| {
+                event.event_type == event_type
+                    && event.state_key.as_ref().map(String::as_str) == Some(&*state_key)
+            });
+
+        let event = match event {
+            Some(event) => event,
+            None => Err(ApiError::not_found(format!(
+                "The room has no state event of type {} with state key '{}'",
+                event_type, state_key
+            )))?,
+        };
+
+        let content: Value = from_str(&event.content).map_err(ApiError::from)?;
+
+        Ok(Response::with((Status::Ok, SerializableResponse(content))))
+    }
+}
+
+/// Return the state of a room as visible to the given user.
+///
+/// Joined users see the current state and users who left or were banned see the state at the
+/// time they left. Any other user, including users who forgot the room, is not allowed to see the
+/// state.
+fn visible_room_state(
+    connection: &PgConnection,
+    room_id: &RoomId,
+    user: &User,
+) -> Result<Vec<Event>, ApiError> {
+    let room = match Room::find(connection, room_id)? {
+        Some(room) => room,
+        None => Err(ApiError::unauthorized(
+            "The room was not found on this server".to_string(),
+        ))?,
+    };
+
+    let visible_until = match RoomMembership::find(connection, &room.id, &user.id)? {
+        Some(membership) => membership.visible_until(connection)?,
+        None => None,
+    };
+
+    match visible_until {
+        Some(None) => Event::get_room_full_state(connection, room_id),
+        Some(Some(until)) => Event::get_room_state_events_until(connection, room_id, until),
+        None => Err(ApiError::unauthorized(
+            "The user is not a member of the room".to_string(),
+        )),
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use crate::test::Test;
@@ -107,6 +173,27 @@ mod tests {
         assert_eq!(test.get(&room_state_path).status, Status::Forbidden);
     }
 
+    #[test]
+    fn forbidden_for_users_that_forgot_the_room() {
+        let test = Test::new();
+        let alice = test.create_user();
+        let bob = test.create_user();
+
+        let room_id = test.create_public_room(&alice.token);
+
+        assert_eq!(test.join_room(&bob.token, &room_id).status, Status::Ok);
+        assert_eq!(test.leave_room(&bob.token, &room_id).status, Status::Ok);
+
+        let room_state_path = format!(
+            "/_matrix/client/r0/rooms/{}/state?access_token={}",
+            room_id, bob.token
+        );
+
+        assert_eq!(test.get(&room_state_path).status, Status::Ok);
+        assert_eq!(test.forget_room(&bob.token, &room_id).status, Status::Ok);
+        assert_eq!(test.get(&room_state_path).status, Status::Forbidden);
+    }
+
     #[test]
     fn all_the_events_are_retrieved() {
         let test = Test::new();
@@ -355,4 +442,170 @@ mod tests {
             }
         }
     }
+
+    #[test]
+    fn single_state_event() {
+        let test = Test::new();
+        let alice = test.create_user();
+        let room_options = r#"{
+            "initial_state": [{
+                "state_key": "",
+                "type": "m.room.topic",
+                "content": { "topic": "Test Topic" }
+            }]
+        }"#;
+        let room_id = test.create_room_with_params(&alice.token, room_options);
+
+        let topic_path = format!(
+            "/_matrix/client/r0/rooms/{}/state/m.room.topic?access_token={}",
+            room_id, alice.token
+        );
+        let response = test.get(&topic_path);
+        assert_eq!(response.status, Status::Ok);
+        assert_eq!(
+            response.json().get("topic").unwrap().as_str().unwrap(),
+            "Test Topic"
+        );
+
+        let power_levels_path = format!(
+            "/_matrix/client/r0/rooms/{}/state/m.room.power_levels?access_token={}",
+            room_id, alice.token
+        );
+        let response = test.get(&power_levels_path);
+        assert_eq!(response.status, Status::Ok);
+        assert_eq!(
+            response.json().pointer(&format!("/users/{}", alice.id)),
+            Some(&Value::from(100))
+        );
+    }
+
+    #[test]
+    fn single_state_event_with_state_key() {
+        let test = Test::new();
+        let alice = test.create_user();
+        let bob = test.create_user();
+        let room_id = test.create_public_room(&alice.token);
+        assert_eq!(test.join_room(&bob.token, &room_id).status, Status::Ok);
+
+        let encoded_bob_id = bob.id.replace("@", "%40").replace(":", "%3A");
+        for user_id in &[&alice.id, &encoded_bob_id] {
+            let member_path = format!(
+                "/_matrix/client/r0/rooms/{}/state/m.room.member/{}?access_token={}",
+                room_id, user_id, alice.token
+            );
+            let response = test.get(&member_path);
+            assert_eq!(response.status, Status::Ok);
+            assert_eq!(
+                response.json().get("membership").unwrap().as_str().unwrap(),
+                "join"
+            );
+        }
+
+        let member_path = format!(
+            "/_matrix/client/r0/rooms/{}/state/m.room.member/@carl:ruma.test?access_token={}",
+            room_id, alice.token
+        );
+        assert_eq!(test.get(&member_path).status, Status::NotFound);
+    }
+
+    #[test]
+    fn single_state_event_not_found() {
+        let test = Test::new();
+        let alice = test.create_user();
+        let room_id = test.create_room(&alice.token);
+
+        let topic_path = format!(
+            "/_matrix/client/r0/rooms/{}/state/m.room.topic?access_token={}",
+            room_id, alice.token
+        );
+        let response = test.get(&topic_path);
+        assert_eq!(response.status, Status::NotFound);
+        assert_eq!(
+            response.json().get("errcode").unwrap().as_str().unwrap(),
+            "M_NOT_FOUND"
+        );
+    }
+
+    #[test]
+    fn single_state_event_forbidden_for_non_members() {
+        let test = Test::new();
+        let alice = test.create_user();
+        let bob = test.create_user();
+        let room_id = test.create_public_room(&alice.token);
+
+        let power_levels_path = format!(
+            "/_matrix/client/r0/rooms/{}/state/m.room.power_levels?access_token={}",
+            room_id, bob.token
+        );
+        assert_eq!(test.get(&power_levels_path).status, Status::Forbidden);
+    }
+
+    #[test]
+    fn single_state_event_forbidden_for_invited_users() {
+        let test = Test::new();
+        let alice = test.create_user();
+        let bob = test.create_user();
+        let room_id = test.create_public_room(&alice.token);
+
+        assert_eq!(
+            test.invite(&alice.token, &room_id, &bob.id).status,
+            Status::Ok
+        );
+
+        let power_levels_path = format!(
+            "/_matrix/client/r0/rooms/{}/state/m.room.power_levels?access_token={}",
+            room_id, bob.token
+        );
+        let response = test.get(&power_levels_path);
+        assert_eq!(response.status, Status::Forbidden);
+        assert_eq!(
+            response.json().get("errcode").unwrap().as_str().unwrap(),
+            "M_FORBIDDEN"
+        );
+    }
+
+    #[test]
+    fn single_state_event_for_users_that_left() {
+        let test = Test::new();
+        let alice = test.create_user();
+        let bob = test.create_user();
+        let room_options = r#"{
+            "visibility": "public",
+            "initial_state": [{
+                "state_key": "",
+                "type": "m.room.topic",
+                "content": { "topic": "Topic for Bob" }
+            }]
+        }"#;
+        let room_id = test.create_room_with_params(&alice.token, room_options);
+
+        assert_eq!(test.join_room(&bob.token, &room_id).status, Status::Ok);
+        assert_eq!(test.leave_room(&bob.token, &room_id).status, Status::Ok);
+
+        let event_content = r#"{"topic": "Topic for Alice"}"#;
+        let response = test.send_state_event(&alice.token, &room_id, "m.room.topic", event_content);
+        assert_eq!(response.status, Status::Ok);
+
+        let topic_path = format!(
+            "/_matrix/client/r0/rooms/{}/state/m.room.topic?access_token={}",
+            room_id, bob.token
+        );
+        let response = test.get(&topic_path);
+        assert_eq!(response.status, Status::Ok);
+        assert_eq!(
+            response.json().get("topic").unwrap().as_str().unwrap(),
+            "Topic for Bob"
+        );
+
+        let topic_path = format!(
+            "/_matrix/client/r0/rooms/{}/state/m.room.topic?access_token={}",
+            room_id, alice.token
+        );
+        let response = test.get(&topic_path);
+        assert_eq!(response.status, Status::Ok);
+        assert_eq!(
+            response.json().get("topic").unwrap().as_str().unwrap(),
+            "Topic for Alice"
+        );
+    }
 }
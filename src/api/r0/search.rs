@@ -0,0 +1,636 @@
+//! Endpoint for searching the events of the rooms a user can see.
+
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::str::FromStr;
+
+use bodyparser;
+use diesel::pg::PgConnection;
+use iron::status::Status;
+use iron::{Chain, Handler, IronResult, Plugin, Request, Response};
+use ruma_events::collections::all::RoomEvent;
+use ruma_identifiers::{RoomId, UserId};
+use url::Url;
+
+use crate::db::DB;
+use crate::error::ApiError;
+use crate::middleware::{AccessTokenAuth, JsonRequest, MiddlewareChain};
+use crate::models::event::{Event, EventSearch, SearchKey, SearchOrder};
+use crate::models::filter::RoomEventFilter;
+use crate::models::profile::Profile;
+use crate::models::room_membership::RoomMembership;
+use crate::models::user::User;
+use crate::modifier::SerializableResponse;
+use crate::query::Batch;
+
+/// The number of results returned when the filter does not specify a limit.
+const DEFAULT_LIMIT: i64 = 10;
+
+/// The number of events returned on each side of a result when the client asks for context
+/// without specifying a limit.
+const DEFAULT_CONTEXT_LIMIT: i64 = 5;
+
+/// The `/search` endpoint.
+#[derive(Clone, Copy, Debug)]
+pub struct Search;
+
+/// The request body for this API.
+#[derive(Clone, Debug, Deserialize)]
+struct SearchRequest {
+    /// The categories to search in.
+    search_categories: SearchCategories,
+}
+
+/// The categories of a search request.
+#[derive(Clone, Debug, Deserialize)]
+struct SearchCategories {
+    /// The criteria for searching room events.
+    room_events: Option<RoomEventsCriteria>,
+}
+
+/// The criteria for searching room events.
+#[derive(Clone, Debug, Deserialize)]
+struct RoomEventsCriteria {
+    /// The words to look for.
+    search_term: String,
+    /// The content keys to look in, all of them if unspecified.
+    keys: Option<Vec<SearchKey>>,
+    /// A filter restricting the events to search.
+    filter: Option<RoomEventFilter>,
+    /// The order of the results, by rank if unspecified.
+    order_by: Option<SearchOrder>,
+    /// Whether to return the events around each result, and how many.
+    event_context: Option<EventContextCriteria>,
+}
+
+/// The events to return around each search result.
+#[derive(Clone, Copy, Debug, Deserialize)]
+struct EventContextCriteria {
+    /// The number of events to return before the result.
+    #[serde(default = "default_context_limit")]
+    before_limit: i64,
+    /// The number of events to return after the result.
+    #[serde(default = "default_context_limit")]
+    after_limit: i64,
+    /// Whether to return the profiles of the senders of the returned events.
+    #[serde(default)]
+    include_profile: bool,
+}
+
+/// Helper function for the limits of `EventContextCriteria` when deserializing with serde.
+fn default_context_limit() -> i64 {
+    DEFAULT_CONTEXT_LIMIT
+}
+
+/// The body of the response for this API.
+#[derive(Debug, Serialize)]
+struct SearchResponse {
+    /// The results for each requested category.
+    search_categories: SearchResultCategories,
+}
+
+/// The results of a search, by category.
+#[derive(Debug, Serialize)]
+struct SearchResultCategories {
+    /// The results of the room events search.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    room_events: Option<RoomEventResults>,
+}
+
+/// The results of a room events search.
+#[derive(Debug, Serialize)]
+struct RoomEventResults {
+    /// The total number of matching events.
+    count: i64,
+    /// The words to highlight in the results.
+    highlights: Vec<String>,
+    /// The page of matching events.
+    results: Vec<SearchResult>,
+    /// The token to pass as `next_batch` to get the next page, if there is one.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    next_batch: Option<String>,
+}
+
+/// A matching event.
+#[derive(Debug, Serialize)]
+struct SearchResult {
+    /// How well the event matches the search term.
+    rank: f32,
+    /// The event.
+    result: RoomEvent,
+    /// The events around the event, if requested.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    context: Option<EventContext>,
+}
+
+/// The events around a search result.
+#[derive(Debug, Serialize)]
+struct EventContext {
+    /// A token to paginate backward from the earliest returned event.
+    start: String,
+    /// A token to paginate forward from the latest returned event.
+    end: String,
+    /// The events before the result, most recent first.
+    events_before: Vec<RoomEvent>,
+    /// The events after the result, oldest first.
+    events_after: Vec<RoomEvent>,
+    /// The profiles of the senders of the events, if requested.
+    #[serde(skip_serializing_if = "HashMap::is_empty")]
+    profile_info: HashMap<String, ProfileInfo>,
+}
+
+/// The profile of an event sender.
+#[derive(Debug, Serialize)]
+struct ProfileInfo {
+    /// The sender's display name.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    displayname: Option<String>,
+    /// The sender's avatar URL.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    avatar_url: Option<String>,
+}
+
+middleware_chain!(Search, [JsonRequest, AccessTokenAuth]);
+
+impl Handler for Search {
+    fn handle(&self, request: &mut Request<'_, '_>) -> IronResult<Response> {
+        let search_request = match request.get::<bodyparser::Struct<SearchRequest>>() {
+            Ok(Some(search_request)) => search_request,
+            Ok(None) | Err(_) => Err(ApiError::bad_json(None))?,
+        };
+
+        let user = request
+            .extensions
+            .get::<User>()
+            .expect("AccessTokenAuth should ensure a user")
+            .clone();
+
+        let url: Url = request.url.clone().into();
+        let mut offset = 0;
+        for (key, value) in url.query_pairs().into_owned() {
+            if key == "next_batch" {
+                offset = i64::from_str(&value)
+                    .map_err(|err| ApiError::invalid_param("next_batch", err))?;
+
+                if offset < 0 {
+                    Err(ApiError::invalid_param(
+                        "next_batch",
+                        "Must not be negative",
+                    ))?;
+                }
+            }
+        }
+
+        let room_events = match search_request.search_categories.room_events {
+            Some(criteria) => {
+                if let Some(event_context) = criteria.event_context {
+                    if event_context.before_limit < 0 {
+                        Err(ApiError::invalid_param(
+                            "before_limit",
+                            "Must not be negative",
+                        ))?;
+                    }
+
+                    if event_context.after_limit < 0 {
+                        Err(ApiError::invalid_param(
+                            "after_limit",
+                            "Must not be negative",
+                        ))?;
+                    }
+                }
+
+                let connection = DB::from_request(request)?;
+
+                Some(search_room_events(
+                    &connection,
+                    &user.id,
+                    &criteria,
+                    offset,
+                )?)
+            }
+            None => None,
+        };
+
+        let response = SearchResponse {
+            search_categories: SearchResultCategories { room_events },
+        };
+
+        Ok(Response::with((Status::Ok, SerializableResponse(response))))
+    }
+}
+
+/// Search the events of the rooms the user can see, returning the page of results at `offset`.
+fn search_room_events(
+    connection: &PgConnection,
+    user_id: &UserId,
+    criteria: &RoomEventsCriteria,
+    offset: i64,
+) -> Result<RoomEventResults, ApiError> {
+    let mut rooms: Vec<(RoomId, Option<i64>)> = Vec::new();
+    for membership in RoomMembership::find_all_by_uid(connection, user_id)? {
+        if let Some(until) = membership.visible_until(connection)? {
+            rooms.push((membership.room_id, until));
+        }
+    }
+
+    let limit = match criteria.filter {
+        Some(ref filter) if filter.limit > 0 => filter.limit as i64,
+        _ => DEFAULT_LIMIT,
+    };
+
+    let search = EventSearch {
+        search_term: &criteria.search_term,
+        keys: criteria
+            .keys
+            .as_ref()
+            .map(Vec::as_slice)
+            .unwrap_or(&SearchKey::ALL),
+        rooms: &rooms,
+        filter: criteria.filter.as_ref(),
+        order_by: criteria.order_by.unwrap_or(SearchOrder::Rank),
+    };
+
+    let (count, matches) = Event::search(connection, &search, offset, limit)?;
+
+    let next_offset = offset + matches.len() as i64;
+    let next_batch = if next_offset < count {
+        Some(next_offset.to_string())
+    } else {
+        None
+    };
+
+    let mut results = Vec::new();
+    for (event, rank) in matches {
+        let context = match criteria.event_context {
+            Some(event_context) => {
+                let until = rooms
+                    .iter()
+                    .find(|(room_id, _)| event.room_id.as_ref() == Some(room_id))
+                    .and_then(|(_, until)| *until);
+
+                Some(find_context(
+                    connection,
+                    user_id,
+                    &event,
+                    event_context,
+                    until,
+                )?)
+            }
+            None => None,
+        };
+
+        results.push(SearchResult {
+            rank,
+            result: event.for_user(user_id).try_into()?,
+            context,
+        });
+    }
+
+    Ok(RoomEventResults {
+        count,
+        highlights: highlights(&criteria.search_term),
+        results,
+        next_batch,
+    })
+}
+
+/// Return the events around a search result.
+fn find_context(
+    connection: &PgConnection,
+    user_id: &UserId,
+    event: &Event,
+    criteria: EventContextCriteria,
+    until: Option<i64>,
+) -> Result<EventContext, ApiError> {
+    let (events_before, events_after) = Event::find_context(
+        connection,
+        event,
+        criteria.before_limit,
+        criteria.after_limit,
+        until,
+    )?;
+
+    let start = events_before
+        .last()
+        .map_or(event.ordering, |event| event.ordering)
+        - 1;
+    let end = events_after
+        .last()
+        .map_or(event.ordering, |event| event.ordering);
+
+    let mut profile_info = HashMap::new();
+    if criteria.include_profile {
+        let senders: Vec<UserId> = events_before
+            .iter()
+            .chain(events_after.iter())
+            .chain(Some(event))
+            .map(|event| event.sender.clone())
+            .collect();
+
+        for profile in Profile::get_profiles(connection, &senders)? {
+            profile_info.insert(
+                profile.id.to_string(),
+                ProfileInfo {
+                    displayname: profile.displayname,
+                    avatar_url: profile.avatar_url,
+                },
+            );
+        }
+    }
+
+    Ok(EventContext {
+        start: Batch::new(start, 0, 0, 0, 0).to_string(),
+        end: Batch::new(end, 0, 0, 0, 0).to_string(),
+        events_before: events_before
+            .into_iter()
+            .map(|event| event.for_user(user_id).try_into())
+            .collect::<Result<_, _>>()?,
+        events_after: events_after
+            .into_iter()
+            .map(|event| event.for_user(user_id).try_into())
+            .collect::<Result<_, _>>()?,
+        profile_info,
+    })
+}
+
+/// Split a search term into the lowercase words to highlight in the results.
+fn highlights(search_term: &str) -> Vec<String> {
+    let mut words: Vec<String> = search_term
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .map(str::to_lowercase)
+        .collect();
+    words.sort();
+    words.dedup();
+
+    words
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::test::{Response, Test};
+    use iron::status::Status;
+    use serde_json::Value;
+
+    /// Search the room events visible to a user.
+    fn search(
+        test: &Test,
+        access_token: &str,
+        criteria: &str,
+        next_batch: Option<&str>,
+    ) -> Response {
+        let mut search_path = format!("/_matrix/client/r0/search?access_token={}", access_token);
+        if let Some(next_batch) = next_batch {
+            search_path.push_str(&format!("&next_batch={}", next_batch));
+        }
+
+        let body = format!(
+            r#"{{"search_categories": {{"room_events": {}}}}}"#,
+            criteria
+        );
+        test.post(&search_path, &body)
+    }
+
+    /// Return the bodies of the events in the results of a search.
+    fn result_bodies(room_events: &Value) -> Vec<&str> {
+        room_events
+            .get("results")
+            .unwrap()
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|result| {
+                result
+                    .pointer("/result/content/body")
+                    .unwrap()
+                    .as_str()
+                    .unwrap()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn search_messages_of_joined_rooms() {
+        let test = Test::new();
+        let (alice, room_id) = test.initial_fixtures("{}");
+        let (bob, other_room_id) = test.initial_fixtures("{}");
+
+        test.send_message(&alice.token, &room_id, "Where are the holiday photos?", 1);
+        test.send_message(&alice.token, &room_id, "Never mind", 2);
+        test.send_message(&bob.token, &other_room_id, "My holiday photos", 1);
+
+        let response = search(&test, &alice.token, r#"{"search_term": "photo"}"#, None);
+
+        assert_eq!(response.status, Status::Ok);
+        let room_events = response
+            .json()
+            .pointer("/search_categories/room_events")
+            .unwrap();
+        assert_eq!(room_events.get("count").unwrap().as_i64().unwrap(), 1);
+        assert_eq!(
+            result_bodies(room_events),
+            vec!["Where are the holiday photos?"]
+        );
+        assert_eq!(
+            room_events
+                .pointer("/results/0/result/room_id")
+                .unwrap()
+                .as_str()
+                .unwrap(),
+            room_id
+        );
+        assert!(room_events.get("next_batch").is_none());
+        assert_eq!(
+            room_events.get("highlights").unwrap().as_array().unwrap(),
+            &vec![Value::String("photo".to_string())]
+        );
+    }
+
+    #[test]
+    fn search_room_names() {
+        let test = Test::new();
+        let (alice, _) = test.initial_fixtures(r#"{"name": "Holiday planning"}"#);
+        let room_id = test.create_room(&alice.token);
+        test.send_message(&alice.token, &room_id, "Holiday!", 1);
+
+        let criteria = r#"{"search_term": "holiday", "keys": ["content.name"]}"#;
+        let response = search(&test, &alice.token, criteria, None);
+
+        assert_eq!(response.status, Status::Ok);
+        let room_events = response
+            .json()
+            .pointer("/search_categories/room_events")
+            .unwrap();
+        assert_eq!(room_events.get("count").unwrap().as_i64().unwrap(), 1);
+        assert_eq!(
+            room_events
+                .pointer("/results/0/result/content/name")
+                .unwrap()
+                .as_str()
+                .unwrap(),
+            "Holiday planning"
+        );
+    }
+
+    #[test]
+    fn search_after_leaving_a_room() {
+        let test = Test::new();
+        let (alice, room_id) = test.initial_fixtures(r#"{"visibility": "public"}"#);
+        let bob = test.create_user();
+
+        test.send_message(&alice.token, &room_id, "Lunch at noon", 1);
+        test.join_room(&bob.token, &room_id);
+        test.send_message(&alice.token, &room_id, "Lunch is ready", 2);
+        test.leave_room(&bob.token, &room_id);
+        test.send_message(&alice.token, &room_id, "Lunch is over", 3);
+
+        let criteria = r#"{"search_term": "lunch", "order_by": "recent"}"#;
+        let response = search(&test, &bob.token, criteria, None);
+
+        assert_eq!(response.status, Status::Ok);
+        let room_events = response
+            .json()
+            .pointer("/search_categories/room_events")
+            .unwrap();
+        assert_eq!(
+            result_bodies(room_events),
+            vec!["Lunch is ready", "Lunch at noon"]
+        );
+    }
+
+    #[test]
+    fn paginate_search_results() {
+        let test = Test::new();
+        let (alice, room_id) = test.initial_fixtures("{}");
+
+        test.send_message(&alice.token, &room_id, "First cake", 1);
+        test.send_message(&alice.token, &room_id, "Second cake", 2);
+        test.send_message(&alice.token, &room_id, "Third cake", 3);
+
+        let criteria = r#"{"search_term": "cake", "order_by": "recent", "filter": {"limit": 2}}"#;
+        let response = search(&test, &alice.token, criteria, None);
+
+        assert_eq!(response.status, Status::Ok);
+        let room_events = response
+            .json()
+            .pointer("/search_categories/room_events")
+            .unwrap();
+        assert_eq!(room_events.get("count").unwrap().as_i64().unwrap(), 3);
+        assert_eq!(
+            result_bodies(room_events),
+            vec!["Third cake", "Second cake"]
+        );
+        let next_batch = room_events.get("next_batch").unwrap().as_str().unwrap();
+
+        let response = search(&test, &alice.token, criteria, Some(next_batch));
+
+        assert_eq!(response.status, Status::Ok);
+        let room_events = response
+            .json()
+            .pointer("/search_categories/room_events")
+            .unwrap();
+        assert_eq!(result_bodies(room_events), vec!["First cake"]);
+        assert!(room_events.get("next_batch").is_none());
+    }
+
+    #[test]
+    fn search_with_event_context() {
+        let test = Test::new();
+        let (alice, room_id) = test.initial_fixtures("{}");
+
+        let displayname_path = format!(
+            "/_matrix/client/r0/profile/{}/displayname?access_token={}",
+            alice.id, alice.token
+        );
+        test.put(&displayname_path, r#"{"displayname": "Alice"}"#);
+
+        test.send_message(&alice.token, &room_id, "Before", 1);
+        test.send_message(&alice.token, &room_id, "Found it", 2);
+        test.send_message(&alice.token, &room_id, "After", 3);
+
+        let criteria = r#"{
+            "search_term": "found",
+            "event_context": {"before_limit": 1, "after_limit": 1, "include_profile": true}
+        }"#;
+        let response = search(&test, &alice.token, criteria, None);
+
+        assert_eq!(response.status, Status::Ok);
+        let context = response
+            .json()
+            .pointer("/search_categories/room_events/results/0/context")
+            .unwrap();
+        assert_eq!(
+            context
+                .pointer("/events_before/0/content/body")
+                .unwrap()
+                .as_str()
+                .unwrap(),
+            "Before"
+        );
+        assert_eq!(
+            context
+                .pointer("/events_after/0/content/body")
+                .unwrap()
+                .as_str()
+                .unwrap(),
+            "After"
+        );
+        assert_eq!(
+            context
+                .pointer(&format!("/profile_info/{}/displayname", alice.id))
+                .unwrap()
+                .as_str()
+                .unwrap(),
+            "Alice"
+        );
+
+        let messages_path = format!(
+            "/_matrix/client/r0/rooms/{}/messages?from={}&dir=f&access_token={}",
+            room_id,
+            context.get("end").unwrap().as_str().unwrap(),
+            alice.token
+        );
+        let response = test.get(&messages_path);
+
+        assert_eq!(response.status, Status::Ok);
+        assert!(response
+            .json()
+            .get("chunk")
+            .unwrap()
+            .as_array()
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn search_with_invalid_next_batch() {
+        let test = Test::new();
+        let (alice, _) = test.initial_fixtures("{}");
+
+        let response = search(&test, &alice.token, r#"{"search_term": "x"}"#, Some("abc"));
+
+        assert_eq!(response.status, Status::BadRequest);
+        assert_eq!(
+            response.json().get("errcode").unwrap().as_str().unwrap(),
+            "IO_RUMA_INVALID_PARAM"
+        );
+    }
+
+    #[test]
+    fn search_with_negative_context_limit() {
+        let test = Test::new();
+        let (alice, _) = test.initial_fixtures("{}");
+
+        let body = r#"{
+            "search_term": "x",
+            "event_context": {"before_limit": -1}
+        }"#;
+        let response = search(&test, &alice.token, body, None);
+
+        assert_eq!(response.status, Status::BadRequest);
+        assert_eq!(
+            response.json().get("errcode").unwrap().as_str().unwrap(),
+            "IO_RUMA_INVALID_PARAM"
+        );
+    }
+}
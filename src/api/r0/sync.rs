@@ -1,20 +1,36 @@
 //! Endpoints for syncing.
+use std::cmp;
+use std::collections::HashSet;
 use std::str::FromStr;
+use std::time::{Duration, Instant};
 use std::u64;
 
+use diesel::pg::PgConnection;
 use iron::status::Status;
 use iron::{Chain, Handler, IronResult, Request, Response};
 use ruma_events::presence::PresenceState;
+use ruma_identifiers::{RoomId, UserId};
 use serde_json::from_str;
 use url::Url;
 
 use crate::config::Config;
 use crate::db::DB;
 use crate::error::ApiError;
+use crate::filtering;
+use crate::lazy_loading::LazyLoading;
 use crate::middleware::{AccessTokenAuth, MiddlewareChain};
+use crate::models::access_token::AccessToken;
+use crate::models::filter::{ContentFilter, Filter};
+use crate::models::presence_list::PresenceList;
+use crate::models::room_membership::RoomMembership;
 use crate::models::user::User;
 use crate::modifier::SerializableResponse;
+use crate::notifier::{Notifier, Topic};
 use crate::query::{self, Batch, SyncOptions};
+use crate::typing::Typing;
+
+/// The maximum time in milliseconds a `/sync` request waits for new data.
+const MAX_TIMEOUT: u64 = 5 * 60 * 1000;
 
 /// The `/sync` endpoint.
 #[derive(Clone, Copy, Debug)]
@@ -30,8 +46,16 @@ impl Handler for Sync {
             .expect("AccessTokenAuth should ensure a user")
             .clone();
 
-        let connection = DB::from_request(request)?;
+        let access_token_id = request
+            .extensions
+            .get::<AccessToken>()
+            .expect("AccessTokenAuth should ensure an access token")
+            .id;
+
         let config = Config::from_request(request)?;
+        let notifier = Notifier::from_request(request)?;
+        let typing = Typing::from_request(request)?;
+        let lazy_loading = LazyLoading::from_request(request)?;
 
         let url: Url = request.url.clone().into();
         let query_pairs = url.query_pairs().into_owned();
@@ -44,9 +68,8 @@ impl Handler for Sync {
         for tuple in query_pairs {
             match (tuple.0.as_ref(), tuple.1.as_ref()) {
                 ("filter", value) => {
-                    let content =
-                        from_str(value).map_err(|err| ApiError::invalid_param("filter", err))?;
-                    filter = Some(content);
+                    let connection = DB::from_request(request)?;
+                    filter = Some(parse_filter(&connection, &user.id, value)?);
                 }
                 ("since", value) => {
                     let batch = Batch::from_str(value)
@@ -75,13 +98,16 @@ impl Handler for Sync {
                     Err(ApiError::invalid_param("set_presence", "Invalid enum!"))?;
                 }
                 ("timeout", value) => {
-                    timeout = u64::from_str_radix(value, 10)
+                    let requested = u64::from_str_radix(value, 10)
                         .map_err(|err| ApiError::invalid_param("timeout", err))?;
+                    timeout = cmp::min(requested, MAX_TIMEOUT);
                 }
                 _ => (),
             }
         }
 
+        let deadline = Instant::now() + Duration::from_millis(timeout);
+
         let options = SyncOptions {
             filter,
             since,
@@ -90,21 +116,121 @@ impl Handler for Sync {
             timeout,
         };
 
-        let response = query::Sync::sync(&connection, &config.domain, &user, options)?;
+        // Lazy-loaded members are remembered by sync token, unless the client starts over.
+        let members_since = match options.since {
+            Some(ref since) if !options.full_state => Some(since.to_string()),
+            _ => None,
+        };
+        let members_since = members_since.as_ref().map(String::as_str);
+
+        loop {
+            // Read the counter before querying, so that changes made during the query are not
+            // missed.
+            let changes = notifier.current();
+
+            let mut sent_members = lazy_loading.synced_since(access_token_id, members_since);
+
+            // The connection goes back to the pool before waiting.
+            let (response, room_ids, topics) = {
+                let connection = DB::from_request(request)?;
+
+                let response = query::Sync::sync(
+                    &connection,
+                    &config.domain,
+                    &typing,
+                    &mut sent_members,
+                    &user,
+                    options.clone(),
+                )?;
+                let room_ids =
+                    RoomMembership::find_room_ids_by_uid_and_state(&connection, &user.id, "join")?;
+                let topics = sync_topics(&connection, &user, &room_ids)?;
+
+                (response, room_ids, topics)
+            };
+
+            // Only incremental syncs wait for new data.
+            let is_empty = match options.since {
+                Some(ref since) if !options.full_state => !response.has_updates_since(since),
+                _ => false,
+            };
+
+            // Typing notifications expire without any request, so stop waiting when they do.
+            let wake_up = match typing.next_expiry(&room_ids) {
+                Some(expiry) if expiry < deadline => expiry,
+                _ => deadline,
+            };
+
+            if !is_empty || (!notifier.wait(topics, changes, wake_up) && wake_up == deadline) {
+                lazy_loading.remember_synced(
+                    access_token_id,
+                    members_since,
+                    response.next_batch(),
+                    sent_members,
+                );
+
+                return Ok(Response::with((Status::Ok, SerializableResponse(response))));
+            }
+        }
+    }
+}
+
+/// Return the topics of the changes that may show up in a user's sync.
+///
+/// These are the user's own changes, the changes of the users on their presence list and the
+/// changes of the rooms they have joined.
+fn sync_topics(
+    connection: &PgConnection,
+    user: &User,
+    joined_room_ids: &[RoomId],
+) -> Result<HashSet<Topic>, ApiError> {
+    let mut topics = HashSet::new();
+
+    topics.insert(Topic::User(user.id.clone()));
+
+    for user_id in PresenceList::find_observed_users(connection, &user.id)? {
+        topics.insert(Topic::User(user_id));
+    }
+
+    for room_id in joined_room_ids {
+        topics.insert(Topic::Room(room_id.clone()));
+    }
+
+    Ok(topics)
+}
 
-        Ok(Response::with((Status::Ok, SerializableResponse(response))))
+/// Parse the `filter` parameter, which is either the ID of a filter saved by the user or a filter
+/// encoded as JSON.
+fn parse_filter(
+    connection: &PgConnection,
+    user_id: &UserId,
+    value: &str,
+) -> Result<ContentFilter, ApiError> {
+    if let Ok(filter_id) = i64::from_str(value) {
+        let filter = Filter::find(connection, user_id.clone(), filter_id)?;
+
+        return from_str(&filter.content).map_err(ApiError::from);
     }
+
+    let filter = from_str(value).map_err(|err| ApiError::invalid_param("filter", err))?;
+    filtering::validate(&filter).map_err(|reason| ApiError::invalid_param("filter", reason))?;
+
+    Ok(filter)
 }
 
 #[cfg(test)]
 mod tests {
     use std::convert::TryFrom;
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::{Duration, Instant};
 
     use crate::test::Test;
+    use chrono::Utc;
     use iron::status::Status;
     use ruma_events::presence::PresenceState;
     use ruma_identifiers::EventId;
-    use serde_json::from_str;
+    use serde_json::{from_str, json, Value};
 
     use crate::models::filter::ContentFilter;
     use crate::query::SyncOptions;
@@ -313,6 +439,91 @@ mod tests {
         );
     }
 
+    #[test]
+    fn sync_timeline_event_timestamps_and_transaction_ids() {
+        let test = Test::new();
+        let (carl, room_id) = test.initial_fixtures(r#"{"visibility": "public"}"#);
+        let dave = test.create_user();
+        test.join_room(&dave.token, &room_id);
+
+        let sent_at = Utc::now().timestamp_millis() as u64;
+        let response = test.send_message(&carl.token, &room_id, "Hi", 42);
+        assert_eq!(response.status, Status::Ok);
+
+        let options = SyncOptions {
+            filter: Some(from_str(r#"{"room":{"timeline":{"limit":1}}}"#).unwrap()),
+            since: None,
+            full_state: false,
+            set_presence: None,
+            timeout: 0,
+        };
+        let response = test.sync(&carl.token, options.clone());
+        let event = response
+            .json()
+            .pointer(&format!("/rooms/join/{}/timeline/events/0", room_id))
+            .unwrap();
+        let origin_server_ts = event.get("origin_server_ts").unwrap().as_u64().unwrap();
+        assert!(origin_server_ts + 1000 >= sent_at);
+        assert!(origin_server_ts <= Utc::now().timestamp_millis() as u64);
+        assert!(event.pointer("/unsigned/age").unwrap().as_u64().is_some());
+        assert_eq!(
+            event
+                .pointer("/unsigned/transaction_id")
+                .unwrap()
+                .as_str()
+                .unwrap(),
+            "42"
+        );
+
+        let response = test.sync(&dave.token, options);
+        let event = response
+            .json()
+            .pointer(&format!("/rooms/join/{}/timeline/events/0", room_id))
+            .unwrap();
+        assert!(event.pointer("/unsigned/age").is_some());
+        assert!(event.pointer("/unsigned/transaction_id").is_none());
+    }
+
+    #[test]
+    fn sync_state_event_previous_content() {
+        let test = Test::new();
+        let (carl, room_id) = test.initial_fixtures(r#"{"topic": "Cats"}"#);
+
+        let response = test.send_state_event(
+            &carl.token,
+            &room_id,
+            "m.room.topic",
+            r#"{"topic": "Dogs"}"#,
+        );
+        assert_eq!(response.status, Status::Ok);
+
+        let options = SyncOptions {
+            filter: Some(from_str(r#"{"room":{"timeline":{"limit":1}}}"#).unwrap()),
+            since: None,
+            full_state: false,
+            set_presence: None,
+            timeout: 0,
+        };
+        let response = test.sync(&carl.token, options);
+        let event = response
+            .json()
+            .pointer(&format!("/rooms/join/{}/timeline/events/0", room_id))
+            .unwrap();
+        assert_eq!(
+            event.pointer("/content/topic").unwrap().as_str().unwrap(),
+            "Dogs"
+        );
+        assert_eq!(
+            event
+                .pointer("/prev_content/topic")
+                .unwrap()
+                .as_str()
+                .unwrap(),
+            "Cats"
+        );
+        assert!(event.pointer("/unsigned/replaces_state").is_some());
+    }
+
     /// [https://github.com/matrix-org/sytest/blob/0eba37fc567d65f0a005090548c8df4d0e43775f/tests/31sync/04timeline.pl#L223]
     #[test]
     fn syncing_a_new_room_with_a_large_timeline_limit_isnt_limited() {
@@ -394,7 +605,9 @@ mod tests {
                     );
                 }
                 "m.room.member" => {
-                    assert_eq!(e.get("sender").unwrap().as_str().unwrap(), bob.id);
+                    let state_key = e.get("state_key").unwrap().as_str().unwrap();
+                    assert!(state_key == alice.id || state_key == bob.id);
+                    assert_eq!(e.get("sender").unwrap().as_str().unwrap(), state_key);
 
                     assert_eq!(
                         e.pointer("/content/membership").unwrap().as_str().unwrap(),
@@ -432,7 +645,8 @@ mod tests {
             .as_array()
             .unwrap();
 
-        assert_eq!(state_events.len(), 6);
+        // One member event for each of Alice and Bob.
+        assert_eq!(state_events.len(), 7);
 
         for e in state_events.iter() {
             let event_type = e.get("type").unwrap().as_str().unwrap();
@@ -806,6 +1020,57 @@ mod tests {
         );
     }
 
+    #[test]
+    fn sync_forgotten_room() {
+        let test = Test::new();
+        let (alice, room_id) = test.initial_fixtures(r#"{"visibility": "public"}"#);
+        let bob = test.create_user();
+
+        assert_eq!(test.join_room(&bob.token, &room_id).status, Status::Ok);
+        assert_eq!(test.leave_room(&bob.token, &room_id).status, Status::Ok);
+        assert_eq!(test.forget_room(&bob.token, &room_id).status, Status::Ok);
+
+        let include_leave_filter: ContentFilter =
+            from_str(r#"{"room":{"include_leave":true}}"#).unwrap();
+        let options = SyncOptions {
+            filter: Some(include_leave_filter.clone()),
+            since: None,
+            full_state: false,
+            set_presence: None,
+            timeout: 0,
+        };
+
+        let response = test.sync(&bob.token, options);
+        assert_eq!(response.status, Status::Ok);
+        assert!(response
+            .json()
+            .pointer(&format!("/rooms/leave/{}", room_id))
+            .is_none());
+
+        // Joining the room again brings it back.
+        assert_eq!(
+            test.send_message(&alice.token, &room_id, "Welcome back", 1)
+                .status,
+            Status::Ok
+        );
+        assert_eq!(test.join_room(&bob.token, &room_id).status, Status::Ok);
+
+        let options = SyncOptions {
+            filter: Some(include_leave_filter),
+            since: None,
+            full_state: false,
+            set_presence: None,
+            timeout: 0,
+        };
+
+        let response = test.sync(&bob.token, options);
+        assert_eq!(response.status, Status::Ok);
+        assert!(response
+            .json()
+            .pointer(&format!("/rooms/join/{}", room_id))
+            .is_some());
+    }
+
     #[test]
     fn full_state() {
         let test = Test::new();
@@ -1108,49 +1373,1083 @@ mod tests {
     }
 
     #[test]
-    fn invalid_since() {
-        let test = Test::new();
-        let (carl, _) = test.initial_fixtures(r#"{"visibility": "public"}"#);
+    fn long_polling_sync_returns_on_new_event() {
+        let test = Arc::new(Test::new());
+        let (alice, room_id) = test.initial_fixtures(r#"{"visibility": "public"}"#);
 
-        let response = test.get(&format!(
-            "/_matrix/client/r0/sync?since={}&access_token={}",
-            "10s_234", carl.token
-        ));
-        assert_eq!(response.status, Status::BadRequest);
+        let options = SyncOptions {
+            filter: None,
+            since: None,
+            full_state: false,
+            set_presence: None,
+            timeout: 0,
+        };
+
+        let next_batch = Test::get_next_batch(&test.sync(&alice.token, options));
+
+        let sending_test = Arc::clone(&test);
+        let token = alice.token.clone();
+        let sending_room_id = room_id.clone();
+        let handle = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(200));
+            let response = sending_test.send_message(&token, &sending_room_id, "Hi", 1);
+            assert_eq!(response.status, Status::Ok);
+        });
+
+        let options = SyncOptions {
+            filter: None,
+            since: Some(next_batch),
+            full_state: false,
+            set_presence: None,
+            timeout: 20000,
+        };
+
+        let start = Instant::now();
+        let response = test.sync(&alice.token, options);
+
+        assert!(start.elapsed() < Duration::from_secs(20));
+        assert_ne!(Test::get_next_batch(&response), next_batch);
+
+        let events = response
+            .json()
+            .pointer(&format!("/rooms/join/{}/timeline/events", room_id))
+            .unwrap()
+            .as_array()
+            .unwrap();
+        assert_eq!(events.len(), 1);
+        assert_eq!(
+            events[0]
+                .pointer("/content/body")
+                .unwrap()
+                .as_str()
+                .unwrap(),
+            "Hi"
+        );
+
+        handle.join().unwrap();
     }
 
     #[test]
-    fn invalid_timeout() {
+    fn long_polling_sync_times_out_without_new_events() {
         let test = Test::new();
-        let (carl, _) = test.initial_fixtures(r#"{"visibility": "public"}"#);
+        let (alice, _) = test.initial_fixtures(r#"{"visibility": "public"}"#);
 
-        let response = test.get(&format!(
-            "/_matrix/client/r0/sync?timeout={}&access_token={}",
-            "10s_234", carl.token
-        ));
-        assert_eq!(response.status, Status::BadRequest);
+        let options = SyncOptions {
+            filter: None,
+            since: None,
+            full_state: false,
+            set_presence: None,
+            timeout: 0,
+        };
+
+        let next_batch = Test::get_next_batch(&test.sync(&alice.token, options));
+
+        let options = SyncOptions {
+            filter: None,
+            since: Some(next_batch),
+            full_state: false,
+            set_presence: None,
+            timeout: 300,
+        };
+
+        let start = Instant::now();
+        let response = test.sync(&alice.token, options);
+
+        assert!(start.elapsed() >= Duration::from_millis(300));
+        assert_eq!(Test::get_next_batch(&response), next_batch);
     }
 
     #[test]
-    fn invalid_set_presence() {
-        let test = Test::new();
-        let (carl, _) = test.initial_fixtures(r#"{"visibility": "public"}"#);
+    fn long_polling_sync_keeps_waiting_after_unrelated_events() {
+        let test = Arc::new(Test::new());
+        let (alice, _) = test.initial_fixtures(r#"{"visibility": "public"}"#);
+        let bob = test.create_user();
+        let bob_room_id = test.create_room(&bob.token);
 
-        let response = test.get(&format!(
-            "/_matrix/client/r0/sync?set_presence={}&access_token={}",
-            "10s_234", carl.token
-        ));
-        assert_eq!(response.status, Status::BadRequest);
+        let options = SyncOptions {
+            filter: None,
+            since: None,
+            full_state: false,
+            set_presence: None,
+            timeout: 0,
+        };
+
+        let next_batch = Test::get_next_batch(&test.sync(&alice.token, options));
+
+        let sending_test = Arc::clone(&test);
+        let handle = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(100));
+            let response = sending_test.send_message(&bob.token, &bob_room_id, "Hi", 1);
+            assert_eq!(response.status, Status::Ok);
+        });
+
+        let options = SyncOptions {
+            filter: None,
+            since: Some(next_batch),
+            full_state: false,
+            set_presence: None,
+            timeout: 1000,
+        };
+
+        let start = Instant::now();
+        let response = test.sync(&alice.token, options);
+
+        assert!(start.elapsed() >= Duration::from_millis(1000));
+        assert_eq!(Test::get_next_batch(&response), next_batch);
+
+        handle.join().unwrap();
     }
 
     #[test]
-    fn invalid_filter() {
+    fn long_polling_sync_returns_on_invite() {
+        let test = Arc::new(Test::new());
+        let (alice, room_id) = test.initial_fixtures(r#"{"visibility": "private"}"#);
+        let bob = test.create_user();
+
+        let options = SyncOptions {
+            filter: None,
+            since: None,
+            full_state: false,
+            set_presence: None,
+            timeout: 0,
+        };
+
+        let next_batch = Test::get_next_batch(&test.sync(&bob.token, options));
+
+        let inviting_test = Arc::clone(&test);
+        let inviting_room_id = room_id.clone();
+        let bob_id = bob.id.clone();
+        let handle = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(200));
+            let response = inviting_test.invite(&alice.token, &inviting_room_id, &bob_id);
+            assert_eq!(response.status, Status::Ok);
+        });
+
+        let options = SyncOptions {
+            filter: None,
+            since: Some(next_batch),
+            full_state: false,
+            set_presence: None,
+            timeout: 20000,
+        };
+
+        let start = Instant::now();
+        let response = test.sync(&bob.token, options);
+
+        assert!(start.elapsed() < Duration::from_secs(20));
+        assert!(response
+            .json()
+            .pointer(&format!("/rooms/invite/{}", room_id))
+            .is_some());
+
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn sync_typing_notifications() {
         let test = Test::new();
-        let (carl, _) = test.initial_fixtures(r#"{"visibility": "public"}"#);
+        let (alice, room_id) = test.initial_fixtures(r#"{"visibility": "public"}"#);
+        let bob = test.create_user();
 
-        let response = test.get(&format!(
-            "/_matrix/client/r0/sync?filter={}&access_token={}",
-            "{10s_234", carl.token
+        assert_eq!(test.join_room(&bob.token, &room_id).status, Status::Ok);
+
+        let response = test.set_typing(&alice.token, &room_id, &alice.id, r#"{"typing": true}"#);
+        assert_eq!(response.status, Status::Ok);
+
+        let options = SyncOptions {
+            filter: None,
+            since: None,
+            full_state: false,
+            set_presence: None,
+            timeout: 0,
+        };
+
+        let response = test.sync(&bob.token, options);
+        let next_batch = Test::get_next_batch(&response);
+
+        let ephemeral_events = response
+            .json()
+            .pointer(&format!("/rooms/join/{}/ephemeral/events", room_id))
+            .unwrap()
+            .as_array()
+            .unwrap();
+        assert_eq!(ephemeral_events.len(), 1);
+        assert_eq!(
+            ephemeral_events[0].get("type").unwrap().as_str().unwrap(),
+            "m.typing"
+        );
+        assert_eq!(
+            ephemeral_events[0]
+                .pointer("/content/user_ids/0")
+                .unwrap()
+                .as_str()
+                .unwrap(),
+            alice.id
+        );
+
+        // Without any changes, the typing notification is not sent again.
+        let options = SyncOptions {
+            filter: None,
+            since: Some(next_batch),
+            full_state: false,
+            set_presence: None,
+            timeout: 0,
+        };
+
+        let response = test.sync(&bob.token, options);
+        assert_eq!(Test::get_next_batch(&response), next_batch);
+        assert!(response
+            .json()
+            .pointer(&format!("/rooms/join/{}", room_id))
+            .is_none());
+
+        let response = test.set_typing(&alice.token, &room_id, &alice.id, r#"{"typing": false}"#);
+        assert_eq!(response.status, Status::Ok);
+
+        let options = SyncOptions {
+            filter: None,
+            since: Some(next_batch),
+            full_state: false,
+            set_presence: None,
+            timeout: 0,
+        };
+
+        let response = test.sync(&bob.token, options);
+        assert_ne!(Test::get_next_batch(&response), next_batch);
+
+        let user_ids = response
+            .json()
+            .pointer(&format!(
+                "/rooms/join/{}/ephemeral/events/0/content/user_ids",
+                room_id
+            ))
+            .unwrap()
+            .as_array()
+            .unwrap();
+        assert!(user_ids.is_empty());
+    }
+
+    #[test]
+    fn long_polling_sync_returns_when_typing_expires() {
+        let test = Test::new();
+        let (alice, room_id) = test.initial_fixtures(r#"{"visibility": "public"}"#);
+
+        let response = test.set_typing(
+            &alice.token,
+            &room_id,
+            &alice.id,
+            r#"{"typing": true, "timeout": 300}"#,
+        );
+        assert_eq!(response.status, Status::Ok);
+
+        let options = SyncOptions {
+            filter: None,
+            since: None,
+            full_state: false,
+            set_presence: None,
+            timeout: 0,
+        };
+
+        let next_batch = Test::get_next_batch(&test.sync(&alice.token, options));
+
+        let options = SyncOptions {
+            filter: None,
+            since: Some(next_batch),
+            full_state: false,
+            set_presence: None,
+            timeout: 20000,
+        };
+
+        let start = Instant::now();
+        let response = test.sync(&alice.token, options);
+
+        assert!(start.elapsed() < Duration::from_secs(20));
+
+        let user_ids = response
+            .json()
+            .pointer(&format!(
+                "/rooms/join/{}/ephemeral/events/0/content/user_ids",
+                room_id
+            ))
+            .unwrap()
+            .as_array()
+            .unwrap();
+        assert!(user_ids.is_empty());
+    }
+
+    #[test]
+    fn sync_read_receipts() {
+        let test = Test::new();
+        let (alice, room_id) = test.initial_fixtures(r#"{"visibility": "public"}"#);
+        let bob = test.create_user();
+
+        assert_eq!(test.join_room(&bob.token, &room_id).status, Status::Ok);
+
+        let response = test.send_message(&alice.token, &room_id, "Hi", 1);
+        let event_id = response
+            .json()
+            .get("event_id")
+            .unwrap()
+            .as_str()
+            .unwrap()
+            .to_string();
+
+        let options = SyncOptions {
+            filter: None,
+            since: None,
+            full_state: false,
+            set_presence: None,
+            timeout: 0,
+        };
+
+        let next_batch = Test::get_next_batch(&test.sync(&alice.token, options));
+
+        let response = test.send_read_receipt(&bob.token, &room_id, &event_id);
+        assert_eq!(response.status, Status::Ok);
+
+        let options = SyncOptions {
+            filter: None,
+            since: Some(next_batch),
+            full_state: false,
+            set_presence: None,
+            timeout: 0,
+        };
+
+        let response = test.sync(&alice.token, options);
+        let next_batch = Test::get_next_batch(&response);
+
+        let ephemeral_events = response
+            .json()
+            .pointer(&format!("/rooms/join/{}/ephemeral/events", room_id))
+            .unwrap()
+            .as_array()
+            .unwrap();
+        assert_eq!(ephemeral_events.len(), 1);
+        assert_eq!(
+            ephemeral_events[0].get("type").unwrap().as_str().unwrap(),
+            "m.receipt"
+        );
+        assert!(ephemeral_events[0]
+            .get("content")
+            .unwrap()
+            .get(format!("${}:ruma.test", event_id))
+            .unwrap()
+            .pointer(&format!("/m.read/{}/ts", bob.id))
+            .unwrap()
+            .is_u64());
+
+        // The receipt is not sent again.
+        let options = SyncOptions {
+            filter: None,
+            since: Some(next_batch),
+            full_state: false,
+            set_presence: None,
+            timeout: 0,
+        };
+
+        let response = test.sync(&alice.token, options);
+        assert_eq!(Test::get_next_batch(&response), next_batch);
+        assert!(response
+            .json()
+            .pointer(&format!("/rooms/join/{}", room_id))
+            .is_none());
+    }
+
+    #[test]
+    fn sync_global_account_data() {
+        let test = Test::new();
+        let alice = test.create_user();
+
+        let account_data_path = format!(
+            "/_matrix/client/r0/user/{}/account_data/m.direct?access_token={}",
+            alice.id, alice.token
+        );
+        let response = test.put(&account_data_path, r#"{"@bob:ruma.test": []}"#);
+        test.check_empty_response(response);
+
+        let options = SyncOptions {
+            filter: None,
+            since: None,
+            full_state: false,
+            set_presence: None,
+            timeout: 0,
+        };
+
+        let response = test.sync(&alice.token, options);
+        let next_batch = Test::get_next_batch(&response);
+        let events = response.json().pointer("/account_data/events").unwrap();
+        assert_eq!(events.as_array().unwrap().len(), 1);
+        assert_eq!(events.pointer("/0/type").unwrap(), "m.direct");
+        assert!(events.pointer("/0/content/@bob:ruma.test").is_some());
+
+        // Without any changes, the account data is not sent again.
+        let options = SyncOptions {
+            filter: None,
+            since: Some(next_batch),
+            full_state: false,
+            set_presence: None,
+            timeout: 0,
+        };
+
+        let response = test.sync(&alice.token, options.clone());
+        assert_eq!(Test::get_next_batch(&response), next_batch);
+        assert!(response
+            .json()
+            .pointer("/account_data/events")
+            .unwrap()
+            .as_array()
+            .unwrap()
+            .is_empty());
+
+        let response = test.put(&account_data_path, r#"{"@carl:ruma.test": []}"#);
+        test.check_empty_response(response);
+
+        let response = test.sync(&alice.token, options);
+        let events = response.json().pointer("/account_data/events").unwrap();
+        assert_eq!(events.as_array().unwrap().len(), 1);
+        assert!(events.pointer("/0/content/@carl:ruma.test").is_some());
+    }
+
+    #[test]
+    fn sync_room_account_data_and_tags() {
+        let test = Test::new();
+        let (alice, room_id) = test.initial_fixtures("{}");
+
+        let account_data_path = format!(
+            "/_matrix/client/r0/user/{}/rooms/{}/account_data/org.example.color?access_token={}",
+            alice.id, room_id, alice.token
+        );
+        let response = test.put(&account_data_path, r#"{"color": "blue"}"#);
+        test.check_empty_response(response);
+
+        let tag_path = format!(
+            "/_matrix/client/r0/user/{}/rooms/{}/tags/work?access_token={}",
+            alice.id, room_id, alice.token
+        );
+        let response = test.put(&tag_path, r#"{"order": "0.5"}"#);
+        test.check_empty_response(response);
+
+        let options = SyncOptions {
+            filter: None,
+            since: None,
+            full_state: false,
+            set_presence: None,
+            timeout: 0,
+        };
+
+        let response = test.sync(&alice.token, options);
+        let next_batch = Test::get_next_batch(&response);
+        let events = response
+            .json()
+            .pointer(&format!("/rooms/join/{}/account_data/events", room_id))
+            .unwrap()
+            .clone();
+        assert_eq!(events.as_array().unwrap().len(), 2);
+        assert_eq!(events.pointer("/0/type").unwrap(), "org.example.color");
+        assert_eq!(events.pointer("/0/content/color").unwrap(), "blue");
+        assert_eq!(events.pointer("/1/type").unwrap(), "m.tag");
+        assert_eq!(events.pointer("/1/content/tags/work/order").unwrap(), "0.5");
+
+        // Deleting the last tag reports the room without tags.
+        let response = test.delete(&tag_path);
+        test.check_empty_response(response);
+
+        let options = SyncOptions {
+            filter: None,
+            since: Some(next_batch),
+            full_state: false,
+            set_presence: None,
+            timeout: 0,
+        };
+
+        let response = test.sync(&alice.token, options);
+        let events = response
+            .json()
+            .pointer(&format!("/rooms/join/{}/account_data/events", room_id))
+            .unwrap()
+            .clone();
+        assert_eq!(events.as_array().unwrap().len(), 1);
+        assert_eq!(events.pointer("/0/type").unwrap(), "m.tag");
+        assert!(events
+            .pointer("/0/content/tags")
+            .unwrap()
+            .as_object()
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn sync_account_data_with_filter() {
+        let test = Test::new();
+        let (alice, room_id) = test.initial_fixtures("{}");
+
+        for data_type in &["m.direct", "org.example.hidden"] {
+            let account_data_path = format!(
+                "/_matrix/client/r0/user/{}/account_data/{}?access_token={}",
+                alice.id, data_type, alice.token
+            );
+            let response = test.put(&account_data_path, "{}");
+            test.check_empty_response(response);
+        }
+
+        let tag_path = format!(
+            "/_matrix/client/r0/user/{}/rooms/{}/tags/work?access_token={}",
+            alice.id, room_id, alice.token
+        );
+        let response = test.put(&tag_path, "{}");
+        test.check_empty_response(response);
+
+        let options = SyncOptions {
+            filter: Some(
+                from_str(
+                    r#"{
+                        "account_data": {"limit": 0, "not_types": ["org.example.hidden"]},
+                        "room": {"account_data": {"types": ["org.example.color"]}}
+                    }"#,
+                )
+                .unwrap(),
+            ),
+            since: None,
+            full_state: false,
+            set_presence: None,
+            timeout: 0,
+        };
+
+        let response = test.sync(&alice.token, options);
+        let events = response.json().pointer("/account_data/events").unwrap();
+        assert_eq!(events.as_array().unwrap().len(), 1);
+        assert_eq!(events.pointer("/0/type").unwrap(), "m.direct");
+        assert!(response
+            .json()
+            .pointer(&format!("/rooms/join/{}/account_data/events/0", room_id))
+            .is_none());
+    }
+
+    #[test]
+    fn sync_timeline_and_state_filters() {
+        let test = Test::new();
+        let (alice, room_id) = test.initial_fixtures(r#"{"visibility": "public"}"#);
+        let bob = test.create_user();
+
+        assert_eq!(test.join_room(&bob.token, &room_id).status, Status::Ok);
+        test.send_message(&alice.token, &room_id, "From Alice", 1);
+        test.send_message(&bob.token, &room_id, "From Bob", 1);
+
+        let filter = format!(
+            r#"{{"room": {{
+                "timeline": {{"types": ["m.room.mess*"], "not_senders": ["{}"]}},
+                "state": {{"types": ["m.room.create"]}}
+            }}}}"#,
+            bob.id
+        );
+        let options = SyncOptions {
+            filter: Some(from_str(&filter).unwrap()),
+            since: None,
+            full_state: false,
+            set_presence: None,
+            timeout: 0,
+        };
+
+        let response = test.sync(&alice.token, options);
+        let room = response
+            .json()
+            .pointer(&format!("/rooms/join/{}", room_id))
+            .unwrap()
+            .clone();
+
+        let timeline_events = room
+            .pointer("/timeline/events")
+            .unwrap()
+            .as_array()
+            .unwrap();
+        assert_eq!(timeline_events.len(), 1);
+        assert_eq!(
+            timeline_events[0].pointer("/content/body").unwrap(),
+            "From Alice"
+        );
+
+        let state_events = room.pointer("/state/events").unwrap().as_array().unwrap();
+        assert!(state_events
+            .iter()
+            .all(|event| event.get("type").unwrap() == "m.room.create"));
+    }
+
+    #[test]
+    fn sync_moves_past_filtered_timeline_events() {
+        let test = Test::new();
+        let (alice, room_id) = test.initial_fixtures(r#"{"visibility": "public"}"#);
+
+        let filter = || -> ContentFilter {
+            from_str(r#"{"room": {"timeline": {"not_types": ["m.room.message"]}}}"#).unwrap()
+        };
+
+        let options = SyncOptions {
+            filter: Some(filter()),
+            since: None,
+            full_state: false,
+            set_presence: None,
+            timeout: 0,
+        };
+        let first_batch = Test::get_next_batch(&test.sync(&alice.token, options));
+
+        test.send_message(&alice.token, &room_id, "Hidden", 1);
+
+        let options = SyncOptions {
+            filter: Some(filter()),
+            since: Some(first_batch),
+            full_state: false,
+            set_presence: None,
+            timeout: 0,
+        };
+        let response = test.sync(&alice.token, options);
+        assert!(response
+            .json()
+            .pointer(&format!("/rooms/join/{}", room_id))
+            .is_none());
+
+        let second_batch = Test::get_next_batch(&response);
+        assert!(second_batch.room_key > first_batch.room_key);
+    }
+
+    #[test]
+    fn sync_room_filter_and_event_fields() {
+        let test = Test::new();
+        let (alice, room_id) = test.initial_fixtures("{}");
+        let other_room_id = test.create_room(&alice.token);
+
+        test.send_message(&alice.token, &room_id, "Hi", 1);
+
+        let filter = format!(
+            r#"{{
+                "room": {{"not_rooms": ["{}"], "timeline": {{"types": ["m.room.message"]}}}},
+                "event_fields": ["type", "content.body"]
+            }}"#,
+            other_room_id
+        );
+        let options = SyncOptions {
+            filter: Some(from_str(&filter).unwrap()),
+            since: None,
+            full_state: false,
+            set_presence: None,
+            timeout: 0,
+        };
+
+        let response = test.sync(&alice.token, options);
+        let json = response.json();
+        assert!(json
+            .pointer(&format!("/rooms/join/{}", other_room_id))
+            .is_none());
+
+        let timeline_events = json
+            .pointer(&format!("/rooms/join/{}/timeline/events", room_id))
+            .unwrap();
+        assert_eq!(
+            *timeline_events,
+            json!([{ "type": "m.room.message", "content": { "body": "Hi" } }])
+        );
+    }
+
+    #[test]
+    fn sync_unread_notification_counts() {
+        let test = Test::new();
+        let (alice, room_id) = test.initial_fixtures(r#"{"visibility": "public"}"#);
+        let bob = test.create_user();
+
+        assert_eq!(test.join_room(&bob.token, &room_id).status, Status::Ok);
+
+        let mut event_ids = Vec::new();
+        let messages = [
+            "Hi".to_string(),
+            format!("Are you there, {}?", bob.name),
+            "Never mind".to_string(),
+            format!("{}, ping", bob.name.to_uppercase()),
+        ];
+
+        for (txn_id, message) in messages.iter().enumerate() {
+            let response = test.send_message(&alice.token, &room_id, message, txn_id as u64);
+            let event_id = response.json().get("event_id").unwrap().as_str().unwrap();
+            event_ids.push(event_id.to_string());
+        }
+
+        // Bob's own messages are not notifications.
+        let response = test.send_message(&bob.token, &room_id, "Hello", 10);
+        assert_eq!(response.status, Status::Ok);
+
+        let options = SyncOptions {
+            filter: None,
+            since: None,
+            full_state: false,
+            set_presence: None,
+            timeout: 0,
+        };
+
+        let response = test.sync(&bob.token, options);
+        let next_batch = Test::get_next_batch(&response);
+
+        let counts = response
+            .json()
+            .pointer(&format!("/rooms/join/{}/unread_notifications", room_id))
+            .unwrap();
+        assert_eq!(counts.get("notification_count").unwrap(), 4);
+        assert_eq!(counts.get("highlight_count").unwrap(), 2);
+
+        let response = test.send_read_receipt(&bob.token, &room_id, &event_ids[1]);
+        assert_eq!(response.status, Status::Ok);
+
+        let options = SyncOptions {
+            filter: None,
+            since: Some(next_batch),
+            full_state: false,
+            set_presence: None,
+            timeout: 0,
+        };
+
+        let response = test.sync(&bob.token, options);
+
+        let counts = response
+            .json()
+            .pointer(&format!("/rooms/join/{}/unread_notifications", room_id))
+            .unwrap();
+        assert_eq!(counts.get("notification_count").unwrap(), 2);
+        assert_eq!(counts.get("highlight_count").unwrap(), 1);
+
+        // Receipts only move forward, so acknowledging an older event changes nothing.
+        let response = test.send_read_receipt(&bob.token, &room_id, &event_ids[0]);
+        assert_eq!(response.status, Status::Ok);
+
+        let options = SyncOptions {
+            filter: None,
+            since: None,
+            full_state: false,
+            set_presence: None,
+            timeout: 0,
+        };
+
+        let response = test.sync(&bob.token, options);
+
+        let counts = response
+            .json()
+            .pointer(&format!("/rooms/join/{}/unread_notifications", room_id))
+            .unwrap();
+        assert_eq!(counts.get("notification_count").unwrap(), 2);
+        assert_eq!(counts.get("highlight_count").unwrap(), 1);
+    }
+
+    #[test]
+    fn invalid_since() {
+        let test = Test::new();
+        let (carl, _) = test.initial_fixtures(r#"{"visibility": "public"}"#);
+
+        let response = test.get(&format!(
+            "/_matrix/client/r0/sync?since={}&access_token={}",
+            "10s_234", carl.token
+        ));
+        assert_eq!(response.status, Status::BadRequest);
+    }
+
+    #[test]
+    fn invalid_timeout() {
+        let test = Test::new();
+        let (carl, _) = test.initial_fixtures(r#"{"visibility": "public"}"#);
+
+        let response = test.get(&format!(
+            "/_matrix/client/r0/sync?timeout={}&access_token={}",
+            "10s_234", carl.token
+        ));
+        assert_eq!(response.status, Status::BadRequest);
+    }
+
+    #[test]
+    fn invalid_set_presence() {
+        let test = Test::new();
+        let (carl, _) = test.initial_fixtures(r#"{"visibility": "public"}"#);
+
+        let response = test.get(&format!(
+            "/_matrix/client/r0/sync?set_presence={}&access_token={}",
+            "10s_234", carl.token
+        ));
+        assert_eq!(response.status, Status::BadRequest);
+    }
+
+    #[test]
+    fn invalid_filter() {
+        let test = Test::new();
+        let (carl, _) = test.initial_fixtures(r#"{"visibility": "public"}"#);
+
+        let response = test.get(&format!(
+            "/_matrix/client/r0/sync?filter={}&access_token={}",
+            "{10s_234", carl.token
+        ));
+        assert_eq!(response.status, Status::BadRequest);
+    }
+
+    #[test]
+    fn sync_with_saved_filter() {
+        let test = Test::new();
+        let (carl, room_id) = test.initial_fixtures("{}");
+        test.send_message(&carl.token, &room_id, "Hi", 1);
+
+        let filter_id = test.create_filter(
+            &carl.token,
+            carl.id.as_str(),
+            r#"{"room": {"timeline": {"types": ["m.room.message"]}}}"#,
+        );
+
+        let response = test.get(&format!(
+            "/_matrix/client/r0/sync?filter={}&access_token={}",
+            filter_id, carl.token
+        ));
+        assert_eq!(response.status, Status::Ok);
+
+        let timeline_events = response
+            .json()
+            .pointer(&format!("/rooms/join/{}/timeline/events", room_id))
+            .unwrap()
+            .as_array()
+            .unwrap()
+            .clone();
+        assert_eq!(timeline_events.len(), 1);
+        assert_eq!(timeline_events[0].get("type").unwrap(), "m.room.message");
+    }
+
+    #[test]
+    fn sync_with_filter_of_another_user() {
+        let test = Test::new();
+        let (carl, _) = test.initial_fixtures("{}");
+        let alice = test.create_user();
+
+        let filter_id = test.create_filter(
+            &alice.token,
+            alice.id.as_str(),
+            r#"{"room": {"timeline": {"limit": 1}}}"#,
+        );
+
+        let response = test.get(&format!(
+            "/_matrix/client/r0/sync?filter={}&access_token={}",
+            filter_id, carl.token
+        ));
+        assert_eq!(response.status, Status::NotFound);
+    }
+
+    /// Return the state keys of the member events in the state of a joined room.
+    fn member_state_keys(response: &Value, room_id: &str) -> Vec<String> {
+        response
+            .pointer(&format!("/rooms/join/{}/state/events", room_id))
+            .unwrap()
+            .as_array()
+            .unwrap()
+            .iter()
+            .filter(|event| event.get("type").unwrap() == "m.room.member")
+            .map(|event| {
+                event
+                    .get("state_key")
+                    .unwrap()
+                    .as_str()
+                    .unwrap()
+                    .to_string()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn sync_lazy_loading_members() {
+        let test = Test::new();
+        let (alice, room_id) = test.initial_fixtures(r#"{"visibility": "public"}"#);
+        let bob = test.create_user();
+        let carl = test.create_user();
+
+        assert_eq!(test.join_room(&bob.token, &room_id).status, Status::Ok);
+        assert_eq!(test.join_room(&carl.token, &room_id).status, Status::Ok);
+        test.send_message(&alice.token, &room_id, "Hi", 1);
+
+        let lazy_filter = |include_redundant_members: bool| -> ContentFilter {
+            from_str(&format!(
+                r#"{{"room": {{
+                    "timeline": {{"types": ["m.room.message"]}},
+                    "state": {{"lazy_load_members": true, "include_redundant_members": {}}}
+                }}}}"#,
+                include_redundant_members
+            ))
+            .unwrap()
+        };
+
+        let options = SyncOptions {
+            filter: Some(lazy_filter(false)),
+            since: None,
+            full_state: false,
+            set_presence: None,
+            timeout: 0,
+        };
+        let response = test.sync(&bob.token, options);
+        let next_batch = Test::get_next_batch(&response);
+        assert_eq!(
+            member_state_keys(response.json(), &room_id),
+            vec![alice.id.clone()]
+        );
+
+        // Alice's member event was already sent, so only Carl's is new.
+        test.send_message(&alice.token, &room_id, "Hello", 2);
+        test.send_message(&carl.token, &room_id, "Hey", 1);
+
+        let options = SyncOptions {
+            filter: Some(lazy_filter(false)),
+            since: Some(next_batch),
+            full_state: false,
+            set_presence: None,
+            timeout: 0,
+        };
+        let response = test.sync(&bob.token, options);
+        let next_batch = Test::get_next_batch(&response);
+        assert_eq!(
+            member_state_keys(response.json(), &room_id),
+            vec![carl.id.clone()]
+        );
+
+        test.send_message(&alice.token, &room_id, "Bye", 3);
+
+        let options = SyncOptions {
+            filter: Some(lazy_filter(true)),
+            since: Some(next_batch),
+            full_state: false,
+            set_presence: None,
+            timeout: 0,
+        };
+        let response = test.sync(&bob.token, options);
+        assert_eq!(member_state_keys(response.json(), &room_id), vec![alice.id]);
+    }
+
+    #[test]
+    fn sync_lazy_loading_members_again_when_retried() {
+        let test = Test::new();
+        let (alice, room_id) = test.initial_fixtures(r#"{"visibility": "public"}"#);
+        let bob = test.create_user();
+
+        assert_eq!(test.join_room(&bob.token, &room_id).status, Status::Ok);
+
+        let lazy_filter = || -> ContentFilter {
+            from_str(
+                r#"{"room": {
+                    "timeline": {"types": ["m.room.message"]},
+                    "state": {"lazy_load_members": true}
+                }}"#,
+            )
+            .unwrap()
+        };
+
+        let options = SyncOptions {
+            filter: Some(lazy_filter()),
+            since: None,
+            full_state: false,
+            set_presence: None,
+            timeout: 0,
+        };
+        let next_batch = Test::get_next_batch(&test.sync(&bob.token, options));
+
+        test.send_message(&alice.token, &room_id, "Hi", 1);
+
+        // The client lost the first response, so it retries with the same token.
+        for _ in 0..2 {
+            let options = SyncOptions {
+                filter: Some(lazy_filter()),
+                since: Some(next_batch),
+                full_state: false,
+                set_presence: None,
+                timeout: 0,
+            };
+            let response = test.sync(&bob.token, options);
+            assert_eq!(
+                member_state_keys(response.json(), &room_id),
+                vec![alice.id.clone()]
+            );
+        }
+    }
+
+    #[test]
+    fn sync_room_summary() {
+        let test = Test::new();
+        let (alice, room_id) = test.initial_fixtures(r#"{"visibility": "public"}"#);
+        let bob = test.create_user();
+        let carl = test.create_user();
+
+        assert_eq!(test.join_room(&bob.token, &room_id).status, Status::Ok);
+        assert_eq!(
+            test.invite(&alice.token, &room_id, &carl.id).status,
+            Status::Ok
+        );
+
+        let options = SyncOptions {
+            filter: None,
+            since: None,
+            full_state: false,
+            set_presence: None,
+            timeout: 0,
+        };
+        let response = test.sync(&bob.token, options);
+        let next_batch = Test::get_next_batch(&response);
+        let summary = response
+            .json()
+            .pointer(&format!("/rooms/join/{}/summary", room_id))
+            .unwrap();
+        let mut heroes: Vec<&str> = summary
+            .get("m.heroes")
+            .unwrap()
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|hero| hero.as_str().unwrap())
+            .collect();
+        heroes.sort();
+        let mut expected_heroes = vec![alice.id.as_str(), carl.id.as_str()];
+        expected_heroes.sort();
+        assert_eq!(heroes, expected_heroes);
+        assert_eq!(summary.get("m.joined_member_count").unwrap(), 2);
+        assert_eq!(summary.get("m.invited_member_count").unwrap(), 1);
+
+        // The summary is only sent again once it changes.
+        test.send_message(&alice.token, &room_id, "Hi", 1);
+
+        let options = SyncOptions {
+            filter: None,
+            since: Some(next_batch),
+            full_state: false,
+            set_presence: None,
+            timeout: 0,
+        };
+        let response = test.sync(&bob.token, options);
+        let next_batch = Test::get_next_batch(&response);
+        let room = response
+            .json()
+            .pointer(&format!("/rooms/join/{}", room_id))
+            .unwrap();
+        assert!(room.get("summary").is_none());
+
+        assert_eq!(test.leave_room(&carl.token, &room_id).status, Status::Ok);
+
+        let options = SyncOptions {
+            filter: None,
+            since: Some(next_batch),
+            full_state: false,
+            set_presence: None,
+            timeout: 0,
+        };
+        let response = test.sync(&bob.token, options);
+        let summary = response
+            .json()
+            .pointer(&format!("/rooms/join/{}/summary", room_id))
+            .unwrap();
+        assert_eq!(summary.get("m.heroes").unwrap(), &json!([alice.id]));
+        assert_eq!(summary.get("m.joined_member_count").unwrap(), 2);
+        assert_eq!(summary.get("m.invited_member_count").unwrap(), 0);
+    }
+
+    #[test]
+    fn invalid_inline_filter_fields() {
+        let test = Test::new();
+        let (carl, _) = test.initial_fixtures("{}");
+
+        let response = test.get(&format!(
+            "/_matrix/client/r0/sync?filter={}&access_token={}",
+            "%7B%22event_fields%22%3A%5B%22content..body%22%5D%7D", carl.token
         ));
         assert_eq!(response.status, Status::BadRequest);
     }
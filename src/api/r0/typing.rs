@@ -0,0 +1,164 @@
+//! Endpoints for typing notifications.
+
+use std::cmp;
+use std::time::Duration;
+
+use bodyparser;
+use iron::status::Status;
+use iron::{Chain, Handler, IronResult, Plugin, Request, Response};
+
+use crate::db::DB;
+use crate::error::ApiError;
+use crate::middleware::{AccessTokenAuth, JsonRequest, MiddlewareChain, RoomIdParam, UserIdParam};
+use crate::models::room_membership::RoomMembership;
+use crate::models::user::User;
+use crate::modifier::EmptyResponse;
+use crate::typing::Typing;
+
+/// How long a user is marked as typing if the request does not specify a timeout.
+const DEFAULT_TYPING_TIMEOUT: u64 = 30_000;
+
+/// The longest time a user can be marked as typing with a single request.
+const MAX_TYPING_TIMEOUT: u64 = 5 * 60 * 1000;
+
+/// The PUT `/rooms/:room_id/typing/:user_id` endpoint.
+#[derive(Clone, Copy, Debug)]
+pub struct PutTyping;
+
+/// The body of the request for this API.
+#[derive(Clone, Debug, Deserialize)]
+struct PutTypingRequest {
+    /// Whether the user is typing or not.
+    typing: bool,
+    /// The length of time in milliseconds to mark this user as typing.
+    timeout: Option<u64>,
+}
+
+middleware_chain!(
+    PutTyping,
+    [JsonRequest, RoomIdParam, UserIdParam, AccessTokenAuth]
+);
+
+impl Handler for PutTyping {
+    fn handle(&self, request: &mut Request<'_, '_>) -> IronResult<Response> {
+        let put_typing_request = match request.get::<bodyparser::Struct<PutTypingRequest>>() {
+            Ok(Some(req)) => req,
+            Ok(None) | Err(_) => Err(ApiError::bad_json(None))?,
+        };
+
+        let room_id = request
+            .extensions
+            .get::<RoomIdParam>()
+            .expect("RoomIdParam should ensure a RoomId")
+            .clone();
+
+        let user_id = request
+            .extensions
+            .get::<UserIdParam>()
+            .expect("UserIdParam should ensure a UserId")
+            .clone();
+
+        let user = request
+            .extensions
+            .get::<User>()
+            .expect("AccessTokenAuth should ensure a user")
+            .clone();
+
+        if user_id != user.id {
+            Err(ApiError::unauthorized(
+                "The given user_id does not correspond to the authenticated user".to_string(),
+            ))?;
+        }
+
+        let connection = DB::from_request(request)?;
+        let typing = Typing::from_request(request)?;
+
+        let is_joined = match RoomMembership::find(&connection, &room_id, &user_id)? {
+            Some(membership) => membership.membership == "join",
+            None => false,
+        };
+
+        if !is_joined {
+            Err(ApiError::unauthorized(
+                "The user is not a member of the room".to_string(),
+            ))?;
+        }
+
+        let timeout = if put_typing_request.typing {
+            let timeout = put_typing_request.timeout.unwrap_or(DEFAULT_TYPING_TIMEOUT);
+
+            Some(Duration::from_millis(cmp::min(timeout, MAX_TYPING_TIMEOUT)))
+        } else {
+            None
+        };
+
+        typing.set_typing(&room_id, &user_id, timeout);
+
+        Ok(Response::with(EmptyResponse(Status::Ok)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::test::Test;
+    use iron::status::Status;
+
+    #[test]
+    fn typing_in_joined_room() {
+        let test = Test::new();
+        let (alice, room_id) = test.initial_fixtures("{}");
+
+        let response = test.set_typing(&alice.token, &room_id, &alice.id, r#"{"typing": true}"#);
+
+        assert_eq!(response.status, Status::Ok);
+    }
+
+    #[test]
+    fn typing_with_huge_timeout() {
+        let test = Test::new();
+        let (alice, room_id) = test.initial_fixtures("{}");
+
+        let response = test.set_typing(
+            &alice.token,
+            &room_id,
+            &alice.id,
+            r#"{"typing": true, "timeout": 18446744073709551615}"#,
+        );
+
+        assert_eq!(response.status, Status::Ok);
+    }
+
+    #[test]
+    fn typing_for_other_user() {
+        let test = Test::new();
+        let (alice, room_id) = test.initial_fixtures(r#"{"visibility": "public"}"#);
+        let bob = test.create_user();
+
+        assert_eq!(test.join_room(&bob.token, &room_id).status, Status::Ok);
+
+        let response = test.set_typing(&alice.token, &room_id, &bob.id, r#"{"typing": true}"#);
+
+        assert_eq!(response.status, Status::Forbidden);
+    }
+
+    #[test]
+    fn typing_in_room_without_membership() {
+        let test = Test::new();
+        let (_, room_id) = test.initial_fixtures("{}");
+        let bob = test.create_user();
+
+        let response = test.set_typing(&bob.token, &room_id, &bob.id, r#"{"typing": true}"#);
+
+        assert_eq!(response.status, Status::Forbidden);
+    }
+
+    #[test]
+    fn typing_with_invalid_body() {
+        let test = Test::new();
+        let (alice, room_id) = test.initial_fixtures("{}");
+
+        let response = test.set_typing(&alice.token, &room_id, &alice.id, r#"{"timeout": 10}"#);
+
+        assert_eq!(response.status, Status::UnprocessableEntity);
+    }
+}
@@ -0,0 +1,959 @@
+//! Authorization rules for events.
+//!
+//! Every event is checked against the current state of its room before it is saved, following
+//! the rules of the Matrix specification.
+
+use std::collections::HashMap;
+use std::convert::TryFrom;
+
+use diesel::pg::PgConnection;
+use ruma_events::room::create::CreateEventContent;
+use ruma_events::room::join_rules::{JoinRule, JoinRulesEventContent};
+use ruma_events::room::member::{MemberEventContent, MembershipState};
+use ruma_events::room::power_levels::PowerLevelsEventContent;
+use ruma_events::EventType;
+use ruma_identifiers::{RoomId, UserId};
+use serde::Deserialize;
+use serde_json::from_str;
+
+use crate::error::{ApiError, MapApiError};
+use crate::models::event::{Event, NewEvent};
+use crate::models::room_membership::RoomMembership;
+
+/// The power level of the room's creator while the room has no `m.room.power_levels` event.
+const CREATOR_POWER_LEVEL: u64 = 100;
+
+/// The level required to ban, invite, kick or redact while the room has no
+/// `m.room.power_levels` event.
+const DEFAULT_NAMED_POWER_LEVEL: u64 = 50;
+
+/// The level required to trigger a kind of notification, such as `@room`, when the room's
+/// `m.room.power_levels` event does not set it.
+const DEFAULT_NOTIFICATION_POWER_LEVEL: u64 = 50;
+
+/// The parts of a room's state the authorization rules depend on.
+#[derive(Clone, Debug, Default)]
+pub struct AuthState {
+    /// The user who created the room, once the room has an `m.room.create` event.
+    creator: Option<UserId>,
+    /// The number of state events in the room.
+    state_events: usize,
+    /// The content of the room's `m.room.join_rules` event.
+    join_rule: Option<JoinRule>,
+    /// The content of the room's `m.room.power_levels` event.
+    power_levels: Option<PowerLevelsEventContent>,
+    /// The `notifications` levels of the room's `m.room.power_levels` event.
+    notification_power_levels: HashMap<String, u64>,
+    /// The membership state of every user with an `m.room.member` event in the room.
+    memberships: HashMap<UserId, MembershipState>,
+}
+
+/// The part of the content of `m.room.power_levels` events that `PowerLevelsEventContent` lacks.
+#[derive(Debug, Deserialize)]
+struct NotificationPowerLevels {
+    /// The level required to trigger each kind of notification.
+    #[serde(default)]
+    notifications: HashMap<String, u64>,
+}
+
+/// Check that an event is allowed by the current state of its room.
+pub fn authorize_event(connection: &PgConnection, event: &NewEvent) -> Result<(), ApiError> {
+    let room_id = match event.room_id {
+        Some(ref room_id) => room_id,
+        None => Err(ApiError::bad_event(
+            "Events must be sent to a room".to_string(),
+        ))?,
+    };
+
+    let state = AuthState::load(connection, room_id)?;
+
+    state.authorize(event)?;
+
+    match event.redacts {
+        Some(ref redacts) => {
+            let redacted_event = match Event::find(connection, redacts)? {
+                Some(ref redacted_event) if redacted_event.room_id.as_ref() == Some(room_id) => {
+                    redacted_event.clone()
+                }
+                _ => Err(ApiError::not_found(format!(
+                    "The event {} was not found in the room",
+                    redacts
+                )))?,
+            };
+
+            state.authorize_redaction(event, &redacted_event.sender)
+        }
+        None => Ok(()),
+    }
+}
+
+impl AuthState {
+    /// Load the current state of a room.
+    pub fn load(connection: &PgConnection, room_id: &RoomId) -> Result<Self, ApiError> {
+        let mut state = Self::default();
+
+        for event in Event::get_room_full_state(connection, room_id)? {
+            let state_key = event.state_key.as_ref().map_or("", String::as_str);
+
+            state.apply_state_event(&event.event_type, state_key, &event.content)?;
+        }
+
+        Ok(state)
+    }
+
+    /// The users who have joined the room.
+    pub fn joined_members(&self) -> impl Iterator<Item = &UserId> {
+        self.memberships
+            .iter()
+            .filter(|&(_, membership)| *membership == MembershipState::Join)
+            .map(|(user_id, _)| user_id)
+    }
+
+    /// The number of users who have joined the room.
+    pub fn joined_member_count(&self) -> usize {
+        self.joined_members().count()
+    }
+
+    /// Whether a user may trigger the given kind of notification, such as `room` for `@room`.
+    pub fn has_notification_permission(&self, user_id: &UserId, key: &str) -> bool {
+        let required_power_level = self
+            .notification_power_levels
+            .get(key)
+            .cloned()
+            .unwrap_or(DEFAULT_NOTIFICATION_POWER_LEVEL);
+
+        self.user_power_level(user_id) >= required_power_level
+    }
+
+    /// Check that an event is allowed by this state.
+    pub fn authorize(&self, event: &NewEvent) -> Result<(), ApiError> {
+        let event_type = EventType::from(event.event_type.as_str());
+
+        if event_type == EventType::RoomCreate {
+            return self.authorize_create(event);
+        }
+
+        if self.creator.is_none() {
+            Err(ApiError::unauthorized(
+                "The room was not found on this server".to_string(),
+            ))?;
+        }
+
+        if event_type == EventType::RoomMember {
+            return self.authorize_membership(event);
+        }
+
+        self.ensure_joined(&event.sender)?;
+
+        let sender_power_level = self.user_power_level(&event.sender);
+
+        if event_type == EventType::RoomThirdPartyInvite {
+            if self.invite_power_level() > sender_power_level {
+                Err(ApiError::unauthorized(
+                    "Insufficient power level to invite".to_string(),
+                ))?;
+            }
+
+            return Ok(());
+        }
+
+        if self.required_power_level(&event_type, event.state_key.is_some()) > sender_power_level {
+            Err(ApiError::unauthorized(
+                "Insufficient power level to create this event.".to_string(),
+            ))?;
+        }
+
+        if let Some(ref state_key) = event.state_key {
+            if state_key.starts_with('@') && *state_key != event.sender.to_string() {
+                Err(ApiError::unauthorized(
+                    "State keys starting with @ can only be set by that user".to_string(),
+                ))?;
+            }
+        }
+
+        if event_type == EventType::RoomPowerLevels {
+            self.authorize_power_levels(event, sender_power_level)?;
+        }
+
+        // Redactions only need to be checked against the redacted event's server, which is always
+        // this one.
+        Ok(())
+    }
+
+    /// Check that the sender of a redaction may redact an event sent by the given user.
+    pub fn authorize_redaction(
+        &self,
+        event: &NewEvent,
+        redacted_sender: &UserId,
+    ) -> Result<(), ApiError> {
+        // Users can always redact their own events.
+        if event.sender == *redacted_sender {
+            return Ok(());
+        }
+
+        if self.redact_power_level() > self.user_power_level(&event.sender) {
+            Err(ApiError::unauthorized(
+                "Insufficient power level to redact events of other users.".to_string(),
+            ))?;
+        }
+
+        Ok(())
+    }
+
+    /// Check that a user would be allowed to send a state event of the given type.
+    ///
+    /// Used to gate room settings that are not stored as state events, such as the room's
+    /// visibility in the directory.
+    pub fn authorize_state_change(
+        &self,
+        user_id: &UserId,
+        event_type: &EventType,
+    ) -> Result<(), ApiError> {
+        if self.creator.is_none() {
+            Err(ApiError::unauthorized(
+                "The room was not found on this server".to_string(),
+            ))?;
+        }
+
+        self.ensure_joined(user_id)?;
+
+        if self.required_power_level(event_type, true) > self.user_power_level(user_id) {
+            Err(ApiError::unauthorized(
+                "Insufficient power level to create this event.".to_string(),
+            ))?;
+        }
+
+        Ok(())
+    }
+
+    /// Update the state with an event that has been authorized.
+    pub fn apply(&mut self, event: &NewEvent) -> Result<(), ApiError> {
+        match event.state_key {
+            Some(ref state_key) => {
+                self.apply_state_event(&event.event_type, state_key, &event.content)
+            }
+            None => Ok(()),
+        }
+    }
+
+    /// Update the state with the content of a state event.
+    fn apply_state_event(
+        &mut self,
+        event_type: &str,
+        state_key: &str,
+        content: &str,
+    ) -> Result<(), ApiError> {
+        self.state_events += 1;
+
+        match EventType::from(event_type) {
+            EventType::RoomCreate => {
+                let content: CreateEventContent = from_str(content)?;
+                self.creator = Some(content.creator);
+            }
+            EventType::RoomJoinRules => {
+                let content: JoinRulesEventContent = from_str(content)?;
+                self.join_rule = Some(content.join_rule);
+            }
+            EventType::RoomMember => {
+                let content: MemberEventContent = from_str(content)?;
+                self.memberships
+                    .insert(UserId::try_from(state_key)?, content.membership);
+            }
+            EventType::RoomPowerLevels => {
+                self.power_levels = Some(from_str(content)?);
+
+                let notification_power_levels: NotificationPowerLevels = from_str(content)?;
+                self.notification_power_levels = notification_power_levels.notifications;
+            }
+            _ => {}
+        }
+
+        Ok(())
+    }
+
+    /// Check an `m.room.create` event.
+    fn authorize_create(&self, event: &NewEvent) -> Result<(), ApiError> {
+        if self.state_events > 0 {
+            Err(ApiError::unauthorized(
+                "The room has already been created".to_string(),
+            ))?;
+        }
+
+        match event.room_id {
+            Some(ref room_id) if room_id.hostname() == event.sender.hostname() => Ok(()),
+            _ => Err(ApiError::unauthorized(
+                "Rooms can only be created by users of the room's homeserver".to_string(),
+            )),
+        }
+    }
+
+    /// Check an `m.room.member` event.
+    fn authorize_membership(&self, event: &NewEvent) -> Result<(), ApiError> {
+        let target = match event
+            .state_key
+            .as_ref()
+            .and_then(|state_key| UserId::try_from(state_key.as_str()).ok())
+        {
+            Some(target) => target,
+            None => Err(ApiError::bad_event(
+                "The state key of m.room.member events must be a user ID".to_string(),
+            ))?,
+        };
+        let content: MemberEventContent = event_content(event)?;
+        let target_membership = self.memberships.get(&target).cloned();
+
+        match content.membership {
+            MembershipState::Join => {
+                // The creator joins right after creating the room.
+                if self.state_events == 1 && self.creator.as_ref() == Some(&target) {
+                    return Ok(());
+                }
+
+                if event.sender != target {
+                    Err(ApiError::unauthorized(
+                        "Users can only join rooms on their own behalf".to_string(),
+                    ))?;
+                }
+
+                RoomMembership::verify_join(
+                    target_membership.as_ref(),
+                    self.join_rule.unwrap_or(JoinRule::Invite),
+                )
+            }
+            MembershipState::Invite => {
+                self.ensure_joined(&event.sender)?;
+
+                match target_membership {
+                    Some(MembershipState::Ban) => Err(ApiError::unauthorized(
+                        "The invited user is banned from the room".to_string(),
+                    ))?,
+                    Some(MembershipState::Join) => Err(ApiError::unauthorized(
+                        "The invited user has already joined".to_string(),
+                    ))?,
+                    _ => {}
+                }
+
+                if self.invite_power_level() > self.user_power_level(&event.sender) {
+                    Err(ApiError::unauthorized(
+                        "Insufficient power level to invite".to_string(),
+                    ))?;
+                }
+
+                Ok(())
+            }
+            MembershipState::Leave => {
+                if event.sender == target {
+                    return match target_membership {
+                        Some(MembershipState::Invite) | Some(MembershipState::Join) => Ok(()),
+                        _ => Err(ApiError::unauthorized(format!(
+                            "The user {} is not in the room",
+                            target
+                        ))),
+                    };
+                }
+
+                self.ensure_joined(&event.sender)?;
+
+                let is_banned = target_membership == Some(MembershipState::Ban);
+                let action = if is_banned { "unban" } else { "kick" };
+                let sender_power_level = self.user_power_level(&event.sender);
+
+                if (is_banned && self.ban_power_level() > sender_power_level)
+                    || self.kick_power_level() > sender_power_level
+                {
+                    Err(ApiError::unauthorized(format!(
+                        "Insufficient power level to {} a user",
+                        action
+                    )))?;
+                }
+
+                if self.user_power_level(&target) >= sender_power_level {
+                    Err(ApiError::unauthorized(format!(
+                        "Cannot {} a user with an equal or higher power level",
+                        action
+                    )))?;
+                }
+
+                Ok(())
+            }
+            MembershipState::Ban => {
+                self.ensure_joined(&event.sender)?;
+
+                let sender_power_level = self.user_power_level(&event.sender);
+
+                if self.ban_power_level() > sender_power_level {
+                    Err(ApiError::unauthorized(
+                        "Insufficient power level to ban a user".to_string(),
+                    ))?;
+                }
+
+                if self.user_power_level(&target) >= sender_power_level {
+                    Err(ApiError::unauthorized(
+                        "Cannot ban a user with an equal or higher power level".to_string(),
+                    ))?;
+                }
+
+                Ok(())
+            }
+            MembershipState::Knock => Err(ApiError::unauthorized(
+                "Knocking on rooms is not supported".to_string(),
+            )),
+        }
+    }
+
+    /// Check the changes an `m.room.power_levels` event makes to the current power levels.
+    ///
+    /// Users can only change levels that are at most their own and cannot change the level of
+    /// other users with the same or a higher level.
+    fn authorize_power_levels(
+        &self,
+        event: &NewEvent,
+        sender_power_level: u64,
+    ) -> Result<(), ApiError> {
+        let current = match self.power_levels {
+            Some(ref power_levels) => power_levels,
+            None => return Ok(()),
+        };
+        let new: PowerLevelsEventContent = event_content(event)?;
+
+        let named_levels = [
+            (current.ban, new.ban),
+            (current.events_default, new.events_default),
+            (current.invite, new.invite),
+            (current.kick, new.kick),
+            (current.redact, new.redact),
+            (current.state_default, new.state_default),
+            (current.users_default, new.users_default),
+        ];
+        let event_levels = current
+            .events
+            .keys()
+            .chain(new.events.keys())
+            .map(|event_type| {
+                (
+                    current.events.get(event_type).cloned(),
+                    new.events.get(event_type).cloned(),
+                )
+            });
+
+        if named_levels
+            .iter()
+            .map(|&(old, new)| (Some(old), Some(new)))
+            .chain(event_levels)
+            .any(|(old, new)| exceeds_power_level(old, new, sender_power_level))
+        {
+            Err(ApiError::unauthorized(
+                "Insufficient power level to change these power levels".to_string(),
+            ))?;
+        }
+
+        for user_id in current.users.keys().chain(new.users.keys()) {
+            let old_level = current.users.get(user_id).cloned();
+            let new_level = new.users.get(user_id).cloned();
+
+            if exceeds_power_level(old_level, new_level, sender_power_level) {
+                Err(ApiError::unauthorized(
+                    "Insufficient power level to change these power levels".to_string(),
+                ))?;
+            }
+
+            if *user_id != event.sender
+                && old_level != new_level
+                && old_level >= Some(sender_power_level)
+            {
+                Err(ApiError::unauthorized(
+                    "Cannot change the power level of a user with an equal or higher power level"
+                        .to_string(),
+                ))?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Ensure that a user has joined the room.
+    fn ensure_joined(&self, user_id: &UserId) -> Result<(), ApiError> {
+        match self.memberships.get(user_id) {
+            Some(MembershipState::Join) => Ok(()),
+            Some(_) => Err(ApiError::unauthorized(format!(
+                "The user {} has not joined the room",
+                user_id
+            ))),
+            None => Err(ApiError::unauthorized(format!(
+                "The user {} is not a member of the room",
+                user_id
+            ))),
+        }
+    }
+
+    /// The power level of a user in the room.
+    fn user_power_level(&self, user_id: &UserId) -> u64 {
+        match self.power_levels {
+            Some(ref power_levels) => *power_levels
+                .users
+                .get(user_id)
+                .unwrap_or(&power_levels.users_default),
+            None if self.creator.as_ref() == Some(user_id) => CREATOR_POWER_LEVEL,
+            None => 0,
+        }
+    }
+
+    /// The power level required to send an event of the given type.
+    fn required_power_level(&self, event_type: &EventType, is_state_event: bool) -> u64 {
+        match self.power_levels {
+            Some(ref power_levels) => match power_levels.events.get(event_type) {
+                Some(power_level) => *power_level,
+                None if is_state_event => power_levels.state_default,
+                None => power_levels.events_default,
+            },
+            None => 0,
+        }
+    }
+
+    /// The power level required to ban users.
+    fn ban_power_level(&self) -> u64 {
+        self.power_levels
+            .as_ref()
+            .map_or(DEFAULT_NAMED_POWER_LEVEL, |power_levels| power_levels.ban)
+    }
+
+    /// The power level required to invite users.
+    fn invite_power_level(&self) -> u64 {
+        self.power_levels
+            .as_ref()
+            .map_or(DEFAULT_NAMED_POWER_LEVEL, |power_levels| {
+                power_levels.invite
+            })
+    }
+
+    /// The power level required to kick users.
+    fn kick_power_level(&self) -> u64 {
+        self.power_levels
+            .as_ref()
+            .map_or(DEFAULT_NAMED_POWER_LEVEL, |power_levels| power_levels.kick)
+    }
+
+    /// The power level required to redact events of other users.
+    fn redact_power_level(&self) -> u64 {
+        self.power_levels
+            .as_ref()
+            .map_or(DEFAULT_NAMED_POWER_LEVEL, |power_levels| {
+                power_levels.redact
+            })
+    }
+}
+
+/// Whether a power level change involves a level higher than the sender's own.
+fn exceeds_power_level(old: Option<u64>, new: Option<u64>, sender_power_level: u64) -> bool {
+    old != new && (old > Some(sender_power_level) || new > Some(sender_power_level))
+}
+
+/// Convert the content of a new event into the type expected for its event type.
+fn event_content<T>(event: &NewEvent) -> Result<T, ApiError>
+where
+    T: for<'de> Deserialize<'de>,
+{
+    from_str(&event.content).map_api_err(|_| {
+        ApiError::bad_event(format!(
+            "Event content did not match expected structure for event of type {}.",
+            event.event_type
+        ))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::TryFrom;
+
+    use ruma_identifiers::{EventId, RoomId, UserId};
+    use serde_json::{json, Value};
+
+    use super::AuthState;
+    use crate::models::event::NewEvent;
+
+    const ALICE: &str = "@alice:ruma.test";
+    const BOB: &str = "@bob:ruma.test";
+    const CARL: &str = "@carl:ruma.test";
+
+    fn new_event(
+        event_type: &str,
+        state_key: Option<&str>,
+        sender: &str,
+        content: Value,
+    ) -> NewEvent {
+        NewEvent {
+            event_type: event_type.to_string(),
+            id: EventId::new("ruma.test").unwrap(),
+            content: content.to_string(),
+            room_id: Some(RoomId::try_from("!room:ruma.test").unwrap()),
+            sender: UserId::try_from(sender).unwrap(),
+            state_key: state_key.map(str::to_string),
+            redacts: None,
+            transaction_id: None,
+            replaces_state: None,
+            prev_content: None,
+        }
+    }
+
+    fn member_event(sender: &str, target: &str, membership: &str) -> NewEvent {
+        new_event(
+            "m.room.member",
+            Some(target),
+            sender,
+            json!({ "membership": membership }),
+        )
+    }
+
+    fn power_levels_event(sender: &str, content: Value) -> NewEvent {
+        new_event("m.room.power_levels", Some(""), sender, content)
+    }
+
+    fn apply_all(state: &mut AuthState, events: Vec<NewEvent>) {
+        for event in &events {
+            state.authorize(event).unwrap();
+            state.apply(event).unwrap();
+        }
+    }
+
+    /// An invite-only room created by Alice, which Bob has joined.
+    fn room_state() -> AuthState {
+        let mut state = AuthState::default();
+
+        apply_all(
+            &mut state,
+            vec![
+                new_event(
+                    "m.room.create",
+                    Some(""),
+                    ALICE,
+                    json!({ "creator": ALICE }),
+                ),
+                member_event(ALICE, ALICE, "join"),
+                power_levels_event(
+                    ALICE,
+                    json!({
+                        "ban": 50,
+                        "events": {},
+                        "events_default": 0,
+                        "invite": 50,
+                        "kick": 50,
+                        "redact": 50,
+                        "state_default": 50,
+                        "users": { ALICE: 100 },
+                        "users_default": 0
+                    }),
+                ),
+                new_event(
+                    "m.room.join_rules",
+                    Some(""),
+                    ALICE,
+                    json!({ "join_rule": "invite" }),
+                ),
+                member_event(ALICE, BOB, "invite"),
+                member_event(BOB, BOB, "join"),
+            ],
+        );
+
+        state
+    }
+
+    fn error_message(state: &AuthState, event: &NewEvent) -> String {
+        state.authorize(event).unwrap_err().to_string()
+    }
+
+    #[test]
+    fn create_event_must_be_the_first_event() {
+        let create_event = new_event(
+            "m.room.create",
+            Some(""),
+            ALICE,
+            json!({ "creator": ALICE }),
+        );
+
+        assert!(AuthState::default().authorize(&create_event).is_ok());
+        assert_eq!(
+            error_message(&room_state(), &create_event),
+            "The room has already been created"
+        );
+    }
+
+    #[test]
+    fn create_event_from_another_server() {
+        let sender = "@alice:example.com";
+        let create_event = new_event(
+            "m.room.create",
+            Some(""),
+            sender,
+            json!({ "creator": sender }),
+        );
+
+        assert!(AuthState::default().authorize(&create_event).is_err());
+    }
+
+    #[test]
+    fn events_require_a_create_event() {
+        assert_eq!(
+            error_message(&AuthState::default(), &member_event(ALICE, ALICE, "join")),
+            "The room was not found on this server"
+        );
+    }
+
+    #[test]
+    fn only_the_creator_joins_right_after_create() {
+        let mut state = AuthState::default();
+        apply_all(
+            &mut state,
+            vec![new_event(
+                "m.room.create",
+                Some(""),
+                ALICE,
+                json!({ "creator": ALICE }),
+            )],
+        );
+
+        assert!(state.authorize(&member_event(BOB, BOB, "join")).is_err());
+        assert!(state.authorize(&member_event(ALICE, ALICE, "join")).is_ok());
+
+        let topic_event = new_event("m.room.topic", Some(""), ALICE, json!({ "topic": "Hi" }));
+        assert_eq!(
+            error_message(&state, &topic_event),
+            format!("The user {} is not a member of the room", ALICE)
+        );
+    }
+
+    #[test]
+    fn users_join_on_their_own_behalf() {
+        let state = room_state();
+
+        assert_eq!(
+            error_message(&state, &member_event(ALICE, CARL, "join")),
+            "Users can only join rooms on their own behalf"
+        );
+        assert_eq!(
+            error_message(&state, &member_event(CARL, CARL, "join")),
+            "You are not invited to this room"
+        );
+    }
+
+    #[test]
+    fn invite_requires_invite_power_level() {
+        let state = room_state();
+
+        assert_eq!(
+            error_message(&state, &member_event(BOB, CARL, "invite")),
+            "Insufficient power level to invite"
+        );
+        assert!(state
+            .authorize(&member_event(ALICE, CARL, "invite"))
+            .is_ok());
+        assert_eq!(
+            error_message(&state, &member_event(ALICE, BOB, "invite")),
+            "The invited user has already joined"
+        );
+    }
+
+    #[test]
+    fn kick_and_ban_require_a_higher_power_level() {
+        let mut state = room_state();
+
+        assert_eq!(
+            error_message(&state, &member_event(BOB, ALICE, "leave")),
+            "Insufficient power level to kick a user"
+        );
+        assert_eq!(
+            error_message(&state, &member_event(BOB, ALICE, "ban")),
+            "Insufficient power level to ban a user"
+        );
+
+        apply_all(
+            &mut state,
+            vec![power_levels_event(
+                ALICE,
+                json!({
+                    "ban": 0,
+                    "events": {},
+                    "kick": 0,
+                    "users": { ALICE: 100 }
+                }),
+            )],
+        );
+
+        assert_eq!(
+            error_message(&state, &member_event(BOB, ALICE, "leave")),
+            "Cannot kick a user with an equal or higher power level"
+        );
+        assert_eq!(
+            error_message(&state, &member_event(BOB, ALICE, "ban")),
+            "Cannot ban a user with an equal or higher power level"
+        );
+        assert!(state.authorize(&member_event(ALICE, BOB, "leave")).is_ok());
+        assert!(state.authorize(&member_event(ALICE, BOB, "ban")).is_ok());
+    }
+
+    #[test]
+    fn unban_requires_ban_power_level() {
+        let mut state = room_state();
+        apply_all(&mut state, vec![member_event(ALICE, CARL, "ban")]);
+
+        assert_eq!(
+            error_message(&state, &member_event(BOB, CARL, "leave")),
+            "Insufficient power level to unban a user"
+        );
+        assert_eq!(
+            error_message(&state, &member_event(CARL, CARL, "join")),
+            "The user is banned from the room"
+        );
+        assert!(state.authorize(&member_event(ALICE, CARL, "leave")).is_ok());
+    }
+
+    #[test]
+    fn state_default_and_events_default() {
+        let state = room_state();
+
+        let message_event = new_event(
+            "m.room.message",
+            None,
+            BOB,
+            json!({ "body": "Hi", "msgtype": "m.text" }),
+        );
+        let topic_event = new_event("m.room.topic", Some(""), BOB, json!({ "topic": "Hi" }));
+
+        assert!(state.authorize(&message_event).is_ok());
+        assert_eq!(
+            error_message(&state, &topic_event),
+            "Insufficient power level to create this event."
+        );
+    }
+
+    #[test]
+    fn users_default_applies_to_users_without_a_power_level() {
+        let mut state = room_state();
+        apply_all(
+            &mut state,
+            vec![power_levels_event(
+                ALICE,
+                json!({
+                    "events": {},
+                    "users": { ALICE: 100 },
+                    "users_default": 50
+                }),
+            )],
+        );
+
+        let topic_event = new_event("m.room.topic", Some(""), BOB, json!({ "topic": "Hi" }));
+
+        assert!(state.authorize(&topic_event).is_ok());
+    }
+
+    #[test]
+    fn state_keys_with_user_ids_belong_to_that_user() {
+        let state = room_state();
+
+        let own_event = new_event("org.ruma.test", Some(ALICE), ALICE, json!({}));
+        let other_event = new_event("org.ruma.test", Some(BOB), ALICE, json!({}));
+
+        assert!(state.authorize(&own_event).is_ok());
+        assert_eq!(
+            error_message(&state, &other_event),
+            "State keys starting with @ can only be set by that user"
+        );
+    }
+
+    #[test]
+    fn power_level_changes_are_limited_by_the_sender_power_level() {
+        let mut state = room_state();
+        apply_all(
+            &mut state,
+            vec![power_levels_event(
+                ALICE,
+                json!({
+                    "events": {},
+                    "state_default": 0,
+                    "users": { ALICE: 100, BOB: 50, CARL: 50 }
+                }),
+            )],
+        );
+
+        // Bob cannot raise his own power level above his current one.
+        let raise_own_level = power_levels_event(
+            BOB,
+            json!({
+                "events": {},
+                "state_default": 0,
+                "users": { ALICE: 100, BOB: 100, CARL: 50 }
+            }),
+        );
+        assert_eq!(
+            error_message(&state, &raise_own_level),
+            "Insufficient power level to change these power levels"
+        );
+
+        // Bob cannot change levels above his own.
+        let require_higher_level = power_levels_event(
+            BOB,
+            json!({
+                "events": { "m.room.name": 75 },
+                "state_default": 0,
+                "users": { ALICE: 100, BOB: 50, CARL: 50 }
+            }),
+        );
+        assert!(state.authorize(&require_higher_level).is_err());
+
+        // Bob cannot change the level of a user with an equal or higher level.
+        let demote_alice = power_levels_event(
+            BOB,
+            json!({
+                "events": {},
+                "state_default": 0,
+                "users": { ALICE: 0, BOB: 50, CARL: 50 }
+            }),
+        );
+        assert!(state.authorize(&demote_alice).is_err());
+
+        let demote_carl = power_levels_event(
+            BOB,
+            json!({
+                "events": {},
+                "state_default": 0,
+                "users": { ALICE: 100, BOB: 50, CARL: 0 }
+            }),
+        );
+        assert_eq!(
+            error_message(&state, &demote_carl),
+            "Cannot change the power level of a user with an equal or higher power level"
+        );
+
+        let demote_bob = power_levels_event(
+            ALICE,
+            json!({
+                "events": {},
+                "state_default": 0,
+                "users": { ALICE: 100 }
+            }),
+        );
+        assert!(state.authorize(&demote_bob).is_ok());
+    }
+
+    #[test]
+    fn redacting_events_of_other_users_requires_redact_power_level() {
+        let state = room_state();
+        let alice = UserId::try_from(ALICE).unwrap();
+        let bob = UserId::try_from(BOB).unwrap();
+
+        let redaction = new_event("m.room.redaction", None, BOB, json!({}));
+
+        assert!(state.authorize(&redaction).is_ok());
+        assert!(state.authorize_redaction(&redaction, &bob).is_ok());
+        assert_eq!(
+            state
+                .authorize_redaction(&redaction, &alice)
+                .unwrap_err()
+                .to_string(),
+            "Insufficient power level to redact events of other users."
+        );
+
+        let redaction = new_event("m.room.redaction", None, ALICE, json!({}));
+
+        assert!(state.authorize_redaction(&redaction, &bob).is_ok());
+    }
+}
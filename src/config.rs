@@ -2,7 +2,7 @@
 
 use std::fs::File;
 use std::io::Read;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
 
 use base64::decode;
@@ -32,6 +32,8 @@ enum RawConfig {
 /// Version 1 of the configuration format.
 #[derive(Deserialize)]
 struct V1Config {
+    /// See the similarly named field on `Config`.
+    access_token_lifetime: Option<u32>,
     /// See the similarly named field on `Config`.
     bind_address: Option<String>,
     /// See the similarly named field on `Config`.
@@ -41,12 +43,18 @@ struct V1Config {
     /// See the similarly named field on `Config`.
     macaroon_secret_key: String,
     /// See the similarly named field on `Config`.
+    media_directory: Option<String>,
+    /// See the similarly named field on `Config`.
     postgres_url: String,
+    /// See the similarly named field on `Config`.
+    worker_threads: Option<usize>,
 }
 
 /// Server configuration provided by the user.
 #[derive(Debug, Clone)]
 pub struct Config {
+    /// The number of seconds an access token is valid for after it is issued. Defaults to 3600.
+    pub access_token_lifetime: u32,
     /// The network address where the server should listen for connections. Defaults to 127.0.0.1.
     pub bind_address: String,
     /// The network port where the server should listen for connections. Defaults to 3000.
@@ -58,9 +66,14 @@ pub struct Config {
     /// cryptographically random bytes, encoded as a Base64 string. Changing this value will
     /// invalidate any previously generated macaroons.
     pub macaroon_secret_key: Vec<u8>,
+    /// The directory where uploaded media is stored. Defaults to "media".
+    pub media_directory: PathBuf,
     /// A [PostgreSQL connection string](http://www.postgresql.org/docs/current/static/libpq-connect.html#LIBPQ-CONNSTRING)
     /// for Ruma's PostgreSQL database.
     pub postgres_url: String,
+    /// The number of threads handling requests. Long-polling `/sync` requests may occupy at most
+    /// half of them. Defaults to 64.
+    pub worker_threads: usize,
 }
 
 impl Config {
@@ -104,13 +117,20 @@ impl Config {
         };
 
         Ok(Self {
+            access_token_lifetime: v1_config.access_token_lifetime.unwrap_or(3600),
             bind_address: v1_config
                 .bind_address
                 .unwrap_or_else(|| "127.0.0.1".to_string()),
             bind_port: v1_config.bind_port.unwrap_or_else(|| "3000".to_string()),
             domain: v1_config.domain,
             macaroon_secret_key,
+            media_directory: PathBuf::from(
+                v1_config
+                    .media_directory
+                    .unwrap_or_else(|| "media".to_string()),
+            ),
             postgres_url: v1_config.postgres_url,
+            worker_threads: v1_config.worker_threads.unwrap_or(64),
         })
     }
 
@@ -29,6 +29,9 @@ pub struct ApiError {
     errcode: ApiErrorCode,
     /// A human-readable message describing the error.
     error: String,
+    /// Whether the client can log in again without discarding its data, for unknown tokens.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    soft_logout: Option<bool>,
 }
 
 /// The error code for a client-facing error.
@@ -57,6 +60,8 @@ pub enum ApiErrorCode {
     NotJson,
     /// Ruma does not implement the requested API.
     Unimplemented,
+    /// The request or entity was too large.
+    TooLarge,
     /// Errors not fitting into another category.
     Unknown,
     /// The access token specified was not recognised.
@@ -87,122 +92,153 @@ pub trait MapApiError {
 }
 
 impl ApiError {
+    /// Create an error with the given code and message.
+    fn new(errcode: ApiErrorCode, error: String) -> Self {
+        Self {
+            errcode,
+            error,
+            soft_logout: None,
+        }
+    }
+
     /// Create an error for requests that try to create a room alias that is already taken.
     pub fn alias_taken<T: Into<Option<String>>>(message: T) -> Self {
         let message = message.into();
-        Self {
-            errcode: ApiErrorCode::AliasTaken,
-            error: message.unwrap_or_else(|| "Alias already taken.".to_string()),
-        }
+        Self::new(
+            ApiErrorCode::AliasTaken,
+            message.unwrap_or_else(|| "Alias already taken.".to_string()),
+        )
     }
 
     /// Create an error for invalid or incomplete input to event creation API endpoints.
     pub fn bad_event<T: Into<Option<String>>>(message: T) -> Self {
         let message = message.into();
-        Self {
-            errcode: ApiErrorCode::BadEvent,
-            error: message.unwrap_or_else(|| "Invalid event data.".to_string()),
-        }
+        Self::new(
+            ApiErrorCode::BadEvent,
+            message.unwrap_or_else(|| "Invalid event data.".to_string()),
+        )
     }
 
     /// Create an error for invalid or incomplete JSON in request bodies.
     pub fn bad_json<T: Into<Option<String>>>(message: T) -> Self {
         let message = message.into();
-        Self {
-            errcode: ApiErrorCode::BadJson,
-            error: message
-                .unwrap_or_else(|| "Invalid or missing key-value pairs in JSON.".to_string()),
-        }
+        Self::new(
+            ApiErrorCode::BadJson,
+            message.unwrap_or_else(|| "Invalid or missing key-value pairs in JSON.".to_string()),
+        )
     }
 
     /// Create an error for endpoints where guest accounts are not supported.
     pub fn guest_forbidden<T: Into<Option<String>>>(message: T) -> Self {
         let message = message.into();
-        Self {
-            errcode: ApiErrorCode::GuestAccessForbidden,
-            error: message.unwrap_or_else(|| "Guest accounts are forbidden.".to_string()),
-        }
+        Self::new(
+            ApiErrorCode::GuestAccessForbidden,
+            message.unwrap_or_else(|| "Guest accounts are forbidden.".to_string()),
+        )
     }
 
     /// Create an error for invalid input parameters.
     pub fn invalid_param(param_name: &str, msg: impl Display) -> Self {
-        Self {
-            errcode: ApiErrorCode::InvalidParam,
-            error: format!("Parameter '{}' is not valid: {}", param_name, msg),
-        }
+        Self::new(
+            ApiErrorCode::InvalidParam,
+            format!("Parameter '{}' is not valid: {}", param_name, msg),
+        )
     }
 
     /// Create an error for requests missing a value for a required parameter.
     pub fn missing_param(param_name: &str) -> Self {
-        Self {
-            errcode: ApiErrorCode::MissingParam,
-            error: format!("Missing value for required parameter: {}.", param_name),
-        }
+        Self::new(
+            ApiErrorCode::MissingParam,
+            format!("Missing value for required parameter: {}.", param_name),
+        )
     }
 
     /// Create an error for requests that do not map to a resource.
     pub fn not_found<T: Into<Option<String>>>(message: T) -> Self {
         let message = message.into();
-        Self {
-            errcode: ApiErrorCode::NotFound,
-            error: message.unwrap_or_else(|| "No resource was found for this request.".to_string()),
-        }
+        Self::new(
+            ApiErrorCode::NotFound,
+            message.unwrap_or_else(|| "No resource was found for this request.".to_string()),
+        )
     }
 
     /// Create an error for requests without JSON bodies.
     pub fn not_json<T: Into<Option<String>>>(message: T) -> Self {
         let message = message.into();
-        Self {
-            errcode: ApiErrorCode::NotJson,
-            error: message.unwrap_or_else(|| "No JSON found in request body.".to_string()),
-        }
+        Self::new(
+            ApiErrorCode::NotJson,
+            message.unwrap_or_else(|| "No JSON found in request body.".to_string()),
+        )
     }
 
     /// Create an error for requests that are not marked as containing JSON.
     pub fn wrong_content_type<T: Into<Option<String>>>(message: T) -> Self {
         let message = message.into();
-        Self {
-            errcode: ApiErrorCode::NotJson,
-            error: message.unwrap_or_else(|| {
+        Self::new(
+            ApiErrorCode::NotJson,
+            message.unwrap_or_else(|| {
                 "Request's Content-Type header must be application/json.".to_string()
             }),
-        }
+        )
     }
 
     /// Create an error for requests that did not provide required authentication parameters.
     pub fn unauthorized<T: Into<Option<String>>>(message: T) -> Self {
         let message = message.into();
-        Self {
-            errcode: ApiErrorCode::Forbidden,
-            error: message.unwrap_or_else(|| "Authentication is required.".to_string()),
-        }
+        Self::new(
+            ApiErrorCode::Forbidden,
+            message.unwrap_or_else(|| "Authentication is required.".to_string()),
+        )
+    }
+
+    /// Create an error for requests whose body exceeds the size Ruma accepts.
+    pub fn too_large<T: Into<Option<String>>>(message: T) -> Self {
+        let message = message.into();
+        Self::new(
+            ApiErrorCode::TooLarge,
+            message.unwrap_or_else(|| "The request body is too large.".to_string()),
+        )
     }
 
     /// Create an error for Matrix APIs that Ruma intentionally does not implement.
     pub fn unimplemented<T: Into<Option<String>>>(message: T) -> Self {
         let message = message.into();
-        Self {
-            errcode: ApiErrorCode::Unimplemented,
-            error: message
-                .unwrap_or_else(|| "The homeserver does not implement this API.".to_string()),
-        }
+        Self::new(
+            ApiErrorCode::Unimplemented,
+            message.unwrap_or_else(|| "The homeserver does not implement this API.".to_string()),
+        )
     }
 
     /// Create an error for Matrix APIs that Ruma intentionally does not implement.
     pub fn limited_rate<T: Into<Option<String>>>(message: T) -> Self {
         let message = message.into();
-        Self {
-            errcode: ApiErrorCode::LimitExceeded,
-            error: message.unwrap_or_else(|| "Too many retry!".to_string()),
-        }
+        Self::new(
+            ApiErrorCode::LimitExceeded,
+            message.unwrap_or_else(|| "Too many retry!".to_string()),
+        )
     }
 
     /// Create a generic error for anything not specifically covered by the Matrix spec.
     pub fn unknown<T: Into<Option<String>>>(message: T) -> Self {
+        let message = message.into();
+        Self::new(
+            ApiErrorCode::Unknown,
+            message.unwrap_or_else(|| "An unknown server-side error occurred.".to_string()),
+        )
+    }
+
+    /// Create an error for requests with an access token that is not valid.
+    ///
+    /// `soft_logout` is set for tokens that expired, so that clients log in again without
+    /// discarding their data.
+    pub fn unknown_token<T: Into<Option<String>>>(message: T, soft_logout: bool) -> Self {
         let message = message.into();
         Self {
-            errcode: ApiErrorCode::Unknown,
-            error: message.unwrap_or_else(|| "An unknown server-side error occurred.".to_string()),
+            soft_logout: Some(soft_logout),
+            ..Self::new(
+                ApiErrorCode::UnknownToken,
+                message.unwrap_or_else(|| "Unrecognised access token.".to_string()),
+            )
         }
     }
 }
@@ -343,6 +379,7 @@ impl ApiErrorCode {
             }
             ApiErrorCode::LimitExceeded => Status::TooManyRequests,
             ApiErrorCode::NotFound | ApiErrorCode::Unimplemented => Status::NotFound,
+            ApiErrorCode::TooLarge => Status::PayloadTooLarge,
             ApiErrorCode::Unknown => Status::InternalServerError,
             ApiErrorCode::UnknownToken => Status::Unauthorized,
         }
@@ -365,6 +402,7 @@ impl Serialize for ApiErrorCode {
             ApiErrorCode::MissingParam => "M_MISSING_PARAM",
             ApiErrorCode::NotFound => "M_NOT_FOUND",
             ApiErrorCode::NotJson => "M_NOT_JSON",
+            ApiErrorCode::TooLarge => "M_TOO_LARGE",
             ApiErrorCode::Unimplemented => "IO_RUMA_UNIMPLEMENTED",
             ApiErrorCode::Unknown => "M_UNKNOWN",
             ApiErrorCode::UnknownToken => "M_UNKNOWN_TOKEN",
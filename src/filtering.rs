@@ -0,0 +1,537 @@
+//! Evaluation of filters against the events returned by sync.
+//!
+//! Filters let clients choose which events they receive and which fields of them they need,
+//! following the rules of the Matrix specification.
+
+use ruma_identifiers::{RoomId, UserId};
+use serde_json::{Map, Value};
+
+use crate::models::event::Event;
+use crate::models::filter::{ContentFilter, EventFilter, EventFormat, RoomEventFilter, RoomFilter};
+
+/// A filter of a sync request, ready to be evaluated against events.
+#[derive(Clone, Debug)]
+pub struct FilterEvaluator {
+    /// The filter for room data.
+    room: Option<RoomFilter>,
+    /// The filter for presence updates.
+    presence: Option<EventFilter>,
+    /// The filter for global account data.
+    account_data: Option<EventFilter>,
+    /// The format to use for events.
+    event_format: EventFormat,
+    /// The fields to include in events, each split into the keys leading to it.
+    event_fields: Vec<Vec<String>>,
+}
+
+/// The conditions of an `EventFilter` or a `RoomEventFilter`.
+#[derive(Clone, Copy, Debug)]
+struct Conditions<'a> {
+    /// The event type patterns to include, or all types if empty.
+    types: &'a [String],
+    /// The event type patterns to exclude.
+    not_types: &'a [String],
+    /// The senders to include, or all senders if empty.
+    senders: &'a [UserId],
+    /// The senders to exclude.
+    not_senders: &'a [UserId],
+    /// The rooms to include, or all rooms if empty.
+    rooms: &'a [RoomId],
+    /// The rooms to exclude.
+    not_rooms: &'a [RoomId],
+    /// The maximum number of events to return, or no maximum if zero.
+    limit: usize,
+}
+
+impl FilterEvaluator {
+    /// Create an evaluator for a filter, or one including everything if there is none.
+    pub fn new(filter: Option<ContentFilter>) -> Self {
+        match filter {
+            Some(filter) => Self {
+                room: filter.room,
+                presence: filter.presence,
+                account_data: filter.account_data,
+                event_format: filter.event_format.unwrap_or(EventFormat::Client),
+                event_fields: filter
+                    .event_fields
+                    .iter()
+                    .filter_map(|field| field_path(field))
+                    .collect(),
+            },
+            None => Self {
+                room: None,
+                presence: None,
+                account_data: None,
+                event_format: EventFormat::Client,
+                event_fields: Vec::new(),
+            },
+        }
+    }
+
+    /// Whether rooms the user has left should be included.
+    pub fn include_leave(&self) -> bool {
+        match self.room {
+            Some(ref room) => room.include_leave,
+            None => false,
+        }
+    }
+
+    /// Whether a room should be included at all.
+    pub fn includes_room(&self, room_id: &RoomId) -> bool {
+        match self.room {
+            Some(ref room) => {
+                (room.rooms.is_empty() || room.rooms.contains(room_id))
+                    && !room.not_rooms.contains(room_id)
+            }
+            None => true,
+        }
+    }
+
+    /// The maximum number of events in a timeline, or zero if there is no maximum.
+    pub fn timeline_limit(&self) -> usize {
+        self.room_conditions(|room| room.timeline.as_ref())
+            .map(|conditions| conditions.limit)
+            .unwrap_or(0)
+    }
+
+    /// Whether an event should be included in a timeline.
+    pub fn timeline_matches(&self, event: &Event) -> bool {
+        match self.room_conditions(|room| room.timeline.as_ref()) {
+            Some(conditions) => conditions.matches_event(event),
+            None => true,
+        }
+    }
+
+    /// Whether only the member events of the senders in a timeline should be included in the
+    /// room's state.
+    pub fn lazy_load_members(&self) -> bool {
+        match self.room.as_ref().and_then(|room| room.state.as_ref()) {
+            Some(state) => state.lazy_load_members,
+            None => false,
+        }
+    }
+
+    /// Whether member events already sent to the client should be sent again when lazy-loading
+    /// members.
+    pub fn include_redundant_members(&self) -> bool {
+        match self.room.as_ref().and_then(|room| room.state.as_ref()) {
+            Some(state) => state.include_redundant_members,
+            None => false,
+        }
+    }
+
+    /// Keep the state events that should be included in a room's state.
+    pub fn filter_state(&self, events: Vec<Event>) -> Vec<Event> {
+        match self.room_conditions(|room| room.state.as_ref()) {
+            Some(conditions) => conditions.limit(
+                events
+                    .into_iter()
+                    .filter(|event| conditions.matches_event(event))
+                    .collect(),
+            ),
+            None => events,
+        }
+    }
+
+    /// Keep the ephemeral events of a room that should be included.
+    pub fn filter_ephemeral(&self, room_id: &RoomId, events: Vec<Value>) -> Vec<Value> {
+        match self.room_conditions(|room| room.ephemeral.as_ref()) {
+            Some(conditions) => conditions.filter_values(Some(room_id), events),
+            None => events,
+        }
+    }
+
+    /// Keep the account data events of a room that should be included.
+    pub fn filter_room_account_data(&self, room_id: &RoomId, events: Vec<Value>) -> Vec<Value> {
+        match self.room_conditions(|room| room.account_data.as_ref()) {
+            Some(conditions) => conditions.filter_values(Some(room_id), events),
+            None => events,
+        }
+    }
+
+    /// Keep the presence events that should be included.
+    pub fn filter_presence(&self, events: Vec<Value>) -> Vec<Value> {
+        match self.presence {
+            Some(ref presence) => Conditions::from(presence).filter_values(None, events),
+            None => events,
+        }
+    }
+
+    /// Keep the global account data events that should be included.
+    pub fn filter_account_data(&self, events: Vec<Value>) -> Vec<Value> {
+        match self.account_data {
+            Some(ref account_data) => Conditions::from(account_data).filter_values(None, events),
+            None => events,
+        }
+    }
+
+    /// Bring an event into the requested format, keeping only the requested fields.
+    ///
+    /// The `client` format leaves out the ID of the room, which is implied by where the event
+    /// appears in the response.
+    pub fn format_event(&self, mut event: Value) -> Value {
+        if self.event_format == EventFormat::Client {
+            if let Some(object) = event.as_object_mut() {
+                object.remove("room_id");
+            }
+        }
+
+        if self.event_fields.is_empty() {
+            return event;
+        }
+
+        let mut projection = Map::new();
+        for path in &self.event_fields {
+            copy_field(&event, &mut projection, path);
+        }
+
+        Value::Object(projection)
+    }
+
+    /// Bring a list of events into the requested format.
+    pub fn format_events(&self, events: Vec<Value>) -> Vec<Value> {
+        events
+            .into_iter()
+            .map(|event| self.format_event(event))
+            .collect()
+    }
+
+    /// The conditions of one of the filters for room data, if there are any.
+    fn room_conditions<F>(&self, select: F) -> Option<Conditions<'_>>
+    where
+        F: FnOnce(&RoomFilter) -> Option<&RoomEventFilter>,
+    {
+        self.room.as_ref().and_then(select).map(Conditions::from)
+    }
+}
+
+impl<'a> From<&'a EventFilter> for Conditions<'a> {
+    fn from(filter: &'a EventFilter) -> Self {
+        Conditions {
+            types: &filter.types,
+            not_types: &filter.not_types,
+            senders: &filter.senders,
+            not_senders: &filter.not_senders,
+            rooms: &[],
+            not_rooms: &[],
+            limit: filter.limit,
+        }
+    }
+}
+
+impl<'a> From<&'a RoomEventFilter> for Conditions<'a> {
+    fn from(filter: &'a RoomEventFilter) -> Self {
+        Conditions {
+            types: &filter.types,
+            not_types: &filter.not_types,
+            senders: &filter.senders,
+            not_senders: &filter.not_senders,
+            rooms: &filter.rooms,
+            not_rooms: &filter.not_rooms,
+            limit: filter.limit,
+        }
+    }
+}
+
+impl<'a> Conditions<'a> {
+    /// Whether an event with the given properties passes the conditions.
+    ///
+    /// Events without a sender are excluded whenever senders are required.
+    fn matches(&self, event_type: &str, sender: Option<&str>, room_id: Option<&RoomId>) -> bool {
+        let type_included = self.types.is_empty()
+            || self
+                .types
+                .iter()
+                .any(|pattern| type_matches(pattern, event_type));
+        let type_excluded = self
+            .not_types
+            .iter()
+            .any(|pattern| type_matches(pattern, event_type));
+
+        let (sender_included, sender_excluded) = match sender {
+            Some(sender) => (
+                self.senders.is_empty() || self.senders.iter().any(|id| id.to_string() == sender),
+                self.not_senders.iter().any(|id| id.to_string() == sender),
+            ),
+            None => (self.senders.is_empty(), false),
+        };
+
+        let (room_included, room_excluded) = match room_id {
+            Some(room_id) => (
+                self.rooms.is_empty() || self.rooms.contains(room_id),
+                self.not_rooms.contains(room_id),
+            ),
+            None => (true, false),
+        };
+
+        type_included
+            && !type_excluded
+            && sender_included
+            && !sender_excluded
+            && room_included
+            && !room_excluded
+    }
+
+    /// Whether a stored event passes the conditions.
+    fn matches_event(&self, event: &Event) -> bool {
+        self.matches(
+            &event.event_type,
+            Some(&event.sender.to_string()),
+            event.room_id.as_ref(),
+        )
+    }
+
+    /// Keep the serialized events passing the conditions, up to the limit.
+    fn filter_values(&self, room_id: Option<&RoomId>, events: Vec<Value>) -> Vec<Value> {
+        let events = events
+            .into_iter()
+            .filter(|event| {
+                let event_type = event.get("type").and_then(Value::as_str).unwrap_or("");
+                let sender = event.get("sender").and_then(Value::as_str);
+
+                self.matches(event_type, sender, room_id)
+            })
+            .collect();
+
+        self.limit(events)
+    }
+
+    /// Keep at most as many events as the limit allows, starting with the first.
+    fn limit<T>(&self, mut events: Vec<T>) -> Vec<T> {
+        if self.limit != 0 {
+            events.truncate(self.limit);
+        }
+
+        events
+    }
+}
+
+/// Check that a filter can be evaluated, returning the reason if it cannot.
+pub fn validate(filter: &ContentFilter) -> Result<(), String> {
+    if let Some(field) = filter
+        .event_fields
+        .iter()
+        .find(|field| field_path(field).is_none())
+    {
+        return Err(format!("Invalid field in event_fields: {}", field));
+    }
+
+    let mut conditions: Vec<Conditions<'_>> = Vec::new();
+    conditions.extend(filter.presence.iter().map(Conditions::from));
+    conditions.extend(filter.account_data.iter().map(Conditions::from));
+
+    if let Some(ref room) = filter.room {
+        let room_filters = [
+            &room.timeline,
+            &room.state,
+            &room.ephemeral,
+            &room.account_data,
+        ];
+        conditions.extend(
+            room_filters
+                .iter()
+                .filter_map(|room_filter| room_filter.as_ref())
+                .map(Conditions::from),
+        );
+    }
+
+    let has_empty_type = conditions.iter().any(|conditions| {
+        conditions
+            .types
+            .iter()
+            .chain(conditions.not_types)
+            .any(String::is_empty)
+    });
+
+    if has_empty_type {
+        return Err("Event types must not be empty".to_string());
+    }
+
+    Ok(())
+}
+
+/// Whether an event type matches a pattern, in which `*` matches any sequence of characters.
+fn type_matches(pattern: &str, event_type: &str) -> bool {
+    let mut parts = pattern.split('*');
+    let prefix = parts.next().unwrap_or("");
+
+    if !event_type.starts_with(prefix) {
+        return false;
+    }
+
+    let mut rest = &event_type[prefix.len()..];
+    let parts: Vec<&str> = parts.collect();
+
+    match parts.split_last() {
+        Some((suffix, middle)) => {
+            for part in middle {
+                match rest.find(part) {
+                    Some(index) => rest = &rest[index + part.len()..],
+                    None => return false,
+                }
+            }
+
+            rest.len() >= suffix.len() && rest.ends_with(suffix)
+        }
+        None => rest.is_empty(),
+    }
+}
+
+/// Split an entry of `event_fields` into the keys leading to the field.
+///
+/// Keys are separated by `.`, and `\` makes the character after it part of the key. Returns
+/// `None` if a key is empty or the entry ends with a lone `\`.
+fn field_path(field: &str) -> Option<Vec<String>> {
+    let mut path = vec![String::new()];
+    let mut characters = field.chars();
+
+    while let Some(character) = characters.next() {
+        let character = match character {
+            '\\' => characters.next()?,
+            '.' => {
+                path.push(String::new());
+                continue;
+            }
+            character => character,
+        };
+
+        if let Some(key) = path.last_mut() {
+            key.push(character);
+        }
+    }
+
+    if path.iter().any(String::is_empty) {
+        return None;
+    }
+
+    Some(path)
+}
+
+/// Copy the field at the end of a path from an event into a projection of it, along with the
+/// objects leading to it.
+fn copy_field(source: &Value, target: &mut Map<String, Value>, path: &[String]) {
+    let (key, rest) = match path.split_first() {
+        Some(split) => split,
+        None => return,
+    };
+
+    let value = match source.get(key) {
+        Some(value) => value,
+        None => return,
+    };
+
+    if rest.is_empty() {
+        target.insert(key.clone(), value.clone());
+        return;
+    }
+
+    let entry = target
+        .entry(key.clone())
+        .or_insert_with(|| Value::Object(Map::new()));
+
+    if let Value::Object(ref mut object) = *entry {
+        copy_field(value, object, rest);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::{from_str, json};
+
+    use super::{field_path, type_matches, validate, FilterEvaluator};
+
+    #[test]
+    fn type_patterns() {
+        assert!(type_matches("m.room.message", "m.room.message"));
+        assert!(!type_matches("m.room.message", "m.room.message.feedback"));
+        assert!(type_matches("m.room.*", "m.room.message"));
+        assert!(type_matches("*", "m.typing"));
+        assert!(type_matches("m.*.member", "m.room.member"));
+        assert!(!type_matches("m.*.member", "m.room.name"));
+        assert!(!type_matches("m.room.*", "m.typing"));
+        assert!(type_matches("*.message", "m.room.message"));
+    }
+
+    #[test]
+    fn escaped_field_paths() {
+        assert_eq!(
+            field_path("content.body"),
+            Some(vec!["content".to_string(), "body".to_string()])
+        );
+        assert_eq!(
+            field_path(r"content.m\.relates_to"),
+            Some(vec!["content".to_string(), "m.relates_to".to_string()])
+        );
+        assert_eq!(
+            field_path(r"a\\.b"),
+            Some(vec![r"a\".to_string(), "b".to_string()])
+        );
+        assert_eq!(field_path("content..body"), None);
+        assert_eq!(field_path(r"content\"), None);
+        assert_eq!(field_path(""), None);
+    }
+
+    #[test]
+    fn invalid_filters() {
+        let filter = from_str(r#"{"event_fields": ["content."]}"#).unwrap();
+        assert!(validate(&filter).is_err());
+
+        let filter = from_str(r#"{"room": {"state": {"not_types": [""]}}}"#).unwrap();
+        assert!(validate(&filter).is_err());
+
+        let filter = from_str(r#"{"room": {"state": {"types": ["m.*"]}}}"#).unwrap();
+        assert!(validate(&filter).is_ok());
+    }
+
+    #[test]
+    fn event_fields_projection() {
+        let filter =
+            from_str(r#"{"event_fields": ["type", "content.body", "unsigned.nothing"]}"#).unwrap();
+        let evaluator = FilterEvaluator::new(Some(filter));
+
+        let event = json!({
+            "type": "m.room.message",
+            "room_id": "!room:ruma.test",
+            "sender": "@alice:ruma.test",
+            "content": { "msgtype": "m.text", "body": "Hi" },
+        });
+
+        assert_eq!(
+            evaluator.format_event(event),
+            json!({ "type": "m.room.message", "content": { "body": "Hi" } })
+        );
+    }
+
+    #[test]
+    fn federation_format_keeps_room_id() {
+        let filter = from_str(r#"{"event_format": "federation"}"#).unwrap();
+        let evaluator = FilterEvaluator::new(Some(filter));
+        let event = json!({ "type": "m.room.message", "room_id": "!room:ruma.test" });
+
+        assert_eq!(evaluator.format_event(event.clone()), event);
+        assert_eq!(
+            FilterEvaluator::new(None).format_event(event),
+            json!({ "type": "m.room.message" })
+        );
+    }
+
+    #[test]
+    fn presence_senders() {
+        let filter = from_str(
+            r#"{"presence": {"limit": 0, "senders": ["@alice:ruma.test"], "not_types": ["m.typing"]}}"#,
+        )
+        .unwrap();
+        let evaluator = FilterEvaluator::new(Some(filter));
+
+        let events = vec![
+            json!({ "type": "m.presence", "sender": "@alice:ruma.test" }),
+            json!({ "type": "m.presence", "sender": "@bob:ruma.test" }),
+            json!({ "type": "m.presence" }),
+        ];
+
+        assert_eq!(
+            evaluator.filter_presence(events),
+            vec![json!({ "type": "m.presence", "sender": "@alice:ruma.test" })]
+        );
+    }
+}
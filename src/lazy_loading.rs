@@ -0,0 +1,161 @@
+//! Lazy-loading of room members.
+//!
+//! Clients that lazy-load members are only sent the member events of the senders of the events
+//! they receive. To avoid sending the same member events over and over again, the member events
+//! sent to each client are remembered in memory.
+//!
+//! Members sent by sync are remembered by the `next_batch` token of the response, so that a
+//! client retrying a sync with the same `since` token is sent the same members again.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+
+use iron::typemap::Key;
+use iron::{Plugin, Request};
+use persistent::Read;
+use ruma_identifiers::{EventId, RoomId};
+
+use crate::error::ApiError;
+use crate::models::event::Event;
+
+/// The IDs of the member events sent to a client by room and user ID.
+type MembersByRoom = HashMap<RoomId, HashMap<String, EventId>>;
+
+/// The member events sent to every client that lazy-loads members.
+#[derive(Debug, Default)]
+pub struct LazyLoading {
+    /// The member events sent to each client, keyed by the ID of the client's access token.
+    clients: Mutex<HashMap<i64, ClientMembers>>,
+}
+
+/// The member events sent to a single client.
+#[derive(Debug, Default)]
+struct ClientMembers {
+    /// The member events sent by sync, keyed by the `next_batch` token they were sent up to.
+    synced: HashMap<String, SentMembers>,
+    /// The member events sent while paginating room messages.
+    paginated: SentMembers,
+}
+
+/// The member events known to a client.
+#[derive(Clone, Debug, Default)]
+pub struct SentMembers {
+    /// The IDs of the member events by room and user ID.
+    members: MembersByRoom,
+}
+
+impl LazyLoading {
+    /// Create a `LazyLoading` that has not sent any members yet.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Extract the `LazyLoading` stored in the request.
+    pub fn from_request(request: &mut Request<'_, '_>) -> Result<Arc<Self>, ApiError> {
+        request.get::<Read<Self>>().map_err(ApiError::from)
+    }
+
+    /// The member events sent by sync to the client using the given access token, up to the
+    /// `since` token.
+    ///
+    /// Clients that do not sync incrementally start over without any members.
+    pub fn synced_since(&self, access_token_id: i64, since: Option<&str>) -> SentMembers {
+        let since = match since {
+            Some(since) => since,
+            None => return SentMembers::default(),
+        };
+
+        self.lock()
+            .get(&access_token_id)
+            .and_then(|client| client.synced.get(since))
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    /// Remember the member events known to the client after the sync response from `since` to
+    /// `next_batch`.
+    ///
+    /// The members for other tokens are forgotten, but those for `since` are kept, so that a
+    /// client which lost the response can retry it.
+    pub fn remember_synced(
+        &self,
+        access_token_id: i64,
+        since: Option<&str>,
+        next_batch: &str,
+        sent_members: SentMembers,
+    ) {
+        let mut clients = self.lock();
+
+        if sent_members.members.is_empty() && !clients.contains_key(&access_token_id) {
+            return;
+        }
+
+        let client = clients.entry(access_token_id).or_default();
+
+        client
+            .synced
+            .retain(|token, _| Some(token.as_str()) == since);
+
+        if !sent_members.members.is_empty() {
+            client.synced.insert(next_batch.to_string(), sent_members);
+        }
+    }
+
+    /// Remember member events of a room as sent to the client while paginating room messages.
+    ///
+    /// Unless `include_redundant` is set, the member events this client was already sent while
+    /// paginating are removed first.
+    pub fn send_paginated(
+        &self,
+        access_token_id: i64,
+        room_id: &RoomId,
+        members: &mut Vec<Event>,
+        include_redundant: bool,
+    ) {
+        self.lock()
+            .entry(access_token_id)
+            .or_default()
+            .paginated
+            .send(room_id, members, include_redundant);
+    }
+
+    /// Forget the member events sent to the client using the given access token.
+    ///
+    /// This must be called when the access token is revoked, so that clients that are gone do
+    /// not take up memory.
+    pub fn forget(&self, access_token_id: i64) {
+        self.lock().remove(&access_token_id);
+    }
+
+    /// Lock the members, recovering them if another thread panicked while holding the lock.
+    fn lock(&self) -> MutexGuard<'_, HashMap<i64, ClientMembers>> {
+        self.clients.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+impl Key for LazyLoading {
+    type Value = Self;
+}
+
+impl SentMembers {
+    /// Remember member events of a room as sent to the client.
+    ///
+    /// Unless `include_redundant` is set, the member events the client was already sent are
+    /// removed first.
+    pub fn send(&mut self, room_id: &RoomId, members: &mut Vec<Event>, include_redundant: bool) {
+        let sent = self.members.entry(room_id.clone()).or_default();
+
+        if !include_redundant {
+            members.retain(|member| match member.state_key {
+                Some(ref user_id) => sent.get(user_id) != Some(&member.id),
+                None => true,
+            });
+        }
+
+        for member in members.iter() {
+            if let Some(ref user_id) = member.state_key {
+                sent.insert(user_id.clone(), member.id.clone());
+            }
+        }
+    }
+}
@@ -43,19 +43,26 @@ pub mod middleware;
 pub mod api {
     pub mod r0;
 }
+pub mod auth;
 pub mod authentication;
 pub mod config;
 pub mod crypto;
 pub mod db;
 pub mod error;
+pub mod filtering;
+pub mod lazy_loading;
 /// Models for the API's domain objects.
 pub mod models;
 pub mod modifier;
+pub mod notifier;
+pub mod push_gateway;
+pub mod push_rules;
 pub mod query;
 pub mod schema;
 pub mod server;
 pub mod swagger;
 #[cfg(test)]
 pub mod test;
+pub mod typing;
 
 embed_migrations!();
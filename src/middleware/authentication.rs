@@ -42,9 +42,12 @@ impl BeforeMiddleware for AccessTokenAuth {
         if let Some((_, ref token)) = query_pairs.find(|&(ref key, _)| key == "access_token") {
             let access_token = match AccessToken::find_valid_by_token(&connection, token)? {
                 Some(access_token) => access_token,
-                None => Err(ApiError::unauthorized("Unknown token".to_string()))?,
+                None => Err(ApiError::unknown_token(None, false))?,
             };
 
+            let config = Config::from_request(request)?;
+            access_token.verify(&config.macaroon_secret_key)?;
+
             match User::find_active_user(&connection, &access_token.user_id)? {
                 Some(user) => {
                     request.extensions.insert::<AccessToken>(access_token);
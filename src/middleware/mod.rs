@@ -4,21 +4,31 @@ use iron::Chain;
 
 mod authentication;
 mod json;
+mod notify_sync;
 mod path_params;
 mod response_headers;
 
 pub use self::authentication::{AccessTokenAuth, UIAuth};
 pub use self::json::JsonRequest;
+pub use self::notify_sync::NotifySync;
 pub use self::path_params::{
-    DataTypeParam, EventTypeParam, FilterIdParam, RoomAliasIdParam, RoomIdOrAliasParam,
-    RoomIdParam, TagParam, TransactionIdParam, UserIdParam,
+    DataTypeParam, EventIdParam, EventTypeParam, FilterIdParam, MediaIdParam, PushRuleKindParam,
+    PushRuleScopeParam, ReceiptTypeParam, RoomAliasIdParam, RoomIdOrAliasParam, RoomIdParam,
+    RuleIdParam, TagParam, TransactionIdParam, UserIdParam,
 };
 pub use self::response_headers::ResponseHeaders;
 
 /// `middleware_chain!(JoinRoom, []);`
 #[macro_export]
 macro_rules! middleware_chain {
-    ($chain:ident) => {chain_impl!($chain, []);};
+    ($chain:ident) => {
+        impl MiddlewareChain for $chain {
+            /// Create a `$chain` without any middleware.
+            fn chain() -> Chain {
+                Chain::new($chain)
+            }
+        }
+    };
     ($chain:ident, [$($middleware:expr),*]) => {
         impl MiddlewareChain for $chain {
             /// Create a `$chain` with all necessary middleware.
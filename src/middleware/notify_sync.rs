@@ -0,0 +1,41 @@
+//! Iron middleware to wake up long-polling `/sync` requests.
+
+use iron::method::Method;
+use iron::{AfterMiddleware, IronResult, Request, Response};
+
+use crate::middleware::RoomIdParam;
+use crate::models::user::User;
+use crate::notifier::{Notifier, Topic};
+
+/// Wakes up waiting `/sync` requests after every successful request that may have changed data.
+///
+/// The change is attributed to the authenticated user and to the room in the path, if any.
+/// Handlers whose changes concern other users or rooms notify them on their own.
+#[derive(Clone, Copy, Debug)]
+pub struct NotifySync;
+
+impl AfterMiddleware for NotifySync {
+    fn after(&self, request: &mut Request<'_, '_>, response: Response) -> IronResult<Response> {
+        let is_write = match request.method {
+            Method::Get | Method::Head | Method::Options => false,
+            _ => true,
+        };
+        let is_success = response.status.map(|status| status.is_success()) == Some(true);
+
+        if is_write && is_success {
+            let mut topics = Vec::new();
+
+            if let Some(user) = request.extensions.get::<User>() {
+                topics.push(Topic::User(user.id.clone()));
+            }
+
+            if let Some(room_id) = request.extensions.get::<RoomIdParam>() {
+                topics.push(Topic::Room(room_id.clone()));
+            }
+
+            Notifier::from_request(request)?.notify(topics);
+        }
+
+        Ok(response)
+    }
+}
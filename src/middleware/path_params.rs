@@ -2,15 +2,17 @@
 
 use std::convert::From;
 use std::convert::TryFrom;
+use std::str::FromStr;
 
 use iron::typemap::Key;
 use iron::{BeforeMiddleware, IronResult, Request};
 use router::Router;
 use ruma_events::EventType;
-use ruma_identifiers::{RoomAliasId, RoomId, RoomIdOrAliasId, UserId};
+use ruma_identifiers::{EventId, RoomAliasId, RoomId, RoomIdOrAliasId, UserId};
 
 use crate::config::Config;
 use crate::error::{ApiError, MapApiError};
+use crate::models::push_rule::PushRuleKind;
 use url::percent_encoding::percent_decode;
 
 /// Extracts a `RoomId` from the URL path parameter `room_id`.
@@ -44,6 +46,37 @@ impl BeforeMiddleware for RoomIdParam {
     }
 }
 
+/// Extracts an `EventId` from the URL path parameter `event_id`.
+#[derive(Clone, Copy, Debug)]
+pub struct EventIdParam;
+
+impl Key for EventIdParam {
+    type Value = EventId;
+}
+
+impl BeforeMiddleware for EventIdParam {
+    fn before(&self, request: &mut Request<'_, '_>) -> IronResult<()> {
+        let params = request
+            .extensions
+            .get::<Router>()
+            .expect("Params object is missing")
+            .clone();
+        let event_id = match params.find("event_id") {
+            Some(event_id) => {
+                let decoded_event_id = percent_decode(event_id.as_bytes())
+                    .decode_utf8()
+                    .map_err(|err| ApiError::invalid_param("event_id", err))?;
+
+                EventId::try_from(decoded_event_id.as_ref())
+                    .map_api_err(|err| ApiError::invalid_param("event_id", err))
+            }
+            None => Err(ApiError::missing_param("event_id")),
+        }?;
+        request.extensions.insert::<Self>(event_id);
+        Ok(())
+    }
+}
+
 /// Extracts a `RoomIdOrAlias` from the URL path parameter `room_id_or_alias`.
 #[derive(Clone, Copy, Debug)]
 pub struct RoomIdOrAliasParam;
@@ -251,6 +284,72 @@ impl BeforeMiddleware for TagParam {
     }
 }
 
+/// Extracts the URL path parameter `receipt_type`.
+#[derive(Clone, Copy, Debug)]
+pub struct ReceiptTypeParam;
+
+impl Key for ReceiptTypeParam {
+    type Value = String;
+}
+
+impl BeforeMiddleware for ReceiptTypeParam {
+    fn before(&self, request: &mut Request<'_, '_>) -> IronResult<()> {
+        let params = request
+            .extensions
+            .get::<Router>()
+            .expect("Params object is missing")
+            .clone();
+
+        let receipt_type = params
+            .find("receipt_type")
+            .ok_or_else(|| ApiError::missing_param("receipt_type"))?;
+
+        request.extensions.insert::<Self>(receipt_type.to_string());
+
+        Ok(())
+    }
+}
+
+/// Extracts the ID of local media from the URL path parameters `server_name` and `media_id`.
+///
+/// Media from other servers cannot be fetched, so it is reported as not found.
+#[derive(Clone, Copy, Debug)]
+pub struct MediaIdParam;
+
+impl Key for MediaIdParam {
+    type Value = String;
+}
+
+impl BeforeMiddleware for MediaIdParam {
+    fn before(&self, request: &mut Request<'_, '_>) -> IronResult<()> {
+        let params = request
+            .extensions
+            .get::<Router>()
+            .expect("Params object is missing")
+            .clone();
+
+        let config = Config::from_request(request)?;
+
+        let server_name = params
+            .find("server_name")
+            .ok_or_else(|| ApiError::missing_param("server_name"))?;
+
+        let media_id = params
+            .find("media_id")
+            .ok_or_else(|| ApiError::missing_param("media_id"))?;
+
+        if server_name != config.domain {
+            Err(ApiError::not_found(
+                "Media from other servers is not supported".to_string(),
+            ))?;
+        }
+
+        request.extensions.insert::<Self>(media_id.to_string());
+
+        Ok(())
+    }
+}
+
 /// Extracts the URL path paramater `transaction_id`.
 #[derive(Clone, Copy, Debug)]
 pub struct TransactionIdParam;
@@ -278,3 +377,85 @@ impl BeforeMiddleware for TransactionIdParam {
         Ok(())
     }
 }
+
+/// Checks the URL path parameter `scope` of push rules, which can only be `global`.
+#[derive(Clone, Copy, Debug)]
+pub struct PushRuleScopeParam;
+
+impl BeforeMiddleware for PushRuleScopeParam {
+    fn before(&self, request: &mut Request<'_, '_>) -> IronResult<()> {
+        let params = request
+            .extensions
+            .get::<Router>()
+            .expect("Params object is missing")
+            .clone();
+
+        match params.find("scope") {
+            Some("global") => Ok(()),
+            Some(_) => Err(ApiError::invalid_param(
+                "scope",
+                "Only the global scope is supported",
+            ))?,
+            None => Err(ApiError::missing_param("scope"))?,
+        }
+    }
+}
+
+/// Extracts a `PushRuleKind` from the URL path parameter `kind`.
+#[derive(Clone, Copy, Debug)]
+pub struct PushRuleKindParam;
+
+impl Key for PushRuleKindParam {
+    type Value = PushRuleKind;
+}
+
+impl BeforeMiddleware for PushRuleKindParam {
+    fn before(&self, request: &mut Request<'_, '_>) -> IronResult<()> {
+        let params = request
+            .extensions
+            .get::<Router>()
+            .expect("Params object is missing")
+            .clone();
+
+        let kind = match params.find("kind") {
+            Some(kind) => {
+                PushRuleKind::from_str(kind).map_err(|err| ApiError::invalid_param("kind", err))
+            }
+            None => Err(ApiError::missing_param("kind")),
+        }?;
+
+        request.extensions.insert::<Self>(kind);
+
+        Ok(())
+    }
+}
+
+/// Extracts the URL path parameter `rule_id`.
+#[derive(Clone, Copy, Debug)]
+pub struct RuleIdParam;
+
+impl Key for RuleIdParam {
+    type Value = String;
+}
+
+impl BeforeMiddleware for RuleIdParam {
+    fn before(&self, request: &mut Request<'_, '_>) -> IronResult<()> {
+        let params = request
+            .extensions
+            .get::<Router>()
+            .expect("Params object is missing")
+            .clone();
+
+        let rule_id = match params.find("rule_id") {
+            Some(rule_id) => percent_decode(rule_id.as_bytes())
+                .decode_utf8()
+                .map(|rule_id| rule_id.to_string())
+                .map_err(|err| ApiError::invalid_param("rule_id", err)),
+            None => Err(ApiError::missing_param("rule_id")),
+        }?;
+
+        request.extensions.insert::<Self>(rule_id);
+
+        Ok(())
+    }
+}
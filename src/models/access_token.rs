@@ -1,7 +1,9 @@
 //! User access tokens.
 
-use base64::encode;
-use chrono::{Duration, Utc};
+use std::str::from_utf8;
+
+use base64::{decode, encode};
+use chrono::{DateTime, Duration, TimeZone, Utc};
 use diesel::pg::data_types::PgTimestamp;
 use diesel::pg::PgConnection;
 use diesel::prelude::*;
@@ -10,11 +12,18 @@ use iron::typemap::Key;
 use macaroons::caveat::Caveat;
 use macaroons::token::Token;
 use macaroons::v1::V1Token;
+use macaroons::verifier::{Eq, Func, LinkVerifier};
 use ruma_identifiers::UserId;
 
 use crate::error::ApiError;
 use crate::schema::access_tokens;
 
+/// The start of the caveat that limits how long a macaroon is valid.
+const TIME_CAVEAT_PREFIX: &str = "time < ";
+
+/// The format of the time in a time caveat, as displayed by `DateTime<Utc>`.
+const TIME_CAVEAT_FORMAT: &str = "%Y-%m-%d %H:%M:%S%.f UTC";
+
 /// A User access token.
 #[derive(AsChangeset, Clone, Debug, Identifiable, Queryable)]
 #[table_name = "access_tokens"]
@@ -44,15 +53,26 @@ pub struct NewAccessToken {
 }
 
 impl AccessToken {
-    /// Create a new `AccessToken` for the given user.
+    /// Create a new `AccessToken` for the given user, valid for `lifetime` seconds.
     pub fn create(
         connection: &PgConnection,
         user_id: &UserId,
         macaroon_secret_key: &[u8],
+        lifetime: u32,
     ) -> Result<Self, ApiError> {
+        let lifetime = Duration::seconds(i64::from(lifetime));
+        let expiration = match Utc::now().checked_add_signed(lifetime) {
+            Some(datetime) => datetime,
+            None => {
+                return Err(ApiError::unknown(
+                    "Failed to generate access token expiration datetime.".to_string(),
+                ))
+            }
+        };
+
         let new_access_token = NewAccessToken {
             user_id: user_id.clone(),
-            value: create_macaroon(macaroon_secret_key, user_id)?,
+            value: create_macaroon(macaroon_secret_key, user_id, expiration)?,
         };
 
         diesel::insert_into(access_tokens::table)
@@ -80,6 +100,14 @@ impl AccessToken {
         }
     }
 
+    /// Verify the access token's macaroon with the master cryptographic key.
+    ///
+    /// The macaroon must be signed with the key and satisfy every first-party caveat, so it must
+    /// be an access token of the token's user that has not expired yet.
+    pub fn verify(&self, macaroon_secret_key: &[u8]) -> Result<(), ApiError> {
+        verify_macaroon(macaroon_secret_key, &self.user_id, &self.value, Utc::now())
+    }
+
     /// Revoke the access token so it cannot be used again.
     pub fn revoke(&mut self, connection: &PgConnection) -> Result<(), ApiError> {
         self.revoked = true;
@@ -96,16 +124,11 @@ impl Key for AccessToken {
 }
 
 /// Creates a macaroon for the given user using the master cryptographic key.
-fn create_macaroon(macaroon_secret_key: &[u8], user_id: &UserId) -> Result<String, ApiError> {
-    let expiration = match Utc::now().checked_add_signed(Duration::hours(1)) {
-        Some(datetime) => datetime,
-        None => {
-            return Err(ApiError::unknown(
-                "Failed to generate access token expiration datetime.".to_string(),
-            ))
-        }
-    };
-
+fn create_macaroon(
+    macaroon_secret_key: &[u8],
+    user_id: &UserId,
+    expiration: DateTime<Utc>,
+) -> Result<String, ApiError> {
     let token = V1Token::new(macaroon_secret_key, b"key".to_vec(), None)
         .add_caveat(&Caveat::first_party(
             format!("user_id = {}", user_id.to_string())
@@ -114,10 +137,124 @@ fn create_macaroon(macaroon_secret_key: &[u8], user_id: &UserId) -> Result<Strin
         ))
         .add_caveat(&Caveat::first_party(b"type = access".to_vec()))
         .add_caveat(&Caveat::first_party(
-            format!("time < {}", expiration).as_bytes().to_owned(),
+            format!("{}{}", TIME_CAVEAT_PREFIX, expiration)
+                .as_bytes()
+                .to_owned(),
         ));
 
     let serialized = token.serialize()?;
 
     Ok(encode(&serialized))
 }
+
+/// Verifies a macaroon for the given user at the given time.
+///
+/// Expired macaroons are rejected with `soft_logout` set, since the client only needs to log in
+/// again.
+fn verify_macaroon(
+    macaroon_secret_key: &[u8],
+    user_id: &UserId,
+    value: &str,
+    now: DateTime<Utc>,
+) -> Result<(), ApiError> {
+    let invalid = || ApiError::unknown_token("Invalid access token.".to_string(), false);
+
+    let serialized = decode(value).map_err(|_| invalid())?;
+    let token = V1Token::deserialize(serialized).map_err(|_| invalid())?;
+
+    // The expiration is checked separately, so that expired tokens can be told apart.
+    let verifier = Eq("user_id", user_id.to_string())
+        .link(Eq("type", "access"))
+        .link(Func(|caveat: &str| expiration(caveat).is_some()));
+    token
+        .verify(macaroon_secret_key, verifier)
+        .map_err(|_| invalid())?;
+
+    let expired = token
+        .caveats
+        .iter()
+        .filter_map(|caveat| from_utf8(&caveat.caveat_id).ok())
+        .filter_map(expiration)
+        .any(|expiration| expiration <= now);
+
+    if expired {
+        return Err(ApiError::unknown_token(
+            "The access token has expired.".to_string(),
+            true,
+        ));
+    }
+
+    Ok(())
+}
+
+/// Returns the expiration time of a time caveat, or `None` for other caveats.
+fn expiration(caveat: &str) -> Option<DateTime<Utc>> {
+    if !caveat.starts_with(TIME_CAVEAT_PREFIX) {
+        return None;
+    }
+
+    let time = &caveat[TIME_CAVEAT_PREFIX.len()..];
+
+    Utc.datetime_from_str(time, TIME_CAVEAT_FORMAT).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::TryFrom;
+
+    use chrono::{Duration, Utc};
+    use ruma_identifiers::UserId;
+    use serde_json::{json, to_value};
+
+    use super::{create_macaroon, verify_macaroon};
+
+    /// The master cryptographic key used to sign the macaroons.
+    const MACAROON_SECRET_KEY: &[u8] = b"YymznQHmKdN9B4f7iBalJB1tWEDy9LdaFSQJEtB3R5w=";
+
+    #[test]
+    fn verify_valid_macaroon() {
+        let user_id = UserId::try_from("@alice:ruma.test").unwrap();
+        let now = Utc::now();
+        let macaroon =
+            create_macaroon(MACAROON_SECRET_KEY, &user_id, now + Duration::hours(1)).unwrap();
+
+        assert!(verify_macaroon(MACAROON_SECRET_KEY, &user_id, &macaroon, now).is_ok());
+    }
+
+    #[test]
+    fn reject_expired_macaroon_with_soft_logout() {
+        let user_id = UserId::try_from("@alice:ruma.test").unwrap();
+        let now = Utc::now();
+        let macaroon =
+            create_macaroon(MACAROON_SECRET_KEY, &user_id, now - Duration::hours(1)).unwrap();
+
+        let error = verify_macaroon(MACAROON_SECRET_KEY, &user_id, &macaroon, now).unwrap_err();
+        assert_eq!(
+            to_value(error).unwrap(),
+            json!({
+                "errcode": "M_UNKNOWN_TOKEN",
+                "error": "The access token has expired.",
+                "soft_logout": true,
+            })
+        );
+    }
+
+    #[test]
+    fn reject_macaroon_of_another_user_or_key() {
+        let user_id = UserId::try_from("@alice:ruma.test").unwrap();
+        let other_user_id = UserId::try_from("@bob:ruma.test").unwrap();
+        let now = Utc::now();
+        let macaroon =
+            create_macaroon(MACAROON_SECRET_KEY, &user_id, now + Duration::hours(1)).unwrap();
+
+        for error in &[
+            verify_macaroon(MACAROON_SECRET_KEY, &other_user_id, &macaroon, now).unwrap_err(),
+            verify_macaroon(b"another key", &user_id, &macaroon, now).unwrap_err(),
+            verify_macaroon(MACAROON_SECRET_KEY, &user_id, "not a macaroon", now).unwrap_err(),
+        ] {
+            let error = to_value(error).unwrap();
+            assert_eq!(error.get("errcode").unwrap(), "M_UNKNOWN_TOKEN");
+            assert_eq!(error.get("soft_logout").unwrap(), false);
+        }
+    }
+}
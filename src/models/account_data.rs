@@ -1,16 +1,24 @@
 //! Account information stored for a user.
 
+use diesel::dsl::sql;
+use diesel::expression::SqlLiteral;
 use diesel::pg::PgConnection;
 use diesel::prelude::*;
 use diesel::result::Error as DieselError;
+use diesel::sql_types::BigInt;
 use iron::typemap::Key;
 use ruma_identifiers::{RoomId, UserId};
 
 use crate::error::ApiError;
 use crate::schema::{account_data, room_account_data};
 
+/// The next position in the account data stream, which global data, room data and room tags share.
+pub fn next_ordering() -> SqlLiteral<BigInt> {
+    sql("nextval('account_data_ordering_seq')")
+}
+
 /// Holds personal information/configuration for a user.
-#[derive(AsChangeset, Debug, Clone, Identifiable, Queryable)]
+#[derive(Debug, Clone, Identifiable, Queryable)]
 #[table_name = "account_data"]
 pub struct AccountData {
     /// Entry ID
@@ -21,6 +29,8 @@ pub struct AccountData {
     pub data_type: String,
     /// The contents.
     pub content: String,
+    /// The position of the latest change in the account data stream.
+    pub ordering: i64,
 }
 
 /// New account data, not yet saved.
@@ -60,10 +70,13 @@ impl AccountData {
     }
 
     /// Update an `AccountData` entry with new content.
-    pub fn update(&mut self, connection: &PgConnection, content: String) -> Result<Self, ApiError> {
-        self.content = content;
-
-        self.save_changes::<Self>(connection)
+    pub fn update(&self, connection: &PgConnection, content: String) -> Result<Self, ApiError> {
+        diesel::update(self)
+            .set((
+                account_data::content.eq(content),
+                account_data::ordering.eq(next_ordering()),
+            ))
+            .get_result(connection)
             .map_err(ApiError::from)
     }
 
@@ -84,10 +97,24 @@ impl AccountData {
             .map_err(ApiError::from)
     }
 
+    /// Get the account data of a user changed after the given position in the stream.
+    pub fn find_changed_since(
+        connection: &PgConnection,
+        uid: &UserId,
+        since: i64,
+    ) -> Result<Vec<Self>, ApiError> {
+        account_data::table
+            .filter(account_data::user_id.eq(uid))
+            .filter(account_data::ordering.gt(since))
+            .order(account_data::ordering.asc())
+            .load::<Self>(connection)
+            .map_err(ApiError::from)
+    }
+
     /// Update an existing entry or create a new one.
     pub fn upsert(connection: &PgConnection, new_data: &NewAccountData) -> Result<Self, ApiError> {
         match Self::find_by_uid_and_type(connection, &new_data.user_id, &new_data.data_type) {
-            Ok(mut saved) => saved.update(connection, new_data.content.clone()),
+            Ok(saved) => saved.update(connection, new_data.content.clone()),
             Err(err) => match err {
                 DieselError::NotFound => Self::create(connection, new_data),
                 _ => Err(ApiError::from(err)),
@@ -101,7 +128,7 @@ impl Key for AccountData {
 }
 
 /// Holds user's information/configuration per room.
-#[derive(AsChangeset, Clone, Debug, Identifiable, Queryable)]
+#[derive(Clone, Debug, Identifiable, Queryable)]
 #[table_name = "room_account_data"]
 pub struct RoomAccountData {
     /// Entry ID
@@ -114,6 +141,8 @@ pub struct RoomAccountData {
     pub data_type: String,
     /// The contents.
     pub content: String,
+    /// The position of the latest change in the account data stream.
+    pub ordering: i64,
 }
 
 /// New room account data, not yet saved.
@@ -157,10 +186,13 @@ impl RoomAccountData {
     }
 
     /// Update an `RoomAccountData` entry with new content.
-    pub fn update(&mut self, connection: &PgConnection, content: String) -> Result<Self, ApiError> {
-        self.content = content;
-
-        self.save_changes::<Self>(connection)
+    pub fn update(&self, connection: &PgConnection, content: String) -> Result<Self, ApiError> {
+        diesel::update(self)
+            .set((
+                room_account_data::content.eq(content),
+                room_account_data::ordering.eq(next_ordering()),
+            ))
+            .get_result(connection)
             .map_err(ApiError::from)
     }
 
@@ -173,6 +205,22 @@ impl RoomAccountData {
             .map_err(ApiError::from)
     }
 
+    /// Get the account data of a user for a room changed after the given position in the stream.
+    pub fn find_changed_since(
+        connection: &PgConnection,
+        uid: &UserId,
+        rid: &RoomId,
+        since: i64,
+    ) -> Result<Vec<Self>, ApiError> {
+        room_account_data::table
+            .filter(room_account_data::user_id.eq(uid))
+            .filter(room_account_data::room_id.eq(rid))
+            .filter(room_account_data::ordering.gt(since))
+            .order(room_account_data::ordering.asc())
+            .load::<Self>(connection)
+            .map_err(ApiError::from)
+    }
+
     /// Update an existing entry or create a new one.
     pub fn upsert(
         connection: &PgConnection,
@@ -184,7 +232,7 @@ impl RoomAccountData {
             &new_data.room_id,
             &new_data.data_type,
         ) {
-            Ok(mut saved) => saved.update(connection, new_data.content.clone()),
+            Ok(saved) => saved.update(connection, new_data.content.clone()),
             Err(err) => match err {
                 DieselError::NotFound => Self::create(connection, new_data),
                 _ => Err(ApiError::from(err)),
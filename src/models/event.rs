@@ -1,17 +1,24 @@
 //! Matrix events.
 
+use std::cmp;
+use std::collections::HashMap;
 use std::convert::{TryFrom, TryInto};
+use std::slice;
+use std::str::FromStr;
 
-use diesel::dsl::{any, max};
+use chrono::Utc;
+use diesel::dsl::{any, max, not, sql};
+use diesel::expression::{AsExpression, BoxableExpression, SqlLiteral, UncheckedBind};
 use diesel::pg::data_types::PgTimestamp;
-use diesel::pg::PgConnection;
+use diesel::pg::{Pg, PgConnection};
 use diesel::prelude::*;
 use diesel::result::Error as DieselError;
+use diesel::sql_types::{Bool, Float, Text};
 use ruma_events::call::answer::AnswerEvent;
 use ruma_events::call::candidates::CandidatesEvent;
 use ruma_events::call::hangup::HangupEvent;
 use ruma_events::call::invite::InviteEvent;
-use ruma_events::collections::all::StateEvent;
+use ruma_events::collections::all::{RoomEvent, StateEvent};
 use ruma_events::room::aliases::AliasesEvent;
 use ruma_events::room::avatar::AvatarEvent;
 use ruma_events::room::canonical_alias::CanonicalAliasEvent;
@@ -23,6 +30,7 @@ use ruma_events::room::member::MemberEvent;
 use ruma_events::room::message::MessageEvent;
 use ruma_events::room::name::NameEvent;
 use ruma_events::room::power_levels::PowerLevelsEvent;
+use ruma_events::room::redaction::RedactionEvent;
 use ruma_events::room::third_party_invite::ThirdPartyInviteEvent;
 use ruma_events::room::topic::TopicEvent;
 use ruma_events::stripped::{
@@ -32,15 +40,20 @@ use ruma_events::stripped::{
     StrippedRoomTopic, StrippedState,
 };
 use ruma_events::{
-    CustomRoomEvent, CustomStateEvent, Event as RumaEventsEvent, EventType, RoomEvent,
-    StateEvent as RumaStateEventTrait,
+    CustomRoomEvent, CustomStateEvent, Event as RumaEventsEvent, EventType,
+    RoomEvent as RumaRoomEventTrait, StateEvent as RumaStateEventTrait,
 };
 use ruma_identifiers::{EventId, RoomId, UserId};
-use serde_json::{from_str, to_string};
+use serde::de::DeserializeOwned;
+use serde_json::{from_str, to_string, Map, Value};
 
 use crate::error::ApiError;
+use crate::models::filter::RoomEventFilter;
 use crate::schema::events;
 
+/// The number of milliseconds between the Unix epoch and PostgreSQL's epoch, 2000-01-01.
+const POSTGRES_EPOCH_MILLIS: i64 = 946_684_800_000;
+
 /// A list of all the state events.
 const STATE_EVENTS: [EventType; 12] = [
     EventType::RoomAliases,
@@ -57,6 +70,28 @@ const STATE_EVENTS: [EventType; 12] = [
     EventType::RoomTopic,
 ];
 
+/// The content keys of each event type that are kept when an event is redacted.
+const REDACTION_ALLOWED_KEYS: [(EventType, &[&str]); 6] = [
+    (EventType::RoomAliases, &["aliases"]),
+    (EventType::RoomCreate, &["creator"]),
+    (EventType::RoomHistoryVisibility, &["history_visibility"]),
+    (EventType::RoomJoinRules, &["join_rule"]),
+    (EventType::RoomMember, &["membership"]),
+    (
+        EventType::RoomPowerLevels,
+        &[
+            "ban",
+            "events",
+            "events_default",
+            "kick",
+            "redact",
+            "state_default",
+            "users",
+            "users_default",
+        ],
+    ),
+];
+
 /// A new event, not yet saved.
 #[derive(Debug, Clone, Insertable)]
 #[table_name = "events"]
@@ -73,6 +108,14 @@ pub struct NewEvent {
     pub sender: UserId,
     /// An event subtype that determines whether or not the event will overwrite a previous one.
     pub state_key: Option<String>,
+    /// The event redacted by this event, if it is a redaction.
+    pub redacts: Option<EventId>,
+    /// The client-generated ID of the transaction the event was sent in, if any.
+    pub transaction_id: Option<String>,
+    /// The state event this event replaces, if it is a state event.
+    pub replaces_state: Option<EventId>,
+    /// JSON of the content of the state event this event replaces.
+    pub prev_content: Option<String>,
 }
 
 /// A Matrix event.
@@ -94,6 +137,183 @@ pub struct Event {
     pub content: String,
     /// The time the event was created.
     pub created_at: PgTimestamp,
+    /// The event redacted by this event, if it is a redaction.
+    pub redacts: Option<EventId>,
+    /// The redaction that stripped the content of this event, if any.
+    pub redacted_by: Option<EventId>,
+    /// The client-generated ID of the transaction the event was sent in, if any.
+    pub transaction_id: Option<String>,
+    /// The state event this event replaces, if it is a state event.
+    pub replaces_state: Option<EventId>,
+    /// JSON of the content of the state event this event replaces.
+    pub prev_content: Option<String>,
+}
+
+/// The direction in which to paginate through a room's events.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Direction {
+    /// Towards older events.
+    Backward,
+    /// Towards newer events.
+    Forward,
+}
+
+impl FromStr for Direction {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        match s {
+            "b" => Ok(Direction::Backward),
+            "f" => Ok(Direction::Forward),
+            _ => Err(String::from("Must be one of 'b' or 'f'")),
+        }
+    }
+}
+
+/// A key of an event's content that can be searched for a term.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+pub enum SearchKey {
+    /// The body of an `m.room.message` event.
+    #[serde(rename = "content.body")]
+    Body,
+    /// The name of an `m.room.name` event.
+    #[serde(rename = "content.name")]
+    Name,
+    /// The topic of an `m.room.topic` event.
+    #[serde(rename = "content.topic")]
+    Topic,
+}
+
+impl SearchKey {
+    /// All searchable keys, which are searched when the client doesn't restrict them.
+    pub const ALL: [SearchKey; 3] = [SearchKey::Body, SearchKey::Name, SearchKey::Topic];
+
+    /// The type of the events the key is searched in.
+    fn event_type(self) -> &'static str {
+        match self {
+            SearchKey::Body => "m.room.message",
+            SearchKey::Name => "m.room.name",
+            SearchKey::Topic => "m.room.topic",
+        }
+    }
+
+    /// The name of the content field holding the searched text.
+    fn field(self) -> &'static str {
+        match self {
+            SearchKey::Body => "body",
+            SearchKey::Name => "name",
+            SearchKey::Topic => "topic",
+        }
+    }
+}
+
+/// The order in which search results are returned.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum SearchOrder {
+    /// The best matches first.
+    Rank,
+    /// The most recent events first.
+    Recent,
+}
+
+/// A full-text search over the events of a set of rooms.
+#[derive(Clone, Copy, Debug)]
+pub struct EventSearch<'a> {
+    /// The words to look for.
+    pub search_term: &'a str,
+    /// The content keys to look in.
+    pub keys: &'a [SearchKey],
+    /// The rooms to search, each up to an optional position after which its events are hidden.
+    pub rooms: &'a [(RoomId, Option<i64>)],
+    /// An optional filter restricting the events to search.
+    pub filter: Option<&'a RoomEventFilter>,
+    /// The order of the results.
+    pub order_by: SearchOrder,
+}
+
+/// The SQL expression ranking how well an event matches a search term.
+type SearchRank =
+    SqlLiteral<Float, UncheckedBind<SqlLiteral<Float>, <String as AsExpression<Text>>::Expression>>;
+
+/// A boolean SQL expression on the `events` table.
+type EventCondition = Box<dyn BoxableExpression<events::table, Pg, SqlType = Bool>>;
+
+impl<'a> EventSearch<'a> {
+    /// Build the query for all matching events, in no particular order.
+    fn query(&self) -> events::BoxedQuery<'static, Pg> {
+        let mut query = events::table.into_boxed();
+
+        let mut visible: Option<EventCondition> = None;
+        for (room_id, until) in self.rooms {
+            let condition: EventCondition = match until {
+                Some(until) => Box::new(
+                    events::room_id
+                        .eq(room_id.clone())
+                        .and(events::ordering.le(*until)),
+                ),
+                None => Box::new(events::room_id.eq(room_id.clone())),
+            };
+
+            visible = Some(match visible {
+                Some(visible) => Box::new(visible.or(condition)),
+                None => condition,
+            });
+        }
+
+        let mut matches: Option<EventCondition> = None;
+        for key in self.keys {
+            // The expression must stay identical to the one of the key's index.
+            let condition: EventCondition = Box::new(
+                sql::<Bool>(&format!(
+                    "(event_type = '{}' AND to_tsvector('english', content::jsonb ->> '{}') \
+                     @@ plainto_tsquery('english', ",
+                    key.event_type(),
+                    key.field(),
+                ))
+                .bind::<Text, _>(self.search_term.to_string())
+                .sql("))"),
+            );
+
+            matches = Some(match matches {
+                Some(matches) => Box::new(matches.or(condition)),
+                None => condition,
+            });
+        }
+
+        // Without any room or key, nothing can match.
+        match (visible, matches) {
+            (Some(visible), Some(matches)) => {
+                query = query.filter(visible).filter(matches);
+            }
+            _ => query = query.filter(sql::<Bool>("FALSE")),
+        }
+
+        if let Some(filter) = self.filter {
+            if !filter.rooms.is_empty() {
+                query = query.filter(events::room_id.eq(any(filter.rooms.clone())));
+            }
+
+            if !filter.not_rooms.is_empty() {
+                query = query.filter(not(events::room_id.eq(any(filter.not_rooms.clone()))));
+            }
+
+            query = Event::filter_events(query, filter);
+        }
+
+        query
+    }
+
+    /// Build the expression ranking how well an event matches the search term.
+    fn rank(&self) -> SearchRank {
+        sql::<Float>(
+            "ts_rank(to_tsvector('english', coalesce(content::jsonb ->> 'body', \
+             content::jsonb ->> 'name', content::jsonb ->> 'topic', '')), \
+             plainto_tsquery('english', ",
+        )
+        .bind::<Text, _>(self.search_term.to_string())
+        .sql("))")
+    }
 }
 
 impl Event {
@@ -132,6 +352,32 @@ impl Event {
             })
     }
 
+    /// Return up to `limit` events of any room created after a specific point in time, oldest
+    /// first, leaving out the events with one of the `excluded` orderings.
+    pub fn find_events_since(
+        connection: &PgConnection,
+        since: i64,
+        excluded: &[i64],
+        limit: i64,
+    ) -> Result<Vec<Self>, ApiError> {
+        events::table
+            .filter(events::ordering.gt(since))
+            .filter(not(events::ordering.eq(any(excluded))))
+            .order(events::ordering.asc())
+            .limit(limit)
+            .get_results(connection)
+            .map_err(ApiError::from)
+    }
+
+    /// Return the ordering of the latest event, or 0 if there are no events yet.
+    pub fn latest_ordering(connection: &PgConnection) -> Result<i64, ApiError> {
+        let ordering: Option<i64> = events::table
+            .select(max(events::ordering))
+            .get_result(connection)?;
+
+        Ok(ordering.unwrap_or(0))
+    }
+
     /// Return all `RoomEvent`'s for a `RoomId` up to a specific point in time.
     pub fn find_room_events_until(
         connection: &PgConnection,
@@ -150,6 +396,249 @@ impl Event {
             })
     }
 
+    /// Return a page of events for a `RoomId`, starting at the position `from`.
+    ///
+    /// A position is an `ordering` value: paginating backward returns the events at or before it,
+    /// starting with the most recent one, and paginating forward returns the events after it,
+    /// starting with the oldest one. If `to` is given, pagination stops at that position.
+    pub fn paginate_room_events(
+        connection: &PgConnection,
+        room_id: &RoomId,
+        from: i64,
+        to: Option<i64>,
+        direction: Direction,
+        limit: i64,
+        filter: Option<&RoomEventFilter>,
+    ) -> Result<Vec<Self>, ApiError> {
+        let mut query = events::table
+            .filter(events::room_id.eq(room_id))
+            .limit(limit)
+            .into_boxed();
+
+        query = match direction {
+            Direction::Backward => {
+                let query = query
+                    .filter(events::ordering.le(from))
+                    .order(events::ordering.desc());
+
+                match to {
+                    Some(to) => query.filter(events::ordering.gt(to)),
+                    None => query,
+                }
+            }
+            Direction::Forward => {
+                let query = query
+                    .filter(events::ordering.gt(from))
+                    .order(events::ordering.asc());
+
+                match to {
+                    Some(to) => query.filter(events::ordering.le(to)),
+                    None => query,
+                }
+            }
+        };
+
+        if let Some(filter) = filter {
+            query = Self::filter_events(query, filter);
+        }
+
+        query.get_results(connection).map_err(ApiError::from)
+    }
+
+    /// Restrict a query to the event types and senders allowed by a `RoomEventFilter`.
+    fn filter_events<'a>(
+        mut query: events::BoxedQuery<'a, Pg>,
+        filter: &RoomEventFilter,
+    ) -> events::BoxedQuery<'a, Pg> {
+        if !filter.types.is_empty() {
+            query = query.filter(events::event_type.eq(any(filter.types.clone())));
+        }
+
+        if !filter.not_types.is_empty() {
+            query = query.filter(not(events::event_type.eq(any(filter.not_types.clone()))));
+        }
+
+        if !filter.senders.is_empty() {
+            query = query.filter(events::sender.eq(any(filter.senders.clone())));
+        }
+
+        if !filter.not_senders.is_empty() {
+            query = query.filter(not(events::sender.eq(any(filter.not_senders.clone()))));
+        }
+
+        query
+    }
+
+    /// Run a full-text search, returning the total number of matching events and the page of
+    /// `limit` matches after skipping `offset` of them, each with its rank.
+    pub fn search(
+        connection: &PgConnection,
+        search: &EventSearch<'_>,
+        offset: i64,
+        limit: i64,
+    ) -> Result<(i64, Vec<(Self, f32)>), ApiError> {
+        let count = search.query().count().get_result(connection)?;
+
+        let query = search
+            .query()
+            .select((events::all_columns, search.rank()))
+            .offset(offset)
+            .limit(limit);
+
+        let results = match search.order_by {
+            SearchOrder::Rank => query
+                .order((search.rank().desc(), events::ordering.desc()))
+                .load(connection)?,
+            SearchOrder::Recent => query.order(events::ordering.desc()).load(connection)?,
+        };
+
+        Ok((count, results))
+    }
+
+    /// Return up to `before_limit` events preceding an event in its room, most recent first, and
+    /// up to `after_limit` events following it, oldest first. Events after the position `until`
+    /// are left out.
+    pub fn find_context(
+        connection: &PgConnection,
+        event: &Self,
+        before_limit: i64,
+        after_limit: i64,
+        until: Option<i64>,
+    ) -> Result<(Vec<Self>, Vec<Self>), ApiError> {
+        let room_id = match event.room_id {
+            Some(ref room_id) => room_id,
+            None => return Ok((Vec::new(), Vec::new())),
+        };
+
+        let events_before = Self::paginate_room_events(
+            connection,
+            room_id,
+            event.ordering - 1,
+            None,
+            Direction::Backward,
+            before_limit,
+            None,
+        )?;
+
+        let events_after = Self::paginate_room_events(
+            connection,
+            room_id,
+            event.ordering,
+            until,
+            Direction::Forward,
+            after_limit,
+            None,
+        )?;
+
+        Ok((events_before, events_after))
+    }
+
+    /// Save a new event, recording the state event it replaces if it is a state event.
+    pub fn create(connection: &PgConnection, new_event: &NewEvent) -> Result<(), ApiError> {
+        Self::create_many(connection, slice::from_ref(new_event))
+    }
+
+    /// Save new events in order, recording for each state event the state event it replaces,
+    /// which may be one of the earlier new events.
+    pub fn create_many(connection: &PgConnection, new_events: &[NewEvent]) -> Result<(), ApiError> {
+        let mut current_state: HashMap<(RoomId, String, String), (EventId, String)> =
+            HashMap::new();
+        let mut events = Vec::with_capacity(new_events.len());
+
+        for new_event in new_events {
+            let mut new_event = new_event.clone();
+
+            if let (Some(room_id), Some(state_key)) = (&new_event.room_id, &new_event.state_key) {
+                let key = (
+                    room_id.clone(),
+                    new_event.event_type.clone(),
+                    state_key.clone(),
+                );
+
+                let replaced = match current_state.get(&key) {
+                    Some(replaced) => Some(replaced.clone()),
+                    None => events::table
+                        .filter(events::room_id.eq(room_id))
+                        .filter(events::event_type.eq(&new_event.event_type))
+                        .filter(events::state_key.eq(state_key))
+                        .order(events::ordering.desc())
+                        .select((events::id, events::content))
+                        .first(connection)
+                        .optional()?,
+                };
+
+                current_state.insert(key, (new_event.id.clone(), new_event.content.clone()));
+
+                if let Some((event_id, content)) = replaced {
+                    new_event.replaces_state = Some(event_id);
+                    new_event.prev_content = Some(content);
+                }
+            }
+
+            events.push(new_event);
+        }
+
+        diesel::insert_into(events::table)
+            .values(&events)
+            .execute(connection)?;
+
+        Ok(())
+    }
+
+    /// Return the time the event was created, in milliseconds since the Unix epoch.
+    pub fn origin_server_ts(&self) -> u64 {
+        (self.created_at.0 / 1000 + POSTGRES_EPOCH_MILLIS) as u64
+    }
+
+    /// Return the data the server adds to the event when serving it: the milliseconds elapsed
+    /// since its creation, the ID of the transaction it was sent in and the state event it
+    /// replaces along with its content, if any.
+    fn unsigned(&self) -> Value {
+        let age = Utc::now().timestamp_millis() - self.origin_server_ts() as i64;
+
+        let mut unsigned = Map::new();
+        unsigned.insert("age".to_string(), Value::from(cmp::max(age, 0)));
+
+        if let Some(ref transaction_id) = self.transaction_id {
+            unsigned.insert(
+                "transaction_id".to_string(),
+                Value::String(transaction_id.clone()),
+            );
+        }
+
+        if let Some(ref replaces_state) = self.replaces_state {
+            unsigned.insert(
+                "replaces_state".to_string(),
+                Value::String(replaces_state.to_string()),
+            );
+        }
+
+        if let Some(prev_content) = self.prev_content::<Value>() {
+            unsigned.insert("prev_content".to_string(), prev_content);
+        }
+
+        Value::Object(unsigned)
+    }
+
+    /// Return the content of the state event this event replaces, if any.
+    ///
+    /// The content is left out if it no longer matches the schema of the event type, which
+    /// happens when the replaced event was redacted.
+    fn prev_content<C: DeserializeOwned>(&self) -> Option<C> {
+        self.prev_content
+            .as_ref()
+            .and_then(|prev_content| from_str(prev_content).ok())
+    }
+
+    /// Hide the transaction ID of the event unless the given user sent it.
+    pub fn for_user(mut self, user_id: &UserId) -> Self {
+        if self.sender != *user_id {
+            self.transaction_id = None;
+        }
+
+        self
+    }
+
     /// Look up an event given its `EventId`.
     pub fn find(connection: &PgConnection, event_id: &EventId) -> Result<Option<Self>, ApiError> {
         match events::table.find(event_id).first(connection) {
@@ -159,25 +648,105 @@ impl Event {
         }
     }
 
-    /// Return the room's state before a specified event.
+    /// Strip the content of the event down to the keys the redaction algorithm allows to keep
+    /// and mark it as redacted by the given redaction event.
+    pub fn redact(
+        &self,
+        connection: &PgConnection,
+        redaction_id: &EventId,
+    ) -> Result<Self, ApiError> {
+        let event_type = EventType::from(self.event_type.as_ref());
+        let allowed_keys: &[&str] = REDACTION_ALLOWED_KEYS
+            .iter()
+            .find(|(allowed_event_type, _)| *allowed_event_type == event_type)
+            .map_or(&[], |(_, keys)| keys);
+
+        let content = match from_str(&self.content).map_err(ApiError::from)? {
+            Value::Object(content) => content
+                .into_iter()
+                .filter(|(key, _)| allowed_keys.contains(&key.as_str()))
+                .collect(),
+            _ => Map::new(),
+        };
+
+        let content = to_string(&content).map_err(ApiError::from)?;
+
+        // The events replacing this one carry a copy of its content.
+        diesel::update(events::table.filter(events::replaces_state.eq(&self.id)))
+            .set(events::prev_content.eq(&content))
+            .execute(connection)?;
+
+        diesel::update(events::table.find(&self.id))
+            .set((
+                events::content.eq(content),
+                events::redacted_by.eq(redaction_id),
+            ))
+            .get_result(connection)
+            .map_err(ApiError::from)
+    }
+
+    /// Convert the event into a custom `RoomEvent`, regardless of its type.
+    fn try_into_custom_room_event(self) -> Result<RoomEvent, ApiError> {
+        if self.state_key.is_some() {
+            Ok(RoomEvent::CustomState(self.try_into()?))
+        } else {
+            Ok(RoomEvent::CustomRoom(self.try_into()?))
+        }
+    }
+
+    /// Return the room's state before the event with the given ordering.
     pub fn get_room_state_events_until(
         connection: &PgConnection,
         room_id: &RoomId,
-        until: &Self,
+        until: i64,
+    ) -> Result<Vec<Self>, ApiError> {
+        Self::get_room_state_at(connection, room_id, until - 1)
+    }
+
+    /// Return the room's state at a position, including the changes made at that position.
+    pub fn get_room_state_at(
+        connection: &PgConnection,
+        room_id: &RoomId,
+        ordering: i64,
     ) -> Result<Vec<Self>, ApiError> {
         let state_events: Vec<String> = STATE_EVENTS.iter().map(EventType::to_string).collect();
 
-        let ordering: Vec<Option<i64>> = events::table
+        let orderings: Vec<Option<i64>> = events::table
             .select(max(events::ordering))
             .filter(events::room_id.eq(room_id))
             .filter(events::event_type.eq(any(state_events)))
-            .filter(events::ordering.lt(until.ordering))
-            .group_by(events::event_type)
+            .filter(events::ordering.le(ordering))
+            .group_by((events::event_type, events::state_key))
             .get_results(connection)
             .map_err(ApiError::from)?;
 
         events::table
-            .filter(events::ordering.nullable().eq(any(ordering)))
+            .filter(events::ordering.nullable().eq(any(orderings)))
+            .get_results(connection)
+            .map_err(ApiError::from)
+    }
+
+    /// Return the member events of the given users in the room's state at a position.
+    pub fn get_room_members_at(
+        connection: &PgConnection,
+        room_id: &RoomId,
+        user_ids: &[UserId],
+        ordering: i64,
+    ) -> Result<Vec<Self>, ApiError> {
+        let user_ids: Vec<String> = user_ids.iter().map(UserId::to_string).collect();
+
+        let orderings: Vec<Option<i64>> = events::table
+            .select(max(events::ordering))
+            .filter(events::room_id.eq(room_id))
+            .filter(events::event_type.eq(EventType::RoomMember.to_string()))
+            .filter(events::state_key.eq(any(user_ids)))
+            .filter(events::ordering.le(ordering))
+            .group_by(events::state_key)
+            .get_results(connection)
+            .map_err(ApiError::from)?;
+
+        events::table
+            .filter(events::ordering.nullable().eq(any(orderings)))
             .get_results(connection)
             .map_err(ApiError::from)
     }
@@ -203,7 +772,29 @@ impl Event {
             .filter(events::room_id.eq(room_id))
             .filter(events::event_type.eq(any(state_events)))
             .filter(events::ordering.gt(since))
-            .group_by(events::event_type)
+            .group_by((events::event_type, events::state_key))
+            .get_results(connection)
+            .map_err(ApiError::from)?;
+
+        events::table
+            .filter(events::ordering.nullable().eq(any(ordering)))
+            .get_results(connection)
+            .map_err(ApiError::from)
+    }
+
+    /// Return the current state events of the given types in several rooms at once.
+    pub fn get_rooms_current_state_events(
+        connection: &PgConnection,
+        room_ids: &[RoomId],
+        event_types: &[EventType],
+    ) -> Result<Vec<Self>, ApiError> {
+        let event_types: Vec<String> = event_types.iter().map(EventType::to_string).collect();
+
+        let ordering: Vec<Option<i64>> = events::table
+            .select(max(events::ordering))
+            .filter(events::room_id.eq(any(room_ids)))
+            .filter(events::event_type.eq(any(event_types)))
+            .group_by((events::room_id, events::event_type, events::state_key))
             .get_results(connection)
             .map_err(ApiError::from)?;
 
@@ -227,6 +818,10 @@ macro_rules! impl_try_from_room_event_for_new_event {
                     room_id: event.room_id().map(|room_id| room_id.clone()),
                     sender: event.sender().clone(),
                     state_key: None,
+                    redacts: None,
+                    transaction_id: None,
+                    replaces_state: None,
+                    prev_content: None,
                 })
             }
         }
@@ -246,6 +841,10 @@ macro_rules! impl_try_from_state_event_for_new_event {
                     room_id: event.room_id().map(|room_id| room_id.clone()),
                     sender: event.sender().clone(),
                     state_key: Some(event.state_key().to_string()),
+                    redacts: None,
+                    transaction_id: None,
+                    replaces_state: None,
+                    prev_content: None,
                 })
             }
         }
@@ -258,19 +857,17 @@ macro_rules! impl_try_into_room_event_for_event {
             type Error = ApiError;
 
             fn try_into(self) -> Result<$ty, Self::Error> {
+                let origin_server_ts = self.origin_server_ts();
+                let unsigned = Some(self.unsigned());
+
                 Ok($ty {
                     content: from_str(&self.content).map_err(ApiError::from)?,
                     event_id: self.id,
                     event_type: EventType::from(self.event_type.as_ref()),
-                    // FIXME: This is a dummy value just to satisfy event types' new schema.
-                    // The real value should come from the database record's created_at timestamp,
-                    // but it's unclear exactly how.
-                    //
-                    // See https://github.com/matrix-org/matrix-doc/issues/2064
-                    origin_server_ts: 0,
+                    origin_server_ts,
                     room_id: self.room_id,
                     sender: self.sender,
-                    unsigned: None,
+                    unsigned,
                 })
             }
         }
@@ -283,21 +880,20 @@ macro_rules! impl_try_into_state_event_for_event {
             type Error = ApiError;
 
             fn try_into(self) -> Result<$ty, Self::Error> {
+                let origin_server_ts = self.origin_server_ts();
+                let unsigned = Some(self.unsigned());
+                let prev_content = self.prev_content();
+
                 Ok($ty {
                     content: from_str(&self.content).map_err(ApiError::from)?,
                     event_id: self.id,
                     event_type: EventType::from(self.event_type.as_ref()),
-                    // FIXME: This is a dummy value just to satisfy event types' new schema.
-                    // The real value should come from the database record's created_at timestamp,
-                    // but it's unclear exactly how.
-                    //
-                    // See https://github.com/matrix-org/matrix-doc/issues/2064
-                    origin_server_ts: 0,
-                    prev_content: None,
+                    origin_server_ts,
+                    prev_content,
                     room_id: self.room_id,
                     sender: self.sender,
-                    state_key: "".to_string(),
-                    unsigned: None,
+                    state_key: self.state_key.unwrap_or_default(),
+                    unsigned,
                 })
             }
         }
@@ -312,7 +908,7 @@ macro_rules! impl_try_into_stripped_state_event_for_event {
             fn try_into(self) -> Result<$ty, Self::Error> {
                 Ok($ty {
                     content: from_str(&self.content).map_err(ApiError::from)?,
-                    state_key: "".to_string(),
+                    state_key: self.state_key.unwrap_or_default(),
                     event_type: EventType::from(self.event_type.as_ref()),
                     sender: self.sender,
                 })
@@ -375,10 +971,61 @@ impl_try_from_state_event_for_new_event!(ThirdPartyInviteEvent);
 impl_try_from_state_event_for_new_event!(TopicEvent);
 impl_try_from_state_event_for_new_event!(CustomStateEvent);
 
+impl TryFrom<RedactionEvent> for NewEvent {
+    type Error = ApiError;
+
+    fn try_from(event: RedactionEvent) -> Result<Self, Self::Error> {
+        Ok(Self {
+            content: to_string(&event.content).map_err(ApiError::from)?,
+            event_type: event.event_type.to_string(),
+            id: event.event_id,
+            room_id: event.room_id,
+            sender: event.sender,
+            state_key: None,
+            redacts: Some(event.redacts),
+            transaction_id: None,
+            replaces_state: None,
+            prev_content: None,
+        })
+    }
+}
+
+impl TryInto<RedactionEvent> for Event {
+    type Error = ApiError;
+
+    fn try_into(self) -> Result<RedactionEvent, Self::Error> {
+        let origin_server_ts = self.origin_server_ts();
+        let unsigned = Some(self.unsigned());
+
+        let redacts = match self.redacts {
+            Some(redacts) => redacts,
+            None => Err(ApiError::bad_event(format!(
+                "The redaction {} does not reference an event",
+                self.id
+            )))?,
+        };
+
+        Ok(RedactionEvent {
+            content: from_str(&self.content)?,
+            event_id: self.id,
+            event_type: EventType::RoomRedaction,
+            origin_server_ts,
+            redacts,
+            room_id: self.room_id,
+            sender: self.sender,
+            unsigned,
+        })
+    }
+}
+
 impl TryInto<MemberEvent> for Event {
     type Error = ApiError;
 
     fn try_into(self) -> Result<MemberEvent, Self::Error> {
+        let origin_server_ts = self.origin_server_ts();
+        let unsigned = Some(self.unsigned());
+        let prev_content = self.prev_content();
+
         Ok(MemberEvent {
             content: from_str(&self.content)?,
             event_id: self.id,
@@ -387,25 +1034,60 @@ impl TryInto<MemberEvent> for Event {
             // Once ruma-events is updated to account for this, this whole TryInto impl can be
             // killed. This is just a dummy value for now to satisfy the old schema.
             invite_room_state: None,
-            // FIXME: This is a dummy value just to satisfy event types' new schema.
-            // The real value should come from the database record's created_at timestamp,
-            // but it's unclear exactly how.
-            //
-            // See https://github.com/matrix-org/matrix-doc/issues/2064
-            origin_server_ts: 0,
-            prev_content: None,
+            origin_server_ts,
+            prev_content,
             room_id: self.room_id,
             sender: self.sender,
-            state_key: "".to_string(),
-            unsigned: None,
+            state_key: self.state_key.unwrap_or_default(),
+            unsigned,
         })
     }
 }
 
+impl TryInto<RoomEvent> for Event {
+    type Error = ApiError;
+
+    fn try_into(self) -> Result<RoomEvent, Self::Error> {
+        // The content of a redacted event may no longer match the schema of its type.
+        if self.redacted_by.is_some() {
+            return self.try_into_custom_room_event();
+        }
+
+        let room_event = match EventType::from(self.event_type.as_ref()) {
+            EventType::CallAnswer => RoomEvent::CallAnswer(self.try_into()?),
+            EventType::CallCandidates => RoomEvent::CallCandidates(self.try_into()?),
+            EventType::CallHangup => RoomEvent::CallHangup(self.try_into()?),
+            EventType::CallInvite => RoomEvent::CallInvite(self.try_into()?),
+            EventType::RoomAliases => RoomEvent::RoomAliases(self.try_into()?),
+            EventType::RoomAvatar => RoomEvent::RoomAvatar(self.try_into()?),
+            EventType::RoomCanonicalAlias => RoomEvent::RoomCanonicalAlias(self.try_into()?),
+            EventType::RoomCreate => RoomEvent::RoomCreate(self.try_into()?),
+            EventType::RoomGuestAccess => RoomEvent::RoomGuestAccess(self.try_into()?),
+            EventType::RoomHistoryVisibility => RoomEvent::RoomHistoryVisibility(self.try_into()?),
+            EventType::RoomJoinRules => RoomEvent::RoomJoinRules(self.try_into()?),
+            EventType::RoomMember => RoomEvent::RoomMember(self.try_into()?),
+            EventType::RoomMessage => RoomEvent::RoomMessage(self.try_into()?),
+            EventType::RoomName => RoomEvent::RoomName(self.try_into()?),
+            EventType::RoomPowerLevels => RoomEvent::RoomPowerLevels(self.try_into()?),
+            EventType::RoomRedaction => RoomEvent::RoomRedaction(self.try_into()?),
+            EventType::RoomThirdPartyInvite => RoomEvent::RoomThirdPartyInvite(self.try_into()?),
+            EventType::RoomTopic => RoomEvent::RoomTopic(self.try_into()?),
+            _ => self.try_into_custom_room_event()?,
+        };
+
+        Ok(room_event)
+    }
+}
+
 impl TryInto<StateEvent> for Event {
     type Error = ApiError;
 
     fn try_into(self) -> Result<StateEvent, Self::Error> {
+        // The content of a redacted event may no longer match the schema of its type.
+        if self.redacted_by.is_some() {
+            return Ok(StateEvent::CustomState(self.try_into()?));
+        }
+
         let state_event = match EventType::from(self.event_type.as_ref()) {
             EventType::RoomAliases => StateEvent::RoomAliases(self.try_into()?),
             EventType::RoomAvatar => StateEvent::RoomAvatar(self.try_into()?),
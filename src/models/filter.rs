@@ -20,6 +20,7 @@ pub struct EventFilter {
     #[serde(default)]
     pub not_types: Vec<String>,
     /// The maximum number of events to return.
+    #[serde(default)]
     pub limit: usize,
     /// A list of senders IDs to include.
     #[serde(skip_serializing_if = "Vec::is_empty")]
@@ -55,6 +56,7 @@ pub struct RoomEventFilter {
     #[serde(default = "default_vec_room_id")]
     pub rooms: Vec<RoomId>,
     /// The maximum number of events to return.
+    #[serde(default)]
     pub limit: usize,
     /// A list of sender IDs to exclude.
     #[serde(skip_serializing_if = "Vec::is_empty")]
@@ -64,6 +66,14 @@ pub struct RoomEventFilter {
     #[serde(skip_serializing_if = "Vec::is_empty")]
     #[serde(default = "default_vec_user_id")]
     pub senders: Vec<UserId>,
+    /// Only include the member events of the senders of the returned events.
+    #[serde(default)]
+    #[serde(skip_serializing_if = "super::is_false")]
+    pub lazy_load_members: bool,
+    /// Include member events the client was already sent when lazy-loading members.
+    #[serde(default)]
+    #[serde(skip_serializing_if = "super::is_false")]
+    pub include_redundant_members: bool,
 }
 
 /// Helper function for `RoomFilter::include_leave` when serializing with serde.
@@ -0,0 +1,157 @@
+//! Files uploaded to the media repository.
+
+use std::fs::{create_dir_all, read, write};
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+
+use diesel::pg::data_types::PgTimestamp;
+use diesel::pg::PgConnection;
+use diesel::prelude::*;
+use diesel::result::Error as DieselError;
+use rand::distributions::Alphanumeric;
+use rand::rngs::OsRng;
+use rand::Rng;
+use ruma_identifiers::UserId;
+
+use crate::error::ApiError;
+use crate::schema::media;
+
+/// The length of generated media IDs.
+const MEDIA_ID_LENGTH: usize = 24;
+
+/// New media, not yet saved.
+#[derive(Debug, Clone, Insertable)]
+#[table_name = "media"]
+pub struct NewMedia {
+    /// The ID of the media, the path portion of its `mxc://` URI.
+    pub media_id: String,
+    /// The user who uploaded the media.
+    pub user_id: UserId,
+    /// The content type given when the media was uploaded.
+    pub content_type: String,
+    /// The file name given when the media was uploaded.
+    pub upload_name: Option<String>,
+    /// The size of the media in bytes.
+    pub content_length: i64,
+}
+
+/// Media stored by the local media repository.
+///
+/// The content itself lives in the media directory, in a file named after the media ID.
+#[derive(Debug, Clone, Queryable)]
+pub struct Media {
+    /// The ID of the media, the path portion of its `mxc://` URI.
+    pub media_id: String,
+    /// The user who uploaded the media.
+    pub user_id: UserId,
+    /// The content type given when the media was uploaded.
+    pub content_type: String,
+    /// The file name given when the media was uploaded.
+    pub upload_name: Option<String>,
+    /// The size of the media in bytes.
+    pub content_length: i64,
+    /// The time the media was uploaded.
+    pub created_at: PgTimestamp,
+}
+
+impl Media {
+    /// Write the content of new media to the media directory and save its metadata.
+    pub fn create(
+        connection: &PgConnection,
+        media_directory: &Path,
+        user_id: UserId,
+        content_type: String,
+        upload_name: Option<String>,
+        content: &[u8],
+    ) -> Result<Self, ApiError> {
+        let new_media = NewMedia {
+            media_id: generate_media_id()?,
+            user_id,
+            content_type,
+            upload_name,
+            content_length: content.len() as i64,
+        };
+
+        // The metadata is only kept if the content could be written.
+        connection.transaction::<Self, ApiError, _>(|| {
+            let media: Self = diesel::insert_into(media::table)
+                .values(&new_media)
+                .get_result(connection)?;
+
+            create_dir_all(media_directory)?;
+            write(Self::path(media_directory, &media.media_id), content)?;
+
+            Ok(media)
+        })
+    }
+
+    /// Return the media with the given ID.
+    pub fn find(connection: &PgConnection, media_id: &str) -> Result<Option<Self>, ApiError> {
+        let media = media::table.find(media_id).get_result(connection);
+
+        match media {
+            Ok(media) => Ok(Some(media)),
+            Err(DieselError::NotFound) => Ok(None),
+            Err(err) => Err(ApiError::from(err)),
+        }
+    }
+
+    /// The `mxc://` URI of the media.
+    pub fn content_uri(&self, domain: &str) -> String {
+        format!("mxc://{}/{}", domain, self.media_id)
+    }
+
+    /// Read the content of the media from the media directory.
+    pub fn read_content(&self, media_directory: &Path) -> Result<Vec<u8>, ApiError> {
+        read(Self::path(media_directory, &self.media_id)).map_err(ApiError::from)
+    }
+
+    /// Read a thumbnail of the media previously saved under the given name, if any.
+    pub fn read_thumbnail(
+        &self,
+        media_directory: &Path,
+        name: &str,
+    ) -> Result<Option<Vec<u8>>, ApiError> {
+        match read(Self::thumbnail_path(media_directory, &self.media_id, name)) {
+            Ok(content) => Ok(Some(content)),
+            Err(ref err) if err.kind() == ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(ApiError::from(err)),
+        }
+    }
+
+    /// Save a thumbnail of the media under the given name, so that it is only generated once.
+    pub fn write_thumbnail(
+        &self,
+        media_directory: &Path,
+        name: &str,
+        content: &[u8],
+    ) -> Result<(), ApiError> {
+        let path = Self::thumbnail_path(media_directory, &self.media_id, name);
+
+        if let Some(thumbnail_directory) = path.parent() {
+            create_dir_all(thumbnail_directory).map_err(ApiError::from)?;
+        }
+
+        write(path, content).map_err(ApiError::from)
+    }
+
+    /// The path of the file holding the content of the media.
+    fn path(media_directory: &Path, media_id: &str) -> PathBuf {
+        media_directory.join(media_id)
+    }
+
+    /// The path of the file holding a thumbnail of the media.
+    fn thumbnail_path(media_directory: &Path, media_id: &str, name: &str) -> PathBuf {
+        media_directory.join("thumbnails").join(media_id).join(name)
+    }
+}
+
+/// Generate a random ID for new media.
+fn generate_media_id() -> Result<String, ApiError> {
+    let mut rng = OsRng::new()?;
+
+    Ok(rng
+        .sample_iter(&Alphanumeric)
+        .take(MEDIA_ID_LENGTH)
+        .collect())
+}
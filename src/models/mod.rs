@@ -2,10 +2,13 @@ pub mod access_token;
 pub mod account_data;
 pub mod event;
 pub mod filter;
+pub mod media;
 pub mod presence_list;
 pub mod presence_status;
 pub mod profile;
+pub mod push_rule;
 pub mod pusher;
+pub mod receipt;
 pub mod room;
 pub mod room_alias;
 pub mod room_membership;
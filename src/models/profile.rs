@@ -126,6 +126,7 @@ impl Profile {
                 user_id: user_id.clone(),
                 sender: user_id.clone(),
                 membership: "join".to_string(),
+                reason: None,
             };
 
             room_membership.update(connection, homeserver_domain, options)?;
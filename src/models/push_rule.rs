@@ -0,0 +1,533 @@
+//! Matrix push rules.
+
+use std::fmt::{Display, Formatter, Result as FmtResult};
+use std::str::FromStr;
+
+use diesel::pg::PgConnection;
+use diesel::prelude::*;
+use diesel::result::Error as DieselError;
+use ruma_identifiers::UserId;
+use serde::de::Error as SerdeError;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use serde_json::{from_str, from_value, json, to_string, Value};
+
+use crate::error::ApiError;
+use crate::schema::push_rules;
+
+/// The ID of the default rule that silences every notification when enabled.
+const MASTER_RULE_ID: &str = ".m.rule.master";
+
+/// The kinds of push rules.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum PushRuleKind {
+    /// Rules with arbitrary conditions, evaluated before all others.
+    Override,
+    /// Rules matching a pattern against the body of messages.
+    Content,
+    /// Rules matching every event of a room.
+    Room,
+    /// Rules matching every event from a user.
+    Sender,
+    /// Rules with arbitrary conditions, evaluated after all others.
+    Underride,
+}
+
+impl PushRuleKind {
+    /// All kinds, in the order their rules are evaluated.
+    pub const ALL: [Self; 5] = [
+        PushRuleKind::Override,
+        PushRuleKind::Content,
+        PushRuleKind::Room,
+        PushRuleKind::Sender,
+        PushRuleKind::Underride,
+    ];
+
+    /// The name of the kind in the API.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            PushRuleKind::Override => "override",
+            PushRuleKind::Content => "content",
+            PushRuleKind::Room => "room",
+            PushRuleKind::Sender => "sender",
+            PushRuleKind::Underride => "underride",
+        }
+    }
+}
+
+impl Display for PushRuleKind {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+impl FromStr for PushRuleKind {
+    type Err = String;
+
+    fn from_str(kind: &str) -> Result<Self, Self::Err> {
+        Self::ALL
+            .iter()
+            .cloned()
+            .find(|candidate| candidate.as_str() == kind)
+            .ok_or_else(|| format!("unknown kind {}", kind))
+    }
+}
+
+/// What to do when a push rule matches an event.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Action {
+    /// Notify the user.
+    Notify,
+    /// Do not notify the user.
+    DontNotify,
+    /// Notify the user, possibly coalescing the notification with others.
+    Coalesce,
+    /// Change a detail of the notification.
+    SetTweak(Tweak),
+}
+
+/// A detail of a notification, such as its sound or whether it is highlighted.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct Tweak {
+    /// The name of the tweak, e.g. `sound` or `highlight`.
+    pub set_tweak: String,
+    /// The value of the tweak. A `highlight` tweak without a value is enabled.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub value: Option<Value>,
+}
+
+impl Serialize for Action {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        match *self {
+            Action::Notify => serializer.serialize_str("notify"),
+            Action::DontNotify => serializer.serialize_str("dont_notify"),
+            Action::Coalesce => serializer.serialize_str("coalesce"),
+            Action::SetTweak(ref tweak) => tweak.serialize(serializer),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for Action {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        match Value::deserialize(deserializer)? {
+            Value::String(ref action) if action == "notify" => Ok(Action::Notify),
+            Value::String(ref action) if action == "dont_notify" => Ok(Action::DontNotify),
+            Value::String(ref action) if action == "coalesce" => Ok(Action::Coalesce),
+            value @ Value::Object(_) => from_value(value)
+                .map(Action::SetTweak)
+                .map_err(D::Error::custom),
+            value => Err(D::Error::custom(format!("unknown action {}", value))),
+        }
+    }
+}
+
+/// A condition an event must meet for an override or underride rule to match.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum PushCondition {
+    /// A property of the event, given as a dot-separated path, matches a glob pattern.
+    EventMatch {
+        /// The path of the property, e.g. `content.body`.
+        key: String,
+        /// The glob pattern to match.
+        pattern: String,
+    },
+    /// The body of the event contains the user's display name.
+    ContainsDisplayName,
+    /// The number of joined members of the room satisfies a comparison, e.g. `>=2`.
+    RoomMemberCount {
+        /// The comparison, an optional operator followed by a number.
+        is: String,
+    },
+    /// The sender has the power level required for a kind of notification.
+    SenderNotificationPermission {
+        /// The kind of notification, e.g. `room`.
+        key: String,
+    },
+}
+
+/// A new or replaced user-defined push rule.
+#[derive(Clone, Debug)]
+pub struct NewPushRule {
+    /// The ID of the rule.
+    pub rule_id: String,
+    /// The conditions of an override or underride rule.
+    pub conditions: Vec<PushCondition>,
+    /// The pattern of a content rule.
+    pub pattern: Option<String>,
+    /// The actions of the rule.
+    pub actions: Vec<Action>,
+}
+
+/// A push rule of a user.
+#[derive(AsChangeset, Clone, Debug, Identifiable, Insertable, Queryable)]
+#[table_name = "push_rules"]
+#[primary_key(user_id, kind, rule_id)]
+pub struct PushRule {
+    /// The user the rule belongs to.
+    pub user_id: UserId,
+    /// The kind of the rule.
+    pub kind: String,
+    /// The ID of the rule, which is a room ID for room rules and a user ID for sender rules.
+    pub rule_id: String,
+    /// The position of the rule among the rules of the same kind, lowest first.
+    pub priority: i32,
+    /// Whether the rule is one of the default rules of the specification.
+    pub is_default: bool,
+    /// Whether the rule is evaluated.
+    pub enabled: bool,
+    /// JSON of the conditions of an override or underride rule.
+    pub conditions: String,
+    /// The pattern of a content rule.
+    pub pattern: Option<String>,
+    /// JSON of the actions of the rule.
+    pub actions: String,
+}
+
+impl PushRule {
+    /// The kind of the rule.
+    pub fn kind(&self) -> Result<PushRuleKind, ApiError> {
+        PushRuleKind::from_str(&self.kind).map_err(ApiError::unknown)
+    }
+
+    /// The conditions of the rule.
+    pub fn conditions(&self) -> Result<Vec<PushCondition>, ApiError> {
+        from_str(&self.conditions).map_err(ApiError::from)
+    }
+
+    /// The actions of the rule.
+    pub fn actions(&self) -> Result<Vec<Action>, ApiError> {
+        from_str(&self.actions).map_err(ApiError::from)
+    }
+
+    /// Return all push rules of a user, in the order they are evaluated.
+    pub fn find_by_user(
+        connection: &PgConnection,
+        user_id: &UserId,
+    ) -> Result<Vec<Self>, ApiError> {
+        Self::create_default_rules(connection, user_id)?;
+
+        let mut rules: Vec<Self> = push_rules::table
+            .filter(push_rules::user_id.eq(user_id))
+            .get_results(connection)?;
+
+        rules.sort_by_key(|rule| {
+            let kind_position = PushRuleKind::ALL
+                .iter()
+                .position(|kind| kind.as_str() == rule.kind);
+
+            (kind_position, rule.priority)
+        });
+
+        Ok(rules)
+    }
+
+    /// Return the push rules of a user of the given kind, in the order they are evaluated.
+    pub fn find_by_kind(
+        connection: &PgConnection,
+        user_id: &UserId,
+        kind: PushRuleKind,
+    ) -> Result<Vec<Self>, ApiError> {
+        Self::create_default_rules(connection, user_id)?;
+
+        push_rules::table
+            .filter(push_rules::user_id.eq(user_id))
+            .filter(push_rules::kind.eq(kind.as_str()))
+            .order(push_rules::priority.asc())
+            .get_results(connection)
+            .map_err(ApiError::from)
+    }
+
+    /// Return a push rule of a user.
+    pub fn find(
+        connection: &PgConnection,
+        user_id: &UserId,
+        kind: PushRuleKind,
+        rule_id: &str,
+    ) -> Result<Option<Self>, ApiError> {
+        Self::create_default_rules(connection, user_id)?;
+
+        let rule = push_rules::table
+            .find((user_id, kind.as_str(), rule_id))
+            .get_result(connection);
+
+        match rule {
+            Ok(rule) => Ok(Some(rule)),
+            Err(DieselError::NotFound) => Ok(None),
+            Err(err) => Err(ApiError::from(err)),
+        }
+    }
+
+    /// Create or replace a user-defined push rule.
+    ///
+    /// The rule is placed right before or after another user-defined rule if requested. Otherwise,
+    /// a replaced rule keeps its position and a new rule becomes the most important user-defined
+    /// rule of its kind.
+    pub fn put(
+        connection: &PgConnection,
+        user_id: &UserId,
+        kind: PushRuleKind,
+        new_rule: NewPushRule,
+        before: Option<&str>,
+        after: Option<&str>,
+    ) -> Result<Self, ApiError> {
+        connection.transaction::<Self, ApiError, _>(|| {
+            let mut rules = Self::find_by_kind(connection, user_id, kind)?;
+
+            let existing_position = rules
+                .iter()
+                .position(|rule| rule.rule_id == new_rule.rule_id);
+
+            let mut rule = match existing_position {
+                Some(position) => rules.remove(position),
+                None => Self {
+                    user_id: user_id.clone(),
+                    kind: kind.to_string(),
+                    rule_id: new_rule.rule_id,
+                    priority: 0,
+                    is_default: false,
+                    enabled: true,
+                    conditions: String::new(),
+                    pattern: None,
+                    actions: String::new(),
+                },
+            };
+
+            rule.conditions = to_string(&new_rule.conditions)?;
+            rule.pattern = new_rule.pattern;
+            rule.actions = to_string(&new_rule.actions)?;
+
+            let position = match (before, after) {
+                (Some(before), _) => user_rule_position(&rules, "before", before)?,
+                (None, Some(after)) => user_rule_position(&rules, "after", after)? + 1,
+                // User-defined rules are evaluated before the default rules, except the master
+                // rule which always comes first.
+                (None, None) => existing_position.unwrap_or_else(|| {
+                    rules
+                        .iter()
+                        .take_while(|rule| rule.rule_id == MASTER_RULE_ID)
+                        .count()
+                }),
+            };
+
+            rules.insert(position, rule);
+
+            for (priority, rule) in rules.iter_mut().enumerate() {
+                rule.priority = priority as i32;
+            }
+
+            diesel::delete(
+                push_rules::table
+                    .filter(push_rules::user_id.eq(user_id))
+                    .filter(push_rules::kind.eq(kind.as_str())),
+            )
+            .execute(connection)?;
+
+            diesel::insert_into(push_rules::table)
+                .values(&rules)
+                .execute(connection)?;
+
+            Ok(rules.swap_remove(position))
+        })
+    }
+
+    /// Delete a push rule.
+    pub fn delete(&self, connection: &PgConnection) -> Result<(), ApiError> {
+        diesel::delete(self).execute(connection)?;
+
+        Ok(())
+    }
+
+    /// Enable or disable the rule.
+    pub fn set_enabled(
+        &mut self,
+        connection: &PgConnection,
+        enabled: bool,
+    ) -> Result<(), ApiError> {
+        self.enabled = enabled;
+
+        self.save_changes::<Self>(connection)?;
+
+        Ok(())
+    }
+
+    /// Replace the actions of the rule.
+    pub fn set_actions(
+        &mut self,
+        connection: &PgConnection,
+        actions: &[Action],
+    ) -> Result<(), ApiError> {
+        self.actions = to_string(actions)?;
+
+        self.save_changes::<Self>(connection)?;
+
+        Ok(())
+    }
+
+    /// Save the default rules of the specification for a user who has no push rules yet.
+    fn create_default_rules(connection: &PgConnection, user_id: &UserId) -> Result<(), ApiError> {
+        let default_rule_count: i64 = push_rules::table
+            .filter(push_rules::user_id.eq(user_id))
+            .filter(push_rules::is_default.eq(true))
+            .count()
+            .get_result(connection)?;
+
+        if default_rule_count == 0 {
+            diesel::insert_into(push_rules::table)
+                .values(&default_rules(user_id))
+                .execute(connection)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// The position of the user-defined rule with the given ID, which a new rule is placed next to.
+fn user_rule_position(
+    rules: &[PushRule],
+    param_name: &str,
+    rule_id: &str,
+) -> Result<usize, ApiError> {
+    match rules.iter().position(|rule| rule.rule_id == rule_id) {
+        Some(position) if rules[position].is_default => Err(ApiError::invalid_param(
+            param_name,
+            "rules cannot be placed relative to default rules",
+        )),
+        Some(position) => Ok(position),
+        None => Err(ApiError::not_found(format!(
+            "The push rule {} was not found",
+            rule_id
+        ))),
+    }
+}
+
+/// The default push rules of the specification, in the order they are evaluated.
+pub fn default_rules(user_id: &UserId) -> Vec<PushRule> {
+    let highlight =
+        json!(["notify", {"set_tweak": "sound", "value": "default"}, {"set_tweak": "highlight"}]);
+    let sound = json!(["notify", {"set_tweak": "sound", "value": "default"}, {"set_tweak": "highlight", "value": false}]);
+    let silent = json!(["notify", {"set_tweak": "highlight", "value": false}]);
+
+    let rules = vec![
+        (
+            PushRuleKind::Override,
+            MASTER_RULE_ID,
+            json!([]),
+            json!(["dont_notify"]),
+        ),
+        (
+            PushRuleKind::Override,
+            ".m.rule.suppress_notices",
+            json!([{"kind": "event_match", "key": "content.msgtype", "pattern": "m.notice"}]),
+            json!(["dont_notify"]),
+        ),
+        (
+            PushRuleKind::Override,
+            ".m.rule.invite_for_me",
+            json!([
+                {"kind": "event_match", "key": "type", "pattern": "m.room.member"},
+                {"kind": "event_match", "key": "content.membership", "pattern": "invite"},
+                {"kind": "event_match", "key": "state_key", "pattern": user_id.to_string()},
+            ]),
+            sound.clone(),
+        ),
+        (
+            PushRuleKind::Override,
+            ".m.rule.member_event",
+            json!([{"kind": "event_match", "key": "type", "pattern": "m.room.member"}]),
+            json!(["dont_notify"]),
+        ),
+        (
+            PushRuleKind::Override,
+            ".m.rule.contains_display_name",
+            json!([{"kind": "contains_display_name"}]),
+            highlight.clone(),
+        ),
+        (
+            PushRuleKind::Override,
+            ".m.rule.tombstone",
+            json!([
+                {"kind": "event_match", "key": "type", "pattern": "m.room.tombstone"},
+                {"kind": "event_match", "key": "state_key", "pattern": ""},
+            ]),
+            json!(["notify", {"set_tweak": "highlight"}]),
+        ),
+        (
+            PushRuleKind::Override,
+            ".m.rule.roomnotif",
+            json!([
+                {"kind": "event_match", "key": "content.body", "pattern": "@room"},
+                {"kind": "sender_notification_permission", "key": "room"},
+            ]),
+            json!(["notify", {"set_tweak": "highlight"}]),
+        ),
+        (
+            PushRuleKind::Content,
+            ".m.rule.contains_user_name",
+            json!([]),
+            highlight,
+        ),
+        (
+            PushRuleKind::Underride,
+            ".m.rule.call",
+            json!([{"kind": "event_match", "key": "type", "pattern": "m.call.invite"}]),
+            json!(["notify", {"set_tweak": "sound", "value": "ring"}, {"set_tweak": "highlight", "value": false}]),
+        ),
+        (
+            PushRuleKind::Underride,
+            ".m.rule.encrypted_room_one_to_one",
+            json!([
+                {"kind": "room_member_count", "is": "2"},
+                {"kind": "event_match", "key": "type", "pattern": "m.room.encrypted"},
+            ]),
+            sound.clone(),
+        ),
+        (
+            PushRuleKind::Underride,
+            ".m.rule.room_one_to_one",
+            json!([
+                {"kind": "room_member_count", "is": "2"},
+                {"kind": "event_match", "key": "type", "pattern": "m.room.message"},
+            ]),
+            sound,
+        ),
+        (
+            PushRuleKind::Underride,
+            ".m.rule.message",
+            json!([{"kind": "event_match", "key": "type", "pattern": "m.room.message"}]),
+            silent.clone(),
+        ),
+        (
+            PushRuleKind::Underride,
+            ".m.rule.encrypted",
+            json!([{"kind": "event_match", "key": "type", "pattern": "m.room.encrypted"}]),
+            silent,
+        ),
+    ];
+
+    rules
+        .into_iter()
+        .enumerate()
+        .map(
+            |(priority, (kind, rule_id, conditions, actions))| PushRule {
+                user_id: user_id.clone(),
+                kind: kind.to_string(),
+                rule_id: rule_id.to_string(),
+                priority: priority as i32,
+                is_default: true,
+                enabled: rule_id != MASTER_RULE_ID,
+                conditions: conditions.to_string(),
+                pattern: match kind {
+                    PushRuleKind::Content => Some(user_id.localpart().to_string()),
+                    _ => None,
+                },
+                actions: actions.to_string(),
+            },
+        )
+        .collect()
+}
@@ -0,0 +1,119 @@
+//! Matrix receipts.
+
+use diesel::dsl::sql;
+use diesel::expression::SqlLiteral;
+use diesel::pg::PgConnection;
+use diesel::prelude::*;
+use diesel::result::Error as DieselError;
+use diesel::sql_types::{BigInt, Text};
+use ruma_identifiers::{EventId, RoomId, UserId};
+
+use crate::error::ApiError;
+use crate::schema::receipts;
+
+/// A new receipt, not yet saved.
+#[derive(Debug, Clone, Insertable)]
+#[table_name = "receipts"]
+pub struct NewReceipt {
+    /// The room the receipt belongs to.
+    pub room_id: RoomId,
+    /// The user who sent the receipt.
+    pub user_id: UserId,
+    /// The type of the receipt, e.g. `m.read`.
+    pub receipt_type: String,
+    /// The event acknowledged by the receipt.
+    pub event_id: EventId,
+    /// The ordering of the acknowledged event.
+    pub event_ordering: i64,
+    /// The time the receipt was sent at, in milliseconds since the Unix epoch.
+    pub ts: i64,
+}
+
+/// The latest receipt of a given type sent by a user in a room.
+#[derive(Debug, Clone, Queryable)]
+pub struct Receipt {
+    /// The room the receipt belongs to.
+    pub room_id: RoomId,
+    /// The user who sent the receipt.
+    pub user_id: UserId,
+    /// The type of the receipt, e.g. `m.read`.
+    pub receipt_type: String,
+    /// The event acknowledged by the receipt.
+    pub event_id: EventId,
+    /// The ordering of the acknowledged event.
+    pub event_ordering: i64,
+    /// The time the receipt was sent at, in milliseconds since the Unix epoch.
+    pub ts: i64,
+    /// Incremented every time a receipt is saved.
+    pub ordering: i64,
+}
+
+impl Receipt {
+    /// Save a receipt, replacing the previous receipt of the same type from the same user in the
+    /// room.
+    ///
+    /// Receipts only move forward: the previous receipt is kept if it acknowledges a later
+    /// event. The check is part of the statement, so concurrent receipts cannot move it back.
+    pub fn upsert(connection: &PgConnection, new_receipt: &NewReceipt) -> Result<Self, ApiError> {
+        diesel::insert_into(receipts::table)
+            .values(new_receipt)
+            .on_conflict((receipts::room_id, receipts::user_id, receipts::receipt_type))
+            .do_update()
+            .set((
+                receipts::event_id.eq(forward_only::<Text>("event_id", "excluded.event_id")),
+                receipts::event_ordering.eq(forward_only::<BigInt>(
+                    "event_ordering",
+                    "excluded.event_ordering",
+                )),
+                receipts::ts.eq(forward_only::<BigInt>("ts", "excluded.ts")),
+                receipts::ordering.eq(forward_only::<BigInt>(
+                    "ordering",
+                    "nextval('receipts_ordering_seq')",
+                )),
+            ))
+            .get_result(connection)
+            .map_err(ApiError::from)
+    }
+
+    /// Return the receipt of the given type sent by a user in a room.
+    pub fn find(
+        connection: &PgConnection,
+        room_id: &RoomId,
+        user_id: &UserId,
+        receipt_type: &str,
+    ) -> Result<Option<Self>, ApiError> {
+        let receipt = receipts::table
+            .find((room_id, user_id, receipt_type))
+            .get_result(connection);
+
+        match receipt {
+            Ok(receipt) => Ok(Some(receipt)),
+            Err(DieselError::NotFound) => Ok(None),
+            Err(err) => Err(ApiError::from(err)),
+        }
+    }
+
+    /// Return the receipts of a room saved after the given ordering.
+    pub fn find_room_receipts_since(
+        connection: &PgConnection,
+        room_id: &RoomId,
+        since: i64,
+    ) -> Result<Vec<Self>, ApiError> {
+        receipts::table
+            .filter(receipts::room_id.eq(room_id))
+            .filter(receipts::ordering.gt(since))
+            .order(receipts::ordering.asc())
+            .get_results(connection)
+            .map_err(ApiError::from)
+    }
+}
+
+/// An SQL expression for the new value of a column when a receipt replaces another one.
+///
+/// The column keeps its saved value unless the new receipt acknowledges a later event.
+fn forward_only<ST>(column: &str, new_value: &str) -> SqlLiteral<ST> {
+    sql(&format!(
+        "CASE WHEN receipts.event_ordering < excluded.event_ordering THEN {} ELSE receipts.{} END",
+        new_value, column
+    ))
+}
@@ -21,10 +21,11 @@ use ruma_events::stripped::StrippedState;
 use ruma_events::EventType;
 use ruma_identifiers::{EventId, RoomAliasId, RoomId, UserId};
 
+use crate::auth::{authorize_event, AuthState};
 use crate::error::ApiError;
 use crate::models::event::{Event, NewEvent};
 use crate::models::room_alias::{NewRoomAlias, RoomAlias};
-use crate::models::room_membership::RoomMembership;
+use crate::models::room_membership::{RoomMembership, RoomMembershipOptions};
 use crate::schema::{events, rooms};
 
 /// Options provided by the user to customize the room upon creation.
@@ -86,7 +87,7 @@ pub enum RoomPreset {
 }
 
 /// Indicates whether or not that the room will be shown in the published room list.
-#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
 pub enum RoomVisibility {
     /// The room will be private.
     #[serde(rename = "private")]
@@ -116,8 +117,6 @@ impl Room {
                 .get_result(connection)
                 .map_err(ApiError::from)?;
 
-            let mut new_events = Vec::new();
-
             let new_create_event: NewEvent = CreateEvent {
                 content: CreateEventContent {
                     creator: new_room.user_id.clone(),
@@ -133,7 +132,22 @@ impl Room {
                 unsigned: None,
             }.try_into()?;
 
-            new_events.push(new_create_event);
+            authorize_event(connection, &new_create_event)?;
+
+            Event::create(connection, &new_create_event)?;
+
+            // The creator has to join before any other event can be sent to the room.
+            let creator_membership_options = RoomMembershipOptions {
+                room_id: room.id.clone(),
+                user_id: room.user_id.clone(),
+                sender: room.user_id.clone(),
+                membership: "join".to_string(),
+                reason: None,
+            };
+
+            RoomMembership::create(connection, homeserver_domain, creator_membership_options)?;
+
+            let mut new_events = Vec::new();
 
             let mut is_canonical_alias_set = false;
             let mut is_history_visibility_set = false;
@@ -472,10 +486,14 @@ impl Room {
                 new_events.push(new_canonical_alias_event);
             }
 
-            diesel::insert_into(events::table)
-                .values(&new_events)
-                .execute(connection)
-                .map_err(ApiError::from)?;
+            let mut auth_state = AuthState::load(connection, &room.id)?;
+
+            for new_event in &new_events {
+                auth_state.authorize(new_event)?;
+                auth_state.apply(new_event)?;
+            }
+
+            Event::create_many(connection, &new_events)?;
 
             for alias in new_room_aliases {
                 RoomAlias::create(connection, homeserver_domain, &alias)?;
@@ -536,6 +554,25 @@ impl Room {
         }
     }
 
+    /// Return all the rooms that are visible in the directory.
+    pub fn find_public(connection: &PgConnection) -> Result<Vec<Self>, ApiError> {
+        rooms::table
+            .filter(rooms::public.eq(true))
+            .get_results(connection)
+            .map_err(ApiError::from)
+    }
+
+    /// Set whether or not the room is visible in the directory.
+    pub fn set_public(&mut self, connection: &PgConnection, public: bool) -> Result<(), ApiError> {
+        diesel::update(rooms::table.find(&self.id))
+            .set(rooms::public.eq(public))
+            .execute(connection)?;
+
+        self.public = public;
+
+        Ok(())
+    }
+
     /// Look up a `Room` given the `RoomId`.
     pub fn find(connection: &PgConnection, room_id: &RoomId) -> Result<Option<Self>, ApiError> {
         let result = rooms::table.find(room_id).get_result(connection);
@@ -11,9 +11,9 @@ use ruma_events::EventType;
 use ruma_identifiers::{EventId, RoomAliasId, RoomId, UserId};
 
 use crate::error::ApiError;
-use crate::models::event::NewEvent;
+use crate::models::event::{Event, NewEvent};
 use crate::models::room::Room;
-use crate::schema::{events, room_aliases};
+use crate::schema::room_aliases;
 
 /// A new room alias, not yet saved.
 #[derive(Debug, Insertable)]
@@ -76,10 +76,7 @@ impl RoomAlias {
                 }
                 .try_into()?;
 
-                diesel::insert_into(events::table)
-                    .values(&new_room_alias_event)
-                    .execute(connection)
-                    .map_err(ApiError::from)?;
+                Event::create(connection, &new_room_alias_event)?;
 
                 diesel::insert_into(room_aliases::table)
                     .values(new_room_alias)
@@ -11,8 +11,9 @@ use ruma_events::room::join_rules::JoinRule;
 use ruma_events::room::member::{MemberEvent, MemberEventContent, MembershipState};
 use ruma_events::EventType;
 use ruma_identifiers::{EventId, RoomId, UserId};
-use serde_json::{from_value, Value};
+use serde_json::{from_str, from_value, to_string, Value};
 
+use crate::auth::authorize_event;
 use crate::error::ApiError;
 use crate::models::event::{Event, NewEvent};
 use crate::models::profile::Profile;
@@ -31,6 +32,8 @@ pub struct RoomMembershipOptions {
     pub sender: UserId,
     /// The current membership state.
     pub membership: String,
+    /// The reason given for the membership change, if any.
+    pub reason: Option<String>,
 }
 
 /// A new Matrix room membership, not yet saved.
@@ -66,6 +69,8 @@ pub struct RoomMembership {
     pub membership: String,
     /// The time the room was created.
     pub created_at: PgTimestamp,
+    /// Whether or not the user has forgotten the room.
+    pub forgotten: bool,
 }
 
 impl RoomMembership {
@@ -75,8 +80,6 @@ impl RoomMembership {
         homeserver_domain: &str,
         options: RoomMembershipOptions,
     ) -> Result<Self, ApiError> {
-        Self::verify_creation_priviledges(connection, &options)?;
-
         let profile = Profile::find_by_uid(connection, &options.user_id)?;
 
         let new_member_event =
@@ -106,8 +109,6 @@ impl RoomMembership {
         let mut new_memberships: Vec<NewRoomMembership> = Vec::new();
 
         for option in options {
-            Self::verify_creation_priviledges(connection, &option)?;
-
             let profile = Profile::find_by_uid(connection, &option.user_id)?;
 
             let new_member_event =
@@ -136,10 +137,11 @@ impl RoomMembership {
     ) -> Result<Vec<Self>, ApiError> {
         connection
             .transaction::<Vec<Self>, ApiError, _>(|| {
-                diesel::insert_into(events::table)
-                    .values(&events)
-                    .execute(connection)
-                    .map_err(ApiError::from)?;
+                for event in &events {
+                    authorize_event(connection, event)?;
+                }
+
+                Event::create_many(connection, &events)?;
 
                 let memberships: Vec<Self> = diesel::insert_into(room_memberships::table)
                     .values(&new_memberships)
@@ -150,45 +152,25 @@ impl RoomMembership {
             .map_err(ApiError::from)
     }
 
-    /// Check if a `User` has enough priviledges to create a `RoomMembership`.
-    fn verify_creation_priviledges(
-        connection: &PgConnection,
-        options: &RoomMembershipOptions,
+    /// Check if a user with the given membership may join a room with the given join rule.
+    ///
+    /// Invited users can always join and joining again only updates the member event, while
+    /// banned users can never join.
+    pub fn verify_join(
+        membership: Option<&MembershipState>,
+        join_rule: JoinRule,
     ) -> Result<(), ApiError> {
-        let room = match Room::find(connection, &options.room_id)? {
-            Some(room) => room,
-            None => Err(ApiError::unauthorized(
-                "The room was not found on this server".to_string(),
-            ))?,
-        };
-
-        let join_rules_event = Event::find_room_join_rules_by_room_id(connection, room.id.clone())?;
-
-        // Only the creator of the room can join an invite-only room, without an invite.
-        if options.membership == "join" {
-            if join_rules_event.content.join_rule == JoinRule::Invite
-                && options.sender != room.user_id
-            {
-                return Err(ApiError::unauthorized(
-                    "You are not invited to this room".to_string(),
-                ));
-            }
-
-            return Ok(());
+        match (membership, join_rule) {
+            (Some(MembershipState::Ban), _) => Err(ApiError::unauthorized(
+                "The user is banned from the room".to_string(),
+            )),
+            (Some(MembershipState::Invite), _)
+            | (Some(MembershipState::Join), _)
+            | (_, JoinRule::Public) => Ok(()),
+            _ => Err(ApiError::unauthorized(
+                "You are not invited to this room".to_string(),
+            )),
         }
-
-        let power_levels = room.current_power_levels(connection)?;
-        let user_power_level = power_levels
-            .users
-            .get(&options.sender)
-            .unwrap_or(&power_levels.users_default);
-
-        if options.membership == "invite" && power_levels.invite > *user_power_level {
-            return Err(ApiError::unauthorized(
-                "Insufficient power level to invite".to_string(),
-            ));
-        }
-        Ok(())
     }
 
     /// Return `RoomMembership` for given `RoomId` and `UserId`.
@@ -222,6 +204,32 @@ impl RoomMembership {
         Ok(room_memberships)
     }
 
+    /// Return the ordering of the last event of the room the user may see with this membership.
+    ///
+    /// Joined users see the whole history of the room, so `Some(None)` is returned for them.
+    /// Users who left or were banned see the history up to their membership event. Any other
+    /// user, such as an invited one or one who forgot the room, may not see the room's events at
+    /// all and `None` is returned.
+    pub fn visible_until(
+        &self,
+        connection: &PgConnection,
+    ) -> Result<Option<Option<i64>>, ApiError> {
+        if self.forgotten {
+            return Ok(None);
+        }
+
+        match self.membership.as_ref() {
+            "join" => Ok(Some(None)),
+            "leave" | "ban" => {
+                let event = Event::find(connection, &self.event_id)?
+                    .expect("A room membership should be associated with an event");
+
+                Ok(Some(Some(event.ordering)))
+            }
+            _ => Ok(None),
+        }
+    }
+
     /// Update an existing `RoomMembership` entry or insert a new one.
     pub fn upsert(
         connection: &PgConnection,
@@ -249,15 +257,16 @@ impl RoomMembership {
 
         let event = Self::create_new_room_member_event(homeserver_domain, &options, profile)?;
 
-        self.membership = options.membership.clone();
-        self.sender = options.sender;
-
         connection
             .transaction::<Self, ApiError, _>(|| {
-                diesel::insert_into(events::table)
-                    .values(&event)
-                    .execute(connection)
-                    .map_err(ApiError::from)?;
+                authorize_event(connection, &event)?;
+
+                self.membership = options.membership;
+                self.sender = options.sender;
+                // A new membership brings a forgotten room back.
+                self.forgotten = false;
+
+                Event::create(connection, &event)?;
 
                 self.save_changes::<Self>(connection)
                     .map_err(ApiError::from)?;
@@ -271,6 +280,14 @@ impl RoomMembership {
             .map_err(ApiError::from)
     }
 
+    /// Mark the room as forgotten by the user, hiding it from their future syncs.
+    pub fn forget(&mut self, connection: &PgConnection) -> Result<Self, ApiError> {
+        self.forgotten = true;
+
+        self.save_changes::<Self>(connection)
+            .map_err(ApiError::from)
+    }
+
     /// Create a new `MemberEvent`.
     pub fn create_new_room_member_event(
         homeserver_domain: &str,
@@ -286,7 +303,7 @@ impl RoomMembership {
             None => (None, None),
         };
 
-        let new_member_event: NewEvent = MemberEvent {
+        let mut new_member_event: NewEvent = MemberEvent {
             content: MemberEventContent {
                 avatar_url,
                 displayname,
@@ -301,12 +318,19 @@ impl RoomMembership {
             origin_server_ts: 0,
             prev_content: None,
             room_id: Some(options.room_id.clone()),
-            sender: options.user_id.clone(),
-            state_key: format!("@{}:{}", options.user_id.clone(), &homeserver_domain),
+            sender: options.sender.clone(),
+            state_key: options.user_id.to_string(),
             unsigned: None,
         }
         .try_into()?;
 
+        // `MemberEventContent` has no field for the reason, so it is added to the JSON content.
+        if let Some(ref reason) = options.reason {
+            let mut content: Value = from_str(&new_member_event.content)?;
+            content["reason"] = Value::String(reason.clone());
+            new_member_event.content = to_string(&content)?;
+        }
+
         Ok(new_member_event)
     }
 
@@ -343,6 +367,7 @@ impl RoomMembership {
                 user_id: user_id.clone(),
                 sender: room.user_id.clone(),
                 membership: "invite".to_string(),
+                reason: None,
             })
             .collect::<Vec<RoomMembershipOptions>>();
 
@@ -385,6 +410,17 @@ impl RoomMembership {
             })
     }
 
+    /// Return all `RoomMembership`'s for given `RoomId`.
+    pub fn find_all_by_room(
+        connection: &PgConnection,
+        room_id: &RoomId,
+    ) -> Result<Vec<Self>, ApiError> {
+        room_memberships::table
+            .filter(room_memberships::room_id.eq(room_id))
+            .get_results(connection)
+            .map_err(ApiError::from)
+    }
+
     /// Return all `RoomMembership`'s for given `UserId` and `MembershipState`.
     pub fn find_by_uid_and_state(
         connection: &PgConnection,
@@ -415,6 +451,21 @@ impl RoomMembership {
             .map_err(ApiError::from)
     }
 
+    /// Return the `RoomId` of every joined membership in the given rooms.
+    ///
+    /// Each room appears once per joined member.
+    pub fn find_joined_room_ids(
+        connection: &PgConnection,
+        room_ids: &[RoomId],
+    ) -> Result<Vec<RoomId>, ApiError> {
+        room_memberships::table
+            .filter(room_memberships::room_id.eq(any(room_ids)))
+            .filter(room_memberships::membership.eq("join"))
+            .select(room_memberships::room_id)
+            .get_results(connection)
+            .map_err(ApiError::from)
+    }
+
     /// Return `RoomId`'s for given `UserId`'s.
     pub fn find_common_rooms(
         connection: &PgConnection,
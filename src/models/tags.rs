@@ -10,7 +10,7 @@ use serde_json::de::from_str;
 
 use crate::error::ApiError;
 use crate::models::room::Room;
-use crate::schema::{room_tags, rooms};
+use crate::schema::{room_tag_changes, room_tags, rooms};
 
 /// A new Matrix room tag, not yet saved.
 #[derive(Debug, Clone, Insertable)]
@@ -132,6 +132,7 @@ impl RoomTag {
                     ),
                     _ => ApiError::from(err),
                 })?;
+            Self::record_change(connection, &user_id, &room_id)?;
             let new_room_tag = NewRoomTag {
                 user_id,
                 room_id,
@@ -151,7 +152,7 @@ impl RoomTag {
         self.content = content;
         self.save_changes::<Self>(connection)
             .map_err(ApiError::from)?;
-        Ok(())
+        Self::record_change(connection, &self.user_id, &self.room_id)
     }
 
     /// Delete a `RoomTag`.
@@ -162,8 +163,8 @@ impl RoomTag {
         tag: String,
     ) -> Result<(), ApiError> {
         let tag = room_tags::table
-            .filter(room_tags::room_id.eq(room_id))
-            .filter(room_tags::user_id.eq(user_id))
+            .filter(room_tags::room_id.eq(&room_id))
+            .filter(room_tags::user_id.eq(&user_id))
             .filter(room_tags::tag.eq(tag));
         tag.clone()
             .first::<Self>(connection)
@@ -181,6 +182,46 @@ impl RoomTag {
                 ),
                 _ => ApiError::from(err),
             })?;
+        Self::record_change(connection, &user_id, &room_id)
+    }
+
+    /// Return the position of the latest change to the tags of a room, if it is after `since`.
+    pub fn changed_since(
+        connection: &PgConnection,
+        user_id: &UserId,
+        room_id: &RoomId,
+        since: i64,
+    ) -> Result<Option<i64>, ApiError> {
+        room_tag_changes::table
+            .filter(room_tag_changes::user_id.eq(user_id))
+            .filter(room_tag_changes::room_id.eq(room_id))
+            .filter(room_tag_changes::ordering.gt(since))
+            .select(room_tag_changes::ordering)
+            .first(connection)
+            .optional()
+            .map_err(ApiError::from)
+    }
+
+    /// Move the tags of a room to the latest position in the account data stream.
+    fn record_change(
+        connection: &PgConnection,
+        user_id: &UserId,
+        room_id: &RoomId,
+    ) -> Result<(), ApiError> {
+        diesel::delete(
+            room_tag_changes::table
+                .filter(room_tag_changes::user_id.eq(user_id))
+                .filter(room_tag_changes::room_id.eq(room_id)),
+        )
+        .execute(connection)?;
+
+        diesel::insert_into(room_tag_changes::table)
+            .values((
+                room_tag_changes::user_id.eq(user_id),
+                room_tag_changes::room_id.eq(room_id),
+            ))
+            .execute(connection)?;
+
         Ok(())
     }
 }
@@ -42,11 +42,13 @@ pub struct NewUser {
 }
 
 impl User {
-    /// Creates a new user in the database.
+    /// Creates a new user in the database, along with an access token valid for
+    /// `access_token_lifetime` seconds.
     pub fn create(
         connection: &PgConnection,
         new_user: &NewUser,
         macaroon_secret_key: &[u8],
+        access_token_lifetime: u32,
     ) -> Result<(Self, AccessToken), ApiError> {
         connection
             .transaction::<(Self, AccessToken), ApiError, _>(|| {
@@ -55,7 +57,12 @@ impl User {
                     .get_result(connection)
                     .map_err(ApiError::from)?;
 
-                let access_token = AccessToken::create(connection, &user.id, macaroon_secret_key)?;
+                let access_token = AccessToken::create(
+                    connection,
+                    &user.id,
+                    macaroon_secret_key,
+                    access_token_lifetime,
+                )?;
 
                 Ok((user, access_token))
             })
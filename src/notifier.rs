@@ -0,0 +1,315 @@
+//! Waking up requests that wait for new data.
+
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, PoisonError};
+use std::time::Instant;
+
+use iron::typemap::Key;
+use iron::{Plugin, Request};
+use persistent::Read;
+use ruma_identifiers::{RoomId, UserId};
+
+use crate::error::ApiError;
+
+/// Something a change can concern, and a waiting request can be interested in.
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub enum Topic {
+    /// A change to a room, such as a new event, seen by every member of the room.
+    Room(RoomId),
+    /// A change to a user's own data, or a change made by the user.
+    User(UserId),
+}
+
+/// Wakes up long-polling `/sync` requests whenever new data may be available to them.
+///
+/// Every change advances a position and records it for the topics it concerns. A request reads
+/// the position before querying the database, and can then sleep until one of its topics changes
+/// instead of querying again in a loop. Only the requests interested in a change are woken up.
+#[derive(Debug)]
+pub struct Notifier {
+    /// The changes so far and the waiting requests.
+    state: Mutex<NotifierState>,
+    /// The maximum number of `/sync` requests waiting at the same time.
+    max_waiters: usize,
+}
+
+/// The state shared by the users of a `Notifier`.
+#[derive(Debug, Default)]
+struct NotifierState {
+    /// The position of the last change.
+    position: u64,
+    /// The position of the last change of each topic.
+    changes: HashMap<Topic, u64>,
+    /// The requests currently waiting.
+    waiters: Vec<Waiter>,
+    /// The identifier given to the next waiting request.
+    next_waiter_id: u64,
+}
+
+/// A waiting request.
+#[derive(Debug)]
+struct Waiter {
+    /// Identifies the waiter, to remove it once it is done waiting.
+    id: u64,
+    /// The topics the waiter is interested in, or `None` for every change.
+    topics: Option<HashSet<Topic>>,
+    /// Signalled when a change concerns one of the topics.
+    wake_up: Arc<Condvar>,
+}
+
+impl Notifier {
+    /// Create a new `Notifier` allowing up to `max_waiters` `/sync` requests to wait at once.
+    pub fn new(max_waiters: usize) -> Self {
+        Self {
+            state: Mutex::new(NotifierState::default()),
+            max_waiters,
+        }
+    }
+
+    /// Extract the `Notifier` stored in the request.
+    pub fn from_request(request: &mut Request<'_, '_>) -> Result<Arc<Self>, ApiError> {
+        request.get::<Read<Self>>().map_err(ApiError::from)
+    }
+
+    /// The position of the last change.
+    pub fn current(&self) -> u64 {
+        self.lock().position
+    }
+
+    /// Record a change concerning the given topics and wake up the requests waiting for them.
+    ///
+    /// Requests waiting for every change are woken up even if no topic is given.
+    pub fn notify<I>(&self, topics: I)
+    where
+        I: IntoIterator<Item = Topic>,
+    {
+        let mut state = self.lock();
+
+        state.position += 1;
+
+        let position = state.position;
+        let topics: Vec<Topic> = topics.into_iter().collect();
+
+        for waiter in &state.waiters {
+            let is_interested = match waiter.topics {
+                Some(ref waiter_topics) => topics.iter().any(|topic| waiter_topics.contains(topic)),
+                None => true,
+            };
+
+            if is_interested {
+                waiter.wake_up.notify_one();
+            }
+        }
+
+        for topic in topics {
+            state.changes.insert(topic, position);
+        }
+    }
+
+    /// Block until one of the topics changes after the position `since` or the deadline has
+    /// passed.
+    ///
+    /// Returns whether a change happened before the deadline. Returns right away if too many
+    /// requests are already waiting, so that they cannot occupy every thread of the server.
+    pub fn wait(&self, topics: HashSet<Topic>, since: u64, deadline: Instant) -> bool {
+        self.wait_for(Some(topics), since, deadline)
+    }
+
+    /// Block until anything changes after the position `since` or the deadline has passed.
+    ///
+    /// Returns whether a change happened before the deadline. Not limited by the maximum number
+    /// of waiting requests, as it is meant for background workers.
+    pub fn wait_any(&self, since: u64, deadline: Instant) -> bool {
+        self.wait_for(None, since, deadline)
+    }
+
+    /// Register a waiter for the topics and block until it is woken up by a change or the
+    /// deadline has passed.
+    fn wait_for(&self, topics: Option<HashSet<Topic>>, since: u64, deadline: Instant) -> bool {
+        let mut state = self.lock();
+
+        if state.has_changed(topics.as_ref(), since) {
+            return true;
+        }
+
+        if topics.is_some() {
+            let waiting_requests = state
+                .waiters
+                .iter()
+                .filter(|waiter| waiter.topics.is_some())
+                .count();
+
+            if waiting_requests >= self.max_waiters {
+                return false;
+            }
+        }
+
+        let id = state.next_waiter_id;
+        let wake_up = Arc::new(Condvar::new());
+
+        state.next_waiter_id = state.next_waiter_id.wrapping_add(1);
+        state.waiters.push(Waiter {
+            id,
+            topics: topics.clone(),
+            wake_up: Arc::clone(&wake_up),
+        });
+
+        let changed = loop {
+            if state.has_changed(topics.as_ref(), since) {
+                break true;
+            }
+
+            let now = Instant::now();
+
+            if now >= deadline {
+                break false;
+            }
+
+            state = wake_up
+                .wait_timeout(state, deadline - now)
+                .unwrap_or_else(PoisonError::into_inner)
+                .0;
+        };
+
+        state.waiters.retain(|waiter| waiter.id != id);
+
+        changed
+    }
+
+    /// Lock the state.
+    ///
+    /// Every change to the state is completed before the lock is released, so poisoning is
+    /// ignored.
+    fn lock(&self) -> MutexGuard<'_, NotifierState> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+impl NotifierState {
+    /// Whether one of the topics, or anything if `None`, changed after the position `since`.
+    fn has_changed(&self, topics: Option<&HashSet<Topic>>, since: u64) -> bool {
+        match topics {
+            Some(topics) => topics.iter().any(|topic| {
+                self.changes
+                    .get(topic)
+                    .map_or(false, |&position| position > since)
+            }),
+            None => self.position > since,
+        }
+    }
+}
+
+impl Key for Notifier {
+    type Value = Self;
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+    use std::convert::TryFrom;
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    use ruma_identifiers::{RoomId, UserId};
+
+    use super::{Notifier, Topic};
+
+    fn room(room_id: &str) -> Topic {
+        Topic::Room(RoomId::try_from(room_id).unwrap())
+    }
+
+    fn topics(topics: Vec<Topic>) -> HashSet<Topic> {
+        topics.into_iter().collect()
+    }
+
+    #[test]
+    fn wait_times_out_without_changes() {
+        let notifier = Notifier::new(1);
+        let since = notifier.current();
+        let start = Instant::now();
+
+        assert!(!notifier.wait(
+            topics(vec![room("!a:ruma.test")]),
+            since,
+            start + Duration::from_millis(50)
+        ));
+        assert!(start.elapsed() >= Duration::from_millis(50));
+    }
+
+    #[test]
+    fn wait_returns_immediately_after_missed_change() {
+        let notifier = Notifier::new(1);
+        let since = notifier.current();
+
+        notifier.notify(vec![room("!a:ruma.test")]);
+
+        assert!(notifier.wait(topics(vec![room("!a:ruma.test")]), since, Instant::now()));
+    }
+
+    #[test]
+    fn wait_ignores_changes_of_other_topics() {
+        let notifier = Notifier::new(1);
+        let since = notifier.current();
+        let user = Topic::User(UserId::try_from("@alice:ruma.test").unwrap());
+
+        notifier.notify(vec![room("!b:ruma.test"), user.clone()]);
+
+        assert!(!notifier.wait(topics(vec![room("!a:ruma.test")]), since, Instant::now()));
+        assert!(notifier.wait(topics(vec![user]), since, Instant::now()));
+        assert!(notifier.wait_any(since, Instant::now()));
+    }
+
+    #[test]
+    fn notify_wakes_up_waiting_threads() {
+        let notifier = Arc::new(Notifier::new(1));
+        let since = notifier.current();
+
+        let notifying = Arc::clone(&notifier);
+        let handle = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(50));
+            notifying.notify(vec![room("!a:ruma.test")]);
+        });
+
+        let start = Instant::now();
+
+        assert!(notifier.wait(
+            topics(vec![room("!a:ruma.test")]),
+            since,
+            start + Duration::from_secs(10)
+        ));
+        assert!(start.elapsed() < Duration::from_secs(10));
+
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn wait_returns_immediately_when_too_many_requests_wait() {
+        let notifier = Arc::new(Notifier::new(1));
+        let since = notifier.current();
+
+        let waiting = Arc::clone(&notifier);
+        let handle = thread::spawn(move || {
+            waiting.wait(
+                topics(vec![room("!a:ruma.test")]),
+                since,
+                Instant::now() + Duration::from_secs(10),
+            )
+        });
+
+        thread::sleep(Duration::from_millis(50));
+
+        let start = Instant::now();
+
+        assert!(!notifier.wait(
+            topics(vec![room("!b:ruma.test")]),
+            since,
+            start + Duration::from_secs(10)
+        ));
+        assert!(start.elapsed() < Duration::from_secs(10));
+
+        notifier.notify(vec![room("!a:ruma.test")]);
+
+        assert!(handle.join().unwrap());
+    }
+}
@@ -0,0 +1,763 @@
+//! Delivery of notifications to HTTP pushers.
+//!
+//! A background thread evaluates the push rules of the members of a room against every new event,
+//! and sends the events that notify them to the push gateways of their HTTP pushers.
+
+use std::cmp;
+use std::collections::{BTreeMap, HashMap};
+use std::convert::TryFrom;
+use std::fmt::{Debug, Formatter, Result as FmtResult};
+use std::io::Read;
+use std::sync::{Arc, Weak};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use diesel::pg::PgConnection;
+use diesel::r2d2::{ConnectionManager, Pool};
+use hyper::client::Client;
+use hyper::header::ContentType;
+use hyper::net::HttpsConnector;
+use hyper_native_tls::NativeTlsClient;
+use ruma_events::EventType;
+use ruma_identifiers::{EventId, RoomId, UserId};
+use serde_json::{from_str, to_string, Value};
+
+use crate::auth::AuthState;
+use crate::error::{ApiError, CliError};
+use crate::models::event::Event;
+use crate::models::profile::Profile;
+use crate::models::push_rule::Action;
+use crate::models::pusher::Pusher;
+use crate::models::room_membership::RoomMembership;
+use crate::notifier::Notifier;
+use crate::push_rules::{highlights, notifies, PushRuleEvaluator};
+
+/// How often the worker checks whether the server is still running while nothing happens.
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// The maximum number of events loaded from the database at once.
+const EVENT_BATCH_SIZE: i64 = 100;
+
+/// How long the worker keeps looking for events older than one it has already evaluated.
+///
+/// Orderings are assigned when events are inserted, so an event can become visible after events
+/// with a greater ordering if its transaction commits later than theirs.
+const RESCAN_WINDOW: Duration = Duration::from_secs(30);
+
+/// The maximum number of push gateways notifications are sent to at the same time.
+const MAX_CONCURRENT_GATEWAYS: usize = 8;
+
+/// The number of times a notification is sent before giving up.
+const MAX_ATTEMPTS: u32 = 5;
+
+/// The delay before the first retry of a notification, doubled for every following retry.
+const INITIAL_RETRY_DELAY: Duration = Duration::from_secs(1);
+
+/// The maximum time to wait for a push gateway to accept or answer a request.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The body of a request to the `/_matrix/push/v1/notify` endpoint of a push gateway.
+#[derive(Clone, Debug, Serialize)]
+pub struct NotifyRequest {
+    /// The notification to deliver.
+    pub notification: Notification,
+}
+
+/// A notification about an event.
+#[derive(Clone, Debug, Serialize)]
+pub struct Notification {
+    /// The ID of the event.
+    pub event_id: EventId,
+    /// The room the event was sent in.
+    pub room_id: RoomId,
+    /// The type of the event.
+    #[serde(rename = "type")]
+    pub event_type: String,
+    /// The user who sent the event.
+    pub sender: UserId,
+    /// The display name of the sender, if they have one.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sender_display_name: Option<String>,
+    /// Whether the user receiving the notification is the target of a membership event.
+    pub user_is_target: bool,
+    /// `high` for notifications that should wake up the device, `low` otherwise.
+    pub prio: String,
+    /// The content of the event.
+    pub content: Value,
+    /// The unread notifications of the user.
+    pub counts: NotificationCounts,
+    /// The devices the notification is for.
+    pub devices: Vec<Device>,
+}
+
+/// The unread notifications of the user receiving a notification.
+#[derive(Clone, Copy, Debug, Serialize)]
+pub struct NotificationCounts {
+    /// The number of unread notifications in the room of the event.
+    pub unread: u64,
+}
+
+/// A device a notification is delivered to.
+#[derive(Clone, Debug, Serialize)]
+pub struct Device {
+    /// The application ID of the pusher.
+    pub app_id: String,
+    /// The push key of the pusher.
+    pub pushkey: String,
+    /// The tweaks set by the push rule that matched the event, such as `sound`.
+    pub tweaks: HashMap<String, Value>,
+}
+
+/// The body of a response from a push gateway.
+#[derive(Debug, Default, Deserialize)]
+struct NotifyResponse {
+    /// The push keys the push gateway does not accept anymore.
+    #[serde(default)]
+    rejected: Vec<String>,
+}
+
+/// A notification waiting to be sent to a pusher.
+#[derive(Clone, Debug)]
+struct PendingPush {
+    /// The pusher to send the notification to.
+    pusher: Pusher,
+    /// The request to send.
+    request: NotifyRequest,
+    /// The number of failed attempts so far.
+    attempts: u32,
+    /// The time of the next attempt.
+    next_attempt: Instant,
+}
+
+/// The outcome of sending a notification.
+#[derive(Debug)]
+enum Delivery {
+    /// The push gateway accepted the notification, except for the given push keys.
+    Sent(Vec<String>),
+    /// The notification could not be delivered, but may be later.
+    Failed(String),
+    /// The push gateway refused the notification, so it is not sent again.
+    Refused(String),
+}
+
+/// Sends notifications to HTTP pushers in the background.
+pub struct PushWorker {
+    /// The database connection pool.
+    pool: Pool<ConnectionManager<PgConnection>>,
+    /// Signals new data. The worker stops once the server holding the notifier is gone.
+    notifier: Weak<Notifier>,
+    /// The HTTP client used to reach push gateways, shared by the threads sending notifications.
+    client: Arc<Client>,
+    /// The ordering up to which every event has been evaluated.
+    watermark: i64,
+    /// The events evaluated after the watermark, with the time they were first seen.
+    recent: BTreeMap<i64, Instant>,
+    /// The notifications waiting to be sent, including those to retry.
+    pending: Vec<PendingPush>,
+}
+
+impl PushWorker {
+    /// Start sending notifications about every event created from now on, in a new thread.
+    pub fn spawn(
+        pool: Pool<ConnectionManager<PgConnection>>,
+        notifier: &Arc<Notifier>,
+    ) -> Result<(), CliError> {
+        let mut client = Client::with_connector(HttpsConnector::new(NativeTlsClient::new()?));
+        client.set_read_timeout(Some(REQUEST_TIMEOUT));
+        client.set_write_timeout(Some(REQUEST_TIMEOUT));
+
+        let worker = Self {
+            pool,
+            notifier: Arc::downgrade(notifier),
+            client: Arc::new(client),
+            watermark: 0,
+            recent: BTreeMap::new(),
+            pending: Vec::new(),
+        };
+
+        thread::Builder::new()
+            .name("push".to_string())
+            .spawn(move || worker.run())?;
+
+        Ok(())
+    }
+
+    /// Evaluate new events and send notifications until the server is gone.
+    fn run(mut self) {
+        let mut since = match self.notifier.upgrade() {
+            Some(notifier) => notifier.current(),
+            None => return,
+        };
+
+        // Events created before the worker started are not pushed.
+        let latest_ordering = self
+            .pool
+            .get()
+            .map_err(ApiError::from)
+            .and_then(|connection| Event::latest_ordering(&connection));
+
+        self.watermark = match latest_ordering {
+            Ok(latest_ordering) => latest_ordering,
+            Err(error) => {
+                error!(
+                    "Failed to start sending notifications to pushers: {}",
+                    error
+                );
+
+                return;
+            }
+        };
+
+        let mut changed = true;
+
+        loop {
+            if changed {
+                if let Err(error) = self.queue_new_events() {
+                    error!("Failed to load new events for pushers: {}", error);
+                }
+            }
+
+            self.send_due_notifications();
+
+            let notifier = match self.notifier.upgrade() {
+                Some(notifier) => notifier,
+                None => return,
+            };
+
+            let deadline = self
+                .pending
+                .iter()
+                .map(|push| push.next_attempt)
+                .fold(Instant::now() + POLL_INTERVAL, cmp::min);
+
+            changed = notifier.wait_any(since, deadline);
+            since = notifier.current();
+        }
+    }
+
+    /// Queue notifications for the events that appeared since the last call.
+    ///
+    /// Events created in the last `RESCAN_WINDOW` are looked for again, skipping those already
+    /// evaluated, so that events whose transaction committed late are not missed.
+    fn queue_new_events(&mut self) -> Result<(), ApiError> {
+        let connection = self.pool.get()?;
+
+        let excluded: Vec<i64> = self.recent.keys().cloned().collect();
+        let mut since = self.watermark;
+
+        loop {
+            let events = Event::find_events_since(&connection, since, &excluded, EVENT_BATCH_SIZE)?;
+            let now = Instant::now();
+
+            for event in &events {
+                since = event.ordering;
+                self.recent.insert(event.ordering, now);
+
+                match pushes_for_event(&connection, event) {
+                    Ok(pushes) => self.pending.extend(pushes),
+                    Err(error) => error!(
+                        "Failed to evaluate push rules for event {}: {}",
+                        event.id, error
+                    ),
+                }
+            }
+
+            if events.len() < EVENT_BATCH_SIZE as usize {
+                break;
+            }
+        }
+
+        self.advance_watermark();
+
+        Ok(())
+    }
+
+    /// Stop looking for events older than the ones seen more than `RESCAN_WINDOW` ago.
+    fn advance_watermark(&mut self) {
+        let now = Instant::now();
+
+        for (&ordering, &seen_at) in &self.recent {
+            if now.duration_since(seen_at) >= RESCAN_WINDOW {
+                self.watermark = cmp::max(self.watermark, ordering);
+            }
+        }
+
+        self.recent = self.recent.split_off(&(self.watermark + 1));
+    }
+
+    /// Send the notifications whose next attempt is due, and schedule retries for the failures.
+    ///
+    /// Notifications are sent in order to each push gateway, and to several gateways at once so
+    /// that a slow gateway does not hold up the others.
+    fn send_due_notifications(&mut self) {
+        let now = Instant::now();
+        let (due, waiting): (Vec<PendingPush>, Vec<PendingPush>) = self
+            .pending
+            .drain(..)
+            .partition(|push| push.next_attempt <= now);
+
+        self.pending = waiting;
+
+        let mut pushes_by_gateway: HashMap<Option<String>, Vec<PendingPush>> = HashMap::new();
+
+        for push in due {
+            pushes_by_gateway
+                .entry(push.pusher.url.clone())
+                .or_default()
+                .push(push);
+        }
+
+        let mut gateways: Vec<Vec<PendingPush>> = pushes_by_gateway
+            .into_iter()
+            .map(|(_, pushes)| pushes)
+            .collect();
+
+        while !gateways.is_empty() {
+            let count = cmp::min(gateways.len(), MAX_CONCURRENT_GATEWAYS);
+
+            let senders: Vec<_> = gateways
+                .drain(..count)
+                .map(|pushes| {
+                    let client = Arc::clone(&self.client);
+
+                    thread::spawn(move || {
+                        pushes
+                            .into_iter()
+                            .map(|push| {
+                                let delivery = send(&client, &push);
+
+                                (push, delivery)
+                            })
+                            .collect::<Vec<_>>()
+                    })
+                })
+                .collect();
+
+            for sender in senders {
+                match sender.join() {
+                    Ok(deliveries) => {
+                        for (push, delivery) in deliveries {
+                            self.handle_delivery(push, delivery);
+                        }
+                    }
+                    Err(_) => error!("A thread sending notifications to pushers panicked"),
+                }
+            }
+        }
+    }
+
+    /// Act on the outcome of sending a notification.
+    fn handle_delivery(&mut self, mut push: PendingPush, delivery: Delivery) {
+        match delivery {
+            Delivery::Sent(rejected) => {
+                if rejected.contains(&push.pusher.pushkey) {
+                    if let Err(error) = self.remove_pusher(&push.pusher) {
+                        error!("Failed to remove a rejected pusher: {}", error);
+                    }
+                }
+            }
+            Delivery::Failed(reason) => {
+                push.attempts += 1;
+
+                if push.attempts < MAX_ATTEMPTS {
+                    debug!("Retrying a notification later: {}", reason);
+
+                    push.next_attempt =
+                        Instant::now() + INITIAL_RETRY_DELAY * 2u32.pow(push.attempts - 1);
+                    self.pending.push(push);
+                } else {
+                    warn!("Giving up on a notification: {}", reason);
+                }
+            }
+            Delivery::Refused(reason) => {
+                warn!("A push gateway refused a notification: {}", reason)
+            }
+        }
+    }
+
+    /// Remove a pusher whose push key was rejected by its push gateway.
+    fn remove_pusher(&self, pusher: &Pusher) -> Result<(), ApiError> {
+        let connection = self.pool.get()?;
+
+        Pusher::delete(&connection, &pusher.user_id, &pusher.app_id)
+    }
+}
+
+impl Debug for PushWorker {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        f.debug_struct("PushWorker")
+            .field("pool", &"Pool { ... }")
+            .field("watermark", &self.watermark)
+            .field("recent", &self.recent)
+            .field("pending", &self.pending)
+            .finish()
+    }
+}
+
+/// Return the notifications an event triggers for the HTTP pushers of the users in its room.
+fn pushes_for_event(
+    connection: &PgConnection,
+    event: &Event,
+) -> Result<Vec<PendingPush>, ApiError> {
+    let room_id = match event.room_id {
+        Some(ref room_id) => room_id,
+        None => return Ok(Vec::new()),
+    };
+
+    let room_state = AuthState::load(connection, room_id)?;
+    let mut recipients: Vec<UserId> = room_state.joined_members().cloned().collect();
+
+    // The target of a membership event may not have joined, but is still told about invites.
+    let target = match event.state_key {
+        Some(ref state_key) if event.event_type == EventType::RoomMember.to_string() => {
+            Some(UserId::try_from(state_key.as_str())?)
+        }
+        _ => None,
+    };
+
+    if let Some(ref target) = target {
+        if !recipients.contains(target) {
+            recipients.push(target.clone());
+        }
+    }
+
+    let content: Value = from_str(&event.content)?;
+    let sender_display_name =
+        Profile::find_by_uid(connection, &event.sender)?.and_then(|profile| profile.displayname);
+
+    let mut pushes = Vec::new();
+
+    for user_id in recipients {
+        let pushers: Vec<Pusher> = Pusher::find_by_uid(connection, &user_id)?
+            .into_iter()
+            .filter(|pusher| pusher.kind == "http" && pusher.url.is_some())
+            .collect();
+
+        if pushers.is_empty() {
+            continue;
+        }
+
+        let display_name =
+            Profile::find_by_uid(connection, &user_id)?.and_then(|profile| profile.displayname);
+        let push_rules = PushRuleEvaluator::load(connection, &user_id, display_name)?;
+
+        let actions = match push_rules.evaluate(event, &room_state)? {
+            Some(ref actions) if notifies(actions) => actions.clone(),
+            _ => continue,
+        };
+
+        let unread = match RoomMembership::find(connection, room_id, &user_id)? {
+            Some(room_membership) => {
+                push_rules
+                    .count_unread(connection, &room_membership)?
+                    .notifications
+            }
+            None => 0,
+        };
+
+        let tweaks = tweaks(&actions);
+        let prio = if highlights(&actions) || tweaks.contains_key("sound") {
+            "high"
+        } else {
+            "low"
+        };
+
+        for pusher in pushers {
+            let notification = Notification {
+                event_id: event.id.clone(),
+                room_id: room_id.clone(),
+                event_type: event.event_type.clone(),
+                sender: event.sender.clone(),
+                sender_display_name: sender_display_name.clone(),
+                user_is_target: target.as_ref() == Some(&user_id),
+                prio: prio.to_string(),
+                content: content.clone(),
+                counts: NotificationCounts { unread },
+                devices: vec![Device {
+                    app_id: pusher.app_id.clone(),
+                    pushkey: pusher.pushkey.clone(),
+                    tweaks: tweaks.clone(),
+                }],
+            };
+
+            pushes.push(PendingPush {
+                pusher,
+                request: NotifyRequest { notification },
+                attempts: 0,
+                next_attempt: Instant::now(),
+            });
+        }
+    }
+
+    Ok(pushes)
+}
+
+/// Send a notification to the push gateway of its pusher.
+fn send(client: &Client, push: &PendingPush) -> Delivery {
+    let url = match push.pusher.url {
+        Some(ref url) => url,
+        None => return Delivery::Refused("The pusher has no URL".to_string()),
+    };
+
+    let body = match to_string(&push.request) {
+        Ok(body) => body,
+        Err(error) => return Delivery::Refused(error.to_string()),
+    };
+
+    let result = client
+        .post(url.as_str())
+        .header(ContentType::json())
+        .body(body.as_str())
+        .send();
+
+    let mut response = match result {
+        Ok(response) => response,
+        Err(error) => return Delivery::Failed(format!("{}: {}", url, error)),
+    };
+
+    if response.status.is_server_error() {
+        return Delivery::Failed(format!("{} responded with {}", url, response.status));
+    }
+
+    if !response.status.is_success() {
+        return Delivery::Refused(format!("{} responded with {}", url, response.status));
+    }
+
+    let mut body = String::new();
+
+    if let Err(error) = response.read_to_string(&mut body) {
+        return Delivery::Failed(format!("{}: {}", url, error));
+    }
+
+    // The notification was accepted, even if the push gateway did not say which keys it
+    // rejected.
+    let response: NotifyResponse = from_str(&body).unwrap_or_default();
+
+    Delivery::Sent(response.rejected)
+}
+
+/// The tweaks set by push rule actions. A `highlight` tweak without a value is enabled.
+fn tweaks(actions: &[Action]) -> HashMap<String, Value> {
+    actions
+        .iter()
+        .filter_map(|action| match *action {
+            Action::SetTweak(ref tweak) => Some((
+                tweak.set_tweak.clone(),
+                tweak.value.clone().unwrap_or(Value::Bool(true)),
+            )),
+            _ => None,
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::{TcpListener, TcpStream};
+    use std::sync::mpsc::{channel, Receiver};
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    use iron::status::Status;
+    use serde_json::{from_str, Value};
+
+    use crate::models::pusher::{PusherData, PusherOptions};
+    use crate::test::{Test, TestUser};
+
+    /// Start a push gateway answering requests with the given statuses and bodies in turn.
+    ///
+    /// Returns the URL of its notify endpoint and the bodies of the requests it receives.
+    fn stub_gateway(responses: Vec<(u16, &'static str)>) -> (String, Receiver<Value>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!(
+            "http://{}/_matrix/push/v1/notify",
+            listener.local_addr().unwrap()
+        );
+        let (sender, receiver) = channel();
+
+        thread::spawn(move || {
+            for (status, body) in responses {
+                let (mut stream, _) = listener.accept().unwrap();
+                let request_body = read_request_body(&mut stream);
+
+                write!(
+                    stream,
+                    "HTTP/1.1 {} Stub\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    status,
+                    body.len(),
+                    body
+                )
+                .unwrap();
+
+                if sender.send(from_str(&request_body).unwrap()).is_err() {
+                    return;
+                }
+            }
+        });
+
+        (url, receiver)
+    }
+
+    fn read_request_body(stream: &mut TcpStream) -> String {
+        let mut reader = BufReader::new(stream);
+        let mut content_length = 0;
+
+        loop {
+            let mut line = String::new();
+            reader.read_line(&mut line).unwrap();
+
+            if line.trim().is_empty() {
+                break;
+            }
+
+            let mut parts = line.splitn(2, ':');
+            let name = parts.next().unwrap().trim().to_lowercase();
+
+            if name == "content-length" {
+                content_length = parts.next().unwrap().trim().parse().unwrap();
+            }
+        }
+
+        let mut body = vec![0; content_length];
+        reader.read_exact(&mut body).unwrap();
+
+        String::from_utf8(body).unwrap()
+    }
+
+    fn set_http_pusher(test: &Test, user: &TestUser, url: String, pushkey: &str) {
+        let options = PusherOptions {
+            lang: "en".to_string(),
+            kind: "http".to_string(),
+            data: PusherData { url: Some(url) },
+            device_display_name: "phone".to_string(),
+            app_id: "io.ruma.test".to_string(),
+            profile_tag: None,
+            pushkey: pushkey.to_string(),
+            app_display_name: "Ruma".to_string(),
+            append: false,
+        };
+
+        assert_eq!(test.set_pusher(&user.token, options).status, Status::Ok);
+    }
+
+    #[test]
+    fn notify_http_pusher() {
+        let test = Test::new();
+        let (alice, room_id) = test.initial_fixtures(r#"{"visibility": "public"}"#);
+        let bob = test.create_user();
+        let (url, requests) = stub_gateway(vec![(200, r#"{"rejected": []}"#)]);
+
+        assert_eq!(test.join_room(&bob.token, &room_id).status, Status::Ok);
+        set_http_pusher(&test, &bob, url, "pushkey");
+
+        let response = test.send_message(&alice.token, &room_id, "Hi", 1);
+        let event_id = response.json().get("event_id").unwrap().as_str().unwrap();
+
+        let request = requests.recv_timeout(Duration::from_secs(10)).unwrap();
+        let notification = request.get("notification").unwrap();
+
+        assert_eq!(
+            notification.get("event_id").unwrap().as_str().unwrap(),
+            format!("${}:ruma.test", event_id)
+        );
+        assert_eq!(
+            notification.get("room_id").unwrap().as_str().unwrap(),
+            room_id
+        );
+        assert_eq!(
+            notification.get("type").unwrap().as_str().unwrap(),
+            "m.room.message"
+        );
+        assert_eq!(
+            notification.get("sender").unwrap().as_str().unwrap(),
+            alice.id
+        );
+        assert_eq!(notification.pointer("/content/body").unwrap(), "Hi");
+        assert_eq!(notification.pointer("/counts/unread").unwrap(), 1);
+        assert_eq!(
+            notification.pointer("/devices/0/pushkey").unwrap(),
+            "pushkey"
+        );
+        assert_eq!(
+            notification.pointer("/devices/0/tweaks/highlight").unwrap(),
+            false
+        );
+    }
+
+    #[test]
+    fn notify_pushers_of_several_gateways() {
+        let test = Test::new();
+        let (alice, room_id) = test.initial_fixtures(r#"{"visibility": "public"}"#);
+        let bob = test.create_user();
+        let carl = test.create_user();
+        let (bob_url, bob_requests) = stub_gateway(vec![(200, r#"{"rejected": []}"#)]);
+        let (carl_url, carl_requests) = stub_gateway(vec![(200, r#"{"rejected": []}"#)]);
+
+        assert_eq!(test.join_room(&bob.token, &room_id).status, Status::Ok);
+        assert_eq!(test.join_room(&carl.token, &room_id).status, Status::Ok);
+        set_http_pusher(&test, &bob, bob_url, "pushkey");
+        set_http_pusher(&test, &carl, carl_url, "other_pushkey");
+
+        test.send_message(&alice.token, &room_id, "Hi", 1);
+
+        let bob_request = bob_requests.recv_timeout(Duration::from_secs(10)).unwrap();
+        let carl_request = carl_requests.recv_timeout(Duration::from_secs(10)).unwrap();
+
+        assert_eq!(
+            bob_request.pointer("/notification/content/body").unwrap(),
+            "Hi"
+        );
+        assert_eq!(
+            carl_request.pointer("/notification/content/body").unwrap(),
+            "Hi"
+        );
+    }
+
+    #[test]
+    fn retry_failed_notifications() {
+        let test = Test::new();
+        let (alice, room_id) = test.initial_fixtures(r#"{"visibility": "public"}"#);
+        let bob = test.create_user();
+        let (url, requests) = stub_gateway(vec![(503, "{}"), (200, r#"{"rejected": []}"#)]);
+
+        assert_eq!(test.join_room(&bob.token, &room_id).status, Status::Ok);
+        set_http_pusher(&test, &bob, url, "pushkey");
+
+        test.send_message(&alice.token, &room_id, "Hi", 1);
+
+        let first = requests.recv_timeout(Duration::from_secs(10)).unwrap();
+        let second = requests.recv_timeout(Duration::from_secs(10)).unwrap();
+
+        assert_eq!(first, second);
+    }
+
+    #[test]
+    fn remove_rejected_pushers() {
+        let test = Test::new();
+        let (alice, room_id) = test.initial_fixtures(r#"{"visibility": "public"}"#);
+        let bob = test.create_user();
+        let (url, requests) = stub_gateway(vec![(200, r#"{"rejected": ["pushkey"]}"#)]);
+
+        assert_eq!(test.join_room(&bob.token, &room_id).status, Status::Ok);
+        set_http_pusher(&test, &bob, url, "pushkey");
+
+        test.send_message(&alice.token, &room_id, "Hi", 1);
+
+        requests.recv_timeout(Duration::from_secs(10)).unwrap();
+
+        let pushers_path = format!("/_matrix/client/r0/pushers?access_token={}", bob.token);
+        let deadline = Instant::now() + Duration::from_secs(10);
+
+        loop {
+            let response = test.get(&pushers_path);
+            let pushers = response.json().get("pushers").unwrap().as_array().unwrap();
+
+            if pushers.is_empty() {
+                break;
+            }
+
+            assert!(Instant::now() < deadline, "The pusher was not removed");
+            thread::sleep(Duration::from_millis(50));
+        }
+    }
+}
@@ -0,0 +1,609 @@
+//! Evaluation of push rules against events.
+//!
+//! The push rules of a user decide which events notify them and how, following the rules of the
+//! Matrix specification.
+
+use std::i64;
+use std::str::FromStr;
+
+use diesel::pg::PgConnection;
+use ruma_identifiers::UserId;
+use serde_json::{from_str, json, Value};
+
+use crate::auth::AuthState;
+use crate::error::ApiError;
+use crate::models::event::{Direction, Event};
+use crate::models::push_rule::{Action, PushCondition, PushRule, PushRuleKind};
+use crate::models::receipt::Receipt;
+use crate::models::room_membership::RoomMembership;
+
+/// The maximum number of unread events inspected when counting the notifications of a room, so
+/// that rooms with a long unread history do not slow down every sync.
+const MAX_UNREAD_EVENTS: i64 = 100;
+
+/// The enabled push rules of a user, ready to be evaluated against events.
+#[derive(Clone, Debug)]
+pub struct PushRuleEvaluator {
+    /// The user the rules belong to.
+    user_id: UserId,
+    /// The display name of the user, matched by `contains_display_name` conditions.
+    display_name: Option<String>,
+    /// The enabled rules, in the order they are evaluated.
+    rules: Vec<Rule>,
+}
+
+/// An enabled push rule with its conditions and actions parsed.
+#[derive(Clone, Debug)]
+struct Rule {
+    /// The kind of the rule.
+    kind: PushRuleKind,
+    /// The ID of the rule.
+    rule_id: String,
+    /// The conditions of an override or underride rule.
+    conditions: Vec<PushCondition>,
+    /// The pattern of a content rule.
+    pattern: Option<String>,
+    /// The actions of the rule.
+    actions: Vec<Action>,
+}
+
+/// The number of unread notifications of a user in a room.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct UnreadCounts {
+    /// The number of events that notify the user.
+    pub notifications: u64,
+    /// The number of those notifications that are highlighted.
+    pub highlights: u64,
+}
+
+/// A piece of a glob pattern.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum GlobToken {
+    /// `*`, matching any number of characters.
+    AnyString,
+    /// `?`, matching exactly one character.
+    AnyChar,
+    /// A lowercase character matching itself.
+    Char(char),
+}
+
+impl PushRuleEvaluator {
+    /// Create an evaluator for the given rules, which must be in the order they are evaluated.
+    pub fn new(
+        user_id: UserId,
+        display_name: Option<String>,
+        rules: Vec<PushRule>,
+    ) -> Result<Self, ApiError> {
+        let rules = rules
+            .into_iter()
+            .filter(|rule| rule.enabled)
+            .map(|rule| {
+                Ok(Rule {
+                    kind: PushRuleKind::from_str(&rule.kind).map_err(ApiError::unknown)?,
+                    conditions: rule.conditions()?,
+                    actions: rule.actions()?,
+                    rule_id: rule.rule_id,
+                    pattern: rule.pattern,
+                })
+            })
+            .collect::<Result<Vec<Rule>, ApiError>>()?;
+
+        Ok(Self {
+            user_id,
+            display_name,
+            rules,
+        })
+    }
+
+    /// Load the push rules of a user.
+    pub fn load(
+        connection: &PgConnection,
+        user_id: &UserId,
+        display_name: Option<String>,
+    ) -> Result<Self, ApiError> {
+        let rules = PushRule::find_by_user(connection, user_id)?;
+
+        Self::new(user_id.clone(), display_name, rules)
+    }
+
+    /// Return the actions of the first rule matching an event, given the current state of its
+    /// room.
+    ///
+    /// Events sent by the user never match, as users are not notified about their own events.
+    pub fn evaluate(
+        &self,
+        event: &Event,
+        room_state: &AuthState,
+    ) -> Result<Option<Vec<Action>>, ApiError> {
+        if event.sender == self.user_id {
+            return Ok(None);
+        }
+
+        let content: Value = from_str(&event.content)?;
+        let event_json = json!({
+            "type": event.event_type,
+            "room_id": event.room_id,
+            "sender": event.sender,
+            "state_key": event.state_key,
+            "content": content,
+        });
+
+        let rule = self
+            .rules
+            .iter()
+            .find(|rule| self.rule_matches(rule, event, &event_json, room_state));
+
+        Ok(rule.map(|rule| rule.actions.clone()))
+    }
+
+    /// Count the notifications in a room since the user's read receipt, or since their
+    /// membership event if they have not sent one.
+    ///
+    /// Only the latest `MAX_UNREAD_EVENTS` events are counted.
+    pub fn count_unread(
+        &self,
+        connection: &PgConnection,
+        room_membership: &RoomMembership,
+    ) -> Result<UnreadCounts, ApiError> {
+        let receipt = Receipt::find(
+            connection,
+            &room_membership.room_id,
+            &self.user_id,
+            "m.read",
+        )?;
+        let since = match receipt {
+            Some(receipt) => receipt.event_ordering,
+            None => match Event::find(connection, &room_membership.event_id)? {
+                Some(event) => event.ordering,
+                None => -1,
+            },
+        };
+
+        let room_state = AuthState::load(connection, &room_membership.room_id)?;
+        let mut counts = UnreadCounts::default();
+
+        let unread_events = Event::paginate_room_events(
+            connection,
+            &room_membership.room_id,
+            i64::MAX,
+            Some(since),
+            Direction::Backward,
+            MAX_UNREAD_EVENTS,
+            None,
+        )?;
+
+        for event in unread_events {
+            let actions = match self.evaluate(&event, &room_state)? {
+                Some(actions) => actions,
+                None => continue,
+            };
+
+            if !notifies(&actions) {
+                continue;
+            }
+
+            counts.notifications += 1;
+
+            if highlights(&actions) {
+                counts.highlights += 1;
+            }
+        }
+
+        Ok(counts)
+    }
+
+    /// Whether a rule matches an event.
+    fn rule_matches(
+        &self,
+        rule: &Rule,
+        event: &Event,
+        event_json: &Value,
+        room_state: &AuthState,
+    ) -> bool {
+        match rule.kind {
+            PushRuleKind::Override | PushRuleKind::Underride => rule
+                .conditions
+                .iter()
+                .all(|condition| self.condition_matches(condition, event, event_json, room_state)),
+            PushRuleKind::Content => match (rule.pattern.as_ref(), body(event_json)) {
+                (Some(pattern), Some(body)) => matches_words(&glob_tokens(pattern), body),
+                _ => false,
+            },
+            PushRuleKind::Room => match event.room_id {
+                Some(ref room_id) => room_id.to_string() == rule.rule_id,
+                None => false,
+            },
+            PushRuleKind::Sender => event.sender.to_string() == rule.rule_id,
+        }
+    }
+
+    /// Whether a condition of an override or underride rule holds for an event.
+    fn condition_matches(
+        &self,
+        condition: &PushCondition,
+        event: &Event,
+        event_json: &Value,
+        room_state: &AuthState,
+    ) -> bool {
+        match *condition {
+            PushCondition::EventMatch {
+                ref key,
+                ref pattern,
+            } => {
+                let value = key
+                    .split('.')
+                    .try_fold(event_json, |value, field| value.get(field))
+                    .and_then(Value::as_str);
+
+                match value {
+                    // The body of messages is matched word by word, other properties as a whole.
+                    Some(value) if key == "content.body" => {
+                        matches_words(&glob_tokens(pattern), value)
+                    }
+                    Some(value) => matches_whole(&glob_tokens(pattern), value),
+                    None => false,
+                }
+            }
+            PushCondition::ContainsDisplayName => {
+                match (self.display_name.as_ref(), body(event_json)) {
+                    (Some(display_name), Some(body)) if !display_name.is_empty() => {
+                        let tokens: Vec<GlobToken> = lowercase_chars(display_name)
+                            .into_iter()
+                            .map(GlobToken::Char)
+                            .collect();
+
+                        matches_words(&tokens, body)
+                    }
+                    _ => false,
+                }
+            }
+            PushCondition::RoomMemberCount { ref is } => {
+                member_count_matches(is, room_state.joined_member_count())
+            }
+            PushCondition::SenderNotificationPermission { ref key } => {
+                room_state.has_notification_permission(&event.sender, key)
+            }
+        }
+    }
+}
+
+/// Whether the actions of a matching rule notify the user.
+pub fn notifies(actions: &[Action]) -> bool {
+    actions
+        .iter()
+        .any(|action| *action == Action::Notify || *action == Action::Coalesce)
+}
+
+/// Whether the actions of a matching rule highlight the notification.
+pub fn highlights(actions: &[Action]) -> bool {
+    actions.iter().any(|action| match *action {
+        Action::SetTweak(ref tweak) if tweak.set_tweak == "highlight" => tweak
+            .value
+            .as_ref()
+            .and_then(Value::as_bool)
+            .unwrap_or(true),
+        _ => false,
+    })
+}
+
+/// The body of an event's content, if it has one.
+fn body(event_json: &Value) -> Option<&str> {
+    event_json
+        .get("content")
+        .and_then(|content| content.get("body"))
+        .and_then(Value::as_str)
+}
+
+/// Parse a glob pattern, lowercased.
+fn glob_tokens(pattern: &str) -> Vec<GlobToken> {
+    lowercase_chars(pattern)
+        .into_iter()
+        .map(|character| match character {
+            '*' => GlobToken::AnyString,
+            '?' => GlobToken::AnyChar,
+            character => GlobToken::Char(character),
+        })
+        .collect()
+}
+
+/// Whether a pattern matches a whole value, ignoring case.
+fn matches_whole(tokens: &[GlobToken], value: &str) -> bool {
+    let text = lowercase_chars(value);
+
+    match_ends(tokens, &text, 0).contains(&text.len())
+}
+
+/// Whether a pattern matches a sequence of whole words within a value, ignoring case.
+fn matches_words(tokens: &[GlobToken], value: &str) -> bool {
+    let text = lowercase_chars(value);
+
+    (0..=text.len())
+        .filter(|&start| start == 0 || !is_word_char(text[start - 1]))
+        .any(|start| {
+            match_ends(tokens, &text, start)
+                .into_iter()
+                .any(|end| end == text.len() || !is_word_char(text[end]))
+        })
+}
+
+/// The positions in the text where a match of the pattern starting at the given position can end.
+///
+/// The positions are returned in ascending order.
+fn match_ends(tokens: &[GlobToken], text: &[char], start: usize) -> Vec<usize> {
+    let mut positions = vec![start];
+
+    for token in tokens {
+        positions = match *token {
+            GlobToken::AnyString => match positions.first() {
+                Some(&first) => (first..=text.len()).collect(),
+                None => Vec::new(),
+            },
+            GlobToken::AnyChar => positions
+                .into_iter()
+                .filter(|&position| position < text.len())
+                .map(|position| position + 1)
+                .collect(),
+            GlobToken::Char(character) => positions
+                .into_iter()
+                .filter(|&position| text.get(position) == Some(&character))
+                .map(|position| position + 1)
+                .collect(),
+        };
+    }
+
+    positions
+}
+
+/// The characters of a value, lowercased.
+fn lowercase_chars(value: &str) -> Vec<char> {
+    value.chars().flat_map(char::to_lowercase).collect()
+}
+
+/// Whether a character belongs to a word.
+fn is_word_char(character: char) -> bool {
+    character.is_alphanumeric() || character == '_'
+}
+
+/// Whether the number of joined members of a room satisfies a `room_member_count` condition.
+fn member_count_matches(is: &str, member_count: usize) -> bool {
+    let operator_length = is
+        .find(|character: char| character.is_ascii_digit())
+        .unwrap_or(is.len());
+    let (operator, number) = is.split_at(operator_length);
+
+    let number: usize = match number.parse() {
+        Ok(number) => number,
+        Err(_) => return false,
+    };
+
+    match operator {
+        "" | "==" => member_count == number,
+        "<" => member_count < number,
+        ">" => member_count > number,
+        "<=" => member_count <= number,
+        ">=" => member_count >= number,
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::TryFrom;
+
+    use diesel::pg::data_types::PgTimestamp;
+    use ruma_identifiers::{EventId, RoomId, UserId};
+    use serde_json::{json, Value};
+
+    use super::{
+        glob_tokens, highlights, matches_whole, matches_words, member_count_matches, notifies,
+        PushRuleEvaluator,
+    };
+    use crate::auth::AuthState;
+    use crate::models::event::{Event, NewEvent};
+    use crate::models::push_rule::default_rules;
+
+    const ALICE: &str = "@alice:ruma.test";
+    const BOB: &str = "@bob:ruma.test";
+
+    fn event(event_type: &str, state_key: Option<&str>, sender: &str, content: Value) -> Event {
+        Event {
+            id: EventId::new("ruma.test").unwrap(),
+            ordering: 1,
+            room_id: Some(RoomId::try_from("!room:ruma.test").unwrap()),
+            sender: UserId::try_from(sender).unwrap(),
+            event_type: event_type.to_string(),
+            state_key: state_key.map(str::to_string),
+            content: content.to_string(),
+            created_at: PgTimestamp(0),
+            redacts: None,
+            redacted_by: None,
+            transaction_id: None,
+            replaces_state: None,
+            prev_content: None,
+        }
+    }
+
+    fn message(sender: &str, body: &str) -> Event {
+        event(
+            "m.room.message",
+            None,
+            sender,
+            json!({ "msgtype": "m.text", "body": body }),
+        )
+    }
+
+    /// A room Alice and Bob have joined.
+    fn room_state() -> AuthState {
+        let mut state = AuthState::default();
+
+        for user_id in &[ALICE, BOB] {
+            let member_event = NewEvent {
+                event_type: "m.room.member".to_string(),
+                id: EventId::new("ruma.test").unwrap(),
+                content: json!({ "membership": "join" }).to_string(),
+                room_id: Some(RoomId::try_from("!room:ruma.test").unwrap()),
+                sender: UserId::try_from(*user_id).unwrap(),
+                state_key: Some(user_id.to_string()),
+                redacts: None,
+                transaction_id: None,
+                replaces_state: None,
+                prev_content: None,
+            };
+
+            state.apply(&member_event).unwrap();
+        }
+
+        state
+    }
+
+    fn evaluator() -> PushRuleEvaluator {
+        let user_id = UserId::try_from(BOB).unwrap();
+        let rules = default_rules(&user_id);
+
+        PushRuleEvaluator::new(user_id, Some("Robert".to_string()), rules).unwrap()
+    }
+
+    #[test]
+    fn glob_patterns() {
+        assert!(matches_whole(&glob_tokens("m.room.*"), "m.room.message"));
+        assert!(matches_whole(
+            &glob_tokens("M.ROOM.MESSAGE"),
+            "m.room.message"
+        ));
+        assert!(matches_whole(
+            &glob_tokens("m.room.messag?"),
+            "m.room.message"
+        ));
+        assert!(!matches_whole(&glob_tokens("m.room"), "m.room.message"));
+        assert!(!matches_whole(
+            &glob_tokens("m.room.messag?"),
+            "m.room.messages"
+        ));
+    }
+
+    #[test]
+    fn word_patterns() {
+        assert!(matches_words(&glob_tokens("cake"), "I like cake!"));
+        assert!(matches_words(&glob_tokens("cake*lie"), "The cake is a lie"));
+        assert!(matches_words(&glob_tokens("@room"), "Hey @room, look"));
+        assert!(!matches_words(&glob_tokens("cake"), "cupcakes"));
+        assert!(!matches_words(&glob_tokens("cake"), "cake_shop"));
+    }
+
+    #[test]
+    fn member_count_comparisons() {
+        assert!(member_count_matches("2", 2));
+        assert!(member_count_matches("==2", 2));
+        assert!(member_count_matches("<3", 2));
+        assert!(member_count_matches(">=2", 2));
+        assert!(!member_count_matches(">2", 2));
+        assert!(!member_count_matches("two", 2));
+    }
+
+    #[test]
+    fn default_rules_notify_about_messages() {
+        let actions = evaluator()
+            .evaluate(&message(ALICE, "Hi"), &room_state())
+            .unwrap()
+            .unwrap();
+
+        assert!(notifies(&actions));
+        assert!(!highlights(&actions));
+    }
+
+    #[test]
+    fn default_rules_highlight_mentions() {
+        let evaluator = evaluator();
+        let state = room_state();
+
+        for body in &["Hi bob", "Hi robert!"] {
+            let actions = evaluator
+                .evaluate(&message(ALICE, body), &state)
+                .unwrap()
+                .unwrap();
+
+            assert!(highlights(&actions), "{}", body);
+        }
+
+        let actions = evaluator
+            .evaluate(&message(ALICE, "Bobby"), &state)
+            .unwrap()
+            .unwrap();
+
+        assert!(!highlights(&actions));
+    }
+
+    #[test]
+    fn default_rules_suppress_notices_and_own_events() {
+        let evaluator = evaluator();
+        let state = room_state();
+
+        let notice = event(
+            "m.room.message",
+            None,
+            ALICE,
+            json!({ "msgtype": "m.notice", "body": "bob" }),
+        );
+        let actions = evaluator.evaluate(&notice, &state).unwrap().unwrap();
+        assert!(!notifies(&actions));
+
+        assert!(evaluator
+            .evaluate(&message(BOB, "Hi"), &state)
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    fn default_rules_notify_about_invites() {
+        let invite = event(
+            "m.room.member",
+            Some(BOB),
+            ALICE,
+            json!({ "membership": "invite" }),
+        );
+
+        let actions = evaluator()
+            .evaluate(&invite, &room_state())
+            .unwrap()
+            .unwrap();
+
+        assert!(notifies(&actions));
+    }
+
+    #[test]
+    fn room_notifications_require_power_level() {
+        let evaluator = evaluator();
+        let mut state = room_state();
+
+        let actions = evaluator
+            .evaluate(&message(ALICE, "@room hello"), &state)
+            .unwrap()
+            .unwrap();
+        assert!(!highlights(&actions));
+
+        let power_levels_event = NewEvent {
+            event_type: "m.room.power_levels".to_string(),
+            id: EventId::new("ruma.test").unwrap(),
+            content: json!({
+                "events": {},
+                "notifications": { "room": 100 },
+                "users": { ALICE: 100 }
+            })
+            .to_string(),
+            room_id: Some(RoomId::try_from("!room:ruma.test").unwrap()),
+            sender: UserId::try_from(ALICE).unwrap(),
+            state_key: Some(String::new()),
+            redacts: None,
+            transaction_id: None,
+            replaces_state: None,
+            prev_content: None,
+        };
+        state.apply(&power_levels_event).unwrap();
+
+        let actions = evaluator
+            .evaluate(&message(ALICE, "@room hello"), &state)
+            .unwrap()
+            .unwrap();
+        assert!(highlights(&actions));
+    }
+}
@@ -2,7 +2,7 @@
 
 use std::cmp;
 use std::collections::HashMap;
-use std::convert::TryInto;
+use std::convert::{TryFrom, TryInto};
 use std::fmt::{Display, Formatter, Result as FmtResult};
 use std::i64;
 use std::iter::Iterator;
@@ -10,20 +10,34 @@ use std::str::FromStr;
 
 use diesel::pg::PgConnection;
 use ruma_events::collections::all::{RoomEvent, StateEvent};
-use ruma_events::presence::PresenceEvent;
 use ruma_events::presence::PresenceState;
+use ruma_events::receipt::{Receipt as ReceiptInfo, ReceiptEvent, ReceiptEventContent, Receipts};
+use ruma_events::room::member::MemberEvent;
 use ruma_events::stripped::StrippedState;
+use ruma_events::tag::{TagEvent, TagEventContent};
+use ruma_events::typing::{TypingEvent, TypingEventContent};
 use ruma_events::EventType;
-use ruma_identifiers::RoomId;
-use serde_json::Value;
+use ruma_identifiers::{RoomId, UserId};
+use serde_json::{from_str, json, to_value, Value};
 
 use crate::error::ApiError;
+use crate::filtering::FilterEvaluator;
+use crate::lazy_loading::SentMembers;
+use crate::models::account_data::{AccountData, RoomAccountData};
 use crate::models::event::Event;
-use crate::models::filter::{ContentFilter, RoomEventFilter, RoomFilter};
+use crate::models::filter::ContentFilter;
 use crate::models::presence_list::PresenceList;
 use crate::models::presence_status::PresenceStatus;
+use crate::models::profile::Profile;
+use crate::models::receipt::Receipt;
 use crate::models::room_membership::RoomMembership;
+use crate::models::tags::RoomTag;
 use crate::models::user::User;
+use crate::push_rules::PushRuleEvaluator;
+use crate::typing::Typing;
+
+/// The maximum number of heroes in a room summary.
+const MAX_HEROES: usize = 5;
 
 /// Counts of unread notifications for a room.
 #[derive(Debug, Clone, Serialize)]
@@ -38,7 +52,7 @@ struct UnreadNotificationCounts {
 #[derive(Debug, Clone, Serialize)]
 struct Timeline {
     /// List of events.
-    events: Vec<RoomEvent>,
+    events: Vec<Value>,
     /// True if the number of events returned was limited by the limit on the filter.
     limited: bool,
     /// A token that can be supplied to to the from parameter of the `rooms/{roomId}/messages` endpoint.
@@ -56,7 +70,7 @@ struct Events<T> {
 #[derive(Debug, Clone, Serialize)]
 struct LeftRoom {
     /// The state updates for the room up to the start of the timeline.
-    state: Events<StateEvent>,
+    state: Events<Value>,
     /// The timeline of messages and state changes in the room up to the point when the user left.
     timeline: Timeline,
 }
@@ -65,7 +79,7 @@ struct LeftRoom {
 #[derive(Debug, Clone, Serialize)]
 struct InvitedRoom {
     /// The state of a room that the user has been invited to.
-    invite_state: Events<StrippedState>,
+    invite_state: Events<Value>,
 }
 
 /// Information about the rooms the user has joined.
@@ -78,12 +92,29 @@ struct JoinedRoom {
     /// Updates to the state, between the time indicated by the since parameter,
     /// and the start of the timeline (or all state up to the start of the timeline,
     /// if since is not given, or full_state is true).
-    state: Events<StateEvent>,
+    state: Events<Value>,
     /// The private data that this user has attached to this room.
     account_data: Events<Value>,
     /// The ephemeral events in the room that aren't recorded in the timeline or
     /// state of the room. e.g. typing.
     ephemeral: Events<Value>,
+    /// A summary of the room's members, if it changed since the since parameter.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    summary: Option<RoomSummary>,
+}
+
+/// A summary of a room's members, which clients use to name rooms without a name.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+struct RoomSummary {
+    /// The members to name the room after, not including the syncing user.
+    #[serde(rename = "m.heroes")]
+    heroes: Vec<UserId>,
+    /// The number of members who joined the room.
+    #[serde(rename = "m.joined_member_count")]
+    joined_member_count: u64,
+    /// The number of members who are invited to the room.
+    #[serde(rename = "m.invited_member_count")]
+    invited_member_count: u64,
 }
 
 /// Information about rooms the user has joined, been invited to, or left.
@@ -103,7 +134,9 @@ pub struct Sync {
     /// The batch token to supply in the since param of the next /sync request.
     next_batch: String,
     /// The updates to the presence status of other users.
-    presence: Events<PresenceEvent>,
+    presence: Events<Value>,
+    /// The global private data created by this user.
+    account_data: Events<Value>,
     /// Updates to rooms.
     rooms: Rooms,
 }
@@ -115,14 +148,29 @@ pub struct Batch {
     pub room_key: i64,
     /// The presence ordering key.
     pub presence_key: i64,
+    /// The typing notification position.
+    pub typing_key: i64,
+    /// The receipt ordering key.
+    pub receipt_key: i64,
+    /// The account data ordering key.
+    pub account_data_key: i64,
 }
 
 impl Batch {
     /// Create a new `Batch`.
-    pub fn new(room_key: i64, presence_key: i64) -> Self {
+    pub fn new(
+        room_key: i64,
+        presence_key: i64,
+        typing_key: i64,
+        receipt_key: i64,
+        account_data_key: i64,
+    ) -> Self {
         Self {
             room_key,
             presence_key,
+            typing_key,
+            receipt_key,
+            account_data_key,
         }
     }
 }
@@ -130,7 +178,15 @@ impl Batch {
 impl Display for Batch {
     /// Make a String from a `Batch`.
     fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
-        write!(f, "{}_{}", self.room_key, self.presence_key)
+        write!(
+            f,
+            "{}_{}_{}_{}_{}",
+            self.room_key,
+            self.presence_key,
+            self.typing_key,
+            self.receipt_key,
+            self.account_data_key
+        )
     }
 }
 
@@ -139,7 +195,7 @@ impl FromStr for Batch {
     fn from_str(s: &str) -> Result<Self, String> {
         let values: Vec<&str> = s.split('_').collect();
 
-        if values.len() != 2 {
+        if values.len() != 5 {
             return Err(String::from("Wrong number of tokens"));
         }
 
@@ -147,7 +203,19 @@ impl FromStr for Batch {
 
         let presence_key = i64::from_str_radix(values[1], 10).map_err(|err| err.to_string())?;
 
-        Ok(Self::new(room_key, presence_key))
+        let typing_key = i64::from_str_radix(values[2], 10).map_err(|err| err.to_string())?;
+
+        let receipt_key = i64::from_str_radix(values[3], 10).map_err(|err| err.to_string())?;
+
+        let account_data_key = i64::from_str_radix(values[4], 10).map_err(|err| err.to_string())?;
+
+        Ok(Self::new(
+            room_key,
+            presence_key,
+            typing_key,
+            receipt_key,
+            account_data_key,
+        ))
     }
 }
 
@@ -177,11 +245,58 @@ pub enum Context<'a> {
     Initial,
 }
 
+impl RoomSummary {
+    /// Summarize the members of a room for a user, given their user IDs and memberships.
+    ///
+    /// The heroes are the joined and invited members other than the user, or the members who
+    /// left or were banned if there are none, sorted by user ID.
+    fn new(user_id: &UserId, members: Vec<(UserId, String)>) -> Self {
+        let mut joined_member_count = 0;
+        let mut invited_member_count = 0;
+        let mut current_members = Vec::new();
+        let mut former_members = Vec::new();
+
+        for (member, membership) in members {
+            match membership.as_str() {
+                "join" => joined_member_count += 1,
+                "invite" => invited_member_count += 1,
+                _ => (),
+            }
+
+            if &member == user_id {
+                continue;
+            }
+
+            match membership.as_str() {
+                "join" | "invite" => current_members.push(member),
+                "leave" | "ban" => former_members.push(member),
+                _ => (),
+            }
+        }
+
+        let mut heroes = if current_members.is_empty() {
+            former_members
+        } else {
+            current_members
+        };
+        heroes.sort_by_key(ToString::to_string);
+        heroes.truncate(MAX_HEROES);
+
+        Self {
+            heroes,
+            joined_member_count,
+            invited_member_count,
+        }
+    }
+}
+
 impl Sync {
     /// Query sync.
     pub fn sync(
         connection: &PgConnection,
         homeserver_domain: &str,
+        typing: &Typing,
+        sent_members: &mut SentMembers,
         user: &User,
         options: SyncOptions,
     ) -> Result<Self, ApiError> {
@@ -195,10 +310,7 @@ impl Sync {
             }
         }
 
-        let filter_room = match options.filter {
-            Some(filter) => filter.room,
-            None => None,
-        };
+        let filter = FilterEvaluator::new(options.filter);
 
         let (presence_key, presence) = Self::get_presence_events(
             connection,
@@ -208,17 +320,53 @@ impl Sync {
             &context,
         )?;
 
-        let (room_key, rooms) = Self::get_rooms_events(connection, user, filter_room, &context)?;
-        let batch = Batch::new(room_key, presence_key);
+        // Read the position first, so that changes made while querying are reported again next
+        // time rather than missed.
+        let joined_room_ids =
+            RoomMembership::find_room_ids_by_uid_and_state(connection, &user.id, "join")?;
+        let typing_key = match context {
+            Context::Incremental(batch) => {
+                cmp::max(batch.typing_key, typing.position(&joined_room_ids))
+            }
+            Context::FullState(_) | Context::Initial => typing.position(&joined_room_ids),
+        };
+
+        let (account_data_key, account_data) =
+            Self::get_account_data_events(connection, user, &filter, &context)?;
+
+        let (batch, rooms) = Self::get_rooms_events(
+            connection,
+            user,
+            &filter,
+            &context,
+            typing,
+            sent_members,
+            Batch::new(0, presence_key, typing_key, 0, account_data_key),
+        )?;
         let state = Self {
             next_batch: batch.to_string(),
-            presence: Events { events: presence },
+            presence: Events {
+                events: filter.format_events(filter.filter_presence(presence)),
+            },
+            account_data: Events {
+                events: filter.format_events(account_data),
+            },
             rooms,
         };
 
         Ok(state)
     }
 
+    /// The token to sync from next time.
+    pub fn next_batch(&self) -> &str {
+        &self.next_batch
+    }
+
+    /// Whether the response contains anything that happened after the given batch.
+    pub fn has_updates_since(&self, since: &Batch) -> bool {
+        self.next_batch != since.to_string()
+    }
+
     /// Return presence events for sync from database and options.
     fn get_presence_events(
         connection: &PgConnection,
@@ -226,7 +374,7 @@ impl Sync {
         user: &User,
         set_presence: Option<PresenceState>,
         context: &Context<'_>,
-    ) -> Result<(i64, Vec<PresenceEvent>), ApiError> {
+    ) -> Result<(i64, Vec<Value>), ApiError> {
         let set_presence = match set_presence {
             Some(set_presence) => set_presence,
             None => PresenceState::Online,
@@ -245,26 +393,137 @@ impl Sync {
             Context::Initial => None,
         };
 
-        PresenceList::find_events_by_uid(connection, &user.id, since)
+        let (presence_key, events) = PresenceList::find_events_by_uid(connection, &user.id, since)?;
+        let events = events
+            .into_iter()
+            .map(to_value)
+            .collect::<Result<Vec<Value>, _>>()?;
+
+        Ok((presence_key, events))
+    }
+
+    /// Return the position in the account data stream to report changes after.
+    ///
+    /// Like ephemeral events, a full state sync reports all account data again.
+    fn account_data_since(context: &Context<'_>) -> i64 {
+        match *context {
+            Context::Incremental(batch) => batch.account_data_key,
+            Context::FullState(_) | Context::Initial => 0,
+        }
+    }
+
+    /// Return the global account data events of a user for sync.
+    ///
+    /// Also returns the position of the latest change, or the position of the given batch if
+    /// there is none.
+    fn get_account_data_events(
+        connection: &PgConnection,
+        user: &User,
+        filter: &FilterEvaluator,
+        context: &Context<'_>,
+    ) -> Result<(i64, Vec<Value>), ApiError> {
+        let mut ordering = match *context {
+            Context::Incremental(batch) | Context::FullState(batch) => batch.account_data_key,
+            Context::Initial => 0,
+        };
+        let mut events = Vec::new();
+
+        for data in AccountData::find_changed_since(
+            connection,
+            &user.id,
+            Self::account_data_since(context),
+        )? {
+            ordering = cmp::max(ordering, data.ordering);
+            events.push(Self::account_data_event(&data.data_type, &data.content)?);
+        }
+
+        Ok((ordering, filter.filter_account_data(events)))
+    }
+
+    /// Return the account data events of a user for a joined room.
+    ///
+    /// The tags of the room are reported as a single `m.tag` event whenever any of them changed.
+    /// Also returns the position of the latest change.
+    fn get_room_account_data_events(
+        connection: &PgConnection,
+        user_id: &UserId,
+        room_id: &RoomId,
+        filter: &FilterEvaluator,
+        since: i64,
+    ) -> Result<(i64, Vec<Value>), ApiError> {
+        let mut ordering = since;
+        let mut changes = Vec::new();
+
+        for data in RoomAccountData::find_changed_since(connection, user_id, room_id, since)? {
+            changes.push((data.ordering, data.data_type, Some(data.content)));
+        }
+
+        if let Some(tags_ordering) = RoomTag::changed_since(connection, user_id, room_id, since)? {
+            changes.push((tags_ordering, EventType::Tag.to_string(), None));
+            changes.sort_by_key(|&(ordering, _, _)| ordering);
+        }
+
+        let mut events = Vec::new();
+
+        for (data_ordering, data_type, content) in changes {
+            ordering = cmp::max(ordering, data_ordering);
+
+            let event = match content {
+                Some(content) => Self::account_data_event(&data_type, &content)?,
+                None => to_value(TagEvent {
+                    content: TagEventContent {
+                        tags: RoomTag::find(connection, user_id.clone(), room_id.clone())?,
+                    },
+                    event_type: EventType::Tag,
+                })?,
+            };
+            events.push(event);
+        }
+
+        Ok((ordering, filter.filter_room_account_data(room_id, events)))
+    }
+
+    /// Build an account data event from stored data.
+    fn account_data_event(data_type: &str, content: &str) -> Result<Value, ApiError> {
+        let content: Value = from_str(content)?;
+
+        Ok(json!({ "type": data_type, "content": content }))
     }
 
     /// Return rooms for sync from database and options.
+    ///
+    /// The presence and typing keys of `next_batch` are returned unchanged, while its room and
+    /// receipt keys are moved to the latest event and receipt returned, and its account data key
+    /// is moved forward to the latest room account data returned.
+    ///
+    /// When the filter lazy-loads members, the state only contains the member events of the
+    /// timeline senders that were not already sent to the client.
     fn get_rooms_events(
         connection: &PgConnection,
         user: &User,
-        room_filter: Option<RoomFilter>,
+        filter: &FilterEvaluator,
         context: &Context<'_>,
-    ) -> Result<(i64, Rooms), ApiError> {
+        typing: &Typing,
+        sent_members: &mut SentMembers,
+        mut next_batch: Batch,
+    ) -> Result<(Batch, Rooms), ApiError> {
         let mut join = HashMap::new();
         let mut invite = HashMap::new();
         let mut leave = HashMap::new();
 
         let room_memberships = RoomMembership::find_all_by_uid(connection, &user.id)?;
+        let profile = Profile::find_by_uid(connection, &user.id)?;
+        let displayname = profile.and_then(|profile| profile.displayname);
+        let push_rules = PushRuleEvaluator::load(connection, &user.id, displayname)?;
 
-        let mut room_ordering = match *context {
-            Context::Incremental(batch) | Context::FullState(batch) => batch.room_key,
-            Context::Initial => 0,
+        let (room_ordering, receipt_ordering) = match *context {
+            Context::Incremental(batch) | Context::FullState(batch) => {
+                (batch.room_key, batch.receipt_key)
+            }
+            Context::Initial => (0, 0),
         };
+        next_batch.room_key = room_ordering;
+        next_batch.receipt_key = receipt_ordering;
 
         let (is_full_state, since) = match *context {
             Context::Incremental(batch) => (false, batch.room_key),
@@ -272,18 +531,53 @@ impl Sync {
             Context::Initial => (false, -1),
         };
 
-        let (timeline_filter, include_leave) = match room_filter {
-            Some(filter) => (filter.timeline, filter.include_leave),
-            None => (None, false),
+        // Summaries are compared to the one at `since`, unless the client starts over.
+        let summary_since = match *context {
+            Context::Incremental(batch) => Some(batch.room_key),
+            Context::FullState(_) | Context::Initial => None,
+        };
+
+        // A full state sync reports ephemeral events the same way an initial sync does.
+        let (typing_since, receipt_since) = match *context {
+            Context::Incremental(batch) => (Some(batch.typing_key), batch.receipt_key),
+            Context::FullState(_) | Context::Initial => (None, 0),
+        };
+
+        // The token to paginate backwards from when a timeline is empty.
+        let timeline_batch = Batch {
+            room_key: since,
+            ..next_batch
         };
 
+        let account_data_since = Self::account_data_since(context);
+
+        let lazy_load_members = filter.lazy_load_members();
+
         for room_membership in room_memberships {
+            if room_membership.forgotten || !filter.includes_room(&room_membership.room_id) {
+                continue;
+            }
+
             match room_membership.membership.as_str() {
                 "join" => {
-                    let events: Vec<Event> =
+                    let room_events =
                         Event::find_room_events(connection, &room_membership.room_id, since)?;
 
-                    let room_state_events: Vec<Event> = if is_full_state {
+                    // The batch moves past the events the filter drops too, so that they are not
+                    // loaded again by every later sync.
+                    let latest_ordering = room_events
+                        .iter()
+                        .map(|event| event.ordering)
+                        .max()
+                        .unwrap_or(since);
+                    next_batch.room_key = cmp::max(latest_ordering, next_batch.room_key);
+
+                    let events: Vec<Event> = room_events
+                        .into_iter()
+                        .filter(|event| filter.timeline_matches(event))
+                        .collect();
+
+                    let room_state_events = if is_full_state {
                         Event::get_room_full_state(connection, &room_membership.room_id)?
                     } else {
                         Event::get_room_state_events_since(
@@ -293,45 +587,125 @@ impl Sync {
                         )?
                     };
 
-                    if events.is_empty() && room_state_events.is_empty() {
-                        continue;
+                    // The summary can only change with the members of the room.
+                    let summary = if room_state_events
+                        .iter()
+                        .any(|event| event.event_type == "m.room.member")
+                    {
+                        Self::get_room_summary(
+                            connection,
+                            &user.id,
+                            &room_membership.room_id,
+                            summary_since,
+                        )?
+                    } else {
+                        None
+                    };
+
+                    let mut room_state_events = filter.filter_state(room_state_events);
+
+                    if lazy_load_members {
+                        room_state_events.retain(|event| event.event_type != "m.room.member");
                     }
 
-                    let (ordering, timeline) =
-                        Self::convert_events_to_timeline(events, &timeline_filter)?;
-                    room_ordering = cmp::max(ordering, room_ordering);
+                    let typing_user_ids =
+                        typing.changes_since(&room_membership.room_id, typing_since);
 
-                    let state_events: Vec<StateEvent> = room_state_events
+                    let receipts = Receipt::find_room_receipts_since(
+                        connection,
+                        &room_membership.room_id,
+                        receipt_since,
+                    )?;
+                    let receipt_ordering = receipts
                         .iter()
-                        .cloned()
-                        .map(|e| e.try_into())
-                        .collect::<Result<Vec<StateEvent>, ApiError>>()?;
+                        .map(|receipt| receipt.ordering)
+                        .max()
+                        .unwrap_or(receipt_since);
+
+                    let ephemeral = filter.filter_ephemeral(
+                        &room_membership.room_id,
+                        Self::get_ephemeral_events(typing_user_ids, receipts)?,
+                    );
+
+                    let (account_data_ordering, account_data) = Self::get_room_account_data_events(
+                        connection,
+                        &user.id,
+                        &room_membership.room_id,
+                        filter,
+                        account_data_since,
+                    )?;
+
+                    if events.is_empty()
+                        && room_state_events.is_empty()
+                        && ephemeral.is_empty()
+                        && account_data.is_empty()
+                        && summary.is_none()
+                    {
+                        continue;
+                    }
+
+                    let unread_counts = push_rules.count_unread(connection, &room_membership)?;
+                    let unread_notifications = UnreadNotificationCounts {
+                        highlight_count: unread_counts.highlights,
+                        notification_count: unread_counts.notifications,
+                    };
+
+                    let (ordering, timeline, senders) =
+                        Self::convert_events_to_timeline(events, &user.id, filter, timeline_batch)?;
+                    next_batch.room_key = cmp::max(ordering, next_batch.room_key);
+
+                    if lazy_load_members {
+                        room_state_events.append(&mut Self::get_lazy_members(
+                            connection,
+                            filter,
+                            sent_members,
+                            &room_membership.room_id,
+                            senders,
+                            ordering,
+                        )?);
+                    }
+
+                    next_batch.receipt_key = cmp::max(receipt_ordering, next_batch.receipt_key);
+                    next_batch.account_data_key =
+                        cmp::max(account_data_ordering, next_batch.account_data_key);
 
                     join.insert(
                         room_membership.room_id,
                         JoinedRoom {
-                            unread_notifications: UnreadNotificationCounts {
-                                highlight_count: 0,
-                                notification_count: 0,
-                            },
+                            unread_notifications,
                             timeline,
                             state: Events {
-                                events: state_events,
+                                events: Self::format_state_events(filter, room_state_events)?,
+                            },
+                            account_data: Events {
+                                events: filter.format_events(account_data),
+                            },
+                            ephemeral: Events {
+                                events: filter.format_events(ephemeral),
                             },
-                            account_data: Events { events: Vec::new() },
-                            ephemeral: Events { events: Vec::new() },
+                            summary,
                         },
                     );
                 }
                 "invite" => {
+                    // A new invite has to move the batch forward, so that waiting syncs see it.
+                    let invite_event = Event::find(connection, &room_membership.event_id)?
+                        .expect("A room membership should be associated with an event");
+                    next_batch.room_key = cmp::max(invite_event.ordering, next_batch.room_key);
+
                     let room_state_events =
                         Event::get_room_full_state(connection, &room_membership.room_id)?;
 
-                    let state_events: Vec<StrippedState> = room_state_events
-                        .iter()
-                        .cloned()
-                        .map(|e| e.try_into())
-                        .collect::<Result<Vec<StrippedState>, ApiError>>()?;
+                    // Stripped state has no representation for redacted content.
+                    let state_events = room_state_events
+                        .into_iter()
+                        .filter(|e| e.redacted_by.is_none())
+                        .map(|e| {
+                            let stripped_state: StrippedState = e.try_into()?;
+
+                            Ok(filter.format_event(to_value(stripped_state)?))
+                        })
+                        .collect::<Result<Vec<Value>, ApiError>>()?;
 
                     invite.insert(
                         room_membership.room_id,
@@ -343,7 +717,7 @@ impl Sync {
                     );
                 }
                 "leave" | "ban" => {
-                    if !include_leave {
+                    if !filter.include_leave() {
                         continue;
                     }
 
@@ -354,29 +728,40 @@ impl Sync {
                         connection,
                         &room_membership.room_id,
                         last_event.ordering,
-                    )?;
+                    )?
+                    .into_iter()
+                    .filter(|event| filter.timeline_matches(event))
+                    .collect();
 
-                    let (ordering, timeline) =
-                        Self::convert_events_to_timeline(events, &timeline_filter)?;
-                    room_ordering = cmp::max(ordering, room_ordering);
+                    let (ordering, timeline, senders) =
+                        Self::convert_events_to_timeline(events, &user.id, filter, timeline_batch)?;
+                    next_batch.room_key = cmp::max(ordering, next_batch.room_key);
 
-                    let room_state_events = Event::get_room_state_events_until(
-                        connection,
-                        &room_membership.room_id,
-                        &last_event,
-                    )?;
-                    let state_events: Vec<StateEvent> = room_state_events
-                        .iter()
-                        .cloned()
-                        .map(|e| e.try_into())
-                        .collect::<Result<Vec<StateEvent>, ApiError>>()?;
+                    let mut room_state_events =
+                        filter.filter_state(Event::get_room_state_events_until(
+                            connection,
+                            &room_membership.room_id,
+                            last_event.ordering,
+                        )?);
+
+                    if lazy_load_members {
+                        room_state_events.retain(|event| event.event_type != "m.room.member");
+                        room_state_events.append(&mut Self::get_lazy_members(
+                            connection,
+                            filter,
+                            sent_members,
+                            &room_membership.room_id,
+                            senders,
+                            ordering,
+                        )?);
+                    }
 
                     leave.insert(
                         room_membership.room_id,
                         LeftRoom {
                             timeline,
                             state: Events {
-                                events: state_events,
+                                events: Self::format_state_events(filter, room_state_events)?,
                             },
                         },
                     );
@@ -386,7 +771,7 @@ impl Sync {
         }
 
         Ok((
-            room_ordering,
+            next_batch,
             Rooms {
                 join,
                 leave,
@@ -395,67 +780,169 @@ impl Sync {
         ))
     }
 
+    /// Return the summary of a joined room, or `None` if it is the same as at `since`.
+    fn get_room_summary(
+        connection: &PgConnection,
+        user_id: &UserId,
+        room_id: &RoomId,
+        since: Option<i64>,
+    ) -> Result<Option<RoomSummary>, ApiError> {
+        let memberships = RoomMembership::find_all_by_room(connection, room_id)?
+            .into_iter()
+            .map(|membership| (membership.user_id, membership.membership))
+            .collect();
+        let summary = RoomSummary::new(user_id, memberships);
+
+        if let Some(since) = since {
+            let mut previous_memberships = Vec::new();
+
+            for event in Event::get_room_state_at(connection, room_id, since)? {
+                if event.event_type == "m.room.member" {
+                    let member_event: MemberEvent = event.try_into()?;
+                    previous_memberships.push((
+                        UserId::try_from(member_event.state_key.as_str())?,
+                        member_event.content.membership.to_string(),
+                    ));
+                }
+            }
+
+            if RoomSummary::new(user_id, previous_memberships) == summary {
+                return Ok(None);
+            }
+        }
+
+        Ok(Some(summary))
+    }
+
+    /// Return the member events of the given timeline senders that the client still needs.
+    ///
+    /// The member events are taken from the state of the room at `ordering`, the latest event of
+    /// the timeline.
+    fn get_lazy_members(
+        connection: &PgConnection,
+        filter: &FilterEvaluator,
+        sent_members: &mut SentMembers,
+        room_id: &RoomId,
+        senders: Vec<UserId>,
+        ordering: i64,
+    ) -> Result<Vec<Event>, ApiError> {
+        let mut members = filter.filter_state(Event::get_room_members_at(
+            connection, room_id, &senders, ordering,
+        )?);
+        sent_members.send(room_id, &mut members, filter.include_redundant_members());
+
+        Ok(members)
+    }
+
+    /// Convert state events into the format requested by the filter.
+    fn format_state_events(
+        filter: &FilterEvaluator,
+        events: Vec<Event>,
+    ) -> Result<Vec<Value>, ApiError> {
+        events
+            .into_iter()
+            .map(|event| {
+                let state_event: StateEvent = event.try_into()?;
+
+                Ok(filter.format_event(to_value(state_event)?))
+            })
+            .collect()
+    }
+
+    /// Return the ephemeral events of a joined room.
+    fn get_ephemeral_events(
+        typing_user_ids: Option<Vec<UserId>>,
+        receipts: Vec<Receipt>,
+    ) -> Result<Vec<Value>, ApiError> {
+        let mut events = Vec::new();
+
+        if let Some(user_ids) = typing_user_ids {
+            let typing_event = TypingEvent {
+                content: TypingEventContent { user_ids },
+                event_type: EventType::Typing,
+                room_id: None,
+            };
+
+            events.push(to_value(typing_event)?);
+        }
+
+        if !receipts.is_empty() {
+            let mut content: ReceiptEventContent = HashMap::new();
+
+            for receipt in receipts {
+                content
+                    .entry(receipt.event_id)
+                    .or_insert_with(|| Receipts {
+                        m_read: HashMap::new(),
+                    })
+                    .m_read
+                    .insert(
+                        receipt.user_id,
+                        ReceiptInfo {
+                            ts: receipt.ts as u64,
+                        },
+                    );
+            }
+
+            let receipt_event = ReceiptEvent {
+                content,
+                event_type: EventType::Receipt,
+                room_id: None,
+            };
+
+            events.push(to_value(receipt_event)?);
+        }
+
+        Ok(events)
+    }
+
     /// Converting events in the correct format for timeline.
     ///
+    /// The events must already have passed the timeline filter, whose limit is applied here.
+    ///
     /// Also returns the max ordering from the given events that will be used
-    /// as the `next_batch` token.
+    /// as the `next_batch` token, and the senders of the returned events.
+    ///
+    /// The `prev_batch` token points just before the first returned event, or at `since` if
+    /// there are no events, so that it can be passed as `from` to `/rooms/:room_id/messages`.
     fn convert_events_to_timeline(
         events: Vec<Event>,
-        timeline_filter: &Option<RoomEventFilter>,
-    ) -> Result<(i64, Timeline), ApiError> {
+        user_id: &UserId,
+        filter: &FilterEvaluator,
+        since: Batch,
+    ) -> Result<(i64, Timeline, Vec<UserId>), ApiError> {
         let mut room_ordering = 0;
         let mut timeline_events = Vec::new();
+        let mut senders = Vec::new();
         let mut limited = false;
 
         let length = events.len();
 
-        let count = match *timeline_filter {
-            None => 0,
-            Some(ref filter) => match filter.limit {
-                0 => 0,
-                x => {
-                    if length > x {
-                        limited = true;
-                        length - x
-                    } else {
-                        0
-                    }
+        let count = match filter.timeline_limit() {
+            0 => 0,
+            x => {
+                if length > x {
+                    limited = true;
+                    length - x
+                } else {
+                    0
                 }
-            },
+            }
         };
 
-        for event in events.into_iter().skip(count) {
-            room_ordering = cmp::max(room_ordering, event.ordering);
+        let mut prev_batch = since;
 
-            let value = match EventType::from(event.event_type.as_ref()) {
-                EventType::CallAnswer => RoomEvent::CallAnswer(event.try_into()?),
-                EventType::CallCandidates => RoomEvent::CallCandidates(event.try_into()?),
-                EventType::CallHangup => RoomEvent::CallHangup(event.try_into()?),
-                EventType::CallInvite => RoomEvent::CallInvite(event.try_into()?),
-                EventType::RoomAliases => RoomEvent::RoomAliases(event.try_into()?),
-                EventType::RoomAvatar => RoomEvent::RoomAvatar(event.try_into()?),
-                EventType::RoomCanonicalAlias => RoomEvent::RoomCanonicalAlias(event.try_into()?),
-                EventType::RoomCreate => RoomEvent::RoomCreate(event.try_into()?),
-                EventType::RoomGuestAccess => RoomEvent::RoomGuestAccess(event.try_into()?),
-                EventType::RoomHistoryVisibility => {
-                    RoomEvent::RoomHistoryVisibility(event.try_into()?)
-                }
-                EventType::RoomJoinRules => RoomEvent::RoomJoinRules(event.try_into()?),
-                EventType::RoomMember => RoomEvent::RoomMember(event.try_into()?),
-                EventType::RoomMessage => RoomEvent::RoomMessage(event.try_into()?),
-                EventType::RoomName => RoomEvent::RoomName(event.try_into()?),
-                EventType::RoomPowerLevels => RoomEvent::RoomPowerLevels(event.try_into()?),
-                EventType::RoomThirdPartyInvite => {
-                    RoomEvent::RoomThirdPartyInvite(event.try_into()?)
-                }
-                EventType::RoomTopic => RoomEvent::RoomTopic(event.try_into()?),
-                _ => {
-                    println!("unhandled {:?}", event.event_type);
-                    continue;
-                }
-            };
+        for (index, event) in events.into_iter().skip(count).enumerate() {
+            if index == 0 {
+                prev_batch.room_key = event.ordering - 1;
+            }
+            room_ordering = cmp::max(room_ordering, event.ordering);
+            if !senders.contains(&event.sender) {
+                senders.push(event.sender.clone());
+            }
 
-            timeline_events.push(value);
+            let room_event: RoomEvent = event.for_user(user_id).try_into()?;
+            timeline_events.push(filter.format_event(to_value(room_event)?));
         }
 
         Ok((
@@ -463,33 +950,43 @@ impl Sync {
             Timeline {
                 events: timeline_events,
                 limited,
-                prev_batch: String::from(""),
+                prev_batch: prev_batch.to_string(),
             },
+            senders,
         ))
     }
 }
 
 #[test]
 fn batch_to_str() {
-    let batch = Batch::new(10, 10);
-    assert_eq!(batch.to_string(), String::from("10_10"));
+    let batch = Batch::new(10, 10, 3, 4, 5);
+    assert_eq!(batch.to_string(), String::from("10_10_3_4_5"));
 }
 
 #[test]
 fn batch_parse() {
-    let batch = Batch::from_str("10_12").unwrap();
+    let batch = Batch::from_str("10_12_3_4_5").unwrap();
     assert_eq!(batch.room_key, 10);
     assert_eq!(batch.presence_key, 12);
+    assert_eq!(batch.typing_key, 3);
+    assert_eq!(batch.receipt_key, 4);
+    assert_eq!(batch.account_data_key, 5);
 }
 
 #[test]
 fn batch_parse_non_number() {
-    let batch = Batch::from_str("10_12a");
+    let batch = Batch::from_str("10_12_3_4_5a");
+    assert!(batch.is_err());
+}
+
+#[test]
+fn batch_parse_too_few() {
+    let batch = Batch::from_str("10_12_3_4");
     assert!(batch.is_err());
 }
 
 #[test]
 fn batch_parse_too_many() {
-    let batch = Batch::from_str("10_12_12");
+    let batch = Batch::from_str("10_12_3_4_5_6");
     assert!(batch.is_err());
 }
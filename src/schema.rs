@@ -21,6 +21,7 @@ table! {
         user_id -> Text,
         data_type -> Text,
         content -> Text,
+        ordering -> BigInt,
     }
 }
 
@@ -34,6 +35,11 @@ table! {
         state_key -> Nullable<Text>,
         content -> Text,
         created_at -> Timestamp,
+        redacts -> Nullable<Text>,
+        redacted_by -> Nullable<Text>,
+        transaction_id -> Nullable<Text>,
+        replaces_state -> Nullable<Text>,
+        prev_content -> Nullable<Text>,
     }
 }
 
@@ -64,6 +70,7 @@ table! {
         sender -> Text,
         membership -> Text,
         created_at -> Timestamp,
+        forgotten -> Bool,
     }
 }
 
@@ -93,6 +100,15 @@ table! {
         room_id -> Text,
         data_type -> Text,
         content -> Text,
+        ordering -> BigInt,
+    }
+}
+
+table! {
+    room_tag_changes (user_id, room_id) {
+        user_id -> Text,
+        room_id -> Text,
+        ordering -> BigInt,
     }
 }
 
@@ -153,6 +169,43 @@ table! {
     }
 }
 
+table! {
+    media(media_id) {
+        media_id -> Text,
+        user_id -> Text,
+        content_type -> Text,
+        upload_name -> Nullable<Text>,
+        content_length -> BigInt,
+        created_at -> Timestamp,
+    }
+}
+
+table! {
+    push_rules(user_id, kind, rule_id) {
+        user_id -> Text,
+        kind -> Text,
+        rule_id -> Text,
+        priority -> Integer,
+        is_default -> Bool,
+        enabled -> Bool,
+        conditions -> Text,
+        pattern -> Nullable<Text>,
+        actions -> Text,
+    }
+}
+
+table! {
+    receipts(room_id, user_id, receipt_type) {
+        room_id -> Text,
+        user_id -> Text,
+        receipt_type -> Text,
+        event_id -> Text,
+        event_ordering -> BigInt,
+        ts -> BigInt,
+        ordering -> BigSerial,
+    }
+}
+
 // Diesel macros needed to enable queries with multiple tables involving foreign key relationships.
 
 allow_tables_to_appear_in_same_query!(events, room_memberships);
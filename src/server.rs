@@ -1,6 +1,7 @@
 //! Iron web server that serves the API.
 
 use std::fmt::{Debug, Formatter, Result as FmtResult};
+use std::sync::Arc;
 
 use diesel::pg::PgConnection;
 use diesel::r2d2::{Builder, ConnectionManager, Pool};
@@ -12,19 +13,28 @@ use persistent::{Read, Write};
 use router::Router;
 
 use crate::api::r0::{
-    AccountPassword, CreateRoom, DeactivateAccount, DeleteRoomAlias, DeleteTag, GetAvatarUrl,
-    GetDisplayName, GetFilter, GetPresenceList, GetPresenceStatus, GetPushers, GetRoomAlias,
-    GetTags, InviteToRoom, JoinRoom, JoinRoomWithIdOrAlias, KickFromRoom, LeaveRoom, Login, Logout,
-    Members, PostFilter, PostPresenceList, Profile, PutAccountData, PutAvatarUrl, PutDisplayName,
-    PutPresenceStatus, PutRoomAccountData, PutRoomAlias, PutTag, Register, RoomState,
-    SendMessageEvent, SetPushers, StateMessageEvent, Sync, Versions,
+    AccountPassword, BanFromRoom, CreateRoom, DeactivateAccount, DeletePushRule, DeleteRoomAlias,
+    DeleteTag, DownloadMedia, EventContext, ForgetRoom, GetAvatarUrl, GetDisplayName, GetFilter,
+    GetPresenceList, GetPresenceStatus, GetPublicRooms, GetPushRule, GetPushRuleActions,
+    GetPushRuleEnabled, GetPushRules, GetPushers, GetRoomAlias, GetRoomVisibility,
+    GetScopedPushRules, GetStateEvent, GetTags, GetThumbnail, InviteToRoom, JoinRoom,
+    JoinRoomWithIdOrAlias, KickFromRoom, LeaveRoom, Login, Logout, Members, Messages, PostFilter,
+    PostPresenceList, PostPublicRooms, PostReceipt, Profile, PutAccountData, PutAvatarUrl,
+    PutDisplayName, PutPresenceStatus, PutPushRule, PutPushRuleActions, PutPushRuleEnabled,
+    PutRoomAccountData, PutRoomAlias, PutRoomVisibility, PutTag, PutTyping, RedactEvent, Register,
+    RoomState, Search, SendMessageEvent, SetPushers, StateMessageEvent, Sync, UnbanFromRoom,
+    UploadMedia, Versions,
 };
 use crate::config::Config;
 use crate::db::DB;
 use crate::embedded_migrations::run as run_pending_migrations;
 use crate::error::{ApiError, CliError};
-use crate::middleware::{MiddlewareChain, ResponseHeaders};
+use crate::lazy_loading::LazyLoading;
+use crate::middleware::{MiddlewareChain, NotifySync, ResponseHeaders};
+use crate::notifier::Notifier;
+use crate::push_gateway::PushWorker;
 use crate::swagger::Swagger;
+use crate::typing::Typing;
 
 /// Ruma's web server.
 pub struct Server<'a> {
@@ -97,6 +107,16 @@ impl<'a> Server<'a> {
             PutRoomAlias::chain(),
             "put_room_alias",
         );
+        r0_router.get(
+            "/directory/list/room/:room_id",
+            GetRoomVisibility::chain(),
+            "get_room_visibility",
+        );
+        r0_router.put(
+            "/directory/list/room/:room_id",
+            PutRoomVisibility::chain(),
+            "put_room_visibility",
+        );
         r0_router.post("/login", Login::chain(), "login");
         r0_router.post("/logout", Logout::chain(), "logout");
         r0_router.post("/register", Register::chain(), "register");
@@ -126,6 +146,11 @@ impl<'a> Server<'a> {
             StateMessageEvent::chain(),
             "state_message_event_with_key",
         );
+        r0_router.put(
+            "/rooms/:room_id/redact/:event_id/:transaction_id",
+            RedactEvent::chain(),
+            "redact_event",
+        );
         r0_router.post("/rooms/:room_id/join", JoinRoom::chain(), "join_room");
         r0_router.post(
             "/rooms/:room_id/invite",
@@ -142,13 +167,56 @@ impl<'a> Server<'a> {
             KickFromRoom::chain(),
             "kick_from_room",
         );
+        r0_router.post("rooms/:room_id/ban", BanFromRoom::chain(), "ban_from_room");
+        r0_router.post(
+            "rooms/:room_id/unban",
+            UnbanFromRoom::chain(),
+            "unban_from_room",
+        );
         r0_router.post("rooms/:room_id/leave", LeaveRoom::chain(), "leave_room");
+        r0_router.post("rooms/:room_id/forget", ForgetRoom::chain(), "forget_room");
         r0_router.get("/rooms/:room_id/members", Members::chain(), "members");
+        r0_router.get("/publicRooms", GetPublicRooms::chain(), "get_public_rooms");
+        r0_router.post(
+            "/publicRooms",
+            PostPublicRooms::chain(),
+            "post_public_rooms",
+        );
+        r0_router.get(
+            "/rooms/:room_id/context/:event_id",
+            EventContext::chain(),
+            "get_event_context",
+        );
+        r0_router.get(
+            "/rooms/:room_id/messages",
+            Messages::chain(),
+            "get_messages",
+        );
         r0_router.get(
             "/rooms/:room_id/state",
             RoomState::chain(),
             "get_room_state",
         );
+        r0_router.get(
+            "/rooms/:room_id/state/:event_type",
+            GetStateEvent::chain(),
+            "get_state_event",
+        );
+        r0_router.get(
+            "/rooms/:room_id/state/:event_type/:state_key",
+            GetStateEvent::chain(),
+            "get_state_event_with_key",
+        );
+        r0_router.post(
+            "/rooms/:room_id/receipt/:receipt_type/:event_id",
+            PostReceipt::chain(),
+            "post_receipt",
+        );
+        r0_router.put(
+            "/rooms/:room_id/typing/:user_id",
+            PutTyping::chain(),
+            "put_typing",
+        );
         r0_router.get("/profile/:user_id", Profile::chain(), "profile");
         r0_router.get(
             "/profile/:user_id/avatar_url",
@@ -191,6 +259,7 @@ impl<'a> Server<'a> {
             "get_filter",
         );
         r0_router.post("/user/:user_id/filter", PostFilter::chain(), "post_filter");
+        r0_router.post("/search", Search::chain(), "search");
         r0_router.get("/sync", Sync::chain(), "sync");
         r0_router.get(
             "/presence/:user_id/status",
@@ -214,6 +283,57 @@ impl<'a> Server<'a> {
         );
         r0_router.get("/pushers", GetPushers::chain(), "pushers");
         r0_router.post("/pushers/set", SetPushers::chain(), "set_pushers");
+        r0_router.get("/pushrules", GetPushRules::chain(), "get_push_rules");
+        r0_router.get(
+            "/pushrules/",
+            GetPushRules::chain(),
+            "get_push_rules_with_slash",
+        );
+        r0_router.get(
+            "/pushrules/:scope",
+            GetScopedPushRules::chain(),
+            "get_scoped_push_rules",
+        );
+        r0_router.get(
+            "/pushrules/:scope/",
+            GetScopedPushRules::chain(),
+            "get_scoped_push_rules_with_slash",
+        );
+        r0_router.get(
+            "/pushrules/:scope/:kind/:rule_id",
+            GetPushRule::chain(),
+            "get_push_rule",
+        );
+        r0_router.put(
+            "/pushrules/:scope/:kind/:rule_id",
+            PutPushRule::chain(),
+            "put_push_rule",
+        );
+        r0_router.delete(
+            "/pushrules/:scope/:kind/:rule_id",
+            DeletePushRule::chain(),
+            "delete_push_rule",
+        );
+        r0_router.get(
+            "/pushrules/:scope/:kind/:rule_id/enabled",
+            GetPushRuleEnabled::chain(),
+            "get_push_rule_enabled",
+        );
+        r0_router.put(
+            "/pushrules/:scope/:kind/:rule_id/enabled",
+            PutPushRuleEnabled::chain(),
+            "put_push_rule_enabled",
+        );
+        r0_router.get(
+            "/pushrules/:scope/:kind/:rule_id/actions",
+            GetPushRuleActions::chain(),
+            "get_push_rule_actions",
+        );
+        r0_router.put(
+            "/pushrules/:scope/:kind/:rule_id/actions",
+            PutPushRuleActions::chain(),
+            "put_push_rule_actions",
+        );
 
         let mut r0 = Chain::new(r0_router);
 
@@ -230,8 +350,41 @@ impl<'a> Server<'a> {
             run_pending_migrations(&*connection).map_err(CliError::from)?;
         }
 
+        let mut media_router = Router::new();
+
+        media_router.post("/upload", UploadMedia::chain(), "upload_media");
+        media_router.get(
+            "/download/:server_name/:media_id",
+            DownloadMedia::chain(),
+            "download_media",
+        );
+        media_router.get(
+            "/download/:server_name/:media_id/:file_name",
+            DownloadMedia::chain(),
+            "download_media_with_file_name",
+        );
+        media_router.get(
+            "/thumbnail/:server_name/:media_id",
+            GetThumbnail::chain(),
+            "get_thumbnail",
+        );
+
+        let mut media = Chain::new(media_router);
+
+        media.link_before(Read::<Config>::one(self.config.clone()));
+        media.link_before(Write::<DB>::one(connection_pool.clone()));
+        media.link_after(ResponseHeaders);
+
         r0.link_before(Read::<Config>::one(self.config.clone()));
+        let notifier = Arc::new(Notifier::new(self.config.worker_threads / 2));
+
+        PushWorker::spawn(connection_pool.clone(), &notifier)?;
+
         r0.link_before(Write::<DB>::one(connection_pool));
+        r0.link_before(Read::<Notifier>::one(notifier));
+        r0.link_before(Read::<Typing>::one(Typing::new()));
+        r0.link_before(Read::<LazyLoading>::one(LazyLoading::new()));
+        r0.link_after(NotifySync);
         r0.link_after(ResponseHeaders);
 
         let mut versions_router = Router::new();
@@ -243,6 +396,7 @@ impl<'a> Server<'a> {
 
         self.mount.mount("/_matrix/client/", versions);
         self.mount.mount("/_matrix/client/r0/", r0);
+        self.mount.mount("/_matrix/media/r0/", media);
 
         Ok(self)
     }
@@ -260,7 +414,8 @@ impl<'a> Server<'a> {
 
         info!("Starting Ruma server on {}.", address);
 
-        let iron = Iron::new(self.mount);
+        let mut iron = Iron::new(self.mount);
+        iron.threads = self.config.worker_threads;
 
         iron.http(&address[..])
     }
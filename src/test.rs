@@ -1,4 +1,5 @@
 use std::convert::TryFrom;
+use std::env;
 use std::fmt::{Debug, Formatter, Result as FmtResult};
 use std::sync::Once;
 
@@ -114,11 +115,14 @@ impl Test {
         });
 
         let config = Config {
+            access_token_lifetime: 3600,
             bind_address: "127.0.0.1".to_string(),
             bind_port: "0".to_string(),
             domain: "ruma.test".to_string(),
             macaroon_secret_key: "YymznQHmKdN9B4f7iBalJB1tWEDy9LdaFSQJEtB3R5w=".into(),
+            media_directory: env::temp_dir().join("ruma_test_media"),
             postgres_url: DATABASE_URL.to_string(),
+            worker_threads: 8,
         };
 
         let r2d2_pool_builder = Pool::builder()
@@ -161,6 +165,17 @@ impl Test {
 
         headers.set(ContentType::json());
 
+        self.request_with_headers(method, path, body, headers)
+    }
+
+    /// Makes a request to the server with the given headers.
+    pub fn request_with_headers(
+        &self,
+        method: Method,
+        path: &str,
+        body: &str,
+        headers: Headers,
+    ) -> Response {
         let response = match request::request(
             method,
             &format!("http://ruma.test{}", path)[..],
@@ -274,6 +289,38 @@ impl Test {
         self.post(&path, &body)
     }
 
+    /// Ban a `User` from a `Room`.
+    pub fn ban_from_room(
+        &self,
+        access_token: &str,
+        room_id: &str,
+        user_id: &str,
+        reason: Option<&str>,
+    ) -> Response {
+        let body = format!(
+            r#"{{"user_id": "{}", "reason": "{}"}}"#,
+            user_id,
+            reason.unwrap_or("")
+        );
+        let path = format!(
+            "/_matrix/client/r0/rooms/{}/ban?access_token={}",
+            room_id, access_token
+        );
+
+        self.post(&path, &body)
+    }
+
+    /// Unban a `User` from a `Room`.
+    pub fn unban_from_room(&self, access_token: &str, room_id: &str, user_id: &str) -> Response {
+        let body = format!(r#"{{"user_id": "{}"}}"#, user_id);
+        let path = format!(
+            "/_matrix/client/r0/rooms/{}/unban?access_token={}",
+            room_id, access_token
+        );
+
+        self.post(&path, &body)
+    }
+
     /// Look up a `RoomId` using an alias.
     pub fn get_room_by_alias(&self, alias: &str) -> Response {
         self.get(&format!("/_matrix/client/r0/directory/room/{}", alias))
@@ -299,6 +346,16 @@ impl Test {
         self.post(&leave_room_path, "{}")
     }
 
+    /// Forget a room.
+    pub fn forget_room(&self, access_token: &str, room_id: &str) -> Response {
+        let forget_room_path = format!(
+            "/_matrix/client/r0/rooms/{}/forget?access_token={}",
+            room_id, access_token
+        );
+
+        self.post(&forget_room_path, "{}")
+    }
+
     /// Create tag
     pub fn create_tag(
         &self,
@@ -367,6 +424,52 @@ impl Test {
         self.put(&state_event_path, event_content)
     }
 
+    /// Upload content to the media repository.
+    pub fn upload_media(
+        &self,
+        access_token: &str,
+        content_type: &str,
+        filename: Option<&str>,
+        content: &str,
+    ) -> Response {
+        let mut upload_path = format!("/_matrix/media/r0/upload?access_token={}", access_token);
+
+        if let Some(filename) = filename {
+            upload_path.push_str(&format!("&filename={}", filename));
+        }
+
+        let mut headers = Headers::new();
+        headers.set_raw("Content-Type", vec![content_type.as_bytes().to_vec()]);
+
+        self.request_with_headers(Method::Post, &upload_path, content, headers)
+    }
+
+    /// Send a read receipt for an event, using the opaque ID returned when it was sent.
+    pub fn send_read_receipt(&self, access_token: &str, room_id: &str, event_id: &str) -> Response {
+        let receipt_path = format!(
+            "/_matrix/client/r0/rooms/{}/receipt/m.read/${}:ruma.test?access_token={}",
+            room_id, event_id, access_token
+        );
+
+        self.post(&receipt_path, "{}")
+    }
+
+    /// Set whether a user is typing in a room.
+    pub fn set_typing(
+        &self,
+        access_token: &str,
+        room_id: &str,
+        user_id: &str,
+        body: &str,
+    ) -> Response {
+        let typing_path = format!(
+            "/_matrix/client/r0/rooms/{}/typing/{}?access_token={}",
+            room_id, user_id, access_token
+        );
+
+        self.put(&typing_path, body)
+    }
+
     /// Create a User and Room.
     pub fn initial_fixtures(&self, body: &str) -> (TestUser, String) {
         let user = self.create_user();
@@ -0,0 +1,295 @@
+//! Typing notifications.
+//!
+//! Typing notifications are ephemeral, so they are only kept in memory.
+
+use std::cmp;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use iron::typemap::Key;
+use iron::{Plugin, Request};
+use persistent::Read;
+use ruma_identifiers::{RoomId, UserId};
+
+use crate::error::ApiError;
+
+/// The users currently typing in each room.
+#[derive(Debug)]
+pub struct Typing {
+    /// The state shared between requests.
+    state: Mutex<TypingState>,
+    /// The position when the server started, before any change.
+    ///
+    /// Positions are seeded from the clock so that they keep increasing across restarts.
+    started_at: i64,
+}
+
+/// The typing state of all rooms.
+#[derive(Debug, Default)]
+struct TypingState {
+    /// The position of the last change in any room.
+    position: i64,
+    /// The typing state of every room where someone has typed.
+    rooms: HashMap<RoomId, RoomTyping>,
+}
+
+/// The typing state of a room.
+#[derive(Debug, Default)]
+struct RoomTyping {
+    /// The position at which the set of typing users last changed.
+    changed_at: i64,
+    /// The users currently typing, and when they stop typing.
+    users: HashMap<UserId, Instant>,
+}
+
+impl Typing {
+    /// Create an empty `Typing`.
+    pub fn new() -> Self {
+        let started_at = now_millis();
+
+        Self {
+            state: Mutex::new(TypingState {
+                position: started_at,
+                rooms: HashMap::new(),
+            }),
+            started_at,
+        }
+    }
+
+    /// Extract the `Typing` stored in the request.
+    pub fn from_request(request: &mut Request<'_, '_>) -> Result<Arc<Self>, ApiError> {
+        request.get::<Read<Self>>().map_err(ApiError::from)
+    }
+
+    /// Mark a user as typing in a room for the given time, or as no longer typing.
+    pub fn set_typing(&self, room_id: &RoomId, user_id: &UserId, timeout: Option<Duration>) {
+        let now = Instant::now();
+        let mut state = self.lock(now);
+        let position = state.next_position();
+        let room = state.rooms.entry(room_id.clone()).or_default();
+
+        // Only changes to the set of typing users are reported, not extended timeouts.
+        let changed = match timeout {
+            Some(timeout) => room.users.insert(user_id.clone(), now + timeout).is_none(),
+            None => room.users.remove(user_id).is_some(),
+        };
+
+        if changed {
+            room.changed_at = position;
+            state.position = position;
+        }
+    }
+
+    /// The position of the last change in the given rooms, to be used as the typing component
+    /// of a sync batch.
+    ///
+    /// Changes in other rooms do not move the position, so that they do not make a sync look
+    /// like it has new data.
+    pub fn position(&self, room_ids: &[RoomId]) -> i64 {
+        let state = self.lock(Instant::now());
+
+        room_ids
+            .iter()
+            .filter_map(|room_id| state.rooms.get(room_id))
+            .map(|room| room.changed_at)
+            .fold(self.started_at, cmp::max)
+    }
+
+    /// The users typing in a room, if they changed after the position `since`.
+    ///
+    /// Without `since`, the users are returned if anybody is typing. A position from before the
+    /// server started may have missed any change, so the current users are returned for it.
+    pub fn changes_since(&self, room_id: &RoomId, since: Option<i64>) -> Option<Vec<UserId>> {
+        let state = self.lock(Instant::now());
+        let room = state.rooms.get(room_id);
+
+        let changed = match (since, room) {
+            (Some(since), _) if since < self.started_at => true,
+            (Some(since), Some(room)) => room.changed_at > since,
+            (None, Some(room)) => !room.users.is_empty(),
+            (_, None) => false,
+        };
+
+        if !changed {
+            return None;
+        }
+
+        let mut user_ids: Vec<UserId> = room
+            .map(|room| room.users.keys().cloned().collect())
+            .unwrap_or_default();
+        user_ids.sort_by_key(ToString::to_string);
+
+        Some(user_ids)
+    }
+
+    /// The time at which the next user stops typing in the given rooms, if anybody is typing.
+    pub fn next_expiry(&self, room_ids: &[RoomId]) -> Option<Instant> {
+        let state = self.lock(Instant::now());
+
+        room_ids
+            .iter()
+            .filter_map(|room_id| state.rooms.get(room_id))
+            .flat_map(|room| room.users.values())
+            .min()
+            .cloned()
+    }
+
+    /// Lock the state, after removing the users who stopped typing before `now`.
+    fn lock(&self, now: Instant) -> MutexGuard<'_, TypingState> {
+        let mut state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+        let position = state.next_position();
+        let mut changed = false;
+
+        for room in state.rooms.values_mut() {
+            let typing_users = room.users.len();
+
+            room.users.retain(|_, expires_at| *expires_at > now);
+
+            if room.users.len() != typing_users {
+                room.changed_at = position;
+                changed = true;
+            }
+        }
+
+        if changed {
+            state.position = position;
+        }
+
+        state
+    }
+}
+
+impl Default for Typing {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl TypingState {
+    /// The position for the next change, which is always after the previous one.
+    fn next_position(&self) -> i64 {
+        cmp::max(self.position + 1, now_millis())
+    }
+}
+
+/// The current time, in milliseconds since the Unix epoch.
+fn now_millis() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_millis() as i64)
+        .unwrap_or(0)
+}
+
+impl Key for Typing {
+    type Value = Self;
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::TryFrom;
+    use std::thread;
+    use std::time::Duration;
+
+    use ruma_identifiers::{RoomId, UserId};
+
+    use super::Typing;
+
+    fn room_id() -> RoomId {
+        RoomId::try_from("!room:ruma.test").unwrap()
+    }
+
+    fn user_id(localpart: &str) -> UserId {
+        UserId::try_from(format!("@{}:ruma.test", localpart).as_str()).unwrap()
+    }
+
+    #[test]
+    fn only_changes_move_the_position() {
+        let typing = Typing::new();
+        let timeout = Some(Duration::from_secs(30));
+        let rooms = [room_id()];
+
+        typing.set_typing(&room_id(), &user_id("alice"), timeout);
+        let position = typing.position(&rooms);
+
+        typing.set_typing(&room_id(), &user_id("alice"), timeout);
+        assert_eq!(typing.position(&rooms), position);
+        assert_eq!(typing.changes_since(&room_id(), Some(position)), None);
+
+        typing.set_typing(&room_id(), &user_id("bob"), timeout);
+        assert!(typing.position(&rooms) > position);
+        assert_eq!(
+            typing.changes_since(&room_id(), Some(position)),
+            Some(vec![user_id("alice"), user_id("bob")])
+        );
+
+        let position = typing.position(&rooms);
+
+        typing.set_typing(&room_id(), &user_id("alice"), None);
+        assert_eq!(
+            typing.changes_since(&room_id(), Some(position)),
+            Some(vec![user_id("bob")])
+        );
+    }
+
+    #[test]
+    fn changes_in_other_rooms_do_not_move_the_position() {
+        let typing = Typing::new();
+        let other_room_id = RoomId::try_from("!other:ruma.test").unwrap();
+        let position = typing.position(&[room_id()]);
+
+        typing.set_typing(
+            &other_room_id,
+            &user_id("alice"),
+            Some(Duration::from_secs(30)),
+        );
+
+        assert_eq!(typing.position(&[room_id()]), position);
+        assert!(typing.position(&[other_room_id.clone()]) > position);
+        assert!(typing.next_expiry(&[room_id()]).is_none());
+        assert!(typing.next_expiry(&[other_room_id]).is_some());
+    }
+
+    #[test]
+    fn positions_from_before_a_restart_get_the_current_state() {
+        let old_position = Typing::new().position(&[room_id()]);
+
+        thread::sleep(Duration::from_millis(10));
+
+        let typing = Typing::new();
+
+        assert!(typing.position(&[room_id()]) > old_position);
+        assert_eq!(
+            typing.changes_since(&room_id(), Some(old_position)),
+            Some(Vec::new())
+        );
+    }
+
+    #[test]
+    fn typing_expires() {
+        let typing = Typing::new();
+        let rooms = [room_id()];
+
+        typing.set_typing(
+            &room_id(),
+            &user_id("alice"),
+            Some(Duration::from_millis(50)),
+        );
+        let position = typing.position(&rooms);
+
+        assert!(typing.next_expiry(&rooms).is_some());
+        assert_eq!(
+            typing.changes_since(&room_id(), None),
+            Some(vec![user_id("alice")])
+        );
+
+        thread::sleep(Duration::from_millis(100));
+
+        assert!(typing.next_expiry(&rooms).is_none());
+        assert_eq!(typing.changes_since(&room_id(), None), None);
+        assert_eq!(
+            typing.changes_since(&room_id(), Some(position)),
+            Some(Vec::new())
+        );
+    }
+}